@@ -1,4 +1,4 @@
-use std::sync::{MutexGuard, TryLockResult};
+use std::sync::{MutexGuard, TryLockError, TryLockResult};
 
 /// A simple spin mutex implementation.
 /// Atomic waits panic on the main thread, so this is only safe to use in the user space.
@@ -10,6 +10,11 @@ pub trait SpinMutex {
     /// ### Errors
     /// - [`TryLockError::Poisoned`] When the lock is poisoned
     fn spin_lock(&self) -> TryLockResult<MutexGuard<Self::Inner>>;
+
+    /// Spin on the lock without sleeping, recovering the guard if the lock is poisoned.
+    /// Unlike [`SpinMutex::spin_lock`] this can be called in the kernel,
+    /// so it's meant for locks that are only held briefly but taken often, like log buffers.
+    fn busy_lock(&self) -> MutexGuard<'_, Self::Inner>;
 }
 
 impl<T> SpinMutex for std::sync::Mutex<T> {
@@ -31,4 +36,14 @@ impl<T> SpinMutex for std::sync::Mutex<T> {
             }
         }
     }
+
+    fn busy_lock(&self) -> MutexGuard<'_, Self::Inner> {
+        loop {
+            match self.try_lock() {
+                Ok(guard) => return guard,
+                Err(TryLockError::Poisoned(error)) => return error.into_inner(),
+                Err(TryLockError::WouldBlock) => std::hint::spin_loop(),
+            }
+        }
+    }
 }
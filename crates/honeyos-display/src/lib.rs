@@ -49,6 +49,8 @@ pub struct Display {
     pub updated: bool,
     // The text mode context
     text_mode: TextMode,
+    // The process whose output was last appended to the text mode, and the offset it was appended up to
+    output_cursor: Option<(Uuid, u64)>,
 }
 
 impl Display {
@@ -88,6 +90,7 @@ impl Display {
                     control: Control::None,
                     updated: false,
                     text_mode: TextMode::new(width as u32, height as u32),
+                    output_cursor: None,
                 })))
             }
         });
@@ -166,8 +169,24 @@ impl Display {
         &self.text_mode
     }
 
-    /// Aquire text mode context
+    /// Aquire text mode context.
+    /// Forgets the output appended to the text mode, as it may be overwritten.
     pub fn text_mode_mut(&mut self) -> &mut TextMode {
+        self.output_cursor = None;
+        &mut self.text_mode
+    }
+
+    /// Get the offset up to which the output of a process has been appended to the text mode.
+    /// Returns `None` if the text mode has been written to in any other way since.
+    pub fn output_cursor(&self, pid: Uuid) -> Option<u64> {
+        self.output_cursor
+            .filter(|(owner, _)| *owner == pid)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Aquire text mode context to append the output of a process, up to an offset
+    pub fn text_mode_output(&mut self, pid: Uuid, offset: u64) -> &mut TextMode {
+        self.output_cursor = Some((pid, offset));
         &mut self.text_mode
     }
 
@@ -214,6 +233,7 @@ fn register_callbacks(window: &Window) {
                 let (width, height) = (root.client_width(), root.client_height());
 
                 display.text_mode.resize(width as u32, height as u32);
+                display.output_cursor = None;
             })
             .into_js_value()
             .unchecked_ref(),
@@ -1,6 +1,6 @@
 use uuid::Uuid;
 
-use crate::error::Error;
+use crate::{error::Error, FileResult};
 
 /// The trait for a file system handler
 pub trait FsHandler {
@@ -8,6 +8,8 @@ pub trait FsHandler {
     fn get_file(&self, path: &str) -> Result<Uuid, Error>;
     /// Get a directory at the path. Return it's id
     fn get_directory(&self, path: &str) -> Result<Uuid, Error>;
+    /// Get the path of a directory from it's id
+    fn directory_path(&self, dir: Uuid) -> Result<String, Error>;
 
     /// Create a file at the path. Return it's id.
     fn create_file(&mut self, path: &str) -> Result<Uuid, Error>;
@@ -31,4 +33,10 @@ pub trait FsHandler {
     fn file_size(&self, file: Uuid) -> Result<usize, Error>;
     /// Write data to a file
     fn write(&mut self, file: Uuid, at: usize, data: &[u8]) -> Result<(), Error>;
+    /// Resize a file, padding it with zeroes if it grows
+    fn truncate(&mut self, file: Uuid, size: usize) -> Result<(), Error>;
+
+    /// List the names of the files and directories in a directory.
+    /// An empty path lists the root of the file system.
+    fn list_directory(&self, path: &str) -> Result<Vec<(String, FileResult)>, Error>;
 }
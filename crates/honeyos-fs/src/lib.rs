@@ -76,6 +76,19 @@ impl FsManager {
         Ok(())
     }
 
+    /// Get the labels of all mounted file systems, in alphabetical order.
+    /// Blocks until the fs is available.
+    pub fn labels(&self) -> Vec<FsLabel> {
+        loop {
+            let Ok(handlers) = self.handlers.try_read() else {
+                continue;
+            };
+            let mut labels = handlers.keys().copied().collect::<Vec<_>>();
+            labels.sort_by_key(|label| *label as u8);
+            return labels;
+        }
+    }
+
     /// Get a file system.
     /// Blocks until the fs is available.
     pub fn get_fs(&self, label: FsLabel) -> Result<Arc<RwLock<dyn FsHandler>>, Error> {
@@ -114,10 +127,10 @@ impl FsManager {
 impl FsLabel {
     /// Extract the fs label from a path
     pub fn extract_from_path(path: &str) -> Result<Self, Error> {
-        let (fs_label_str, _) = path.split_at(3);
-        if !path.contains(':') {
+        if !path.contains(':') || path.len() < 3 {
             return Err(Error::NoFsLabel(path.to_owned()));
         }
+        let (fs_label_str, _) = path.split_at(3);
 
         let fs_char = fs_label_str
             .get(0..1)
@@ -1,7 +1,7 @@
 use hashbrown::HashMap;
 use uuid::Uuid;
 
-use crate::{error::Error, fshandler::FsHandler, fstable::FsTable, util};
+use crate::{error::Error, fshandler::FsHandler, fstable::FsTable, util, FileResult};
 
 /// The ram file system handler
 /// ### Limits
@@ -69,6 +69,10 @@ impl FsHandler for RamFsHandler {
         self.table.get_directory_from_path(path)
     }
 
+    fn directory_path(&self, dir: Uuid) -> Result<String, Error> {
+        self.table.get_directory_path(dir)
+    }
+
     fn create_file(&mut self, path: &str) -> Result<Uuid, Error> {
         let id = self.table.create_file(path)?;
         self.data.insert(id, Vec::new());
@@ -112,11 +116,40 @@ impl FsHandler for RamFsHandler {
             file_data.resize(at + data.len(), 0);
         }
 
-        let new_size = file_data.len();
-        file_data[at..at + new_size].copy_from_slice(data);
+        file_data[at..at + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn truncate(&mut self, file: Uuid, size: usize) -> Result<(), Error> {
+        let file_data = self
+            .data
+            .get_mut(&file)
+            .ok_or(Error::NoSuchFileWithId(file))?;
+        file_data.resize(size, 0);
         Ok(())
     }
 
+    fn list_directory(&self, path: &str) -> Result<Vec<(String, FileResult)>, Error> {
+        let dir = match util::normalize_path(path).is_empty() {
+            true => None,
+            false => Some(self.get_directory(path)?),
+        };
+
+        let directories = self
+            .table
+            .directories
+            .values()
+            .filter(|d| d.parent == dir)
+            .map(|d| (d.name.clone(), FileResult::Directory(d.id)));
+        let files = self
+            .table
+            .files
+            .values()
+            .filter(|f| f.dir == dir)
+            .map(|f| (f.name.clone(), FileResult::File(f.id)));
+        Ok(directories.chain(files).collect())
+    }
+
     fn move_file(&mut self, src: &str, dest: &str) -> Result<(), Error> {
         let file_id = self.get_file(src)?;
 
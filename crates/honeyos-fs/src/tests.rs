@@ -110,7 +110,9 @@ mod util_tests {
 
 #[cfg(test)]
 mod ramfs_tests {
-    use crate::{fshandler::FsHandler, ramfs::RamFsHandler};
+    use uuid::Uuid;
+
+    use crate::{fshandler::FsHandler, ramfs::RamFsHandler, FileResult};
 
     #[test]
     fn create() {
@@ -194,4 +196,65 @@ mod ramfs_tests {
         fs.get_file("foo/eggs/spameggs.txt").unwrap();
         assert_eq!(None, fs.get_file("spam/eggs/spameggs.txt").ok());
     }
+
+    #[test]
+    fn write_at() {
+        let mut fs = RamFsHandler::new();
+        let file_id = fs.create_file("spam.txt").unwrap();
+
+        fs.write(file_id, 0, b"Hello, world!").unwrap();
+        fs.write(file_id, 7, b"there!").unwrap();
+        assert_eq!(b"Hello, there!", fs.read(file_id).unwrap().as_slice());
+
+        fs.write(file_id, 13, b" Bye").unwrap();
+        assert_eq!(b"Hello, there! Bye", fs.read(file_id).unwrap().as_slice());
+        assert!(fs.write(file_id, 100, b"!").is_err());
+
+        fs.truncate(file_id, 5).unwrap();
+        assert_eq!(b"Hello", fs.read(file_id).unwrap().as_slice());
+        fs.truncate(file_id, 7).unwrap();
+        assert_eq!(b"Hello\0\0", fs.read(file_id).unwrap().as_slice());
+    }
+
+    #[test]
+    fn list_directory() {
+        let mut fs = RamFsHandler::new();
+
+        fs.create_directory("spam/").unwrap();
+        fs.create_directory("spam/eggs").unwrap();
+        fs.create_file("spam/foo.txt").unwrap();
+        fs.create_file("bar.txt").unwrap();
+
+        let mut names = fs
+            .list_directory("spam")
+            .unwrap()
+            .into_iter()
+            .map(|(name, result)| (name, matches!(result, FileResult::Directory(_))))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            vec![("eggs".to_string(), true), ("foo.txt".to_string(), false)],
+            names
+        );
+
+        let mut names = fs
+            .list_directory("")
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["bar.txt".to_string(), "spam".to_string()], names);
+        assert!(fs.list_directory("foo").is_err());
+    }
+
+    #[test]
+    fn directory_path() {
+        let mut fs = RamFsHandler::new();
+
+        fs.create_directory("spam/").unwrap();
+        let dir_id = fs.create_directory("spam/eggs").unwrap();
+        assert_eq!("spam/eggs", fs.directory_path(dir_id).unwrap());
+        assert!(fs.directory_path(Uuid::new_v4()).is_err());
+    }
 }
@@ -0,0 +1,222 @@
+//! The kernel log, like dmesg.
+//! The records logged by the kernel and it's processes are kept in a bounded ring buffer,
+//! so they can be read by processes without access to the browser console.
+//! Every record is still written to the browser console as well.
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use honeyos_atomics::mutex::SpinMutex;
+use log::{Level, LevelFilter, Log, Metadata, SetLoggerError};
+use uuid::Uuid;
+use web_sys::js_sys::Date;
+
+pub mod tests;
+
+/// The amount of records kept in the kernel log, older records are dropped
+pub const KLOG_CAPACITY: usize = 2048;
+
+/// The subsystem of the records logged by processes
+pub const PROCESS_SUBSYSTEM: &str = "process";
+
+/// The static instance of the kernel log
+static KLOG: OnceLock<Arc<KernelLog>> = OnceLock::new();
+
+/// The logger feeding the kernel log
+static LOGGER: KernelLogger = KernelLogger;
+
+/// A record of the kernel log
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// The sequence number of the record, counting every record ever logged
+    pub seq: u64,
+    /// The time in seconds since the kernel started
+    pub timestamp: f64,
+    pub level: Level,
+    /// The part of the kernel that logged the record, `process` for records logged by a process
+    pub subsystem: String,
+    /// The process that logged the record
+    pub pid: Option<Uuid>,
+    pub message: String,
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{:>12.6}] {:<5} {}",
+            self.timestamp, self.level, self.subsystem
+        )?;
+        if let Some(pid) = self.pid {
+            write!(f, " {}", pid)?;
+        }
+        writeln!(f, ": {}", self.message.trim_end())
+    }
+}
+
+/// The records of the kernel log
+#[derive(Debug, Default)]
+struct Records {
+    entries: VecDeque<Record>,
+    next_seq: u64,
+}
+
+/// The kernel log
+#[derive(Debug)]
+pub struct KernelLog {
+    /// The time the kernel started, in milliseconds since the unix epoch
+    boot: f64,
+    capacity: usize,
+    records: Mutex<Records>,
+}
+
+impl KernelLog {
+    pub fn new(boot: f64, capacity: usize) -> Self {
+        Self {
+            boot,
+            capacity,
+            records: Mutex::new(Records::default()),
+        }
+    }
+
+    /// Get the kernel log
+    pub fn get() -> Arc<KernelLog> {
+        KLOG.get().expect("Kernel log not initialized").clone()
+    }
+
+    /// Initialize the kernel log and install it as the logger of the kernel.
+    /// Records above the level are neither kept nor written to the browser console.
+    /// Should only be called once.
+    pub fn init_once(level: Level) -> Result<(), SetLoggerError> {
+        KLOG.get_or_init(|| Arc::new(KernelLog::new(Date::now(), KLOG_CAPACITY)));
+        log::set_logger(&LOGGER)?;
+        log::set_max_level(level.to_level_filter());
+        Ok(())
+    }
+
+    /// Add a record, timestamped with the current time
+    pub fn record(&self, level: Level, subsystem: &str, pid: Option<Uuid>, message: String) {
+        self.push(Record {
+            seq: 0,
+            timestamp: (Date::now() - self.boot) / 1_000.0,
+            level,
+            subsystem: subsystem.to_string(),
+            pid,
+            message,
+        });
+    }
+
+    /// Add a record, dropping the oldest record if the log is full.
+    /// The sequence number of the record is assigned by the log and returned.
+    pub fn push(&self, mut record: Record) -> u64 {
+        let mut records = self.records.busy_lock();
+        record.seq = records.next_seq;
+        records.next_seq += 1;
+        if records.entries.len() >= self.capacity {
+            records.entries.pop_front();
+        }
+        records.entries.push_back(record);
+        records.next_seq - 1
+    }
+
+    /// Read the records from the sequence number `from` at or above the level as text lines,
+    /// as many as fit in `max` bytes. Records that were dropped are skipped.
+    /// A line longer than `max` is cut off, so a small buffer never stalls the reader.
+    /// Returns the lines and the sequence number to continue reading from.
+    pub fn read(&self, from: u64, level: LevelFilter, max: usize) -> (Vec<u8>, u64) {
+        let records = self.records.busy_lock();
+        let mut data = Vec::new();
+        for record in records.entries.iter().filter(|record| record.seq >= from) {
+            if record.level > level {
+                continue;
+            }
+            let line = record.to_string();
+            if data.len() + line.len() <= max {
+                data.extend_from_slice(line.as_bytes());
+                continue;
+            }
+            if !data.is_empty() || max == 0 {
+                return (data, record.seq);
+            }
+            data.extend_from_slice(&line.as_bytes()[..max]);
+            return (data, record.seq + 1);
+        }
+        (data, records.next_seq)
+    }
+
+    /// Get the sequence number of the oldest record kept
+    pub fn start(&self) -> u64 {
+        let records = self.records.busy_lock();
+        records
+            .entries
+            .front()
+            .map_or(records.next_seq, |record| record.seq)
+    }
+
+    /// Get the sequence number the next record will be assigned
+    pub fn end(&self) -> u64 {
+        self.records.busy_lock().next_seq
+    }
+
+    /// Get the amount of records kept
+    pub fn len(&self) -> usize {
+        self.records.busy_lock().entries.len()
+    }
+
+    /// Check if no records are kept
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The logger of the kernel.
+/// Keeps the records in the kernel log and writes them to the browser console.
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(klog) = KLOG.get() {
+            klog.record(
+                record.level(),
+                subsystem(record.target()),
+                None,
+                record.args().to_string(),
+            );
+        }
+        console_log::log(record);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log a message of a process to the kernel log and the browser console
+pub fn log_process(level: Level, pid: Uuid, message: &str) {
+    if level > log::max_level() {
+        return;
+    }
+    if let Some(klog) = KLOG.get() {
+        klog.record(level, PROCESS_SUBSYSTEM, Some(pid), message.to_string());
+    }
+    console_log::log(
+        &log::Record::builder()
+            .args(format_args!("PID: {} - {}", pid, message))
+            .level(level)
+            .target(PROCESS_SUBSYSTEM)
+            .build(),
+    );
+}
+
+/// Get the subsystem of a log target, like `fs` for `honeyos_fs::fstable`
+pub fn subsystem(target: &str) -> &str {
+    let module = target.split("::").next().unwrap_or(target);
+    module.strip_prefix("honeyos_").unwrap_or(module)
+}
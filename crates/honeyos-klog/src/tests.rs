@@ -0,0 +1,100 @@
+#[cfg(test)]
+mod klog_tests {
+    use log::{Level, LevelFilter};
+    use uuid::Uuid;
+
+    use crate::{subsystem, KernelLog, Record};
+
+    fn record(level: Level, message: &str) -> Record {
+        Record {
+            seq: 0,
+            timestamp: 1.5,
+            level,
+            subsystem: "fs".to_string(),
+            pid: None,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn record_line() {
+        let mut record = record(Level::Warn, "mounted\n");
+        assert_eq!(record.to_string(), "[    1.500000] WARN  fs: mounted\n");
+
+        record.subsystem = "process".to_string();
+        record.pid = Some(Uuid::nil());
+        assert_eq!(
+            record.to_string(),
+            format!("[    1.500000] WARN  process {}: mounted\n", Uuid::nil())
+        );
+    }
+
+    #[test]
+    fn read_from() {
+        let klog = KernelLog::new(0.0, 16);
+        assert_eq!(klog.push(record(Level::Info, "a")), 0);
+        assert_eq!(klog.push(record(Level::Info, "b")), 1);
+        assert_eq!(klog.push(record(Level::Info, "c")), 2);
+
+        let line = record(Level::Info, "a").to_string();
+        let (data, next) = klog.read(0, LevelFilter::Trace, line.len() * 2);
+        assert_eq!(data.len(), line.len() * 2);
+        assert_eq!(next, 2);
+
+        let (data, next) = klog.read(next, LevelFilter::Trace, 1024);
+        assert_eq!(data, record(Level::Info, "c").to_string().as_bytes());
+        assert_eq!(next, 3);
+
+        let (data, next) = klog.read(next, LevelFilter::Trace, 1024);
+        assert!(data.is_empty());
+        assert_eq!(next, 3);
+    }
+
+    #[test]
+    fn level_filter() {
+        let klog = KernelLog::new(0.0, 16);
+        klog.push(record(Level::Debug, "debug"));
+        klog.push(record(Level::Error, "error"));
+        klog.push(record(Level::Info, "info"));
+
+        let (data, next) = klog.read(0, LevelFilter::Warn, 1024);
+        assert_eq!(data, record(Level::Error, "error").to_string().as_bytes());
+        assert_eq!(next, 3);
+
+        let (data, _) = klog.read(0, LevelFilter::Off, 1024);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let klog = KernelLog::new(0.0, 2);
+        klog.push(record(Level::Info, "a"));
+        klog.push(record(Level::Info, "b"));
+        klog.push(record(Level::Info, "c"));
+        assert_eq!(klog.len(), 2);
+        assert_eq!(klog.start(), 1);
+        assert_eq!(klog.end(), 3);
+
+        let (data, _) = klog.read(0, LevelFilter::Trace, 1024);
+        let expected = record(Level::Info, "b").to_string() + &record(Level::Info, "c").to_string();
+        assert_eq!(data, expected.as_bytes());
+    }
+
+    #[test]
+    fn cut_off_long_line() {
+        let klog = KernelLog::new(0.0, 16);
+        klog.push(record(Level::Info, "a long message"));
+        klog.push(record(Level::Info, "b"));
+
+        let (data, next) = klog.read(0, LevelFilter::Trace, 8);
+        assert_eq!(data, b"[    1.5");
+        assert_eq!(next, 1);
+    }
+
+    #[test]
+    fn subsystems() {
+        assert_eq!(subsystem("honeyos_fs::fstable"), "fs");
+        assert_eq!(subsystem("kernel::api::js"), "kernel");
+        assert_eq!(subsystem("process"), "process");
+    }
+}
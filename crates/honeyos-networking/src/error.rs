@@ -6,6 +6,7 @@ pub enum Error {
     RequestInitFailure(JsValue),
     HeaderParseFailure(JsValue),
     InvalidRequestMethod,
+    RequestLimitExceeded(usize),
 }
 
 impl std::error::Error for Error {}
@@ -16,6 +17,9 @@ impl std::fmt::Display for Error {
             Error::RequestInitFailure(e) => writeln!(f, "Failed to initialize request: {:?}", e),
             Error::InvalidRequestMethod => writeln!(f, "The provided request method is invalid"),
             Error::HeaderParseFailure(e) => writeln!(f, "Failed to parse headers: {:?}", e),
+            Error::RequestLimitExceeded(limit) => {
+                writeln!(f, "Exceeded the limit of {} outstanding requests", limit)
+            }
         }
     }
 }
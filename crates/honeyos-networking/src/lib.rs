@@ -48,15 +48,24 @@ impl NetworkingManager {
         });
     }
 
-    /// Create a request.
+    /// Create a request on behalf of an owner.
+    /// Fails if the owner already has `limit` outstanding requests.
     /// Returns it'd id
     pub fn request(
         &mut self,
+        owner: Uuid,
+        limit: Option<usize>,
         url: impl Into<String>,
         method: RequestMethod,
         mode: RequestMode,
         headers: impl Into<String>,
-    ) -> Uuid {
+    ) -> Result<Uuid, Error> {
+        if let Some(limit) = limit {
+            if self.outstanding(owner) >= limit {
+                return Err(Error::RequestLimitExceeded(limit));
+            }
+        }
+
         let url: String = url.into();
         let headers: String = headers.into();
 
@@ -65,13 +74,29 @@ impl NetworkingManager {
         self.scheduled.insert(
             id,
             ScheduledRequest {
+                owner,
                 url,
                 method,
                 mode,
                 headers,
             },
         );
-        id
+        Ok(id)
+    }
+
+    /// Return the amount of requests of an owner that have not finished yet
+    pub fn outstanding(&self, owner: Uuid) -> usize {
+        let scheduled = self
+            .scheduled
+            .values()
+            .filter(|request| request.owner == owner)
+            .count();
+        let processing = self
+            .requests
+            .values()
+            .filter(|request| request.owner == owner && request.status == RequestStatus::Processing)
+            .count();
+        scheduled + processing
     }
 
     /// Return the status of a request.
@@ -98,9 +123,10 @@ impl NetworkingManager {
         Some(request.data.len())
     }
 
-    /// Remove the request from the queue.
+    /// Remove the request from the queue, including requests that have not been sent yet.
     /// Do nothing if it does not exist
     pub fn remove(&mut self, id: Uuid) {
+        self.scheduled.remove(&id);
         self.requests.remove(&id);
     }
 
@@ -119,6 +145,7 @@ impl NetworkingManager {
         self.requests.insert(
             id,
             Request {
+                owner: scheduled.owner,
                 data: Vec::new(),
                 status: RequestStatus::Processing,
             },
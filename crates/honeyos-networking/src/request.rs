@@ -1,6 +1,9 @@
+use uuid::Uuid;
+
 /// A scheduled request
 #[derive(Debug, Clone)]
 pub struct ScheduledRequest {
+    pub owner: Uuid,
     pub url: String,
     pub method: RequestMethod,
     pub mode: RequestMode,
@@ -10,6 +13,7 @@ pub struct ScheduledRequest {
 /// Represents a request stored in the network manager
 #[derive(Debug, Clone)]
 pub struct Request {
+    pub owner: Uuid,
     pub data: Vec<u8>,
     pub status: RequestStatus,
 }
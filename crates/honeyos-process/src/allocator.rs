@@ -0,0 +1,371 @@
+//! The heap allocator for the memory the kernel hands out to processes.
+//! The allocator only does the bookkeeping and never touches the memory itself,
+//! so it can be tested natively.
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The minimum alignment and size granularity of every block
+pub const MIN_ALIGN: u32 = 8;
+
+/// The amount of size classes. Class `n` holds free blocks with a size in `[2^n, 2^(n + 1))`
+const SIZE_CLASSES: usize = 32;
+
+/// The error types for the allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The pointer does not point to the start of a block
+    InvalidPointer(u32),
+    /// The block has already been freed
+    DoubleFree(u32),
+    /// The range overlaps memory of the allocator that is not part of a single allocated block
+    NotAllocated { ptr: u32, len: u32 },
+}
+
+/// A block of memory managed by the allocator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub size: u32,
+    pub free: bool,
+}
+
+/// The outcome of a reallocation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Realloc {
+    /// The block was resized in place
+    InPlace(u32),
+    /// The block was moved. The first `len` bytes need to be copied from the old to the new block.
+    Moved { from: u32, to: u32, len: u32 },
+}
+
+/// A first-fit allocator with size-class free lists.
+/// Free blocks are split on allocation and coalesced with their free neighbours on free.
+/// Memory is added in regions, which don't need to be adjacent to each other.
+#[derive(Debug, Clone)]
+pub struct Allocator {
+    blocks: BTreeMap<u32, Block>,
+    free_lists: Vec<BTreeSet<u32>>,
+    freed: BTreeSet<u32>, // The starts of freed blocks, until their memory is handed out again
+    allocated: u64,
+    peak_allocated: u64,
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        Self {
+            blocks: BTreeMap::new(),
+            free_lists: vec![BTreeSet::new(); SIZE_CLASSES],
+            freed: BTreeSet::new(),
+            allocated: 0,
+            peak_allocated: 0,
+        }
+    }
+
+    /// Add a region of memory the allocator can hand out.
+    /// The region is merged with a free block that ends where it starts.
+    /// The start is aligned up and the size truncated to the minimum alignment.
+    pub fn add_region(&mut self, start: u32, size: u32) {
+        let Some(aligned_start) = align_up(start, MIN_ALIGN) else {
+            return;
+        };
+        let size = size.saturating_sub(aligned_start - start) / MIN_ALIGN * MIN_ALIGN;
+        if size == 0 {
+            return;
+        }
+        self.insert_free(aligned_start, size);
+        self.coalesce(aligned_start);
+    }
+
+    /// Allocate a block of at least `size` bytes aligned to `align`.
+    /// Returns `None` if the alignment is not a power of two or no free block is large enough,
+    /// in which case a region should be added before trying again.
+    pub fn alloc(&mut self, size: u32, align: u32) -> Option<u32> {
+        if !align.is_power_of_two() {
+            return None;
+        }
+        let align = align.max(MIN_ALIGN);
+        let size = block_size(size)?;
+
+        let (start, aligned) = self.find_free(size, align)?;
+        let block = self.remove_free(start);
+
+        // Keep the padding in front of the aligned block free
+        if aligned > start {
+            self.insert_free(start, aligned - start);
+        }
+
+        let available = block.size - (aligned - start);
+        self.blocks.insert(
+            aligned,
+            Block {
+                size: available,
+                free: false,
+            },
+        );
+        self.shrink(aligned, size);
+        self.reuse(aligned, self.blocks[&aligned].size);
+        self.track(0, self.blocks[&aligned].size);
+        Some(aligned)
+    }
+
+    /// Free a block.
+    /// Freeing a block again is reported as a double free, even once it has been merged with it's neighbours.
+    pub fn free(&mut self, ptr: u32) -> Result<(), AllocError> {
+        let Some(block) = self.blocks.get(&ptr).copied() else {
+            return match self.freed.contains(&ptr) {
+                true => Err(AllocError::DoubleFree(ptr)),
+                false => Err(AllocError::InvalidPointer(ptr)),
+            };
+        };
+        if block.free {
+            return Err(AllocError::DoubleFree(ptr));
+        }
+        self.blocks.remove(&ptr);
+        self.insert_free(ptr, block.size);
+        self.coalesce(ptr);
+        self.freed.insert(ptr);
+        self.track(block.size, 0);
+        Ok(())
+    }
+
+    /// Resize a block, in place if possible.
+    /// Returns `Ok(None)` if the block needs to move but no free block is large enough,
+    /// in which case a region should be added before trying again.
+    pub fn realloc(&mut self, ptr: u32, new_size: u32) -> Result<Option<Realloc>, AllocError> {
+        let block = *self
+            .blocks
+            .get(&ptr)
+            .ok_or(AllocError::InvalidPointer(ptr))?;
+        if block.free {
+            return Err(AllocError::InvalidPointer(ptr));
+        }
+        let Some(size) = block_size(new_size) else {
+            return Ok(None);
+        };
+
+        // Shrink in place
+        if size <= block.size {
+            self.shrink(ptr, size);
+            self.track(block.size, self.blocks[&ptr].size);
+            return Ok(Some(Realloc::InPlace(ptr)));
+        }
+
+        // Grow in place by taking from the next block
+        let end = ptr.checked_add(block.size).unwrap_or(ptr);
+        if let Some(next) = self.blocks.get(&end).copied() {
+            if next.free && end != ptr && block.size as u64 + next.size as u64 >= size as u64 {
+                self.remove_free(end);
+                self.blocks.insert(
+                    ptr,
+                    Block {
+                        size: block.size + next.size,
+                        free: false,
+                    },
+                );
+                self.shrink(ptr, size);
+                self.reuse(ptr, self.blocks[&ptr].size);
+                self.track(block.size, self.blocks[&ptr].size);
+                return Ok(Some(Realloc::InPlace(ptr)));
+            }
+        }
+
+        // Move the block. The new block is allocated before the old one is freed, so they never overlap.
+        let Some(to) = self.alloc(new_size, MIN_ALIGN) else {
+            return Ok(None);
+        };
+        self.free(ptr)?;
+        Ok(Some(Realloc::Moved {
+            from: ptr,
+            to,
+            len: block.size,
+        }))
+    }
+
+    /// Check that a range of memory may be accessed by the process.
+    /// Memory outside the regions of the allocator belongs to the process itself and may always be accessed,
+    /// memory inside them only within a single allocated block. Empty ranges access nothing.
+    pub fn check_range(&self, ptr: u32, len: u32) -> Result<(), AllocError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = ptr as u64 + len as u64;
+        let not_allocated = AllocError::NotAllocated { ptr, len };
+
+        // The block containing the start of the range
+        if let Some((&start, block)) = self.blocks.range(..=ptr).next_back() {
+            let block_end = start as u64 + block.size as u64;
+            if (ptr as u64) < block_end {
+                return match !block.free && end <= block_end {
+                    true => Ok(()),
+                    false => Err(not_allocated),
+                };
+            }
+        }
+
+        // A range starting outside the regions may not reach into them
+        match self.blocks.range(ptr..).next() {
+            Some((&start, _)) if (start as u64) < end => Err(not_allocated),
+            _ => Ok(()),
+        }
+    }
+
+    /// Get the block starting at a pointer
+    pub fn block(&self, ptr: u32) -> Option<Block> {
+        self.blocks.get(&ptr).copied()
+    }
+
+    /// The amount of bytes in allocated blocks
+    pub fn allocated(&self) -> u64 {
+        self.allocated
+    }
+
+    /// The highest amount of bytes that were allocated at once
+    pub fn peak_allocated(&self) -> u64 {
+        self.peak_allocated
+    }
+
+    /// The amount of bytes in free blocks
+    pub fn available(&self) -> u64 {
+        self.blocks
+            .values()
+            .filter(|block| block.free)
+            .map(|block| block.size as u64)
+            .sum()
+    }
+
+    /// Track a change in the size of an allocated block
+    fn track(&mut self, old_size: u32, new_size: u32) {
+        self.allocated = self.allocated - old_size as u64 + new_size as u64;
+        self.peak_allocated = self.peak_allocated.max(self.allocated);
+    }
+
+    /// Forget the freed blocks in memory that has been handed out again
+    fn reuse(&mut self, start: u32, size: u32) {
+        let end = start as u64 + size as u64;
+        let reused = self
+            .freed
+            .range(start..)
+            .take_while(|ptr| (**ptr as u64) < end)
+            .copied()
+            .collect::<Vec<_>>();
+        for ptr in reused {
+            self.freed.remove(&ptr);
+        }
+    }
+
+    /// Find a free block that fits `size` bytes at the alignment.
+    /// Returns the start of the free block and the aligned pointer within it.
+    fn find_free(&self, size: u32, align: u32) -> Option<(u32, u32)> {
+        for class in size_class(size)..SIZE_CLASSES {
+            for &start in self.free_lists[class].iter() {
+                let block = self.blocks[&start];
+                let Some(aligned) = align_up(start, align) else {
+                    continue;
+                };
+                let padding = aligned - start;
+                if padding as u64 + size as u64 <= block.size as u64 {
+                    return Some((start, aligned));
+                }
+            }
+        }
+        None
+    }
+
+    /// Split off the tail of an allocated block past `size` bytes as a free block
+    fn shrink(&mut self, ptr: u32, size: u32) {
+        let block = self.blocks[&ptr];
+        let remainder = block.size - size;
+        if remainder < MIN_ALIGN {
+            return;
+        }
+        self.blocks.insert(ptr, Block { size, free: false });
+        self.insert_free(ptr + size, remainder);
+        self.coalesce(ptr + size);
+    }
+
+    /// Merge a free block with the free blocks directly before and after it
+    fn coalesce(&mut self, ptr: u32) {
+        let mut start = ptr;
+        let mut size = self.blocks[&ptr].size;
+
+        // Merge with the next block
+        let end = start.checked_add(size).unwrap_or(start);
+        if let Some(next) = self.blocks.get(&end).copied() {
+            if next.free && end != start {
+                self.remove_free(end);
+                self.remove_free(start);
+                size += next.size;
+                self.insert_free(start, size);
+            }
+        }
+
+        // Merge with the previous block
+        let previous = self
+            .blocks
+            .range(..start)
+            .next_back()
+            .map(|(ptr, block)| (*ptr, *block));
+        if let Some((previous_start, previous)) = previous {
+            if previous.free && previous_start + previous.size == start {
+                self.remove_free(start);
+                self.remove_free(previous_start);
+                start = previous_start;
+                size += previous.size;
+                self.insert_free(start, size);
+            }
+        }
+    }
+
+    /// Insert a free block
+    fn insert_free(&mut self, start: u32, size: u32) {
+        self.blocks.insert(start, Block { size, free: true });
+        self.free_lists[size_class(size)].insert(start);
+    }
+
+    /// Remove a free block
+    fn remove_free(&mut self, start: u32) -> Block {
+        let block = self.blocks.remove(&start).unwrap();
+        self.free_lists[size_class(block.size)].remove(&start);
+        block
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Round a requested size up to the size of a block
+fn block_size(size: u32) -> Option<u32> {
+    let size = size.max(1);
+    size.checked_add(MIN_ALIGN - 1)
+        .map(|size| size / MIN_ALIGN * MIN_ALIGN)
+}
+
+/// Get the size class of a block size
+fn size_class(size: u32) -> usize {
+    (u32::BITS - 1 - size.max(1).leading_zeros()) as usize
+}
+
+/// Align a value up to a power of two.
+/// Returns `None` on overflow.
+fn align_up(value: u32, align: u32) -> Option<u32> {
+    value
+        .checked_add(align - 1)
+        .map(|value| value & !(align - 1))
+}
+
+impl std::error::Error for AllocError {}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AllocError::InvalidPointer(ptr) => writeln!(f, "Invalid pointer: {}", ptr),
+            AllocError::DoubleFree(ptr) => writeln!(f, "Double free of pointer: {}", ptr),
+            AllocError::NotAllocated { ptr, len } => writeln!(
+                f,
+                "{} bytes at pointer {} are not part of an allocated block",
+                len, ptr
+            ),
+        }
+    }
+}
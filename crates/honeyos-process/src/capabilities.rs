@@ -0,0 +1,103 @@
+//! Capabilities decide which parts of the api a process can use.
+//! The capabilities are decided when a process is spawned and can only be reduced from there on,
+//! as subprocesses never get a capability their parent does not have.
+//! Calls behind a capability the process lacks are replaced by stubs when the import object is built,
+//! the file system capabilities are checked per label when a path is accessed.
+use honeyos_fs::FsLabel;
+
+/// The error code returned by the api when a process lacks the capability for a call.
+/// The code is distinct from the other error codes of the api.
+pub const PERMISSION_DENIED: i32 = -10;
+
+/// The amount of file system labels
+const LABELS: u32 = 26;
+
+/// A capability a process can hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Reading files and directories of a file system
+    FsRead(FsLabel),
+    /// Creating, writing and moving files and directories of a file system, or mounting it
+    FsWrite(FsLabel),
+    /// Making network requests
+    Network,
+    /// Taking control of the display
+    Display,
+    /// Evaluating javascript in the kernel
+    JsEval,
+    /// Spawning subprocesses
+    Spawn,
+    /// Spawning threads
+    Thread,
+}
+
+/// A set of capabilities, stored as a bitmask so it can be passed through the api.
+/// Bits `0..26` are read access to the labels `a` to `z`, bits `26..52` write access,
+/// followed by network, display, js eval, spawn and thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capability {
+    /// Get the bit of the capability
+    fn bit(&self) -> u64 {
+        let index = match self {
+            Capability::FsRead(label) => *label as u32,
+            Capability::FsWrite(label) => LABELS + *label as u32,
+            Capability::Network => LABELS * 2,
+            Capability::Display => LABELS * 2 + 1,
+            Capability::JsEval => LABELS * 2 + 2,
+            Capability::Spawn => LABELS * 2 + 3,
+            Capability::Thread => LABELS * 2 + 4,
+        };
+        1 << index
+    }
+}
+
+impl Capabilities {
+    /// Every capability
+    pub const fn all() -> Self {
+        Self((1 << (LABELS * 2 + 5)) - 1)
+    }
+
+    /// No capabilities
+    pub const fn none() -> Self {
+        Self(0)
+    }
+
+    /// Create a set from a bitmask, ignoring unknown bits
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits & Self::all().0)
+    }
+
+    /// Get the bitmask of the set
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Check if the set holds a capability
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.0 & capability.bit() != 0
+    }
+
+    /// Add a capability
+    pub fn grant(&mut self, capability: Capability) {
+        self.0 |= capability.bit();
+    }
+
+    /// Remove a capability
+    pub fn revoke(&mut self, capability: Capability) {
+        self.0 &= !capability.bit();
+    }
+
+    /// Get the capabilities held by both sets
+    pub fn intersect(&self, other: &Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Processes get every capability, unless their spawner reduces them
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
@@ -1,12 +1,34 @@
 use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use honeyos_atomics::{mutex::SpinMutex, rwlock::SpinRwLock};
 use uuid::Uuid;
 use wasm_bindgen::{prelude::wasm_bindgen, JsValue};
-use web_sys::js_sys::{Reflect, WebAssembly, JSON};
+use web_sys::js_sys::{Function, Reflect, WebAssembly, JSON};
 
-use crate::{memory::Memory, stdout::ProcessStdOut};
+use honeyos_fs::{FsLabel, FsManager};
+
+use crate::{
+    capabilities::{Capabilities, Capability, PERMISSION_DENIED},
+    crash::CrashReport,
+    fd::{FdTable, FileDescriptor},
+    handle::{HandleTable, KernelObject},
+    limits::{ChildLimits, LimitError, Resource, ResourceLimits},
+    memory::Memory,
+    ring::CompletionRing,
+    stdin::ProcessStdIn,
+    stdout::{OutputLog, ProcessStdOut, Stream, DEFAULT_MAX_RETENTION},
+    thread::MAIN_THREAD_ID,
+    trace::{ProcessTrace, Tracer, TRACE_VAR},
+    SpawnOptions,
+};
+
+/// The import module the honeyos api is registered in
+pub const HAPI_MODULE: &str = "hapi";
+
+/// The version of the api as `(major, minor)`.
+/// The minor version is raised when items are added, the major version when items change or are removed.
+pub const HAPI_VERSION: (u32, u32) = (1, 1);
 
 /// A function responsible for building the api for wasm processes
 pub type ApiBuilderFn = fn(Arc<ProcessCtx>, &mut ApiModuleBuilder);
@@ -16,43 +38,137 @@ pub type ApiBuilderFn = fn(Arc<ProcessCtx>, &mut ApiModuleBuilder);
 #[wasm_bindgen]
 pub struct ProcessCtx {
     pid: Uuid,
+    tid: u32,
+    parent: Option<Uuid>,
+    stdin: Arc<ProcessStdIn>,
     stdout: Arc<ProcessStdOut>,
+    stderr: Arc<ProcessStdOut>,
+    output: Arc<Mutex<OutputLog>>,
     memory: Arc<Mutex<Memory>>,
     cwd: Arc<RwLock<String>>,
+    args: Arc<Vec<String>>,
+    env: Arc<RwLock<HashMap<String, String>>>,
+    limits: Arc<RwLock<ResourceLimits>>,
+    privileged: bool,
+    capabilities: Capabilities,
+    child_capabilities: Arc<RwLock<Capabilities>>,
+    child_limits: Arc<RwLock<ChildLimits>>,
+    fds: Arc<Mutex<FdTable>>,
+    handles: Arc<Mutex<HandleTable>>,
+    exit_code: Arc<RwLock<Option<i32>>>,
+    spawn_error: Arc<RwLock<Option<String>>>,
+    last_error: Arc<RwLock<Option<(i32, String)>>>,
+    crash_report: Arc<RwLock<Option<Arc<CrashReport>>>>,
+    trace: Arc<ProcessTrace>,
+    ring: Arc<CompletionRing>,
     module: Arc<Vec<u8>>,
     api_builder: ApiBuilderFn,
 }
 
-/// The builder for an api module
+/// The builder for the api modules.
+/// Items are registered in the `hapi` module, unless registered in another import module.
+/// Restricted items are replaced by stubs when the process lacks their capability.
 #[derive(Debug, Clone)]
 pub struct ApiModuleBuilder {
-    values: HashMap<String, JsValue>,
+    capabilities: Capabilities,
+    features: HashSet<String>,
+    modules: HashMap<String, HashMap<String, JsValue>>,
+    restricted: HashMap<String, (Capability, Denied)>,
+    trace: Option<(Arc<ProcessTrace>, u32)>,
+}
+
+/// What the stub of a restricted item returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Denied {
+    /// The `PERMISSION_DENIED` error code
+    Code,
+    /// A null pointer
+    Null,
 }
 
 impl ProcessCtx {
     pub fn new(
         pid: Uuid,
         memory: Arc<Mutex<Memory>>,
-        stdout: Arc<ProcessStdOut>,
         cwd: Arc<RwLock<String>>,
         module: Arc<Vec<u8>>,
+        options: SpawnOptions,
         api_builder: ApiBuilderFn,
     ) -> Self {
-        Self {
+        let output = Arc::new(Mutex::new(OutputLog::new(DEFAULT_MAX_RETENTION)));
+        let fds = FdTable::new(preopens(&cwd.read().unwrap(), options.capabilities));
+        let trace = options.trace
+            || options
+                .env
+                .get(TRACE_VAR)
+                .is_some_and(|value| !value.is_empty() && value != "0");
+        let ctx = Self {
             pid,
+            tid: MAIN_THREAD_ID,
+            parent: options.parent,
             memory,
-            stdout,
+            stdin: Arc::new(match options.stdin {
+                Some(pipe) => ProcessStdIn::from_pipe(pipe),
+                None => ProcessStdIn::new(),
+            }),
+            stdout: Arc::new(match options.stdout {
+                Some(pipe) => ProcessStdOut::piped(pipe),
+                None => ProcessStdOut::logged(output.clone(), Stream::Stdout),
+            }),
+            stderr: Arc::new(match options.stderr {
+                Some(pipe) => ProcessStdOut::piped(pipe),
+                None => ProcessStdOut::logged(output.clone(), Stream::Stderr),
+            }),
+            output,
             cwd,
+            args: Arc::new(options.args),
+            env: Arc::new(RwLock::new(options.env)),
+            limits: Arc::new(RwLock::new(options.limits)),
+            privileged: options.privileged,
+            capabilities: options.capabilities,
+            child_capabilities: Arc::new(RwLock::new(options.capabilities)),
+            child_limits: Arc::new(RwLock::new(ChildLimits::default())),
+            fds: Arc::new(Mutex::new(fds)),
+            handles: Arc::new(Mutex::new(HandleTable::new())),
+            exit_code: Arc::new(RwLock::new(None)),
+            spawn_error: Arc::new(RwLock::new(None)),
+            last_error: Arc::new(RwLock::new(None)),
+            crash_report: Arc::new(RwLock::new(None)),
+            trace: Arc::new(ProcessTrace::new(trace)),
+            ring: Arc::new(CompletionRing::new()),
             module,
             api_builder,
-        }
+        };
+        ctx.apply_retention_limit(options.limits.stdout_retention);
+        ctx
     }
 
-    /// Build form a builder fn
+    /// Build the import modules form a builder fn
     pub fn build_api(self: &Arc<Self>) -> JsValue {
-        let mut api_module_builder = ApiModuleBuilder::new();
+        self.api_module_builder().build()
+    }
+
+    /// Register the api in a builder, without building the import modules
+    pub fn api_module_builder(self: &Arc<Self>) -> ApiModuleBuilder {
+        let mut api_module_builder = ApiModuleBuilder::new(self.capabilities);
+        api_module_builder.trace(self.trace.clone(), self.tid);
         (self.api_builder)(self.clone(), &mut api_module_builder);
-        api_module_builder.build()
+        api_module_builder
+    }
+
+    /// Get the trace buffer of the process
+    pub fn trace(&self) -> Arc<ProcessTrace> {
+        self.trace.clone()
+    }
+
+    /// Get the completion ring of the process
+    pub fn ring(&self) -> Arc<CompletionRing> {
+        self.ring.clone()
+    }
+
+    /// Get the id of the thread this context belongs to
+    pub fn tid(&self) -> u32 {
+        self.tid
     }
 
     /// Get the process id
@@ -60,6 +176,11 @@ impl ProcessCtx {
         self.pid
     }
 
+    /// Get the id of the process that spawned this process, if any
+    pub fn parent(&self) -> Option<Uuid> {
+        self.parent
+    }
+
     /// Get the memory of the wasm module
     pub fn memory<'a>(&'a self) -> MutexGuard<'a, Memory> {
         let memory = self.memory.lock().unwrap(); // Spin locking blocks the entire process. Figure out a way to not block the entire process
@@ -71,16 +192,233 @@ impl ProcessCtx {
         self.memory.lock().unwrap()
     }
 
+    /// Get the stdin of the wasm module
+    pub fn stdin(&self) -> Arc<ProcessStdIn> {
+        self.stdin.clone()
+    }
+
     /// Get the stdout messenger of the wasm module
     pub fn stdout(&self) -> Arc<ProcessStdOut> {
         self.stdout.clone()
     }
 
+    /// Get the stderr messenger of the wasm module
+    pub fn stderr(&self) -> Arc<ProcessStdOut> {
+        self.stderr.clone()
+    }
+
+    /// Lock the log of the stdout and stderr in the order they were written
+    pub fn output(&self) -> MutexGuard<'_, OutputLog> {
+        loop {
+            let Ok(output) = self.output.try_lock() else {
+                continue;
+            };
+            return output;
+        }
+    }
+
     /// Get the working directory
     pub fn cwd(&self) -> String {
         self.cwd.read().unwrap().clone()
     }
 
+    /// Get the command-line arguments
+    pub fn args(&self) -> Arc<Vec<String>> {
+        self.args.clone()
+    }
+
+    /// Get a copy of the environment variables
+    pub fn env(&self) -> HashMap<String, String> {
+        self.env.read().unwrap().clone()
+    }
+
+    /// Get an environment variable
+    pub fn env_var(&self, key: &str) -> Option<String> {
+        self.env.read().unwrap().get(key).cloned()
+    }
+
+    /// Set an environment variable.
+    /// Removes the variable if the value is `None`
+    pub fn set_env_var(&self, key: &str, value: Option<&str>) {
+        let mut env = self.env.spin_write().unwrap();
+        match value {
+            Some(value) => env.insert(key.to_string(), value.to_string()),
+            None => env.remove(key),
+        };
+    }
+
+    /// Get a copy of the resource limits
+    pub fn limits(&self) -> ResourceLimits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Check if the process may raise it's resource limits
+    pub fn privileged(&self) -> bool {
+        self.privileged
+    }
+
+    /// Set the limit of a resource and apply it to the affected subsystem.
+    /// Unprivileged processes can only lower their limits.
+    pub fn set_limit(&self, resource: Resource, limit: Option<u32>) -> Result<(), LimitError> {
+        self.limits
+            .spin_write()
+            .unwrap()
+            .try_set(resource, limit, self.privileged)?;
+        match resource {
+            Resource::MemoryPages => self.memory().set_limit(limit),
+            Resource::StdoutRetention => self.apply_retention_limit(limit),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Cap the retention of the stdout and stderr to the limit
+    fn apply_retention_limit(&self, limit: Option<u32>) {
+        let Some(limit) = limit else {
+            return;
+        };
+        for stream in [&self.stdout, &self.stderr] {
+            stream.set_max_retention(stream.max_retention().min(limit as usize));
+        }
+        let mut output = self.output();
+        let max_retention = output.max_retention().min(limit as usize);
+        output.set_max_retention(max_retention);
+    }
+
+    /// Get the resource limits and privilege subprocesses are spawned with
+    pub fn child_limits(&self) -> ChildLimits {
+        *self.child_limits.read().unwrap()
+    }
+
+    /// Set the limit of a resource subprocesses are spawned with.
+    /// Subprocesses never get higher limits than the process itself.
+    pub fn set_child_limit(&self, resource: Resource, limit: Option<u32>) {
+        self.child_limits
+            .spin_write()
+            .unwrap()
+            .limits
+            .set(resource, limit);
+    }
+
+    /// Set whether subprocesses are spawned privileged.
+    /// Only a privileged process can pass it's privilege on.
+    pub fn set_child_privileged(&self, privileged: bool) {
+        self.child_limits.spin_write().unwrap().privileged = privileged && self.privileged;
+    }
+
+    /// Get the capabilities of the process
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Get the capabilities subprocesses are spawned with
+    pub fn child_capabilities(&self) -> Capabilities {
+        *self.child_capabilities.read().unwrap()
+    }
+
+    /// Set the capabilities subprocesses are spawned with.
+    /// Capabilities the process does not hold itself are dropped.
+    pub fn set_child_capabilities(&self, capabilities: Capabilities) {
+        *self.child_capabilities.spin_write().unwrap() = capabilities.intersect(&self.capabilities);
+    }
+
+    /// Lock the file descriptor table
+    pub fn fds(&self) -> MutexGuard<'_, FdTable> {
+        loop {
+            let Ok(fds) = self.fds.try_lock() else {
+                continue;
+            };
+            return fds;
+        }
+    }
+
+    /// Lock the handle table
+    pub fn handles(&self) -> MutexGuard<'_, HandleTable> {
+        loop {
+            let Ok(handles) = self.handles.try_lock() else {
+                continue;
+            };
+            return handles;
+        }
+    }
+
+    /// Get the amount of objects the process has open, handles and opened file descriptors alike
+    pub fn open_files(&self) -> usize {
+        let handles = self.handles().len();
+        handles + self.fds().open_files()
+    }
+
+    /// Check if the process can open `amount` more objects without exceeding it's open file limit
+    pub fn check_open_files(&self, amount: usize) -> Result<(), LimitError> {
+        let open = self.open_files() + amount.saturating_sub(1);
+        self.limits().check(Resource::OpenFiles, open)
+    }
+
+    /// Insert an object into the handle table and return it's handle.
+    /// Fails if the process has reached it's open file limit.
+    pub fn insert_handle(&self, object: KernelObject) -> Result<u32, LimitError> {
+        // The handles are locked before the descriptors, like in `insert_fd`
+        let mut handles = self.handles();
+        let open = handles.len() + self.fds().open_files();
+        self.limits().check(Resource::OpenFiles, open)?;
+        Ok(handles.insert(object))
+    }
+
+    /// Insert a file descriptor and return it's number.
+    /// Fails if it opens a file or directory while the process has reached it's open file limit.
+    pub fn insert_fd(&self, descriptor: FileDescriptor) -> Result<u32, LimitError> {
+        let handles = self.handles();
+        let mut fds = self.fds();
+        if matches!(
+            descriptor,
+            FileDescriptor::File(_) | FileDescriptor::Directory { .. }
+        ) {
+            let open = handles.len() + fds.open_files();
+            self.limits().check(Resource::OpenFiles, open)?;
+        }
+        Ok(fds.insert(descriptor))
+    }
+
+    /// Get the exit code the process set, if any
+    pub fn exit_code(&self) -> Option<i32> {
+        *self.exit_code.read().unwrap()
+    }
+
+    /// Set the exit code of the process
+    pub fn set_exit_code(&self, code: i32) {
+        *self.exit_code.spin_write().unwrap() = Some(code);
+    }
+
+    /// Get the message of the last failed spawn of the process
+    pub fn spawn_error(&self) -> Option<String> {
+        self.spawn_error.read().unwrap().clone()
+    }
+
+    /// Set the message of the last failed spawn, or clear it after a successful spawn
+    pub fn set_spawn_error(&self, error: Option<String>) {
+        *self.spawn_error.spin_write().unwrap() = error;
+    }
+
+    /// Get the code and message of the last failed api call of the thread
+    pub fn last_error(&self) -> Option<(i32, String)> {
+        self.last_error.spin_read().unwrap().clone()
+    }
+
+    /// Set the code and message of the last failed api call of the thread
+    pub fn set_last_error(&self, code: i32, message: String) {
+        *self.last_error.spin_write().unwrap() = Some((code, message));
+    }
+
+    /// Get the report of the crash that killed the process, if it crashed
+    pub fn crash_report(&self) -> Option<Arc<CrashReport>> {
+        self.crash_report.read().unwrap().clone()
+    }
+
+    /// Record the crash that killed the process
+    pub fn set_crash_report(&self, report: CrashReport) {
+        *self.crash_report.spin_write().unwrap() = Some(Arc::new(report));
+    }
+
     /// Get the module
     pub fn module(&self) -> Arc<Vec<u8>> {
         self.module.clone()
@@ -94,37 +432,152 @@ impl ProcessCtx {
     }
 
     /// Create a new copy for this worker
-    pub fn new_worker(&self, memory_inner: WebAssembly::Memory) -> Self {
+    pub fn new_worker(&self, memory_inner: WebAssembly::Memory, tid: u32) -> Self {
         let memory = self.memory.spin_lock().unwrap();
         let new_memory = Arc::new(Mutex::new(memory.new_inner(memory_inner)));
         let mut clone = self.clone();
         clone.memory = new_memory;
+        clone.tid = tid;
+        clone.last_error = Arc::new(RwLock::new(None));
         clone
     }
 }
 
+/// Get the directories preopened for a process.
+/// The working directory is opened as `.`, followed by the root of each mounted file system.
+/// File systems the process can't read are left out.
+fn preopens(cwd: &str, capabilities: Capabilities) -> Vec<(String, String)> {
+    let readable = |label: FsLabel| capabilities.allows(Capability::FsRead(label));
+    let mut preopens = Vec::new();
+    if FsLabel::extract_from_path(cwd).is_ok_and(readable) {
+        preopens.push((".".to_string(), cwd.to_string()));
+    }
+    for label in FsManager::get()
+        .labels()
+        .into_iter()
+        .filter(|label| readable(*label))
+    {
+        let root = format!("{}:/", label.to_string().to_lowercase());
+        preopens.push((root.clone(), root));
+    }
+    preopens
+}
+
 impl ApiModuleBuilder {
-    pub fn new() -> Self {
+    pub fn new(capabilities: Capabilities) -> Self {
         Self {
-            values: HashMap::new(),
+            capabilities,
+            features: HashSet::new(),
+            modules: HashMap::new(),
+            restricted: HashMap::new(),
+            trace: None,
         }
     }
 
-    /// Register an item
+    /// Record the calls to the built functions in a trace buffer while it's enabled, as made by a thread
+    pub fn trace(&mut self, trace: Arc<ProcessTrace>, tid: u32) -> &mut Self {
+        self.trace = Some((trace, tid));
+        self
+    }
+
+    /// Declare a feature of the api, like `fs` for the file system items
+    pub fn feature(&mut self, name: impl Into<String>) -> &mut Self {
+        self.features.insert(name.into());
+        self
+    }
+
+    /// Get the features of the api and the names of the items in the `hapi` module registered so far
+    pub fn features(&self) -> HashSet<String> {
+        let items = self.modules.get(HAPI_MODULE).into_iter().flatten();
+        self.features
+            .iter()
+            .cloned()
+            .chain(items.map(|(name, _)| name.clone()))
+            .collect()
+    }
+
+    /// Register an item in the `hapi` module
     pub fn register(&mut self, name: impl Into<String>, value: JsValue) -> &mut Self {
-        let name: String = name.into();
-        if self.values.contains_key(&name) {
-            self.values.remove(&name);
-        }
-        self.values.insert(name, value);
+        self.register_in(HAPI_MODULE, name, value)
+    }
+
+    /// Register an item in the `hapi` module that requires a capability.
+    /// Without the capability the item is replaced by a stub returning `PERMISSION_DENIED`.
+    pub fn register_restricted(
+        &mut self,
+        name: impl Into<String>,
+        capability: Capability,
+        value: JsValue,
+    ) -> &mut Self {
+        let name = name.into();
+        self.restricted
+            .insert(name.clone(), (capability, Denied::Code));
+        self.register(name, value)
+    }
+
+    /// Register an item in the `hapi` module that requires a capability and returns a pointer.
+    /// Without the capability the item is replaced by a stub returning null.
+    pub fn register_restricted_ptr(
+        &mut self,
+        name: impl Into<String>,
+        capability: Capability,
+        value: JsValue,
+    ) -> &mut Self {
+        let name = name.into();
+        self.restricted
+            .insert(name.clone(), (capability, Denied::Null));
+        self.register(name, value)
+    }
+
+    /// Register an item in an import module, replacing an item with the same name
+    pub fn register_in(
+        &mut self,
+        module: impl Into<String>,
+        name: impl Into<String>,
+        value: JsValue,
+    ) -> &mut Self {
+        self.modules
+            .entry(module.into())
+            .or_default()
+            .insert(name.into(), value);
         self
     }
 
-    /// Build the module object
+    /// Check if an item is registered in an import module
+    pub fn contains(&self, module: &str, name: &str) -> bool {
+        self.modules
+            .get(module)
+            .is_some_and(|module| module.contains_key(name))
+    }
+
+    /// Build the object holding each import module
     pub fn build(self) -> JsValue {
+        let denied_code = Function::new_no_args(&format!("return {};", PERMISSION_DENIED));
+        let denied_null = Function::new_no_args("return 0;");
+        let tracer = self.trace.map(|(trace, tid)| Tracer::new(trace, tid));
+
         let imports = JSON::parse("{}").unwrap();
-        for (name, value) in self.values.iter() {
-            Reflect::set(&imports, &name.into(), value).unwrap();
+        for (module_name, values) in self.modules.iter() {
+            let module = JSON::parse("{}").unwrap();
+            for (name, value) in values.iter() {
+                let denied = match self.restricted.get(name) {
+                    Some((capability, denied)) if module_name == HAPI_MODULE => {
+                        (!self.capabilities.allows(*capability)).then_some(*denied)
+                    }
+                    _ => None,
+                };
+                let value = match denied {
+                    Some(Denied::Code) => &denied_code,
+                    Some(Denied::Null) => &denied_null,
+                    None => value,
+                };
+                let value = match &tracer {
+                    Some(tracer) => tracer.wrap(name, value),
+                    None => value.clone(),
+                };
+                Reflect::set(&module, &name.into(), &value).unwrap();
+            }
+            Reflect::set(&imports, &module_name.into(), &module).unwrap();
         }
         imports
     }
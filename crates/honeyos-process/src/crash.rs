@@ -0,0 +1,163 @@
+//! Crash reports of processes whose worker threw.
+//! The worker sends the error, it's stack trace and the exported globals to the kernel,
+//! which records the report as the reason the process died.
+//! Processes with a `CRASH_DUMP_DIR` also get a dump written to that directory,
+//! holding the report and a snapshot of their memory.
+use honeyos_fs::{FsLabel, FsManager};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::js_sys::{Array, Reflect, Uint8Array};
+
+use crate::{
+    capabilities::Capability, context::ProcessCtx, manifest::Manifest, stdout::ProcessStdOut,
+};
+
+/// The environment variable holding the directory crash dumps are written to
+pub const CRASH_DUMP_DIR_VAR: &str = "CRASH_DUMP_DIR";
+
+/// The amount of bytes of stdout and stderr kept in a report
+pub const CRASH_OUTPUT_TAIL: usize = 4096;
+
+/// The amount of crash reports of dead processes the kernel keeps
+pub const CRASH_REPORT_CAPACITY: usize = 16;
+
+/// The prefix of the error the threads of a process unwind with when it calls exit.
+/// The workers don't report such errors as crashes.
+pub const EXIT_SENTINEL: &str = "honeyos::exit";
+
+/// The report of a crashed process
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CrashReport {
+    pub pid: String,
+    pub title: String,
+    /// The thread that crashed
+    pub tid: u32,
+    /// The error the worker threw
+    pub message: String,
+    /// The stack trace of the error, including the wasm frames
+    pub stack: Option<String>,
+    /// The values of the exported globals, like the stack pointer, as `(name, value)`
+    pub globals: Vec<(String, String)>,
+    /// The last output written to stdout
+    pub stdout_tail: String,
+    /// The last output written to stderr
+    pub stderr_tail: String,
+    pub manifest: Option<Manifest>,
+    /// The path of the dump, if one was written
+    pub dump_path: Option<String>,
+}
+
+impl CrashReport {
+    /// Create the report of a crashed process
+    pub fn new(
+        ctx: &ProcessCtx,
+        title: &str,
+        manifest: Option<Manifest>,
+        tid: u32,
+        message: String,
+    ) -> Self {
+        Self {
+            pid: ctx.pid().to_string(),
+            title: title.to_string(),
+            tid,
+            message,
+            stack: None,
+            globals: Vec::new(),
+            stdout_tail: output_tail(&ctx.stdout()),
+            stderr_tail: output_tail(&ctx.stderr()),
+            manifest,
+            dump_path: None,
+        }
+    }
+
+    /// Add the thread, stack trace and globals sent by the worker.
+    /// Returns the memory snapshot, if the worker sent one.
+    pub fn add_worker_data(&mut self, data: &JsValue) -> Option<Vec<u8>> {
+        if let Some(tid) = Reflect::get(data, &"tid".into())
+            .ok()
+            .and_then(|tid| tid.as_f64())
+        {
+            self.tid = tid as u32;
+        }
+        self.stack = Reflect::get(data, &"stack".into())
+            .ok()
+            .and_then(|stack| stack.as_string());
+        if let Ok(globals) = Reflect::get(data, &"globals".into()) {
+            if let Ok(globals) = globals.dyn_into::<Array>() {
+                for global in globals.iter() {
+                    let global: Array = global.unchecked_into();
+                    if let (Some(name), Some(value)) =
+                        (global.get(0).as_string(), global.get(1).as_string())
+                    {
+                        self.globals.push((name, value));
+                    }
+                }
+            }
+        }
+        Reflect::get(data, &"memory".into())
+            .ok()
+            .and_then(|memory| memory.dyn_into::<Uint8Array>().ok())
+            .map(|memory| memory.to_vec())
+    }
+
+    /// Serialize the report as json
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Get the tail of the retained output of a stream
+fn output_tail(stdout: &ProcessStdOut) -> String {
+    tail(&stdout.bytes(), CRASH_OUTPUT_TAIL)
+}
+
+/// Get the last `max` bytes of the output as a string
+pub fn tail(bytes: &[u8], max: usize) -> String {
+    let start = bytes.len().saturating_sub(max);
+    String::from_utf8_lossy(&bytes[start..]).into_owned()
+}
+
+/// Get the directory the process writes crash dumps to, if it has one.
+/// Dumps are only written to file systems the process may write to.
+pub fn dump_dir(ctx: &ProcessCtx) -> Option<String> {
+    let dir = ctx.env().get(CRASH_DUMP_DIR_VAR)?.clone();
+    let label = FsLabel::extract_from_path(&dir).ok()?;
+    if !ctx.capabilities().allows(Capability::FsWrite(label)) {
+        return None;
+    }
+    Some(dir)
+}
+
+/// Write a dump of a crashed process to a directory, as `<pid>.json` holding the report and `<pid>.mem` holding the memory.
+/// Returns the path of the report.
+pub fn write_dump(
+    dir: &str,
+    report: &CrashReport,
+    memory: Option<&[u8]>,
+) -> anyhow::Result<String> {
+    let dir = dir.trim_end_matches('/');
+    let report_path = format!("{}/{}.json", dir, report.pid);
+    let mut report = report.clone();
+    report.dump_path = Some(report_path.clone());
+
+    write_file(&report_path, report.to_json().as_bytes())?;
+    if let Some(memory) = memory {
+        write_file(&format!("{}/{}.mem", dir, report.pid), memory)?;
+    }
+    Ok(report_path)
+}
+
+/// Create a file and write the data to it
+fn write_file(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let label = FsLabel::extract_from_path(path)?;
+    let fs = FsManager::get().get_fs(label)?;
+    // The dump is written by the kernel, which can't block
+    let mut fs = loop {
+        if let Ok(fs) = fs.try_write() {
+            break fs;
+        }
+    };
+    let file = fs.create_file(path.get(3..).unwrap_or_default())?;
+    fs.write(file, 0, data)?;
+    Ok(())
+}
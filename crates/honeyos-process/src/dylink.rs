@@ -0,0 +1,601 @@
+//! Dynamic linking of shared libraries, following the `dylink.0` conventions of the wasm tool-conventions:
+//! https://github.com/WebAssembly/tool-conventions/blob/main/DynamicLinking.md
+//!
+//! The libraries a binary needs are resolved from the `LIBRARY_PATH` of the process when it's spawned,
+//! which also reserves the memory their data is placed at.
+//! Each worker of the process then instantiates the libraries before the binary, sharing it's memory and table,
+//! and adds their exports to the environment module of the binary.
+use std::sync::Arc;
+
+use hashbrown::{HashMap, HashSet};
+use honeyos_fs::{FsLabel, FsManager};
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use wasmparser::{BinaryReaderError, Dylink0SectionReader, Dylink0Subsection};
+use web_sys::js_sys::{Array, Function, Object, Reflect, WebAssembly, JSON};
+
+use crate::{
+    capabilities::{Capabilities, Capability},
+    context::ProcessCtx,
+    error::SpawnError,
+    memory::Memory,
+    requirements::WasmRequirements,
+};
+
+/// The name of the custom section holding the dynamic linking information
+pub const DYLINK_SECTION: &str = "dylink.0";
+
+/// The environment variable holding the directories libraries are searched in, seperated by `;`
+pub const LIBRARY_PATH_VAR: &str = "LIBRARY_PATH";
+
+/// The directories libraries are searched in, if the process has no `LIBRARY_PATH`
+pub const DEFAULT_LIBRARY_PATH: &str = "a:/lib";
+
+/// The seperator of the directories in the library path.
+/// Paths contain `:` after their fs label, so it can't be used.
+const LIBRARY_PATH_SEPARATOR: char = ';';
+
+/// (64Kib) The size of the stack shared by the position independent modules of a thread
+const DYLINK_STACK_SIZE: u32 = 65536;
+
+/// The alignment of the stack
+const DYLINK_STACK_ALIGN: u32 = 16;
+
+/// The environment items the linker provides to position independent modules
+const LINKER_IMPORTS: [&str; 4] = [
+    "__indirect_function_table",
+    "__memory_base",
+    "__table_base",
+    "__stack_pointer",
+];
+
+/// The import module of the addresses of data symbols
+const GOT_MEM_MODULE: &str = "GOT.mem";
+
+/// The import module of the table indices of function symbols
+const GOT_FUNC_MODULE: &str = "GOT.func";
+
+/// The functions a library exports to relocate and initialize itself
+const APPLY_DATA_RELOCS: &str = "__wasm_apply_data_relocs";
+const CALL_CTORS: &str = "__wasm_call_ctors";
+
+/// The dynamic linking information of a position independent module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DylinkInfo {
+    /// The size of the data of the module in bytes
+    pub memory_size: u32,
+    /// The alignment of the data as a power of two
+    pub memory_alignment: u32,
+    /// The amount of table slots the module uses
+    pub table_size: u32,
+    /// The alignment of the table slots as a power of two
+    pub table_alignment: u32,
+    /// The names of the libraries the module needs
+    pub needed: Vec<String>,
+}
+
+/// A shared library needed by a process
+#[derive(Debug, Clone)]
+pub struct Library {
+    /// The name the library is needed by
+    pub name: String,
+    /// The path the library was loaded from
+    pub path: String,
+    pub bin: Arc<Vec<u8>>,
+    pub requirements: Arc<WasmRequirements>,
+    /// The address the data of the library is placed at
+    pub memory_base: u32,
+}
+
+/// The modules a process links when it's instantiated
+#[derive(Debug, Clone, Default)]
+pub struct LinkPlan {
+    /// The libraries in the order they are instantiated, dependencies first
+    pub libraries: Vec<Library>,
+    /// The dynamic linking information of the binary, if it's position independent
+    pub binary: Option<DylinkInfo>,
+    /// The address the data of the binary is placed at, if it's position independent
+    pub binary_memory_base: u32,
+    /// The minimum size of the table imported by the binary
+    pub table_minimum: u32,
+    /// The symbols exported by the libraries and the binary
+    symbols: HashSet<String>,
+}
+
+impl DylinkInfo {
+    /// Parse the contents of the `dylink.0` section
+    pub fn parse(data: &[u8], offset: usize) -> Result<Self, BinaryReaderError> {
+        let mut info = Self::default();
+        for subsection in Dylink0SectionReader::new(data, offset) {
+            match subsection? {
+                Dylink0Subsection::MemInfo(mem_info) => {
+                    info.memory_size = mem_info.memory_size;
+                    info.memory_alignment = mem_info.memory_alignment;
+                    info.table_size = mem_info.table_size;
+                    info.table_alignment = mem_info.table_alignment;
+                }
+                Dylink0Subsection::Needed(needed) => {
+                    info.needed
+                        .extend(needed.into_iter().map(|name| name.to_string()));
+                }
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+}
+
+impl LinkPlan {
+    /// Resolve the libraries a binary needs from the directories in the library path.
+    /// `read` reads a library file, returning `None` if it does not exist or may not be read.
+    pub fn resolve(
+        requirements: &WasmRequirements,
+        library_path: &str,
+        read: impl Fn(&str) -> Option<Vec<u8>>,
+    ) -> Result<Self, SpawnError> {
+        let mut libraries = Vec::new();
+        let mut visited = HashSet::new();
+        let needed = requirements
+            .dylink
+            .as_ref()
+            .map(|dylink| dylink.needed.as_slice())
+            .unwrap_or_default();
+        for name in needed {
+            resolve_library(name, library_path, &read, &mut visited, &mut libraries)?;
+        }
+
+        let symbols = libraries
+            .iter()
+            .flat_map(|library| library.requirements.exports.iter())
+            .chain(requirements.exports.iter())
+            .cloned()
+            .collect();
+        Ok(Self {
+            libraries,
+            binary: requirements.dylink.clone(),
+            binary_memory_base: 0,
+            table_minimum: requirements.table_minimum,
+            symbols,
+        })
+    }
+
+    /// Check if the process is linked statically, without any position independent modules
+    pub fn is_static(&self) -> bool {
+        self.libraries.is_empty() && self.binary.is_none()
+    }
+
+    /// Check if the linker provides an import of a module
+    pub fn provides(&self, module: &str, name: &str, position_independent: bool) -> bool {
+        match module {
+            "env" => {
+                (position_independent && LINKER_IMPORTS.contains(&name))
+                    || self.symbols.contains(name)
+            }
+            GOT_MEM_MODULE | GOT_FUNC_MODULE => self.symbols.contains(name),
+            _ => false,
+        }
+    }
+
+    /// Reserve the memory the data of the libraries and the binary is placed at
+    pub fn reserve_memory(&mut self, memory: &mut Memory) -> Result<(), SpawnError> {
+        for library in self.libraries.iter_mut() {
+            let Some(dylink) = &library.requirements.dylink else {
+                continue;
+            };
+            library.memory_base = reserve(memory, dylink)
+                .ok_or_else(|| SpawnError::Memory(format!("No memory for `{}`", library.name)))?;
+        }
+        if let Some(dylink) = &self.binary {
+            self.binary_memory_base = reserve(memory, dylink)
+                .ok_or_else(|| SpawnError::Memory("No memory for the binary".to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve a library and it's dependencies, which are added first
+fn resolve_library(
+    name: &str,
+    library_path: &str,
+    read: &impl Fn(&str) -> Option<Vec<u8>>,
+    visited: &mut HashSet<String>,
+    libraries: &mut Vec<Library>,
+) -> Result<(), SpawnError> {
+    // Libraries may depend on each other, so each library is only loaded once
+    if !visited.insert(name.to_string()) {
+        return Ok(());
+    }
+
+    let (path, bin) = search(name, library_path, read)
+        .ok_or_else(|| SpawnError::MissingLibrary(name.to_string()))?;
+    let requirements = WasmRequirements::parse(&bin)
+        .map_err(|e| SpawnError::InvalidLibrary(name.to_string(), e.to_string()))?;
+    let Some(dylink) = &requirements.dylink else {
+        return Err(SpawnError::InvalidLibrary(
+            name.to_string(),
+            "The library is not position independent".to_string(),
+        ));
+    };
+    for dependency in dylink.needed.iter() {
+        resolve_library(dependency, library_path, read, visited, libraries)?;
+    }
+    libraries.push(Library {
+        name: name.to_string(),
+        path,
+        bin: Arc::new(bin),
+        requirements: Arc::new(requirements),
+        memory_base: 0,
+    });
+    Ok(())
+}
+
+/// Search a library in the library path. Names with an fs label are read as is.
+fn search(
+    name: &str,
+    library_path: &str,
+    read: &impl Fn(&str) -> Option<Vec<u8>>,
+) -> Option<(String, Vec<u8>)> {
+    if name.contains(':') {
+        return read(name).map(|bin| (name.to_string(), bin));
+    }
+    library_path
+        .split(LIBRARY_PATH_SEPARATOR)
+        .filter(|directory| !directory.is_empty())
+        .find_map(|directory| {
+            let path = format!("{}/{}", directory.trim_end_matches('/'), name);
+            read(&path).map(|bin| (path, bin))
+        })
+}
+
+/// Reserve memory for the data of a module, returning it's address
+fn reserve(memory: &mut Memory, dylink: &DylinkInfo) -> Option<u32> {
+    if dylink.memory_size == 0 {
+        return Some(0);
+    }
+    let align = 1u32.checked_shl(dylink.memory_alignment)?;
+    memory.alloc_aligned(dylink.memory_size, align)
+}
+
+/// Read a library from the file system, if the process may read it
+pub fn read_library(path: &str, capabilities: Capabilities) -> Option<Vec<u8>> {
+    let label = FsLabel::extract_from_path(path).ok()?;
+    if !capabilities.allows(Capability::FsRead(label)) {
+        return None;
+    }
+    let fs = FsManager::get().get_fs(label).ok()?;
+    let fs = fs.read().ok()?;
+    let file = fs.get_file(path.get(3..)?).ok()?;
+    fs.read(file).ok()
+}
+
+/// A symbol exported by a module
+struct Symbol {
+    value: JsValue,
+    /// The address the data of the exporting module is placed at
+    memory_base: u32,
+}
+
+/// A global holding the address or table index of a symbol
+struct GotEntry {
+    name: String,
+    function: bool,
+    global: WebAssembly::Global,
+}
+
+/// Links the libraries and the binary of a process within a worker.
+/// Every worker links the modules in the same order, so the table layout and function pointers match across threads.
+pub struct Linker {
+    ctx: Arc<ProcessCtx>,
+    plan: LinkPlan,
+    table: WebAssembly::Table,
+    stack: Option<u32>,
+    stack_pointer: Option<WebAssembly::Global>,
+    symbols: HashMap<String, Symbol>,
+    /// The exported functions, called by the functions imported before they are defined
+    functions: Object,
+    forwarded: Vec<String>,
+    got: Vec<GotEntry>,
+    function_indices: HashMap<String, u32>,
+    instances: Vec<WebAssembly::Instance>,
+}
+
+impl Linker {
+    pub fn new(ctx: Arc<ProcessCtx>, plan: LinkPlan, table: WebAssembly::Table) -> Self {
+        Self {
+            ctx,
+            plan,
+            table,
+            stack: None,
+            stack_pointer: None,
+            symbols: HashMap::new(),
+            functions: Object::new(),
+            forwarded: Vec::new(),
+            got: Vec::new(),
+            function_indices: HashMap::new(),
+            instances: Vec::new(),
+        }
+    }
+
+    /// Instantiate the compiled libraries, in the order of the link plan
+    pub async fn link_libraries(
+        &mut self,
+        modules: &Array,
+        imports: &JsValue,
+    ) -> anyhow::Result<()> {
+        // The binary places it's functions at fixed slots, unless it's position independent
+        if self.plan.binary.is_none() && self.table.length() < self.plan.table_minimum {
+            self.grow_table(self.plan.table_minimum - self.table.length())?;
+        }
+
+        for (library, module) in self.plan.libraries.clone().iter().zip(modules.iter()) {
+            let module: WebAssembly::Module = module.unchecked_into();
+            let dylink = library.requirements.dylink.clone().unwrap_or_default();
+            let table_base = self.reserve_table(&dylink)?;
+            let library_imports =
+                self.imports(&module, imports, Some((library.memory_base, table_base)))?;
+
+            let instance =
+                JsFuture::from(WebAssembly::instantiate_module(&module, &library_imports))
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("Failed to instantiate `{}`: {:?}", library.name, e)
+                    })?;
+            let instance: WebAssembly::Instance = instance.unchecked_into();
+
+            self.add_exports(&instance, library.memory_base, false);
+            self.instances.push(instance);
+        }
+        Ok(())
+    }
+
+    /// Build the imports of the binary, adding the exports of the libraries
+    pub fn binary_imports(
+        &mut self,
+        module: &WebAssembly::Module,
+        imports: &JsValue,
+    ) -> anyhow::Result<JsValue> {
+        let bases = match self.plan.binary.clone() {
+            Some(dylink) => Some((self.plan.binary_memory_base, self.reserve_table(&dylink)?)),
+            None => None,
+        };
+        Ok(self.imports(module, imports, bases)?.into())
+    }
+
+    /// Resolve the symbols imported through the global offset table and initialize the modules.
+    /// The libraries are only relocated and constructed once, by the main thread.
+    /// A position independent binary is relocated and constructed after them.
+    pub fn finish(
+        &mut self,
+        binary: &WebAssembly::Instance,
+        main_thread: bool,
+    ) -> anyhow::Result<()> {
+        // The exports of the binary take precedence over those of the libraries
+        self.add_exports(binary, self.plan.binary_memory_base, true);
+
+        for name in self.forwarded.iter() {
+            if !self.symbols.contains_key(name) {
+                return Err(anyhow::anyhow!("Undefined symbol `{}`", name));
+            }
+        }
+
+        for entry in std::mem::take(&mut self.got) {
+            let symbol = self
+                .symbols
+                .get(&entry.name)
+                .ok_or_else(|| anyhow::anyhow!("Undefined symbol `{}`", entry.name))?;
+            let value = if entry.function {
+                let function =
+                    symbol.value.clone().dyn_into::<Function>().map_err(|_| {
+                        anyhow::anyhow!("Symbol `{}` is not a function", entry.name)
+                    })?;
+                self.function_index(&entry.name, &function)?
+            } else {
+                let address = symbol
+                    .value
+                    .dyn_ref::<WebAssembly::Global>()
+                    .and_then(|global| global.value().as_f64())
+                    .ok_or_else(|| anyhow::anyhow!("Symbol `{}` is not data", entry.name))?;
+                (address as u32).wrapping_add(symbol.memory_base)
+            };
+            entry.global.set_value(&value.into());
+        }
+
+        if main_thread {
+            let binary = self.plan.binary.is_some().then_some(binary);
+            for instance in self.instances.iter().chain(binary) {
+                let exports = instance.exports();
+                for name in [APPLY_DATA_RELOCS, CALL_CTORS] {
+                    if let Ok(function) = Reflect::get(&exports, &name.into())
+                        .map_err(js_error)?
+                        .dyn_into::<Function>()
+                    {
+                        function
+                            .call0(&JsValue::NULL)
+                            .map_err(|e| anyhow::anyhow!("Failed to run `{}`: {:?}", name, e))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the imports of a module.
+    /// Position independent modules are placed at their memory and table base.
+    fn imports(
+        &mut self,
+        module: &WebAssembly::Module,
+        imports: &JsValue,
+        bases: Option<(u32, u32)>,
+    ) -> anyhow::Result<Object> {
+        let module_imports = Object::assign(&Object::new(), imports.unchecked_ref());
+        let env = Object::assign(
+            &Object::new(),
+            &Reflect::get(imports, &"env".into())
+                .map_err(js_error)?
+                .unchecked_into(),
+        );
+        let got_mem = Object::new();
+        let got_func = Object::new();
+
+        for import in WebAssembly::Module::imports(module).iter() {
+            let import_module = Reflect::get(&import, &"module".into())
+                .map_err(js_error)?
+                .as_string();
+            let name = Reflect::get(&import, &"name".into())
+                .map_err(js_error)?
+                .as_string()
+                .unwrap_or_default();
+            let kind = Reflect::get(&import, &"kind".into())
+                .map_err(js_error)?
+                .as_string();
+
+            match import_module.as_deref() {
+                Some("env") => {
+                    if Reflect::has(&env, &name.as_str().into()).map_err(js_error)? {
+                        continue;
+                    }
+                    let value: JsValue = match (name.as_str(), bases) {
+                        ("__indirect_function_table", Some(_)) => self.table.clone().into(),
+                        ("__memory_base", Some((memory_base, _))) => {
+                            i32_global(memory_base, false)?.into()
+                        }
+                        ("__table_base", Some((_, table_base))) => {
+                            i32_global(table_base, false)?.into()
+                        }
+                        ("__stack_pointer", Some(_)) => self.stack_pointer()?.into(),
+                        _ => match self.symbols.get(&name) {
+                            Some(symbol) => symbol.value.clone(),
+                            // Functions defined by a module that is instantiated later are called through the linker
+                            None if kind.as_deref() == Some("function") => {
+                                self.forwarded.push(name.clone());
+                                forwarder(&self.functions, &name)?.into()
+                            }
+                            None => continue,
+                        },
+                    };
+                    Reflect::set(&env, &name.into(), &value).map_err(js_error)?;
+                }
+                Some(got @ (GOT_MEM_MODULE | GOT_FUNC_MODULE)) => {
+                    let global = i32_global(0, true)?;
+                    let object = if got == GOT_MEM_MODULE {
+                        &got_mem
+                    } else {
+                        &got_func
+                    };
+                    Reflect::set(object, &name.as_str().into(), &global).map_err(js_error)?;
+                    self.got.push(GotEntry {
+                        name,
+                        function: got == GOT_FUNC_MODULE,
+                        global,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        Reflect::set(&module_imports, &"env".into(), &env).map_err(js_error)?;
+        Reflect::set(&module_imports, &GOT_MEM_MODULE.into(), &got_mem).map_err(js_error)?;
+        Reflect::set(&module_imports, &GOT_FUNC_MODULE.into(), &got_func).map_err(js_error)?;
+        Ok(module_imports)
+    }
+
+    /// Add the exports of an instance to the symbols.
+    /// The first definition of a symbol is kept, unless it's overridden.
+    fn add_exports(&mut self, instance: &WebAssembly::Instance, memory_base: u32, overwrite: bool) {
+        let exports = instance.exports();
+        for entry in Object::entries(&exports).iter() {
+            let entry: Array = entry.unchecked_into();
+            let Some(name) = entry.get(0).as_string() else {
+                continue;
+            };
+            if !overwrite && self.symbols.contains_key(&name) {
+                continue;
+            }
+            let value = entry.get(1);
+            if value.is_function() {
+                let _ = Reflect::set(&self.functions, &name.as_str().into(), &value);
+            }
+            self.symbols.insert(name, Symbol { value, memory_base });
+        }
+    }
+
+    /// Reserve table slots for a position independent module, returning the first slot
+    fn reserve_table(&self, dylink: &DylinkInfo) -> anyhow::Result<u32> {
+        let align = 1u32
+            .checked_shl(dylink.table_alignment)
+            .ok_or_else(|| anyhow::anyhow!("Invalid table alignment"))?;
+        let base = self.table.length().next_multiple_of(align);
+        self.grow_table(base - self.table.length() + dylink.table_size)?;
+        Ok(base)
+    }
+
+    /// Get the table index of a function, adding it to the table if it has no index yet
+    fn function_index(&mut self, name: &str, function: &Function) -> anyhow::Result<u32> {
+        if let Some(index) = self.function_indices.get(name) {
+            return Ok(*index);
+        }
+        let index = self.grow_table(1)?;
+        self.table
+            .set(index, function)
+            .map_err(|e| anyhow::anyhow!("Failed to add `{}` to the table: {:?}", name, e))?;
+        self.function_indices.insert(name.to_string(), index);
+        Ok(index)
+    }
+
+    /// Grow the table, returning the previous length
+    fn grow_table(&self, slots: u32) -> anyhow::Result<u32> {
+        self.table
+            .grow(slots)
+            .map_err(|e| anyhow::anyhow!("Failed to grow the table: {:?}", e))
+    }
+
+    /// Get the stack allocated for the thread, if a position independent module needed one.
+    /// The stack is not freed by the linker, as it's used until the thread finishes.
+    pub fn stack(&self) -> Option<u32> {
+        self.stack
+    }
+
+    /// Get the stack pointer shared by the position independent modules of this thread,
+    /// allocating the stack when it's first needed
+    fn stack_pointer(&mut self) -> anyhow::Result<WebAssembly::Global> {
+        if let Some(stack_pointer) = &self.stack_pointer {
+            return Ok(stack_pointer.clone());
+        }
+        let stack = self
+            .ctx
+            .memory()
+            .alloc_aligned(DYLINK_STACK_SIZE, DYLINK_STACK_ALIGN)
+            .ok_or_else(|| anyhow::anyhow!("Failed to allocate the stack"))?;
+        self.stack = Some(stack);
+        // The stack grows downwards
+        let stack_pointer = i32_global(stack + DYLINK_STACK_SIZE, true)?;
+        self.stack_pointer = Some(stack_pointer.clone());
+        Ok(stack_pointer)
+    }
+}
+
+/// Create an i32 global
+fn i32_global(value: u32, mutable: bool) -> anyhow::Result<WebAssembly::Global> {
+    let descriptor = JSON::parse("{}").unwrap();
+    Reflect::set(&descriptor, &"value".into(), &"i32".into()).unwrap();
+    Reflect::set(&descriptor, &"mutable".into(), &mutable.into()).unwrap();
+    WebAssembly::Global::new(descriptor.unchecked_ref(), &(value as i32).into())
+        .map_err(|e| anyhow::anyhow!("Failed to create global: {:?}", e))
+}
+
+/// Create a function that calls the exported function with the same name, once it's defined
+fn forwarder(functions: &Object, name: &str) -> anyhow::Result<Function> {
+    let factory = Function::new_with_args(
+        "functions, name",
+        "return function () { return functions[name].apply(null, arguments); };",
+    );
+    factory
+        .call2(&JsValue::NULL, functions, &name.into())
+        .map(|forwarder| forwarder.unchecked_into())
+        .map_err(|e| anyhow::anyhow!("Failed to create forwarder for `{}`: {:?}", name, e))
+}
+
+/// Convert a js error
+fn js_error(e: JsValue) -> anyhow::Error {
+    anyhow::anyhow!("{:?}", e)
+}
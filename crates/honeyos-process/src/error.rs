@@ -0,0 +1,94 @@
+use crate::{context::HAPI_VERSION, limits::LimitError, manifest::ManifestError};
+
+/// The error types for spawning a process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The binary is not a valid wasm module
+    InvalidBinary(String),
+    /// The binary imports items the api does not provide, as `module.name`
+    UnknownImports(Vec<String>),
+    /// The binary does not export it's entrypoint function
+    MissingEntrypoint(String),
+    /// The manifest of the binary is invalid
+    InvalidManifest(ManifestError),
+    /// The binary exceeds a resource limit of the process
+    Limit(LimitError),
+    /// The memory of the process could not be created
+    Memory(String),
+    /// A library the binary needs was not found in the library path
+    MissingLibrary(String),
+    /// A library the binary needs is invalid, as `(name, error)`
+    InvalidLibrary(String, String),
+    /// The binary requires a newer or incompatible version of the api, as `(major, minor)`
+    UnsupportedHapiVersion((u32, u32)),
+}
+
+impl SpawnError {
+    /// The error code returned by the api when spawning fails.
+    /// The codes are distinct from the other error codes of the api.
+    pub fn code(&self) -> i32 {
+        match self {
+            SpawnError::InvalidBinary(_) => -30,
+            SpawnError::UnknownImports(_) => -31,
+            SpawnError::MissingEntrypoint(_) => -32,
+            SpawnError::InvalidManifest(_) => -33,
+            SpawnError::Memory(_) => -34,
+            SpawnError::MissingLibrary(_) => -35,
+            SpawnError::InvalidLibrary(..) => -36,
+            SpawnError::UnsupportedHapiVersion(_) => -37,
+            SpawnError::Limit(LimitError::Exceeded(resource)) => resource.exceeded_code(),
+            SpawnError::Limit(_) => -1,
+        }
+    }
+}
+
+impl From<ManifestError> for SpawnError {
+    fn from(error: ManifestError) -> Self {
+        SpawnError::InvalidManifest(error)
+    }
+}
+
+impl From<LimitError> for SpawnError {
+    fn from(error: LimitError) -> Self {
+        SpawnError::Limit(error)
+    }
+}
+
+impl From<wasmparser::BinaryReaderError> for SpawnError {
+    fn from(error: wasmparser::BinaryReaderError) -> Self {
+        SpawnError::InvalidBinary(error.to_string())
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpawnError::InvalidBinary(error) => writeln!(f, "Invalid wasm binary: {}", error),
+            SpawnError::UnknownImports(imports) => {
+                writeln!(f, "Unknown imports: {}", imports.join(", "))
+            }
+            SpawnError::MissingEntrypoint(name) => {
+                writeln!(f, "The binary does not export it's entrypoint: {}", name)
+            }
+            SpawnError::InvalidManifest(error) => write!(f, "{}", error),
+            SpawnError::Limit(error) => write!(f, "{}", error),
+            SpawnError::Memory(error) => {
+                writeln!(f, "Failed to create the memory of the process: {}", error)
+            }
+            SpawnError::MissingLibrary(name) => writeln!(f, "Library not found: {}", name),
+            SpawnError::InvalidLibrary(name, error) => {
+                writeln!(f, "Invalid library {}: {}", name, error)
+            }
+            SpawnError::UnsupportedHapiVersion((major, minor)) => {
+                let (hapi_major, hapi_minor) = HAPI_VERSION;
+                writeln!(
+                    f,
+                    "The binary requires api version {}.{}, the kernel provides {}.{}",
+                    major, minor, hapi_major, hapi_minor
+                )
+            }
+        }
+    }
+}
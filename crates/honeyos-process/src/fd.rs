@@ -0,0 +1,119 @@
+//! The file descriptor table of a process, used by the WASI api.
+//! Descriptors only record what they refer to, the file systems are accessed through the `FsManager`.
+use std::collections::BTreeMap;
+
+use honeyos_fs::FsLabel;
+use uuid::Uuid;
+
+/// The descriptor of the stdin
+pub const STDIN_FD: u32 = 0;
+/// The descriptor of the stdout
+pub const STDOUT_FD: u32 = 1;
+/// The descriptor of the stderr
+pub const STDERR_FD: u32 = 2;
+
+/// What a file descriptor refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileDescriptor {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// A directory opened before the process started.
+    /// The name is what the process sees, the path is where it points to.
+    Preopen {
+        name: String,
+        path: String,
+    },
+    /// A directory opened by the process
+    Directory {
+        path: String,
+    },
+    /// A file opened by the process
+    File(OpenFile),
+}
+
+/// A file opened by the process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    pub label: FsLabel,
+    pub id: Uuid,
+    pub path: String,
+    pub offset: u64,
+    pub append: bool,
+}
+
+/// The file descriptors of a process.
+/// New descriptors always get the lowest free number.
+#[derive(Debug, Clone)]
+pub struct FdTable {
+    fds: BTreeMap<u32, FileDescriptor>,
+}
+
+impl FdTable {
+    /// Create a table with the stdio descriptors, followed by a preopen for each directory
+    pub fn new(preopens: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut table = Self {
+            fds: BTreeMap::new(),
+        };
+        table.fds.insert(STDIN_FD, FileDescriptor::Stdin);
+        table.fds.insert(STDOUT_FD, FileDescriptor::Stdout);
+        table.fds.insert(STDERR_FD, FileDescriptor::Stderr);
+        for (name, path) in preopens {
+            table.insert(FileDescriptor::Preopen { name, path });
+        }
+        table
+    }
+
+    /// Insert a descriptor and return it's number
+    pub fn insert(&mut self, descriptor: FileDescriptor) -> u32 {
+        let fd = self
+            .fds
+            .keys()
+            .enumerate()
+            .find(|(index, fd)| *index as u32 != **fd)
+            .map_or(self.fds.len() as u32, |(index, _)| index as u32);
+        self.fds.insert(fd, descriptor);
+        fd
+    }
+
+    /// Get a descriptor
+    pub fn get(&self, fd: u32) -> Option<&FileDescriptor> {
+        self.fds.get(&fd)
+    }
+
+    /// Get a descriptor
+    pub fn get_mut(&mut self, fd: u32) -> Option<&mut FileDescriptor> {
+        self.fds.get_mut(&fd)
+    }
+
+    /// Close a descriptor
+    pub fn remove(&mut self, fd: u32) -> Option<FileDescriptor> {
+        self.fds.remove(&fd)
+    }
+
+    /// Move a descriptor to another number, closing the descriptor at that number.
+    /// Returns false if the descriptor does not exist.
+    pub fn renumber(&mut self, from: u32, to: u32) -> bool {
+        if !self.fds.contains_key(&to) {
+            return false;
+        }
+        let Some(descriptor) = self.fds.remove(&from) else {
+            return false;
+        };
+        self.fds.insert(to, descriptor);
+        true
+    }
+
+    /// The amount of files and directories opened by the process
+    pub fn open_files(&self) -> usize {
+        self.fds
+            .values()
+            .filter(|fd| {
+                matches!(
+                    fd,
+                    FileDescriptor::File(_) | FileDescriptor::Directory { .. }
+                )
+            })
+            .count()
+    }
+}
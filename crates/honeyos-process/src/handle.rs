@@ -0,0 +1,194 @@
+//! The handle table of a process.
+//! Handles are small numbers a process refers to kernel objects by, like processes, pipes, files and threads.
+//! Every handle has a kind that is checked when it's used, so a handle can't be passed as another kind of object.
+//! The handles of a process are released when it exits.
+use std::collections::BTreeMap;
+
+use honeyos_fs::FsLabel;
+use uuid::Uuid;
+
+/// The handle a process refers to itself by, where a process handle is expected.
+/// Handles in the table start at `1`.
+pub const SELF_HANDLE: u32 = 0;
+
+/// The kind of object a handle refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandleKind {
+    Process = 1,
+    Request = 2,
+    Pipe = 3,
+    File = 4,
+    Directory = 5,
+    Thread = 6,
+}
+
+/// A kernel object referred to by a handle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelObject {
+    Process(Uuid),
+    /// A network request
+    Request(Uuid),
+    /// A pipe end owned by the process
+    Pipe(Uuid),
+    File(FsLabel, Uuid),
+    Directory(FsLabel, Uuid),
+    /// A thread of the process
+    Thread(u32),
+}
+
+impl KernelObject {
+    /// Get the kind of the object
+    pub fn kind(&self) -> HandleKind {
+        match self {
+            KernelObject::Process(_) => HandleKind::Process,
+            KernelObject::Request(_) => HandleKind::Request,
+            KernelObject::Pipe(_) => HandleKind::Pipe,
+            KernelObject::File(..) => HandleKind::File,
+            KernelObject::Directory(..) => HandleKind::Directory,
+            KernelObject::Thread(_) => HandleKind::Thread,
+        }
+    }
+}
+
+/// The error types for handles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandleError {
+    /// The handle is not in the table of the process
+    NoSuchHandle(u32),
+    /// The handle refers to another kind of object
+    WrongKind {
+        handle: u32,
+        expected: HandleKind,
+        found: HandleKind,
+    },
+}
+
+/// The handles of a process.
+/// New handles always get the lowest free number.
+#[derive(Debug, Clone, Default)]
+pub struct HandleTable {
+    handles: BTreeMap<u32, KernelObject>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert an object and return it's handle
+    pub fn insert(&mut self, object: KernelObject) -> u32 {
+        let handle = self
+            .handles
+            .keys()
+            .zip(1..)
+            .find(|(handle, expected)| **handle != *expected)
+            .map_or(self.handles.len() as u32 + 1, |(_, expected)| expected);
+        self.handles.insert(handle, object);
+        handle
+    }
+
+    /// Get the object of a handle
+    pub fn get(&self, handle: u32) -> Result<&KernelObject, HandleError> {
+        self.handles
+            .get(&handle)
+            .ok_or(HandleError::NoSuchHandle(handle))
+    }
+
+    /// Get the id of the process of a process handle
+    pub fn process(&self, handle: u32) -> Result<Uuid, HandleError> {
+        match self.get(handle)? {
+            KernelObject::Process(id) => Ok(*id),
+            object => Err(wrong_kind(handle, HandleKind::Process, object)),
+        }
+    }
+
+    /// Get the id of the request of a request handle
+    pub fn request(&self, handle: u32) -> Result<Uuid, HandleError> {
+        match self.get(handle)? {
+            KernelObject::Request(id) => Ok(*id),
+            object => Err(wrong_kind(handle, HandleKind::Request, object)),
+        }
+    }
+
+    /// Get the id of the pipe end of a pipe handle
+    pub fn pipe(&self, handle: u32) -> Result<Uuid, HandleError> {
+        match self.get(handle)? {
+            KernelObject::Pipe(id) => Ok(*id),
+            object => Err(wrong_kind(handle, HandleKind::Pipe, object)),
+        }
+    }
+
+    /// Get the file system and id of the file of a file handle
+    pub fn file(&self, handle: u32) -> Result<(FsLabel, Uuid), HandleError> {
+        match self.get(handle)? {
+            KernelObject::File(label, id) => Ok((*label, *id)),
+            object => Err(wrong_kind(handle, HandleKind::File, object)),
+        }
+    }
+
+    /// Get the file system and id of the directory of a directory handle
+    pub fn directory(&self, handle: u32) -> Result<(FsLabel, Uuid), HandleError> {
+        match self.get(handle)? {
+            KernelObject::Directory(label, id) => Ok((*label, *id)),
+            object => Err(wrong_kind(handle, HandleKind::Directory, object)),
+        }
+    }
+
+    /// Get the id of the thread of a thread handle
+    pub fn thread(&self, handle: u32) -> Result<u32, HandleError> {
+        match self.get(handle)? {
+            KernelObject::Thread(tid) => Ok(*tid),
+            object => Err(wrong_kind(handle, HandleKind::Thread, object)),
+        }
+    }
+
+    /// Remove a handle, returning the object it referred to
+    pub fn remove(&mut self, handle: u32) -> Result<KernelObject, HandleError> {
+        self.handles
+            .remove(&handle)
+            .ok_or(HandleError::NoSuchHandle(handle))
+    }
+
+    /// Remove every handle, returning the objects they referred to
+    pub fn drain(&mut self) -> Vec<KernelObject> {
+        std::mem::take(&mut self.handles).into_values().collect()
+    }
+
+    /// Get the amount of handles
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Check if the table has no handles
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+}
+
+/// The error for a handle of the wrong kind
+fn wrong_kind(handle: u32, expected: HandleKind, object: &KernelObject) -> HandleError {
+    HandleError::WrongKind {
+        handle,
+        expected,
+        found: object.kind(),
+    }
+}
+
+impl std::error::Error for HandleError {}
+
+impl std::fmt::Display for HandleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandleError::NoSuchHandle(handle) => writeln!(f, "No such handle: {}", handle),
+            HandleError::WrongKind {
+                handle,
+                expected,
+                found,
+            } => writeln!(
+                f,
+                "Handle {} refers to a {:?}, not a {:?}",
+                handle, found, expected
+            ),
+        }
+    }
+}
@@ -1,25 +1,36 @@
 import __init, * as __kernel from "BINDGEN_SHIM_URL";
+import { crashReport, isExit } from "WORKER_COMMON_URL";
 
 self.onmessage = async event => {
     self.onmessage = undefined; // Prevent eval from reading onmessage
-    const [pid, kernel, kernel_memory, memory, f_ptr] = event.data;
+    const [pid, kernel, kernel_memory, memory, module, libraries, f_ptr, tid, arg] = event.data;
 
     __init(kernel, kernel_memory).catch(err => {
         console.error("Failed to initialize module: " + err);
         throw err;
     }).then(async () => {
+        let instance;
         try {
             const table = new WebAssembly.Table({
                 initial: 4,
                 element: "anyfunc"
             });
-            let instance = await __kernel.create_instance(pid, memory, table);
-            instance.exports._thread_entrypoint(f_ptr);
-            postMessage({}); // Tell the kernel the process is dead
+            instance = await __kernel.create_instance(pid, module, libraries, memory, table, tid);
+            const retval = instance.exports._thread_entrypoint(f_ptr, arg);
+            postMessage({ tid, retval }); // Tell the kernel the thread is dead
             close();
         }
         catch (e) {
+            // The threads of a process that called exit unwind without crashing
+            if (isExit(e)) {
+                postMessage({ tid });
+                close();
+                return;
+            }
+
+            // Tell the kernel the thread failed
             console.error(e);
+            postMessage({ tid, ...crashReport(__kernel, pid, e, instance, memory) });
             close();
         }
     })
@@ -0,0 +1,37 @@
+// The code shared by the workers of processes and threads
+
+// The prefix of the error a process and it's threads unwind with when the process calls exit
+const EXIT_SENTINEL = "EXIT_SENTINEL_PREFIX";
+
+// Check if an error was thrown because the process called exit
+export function isExit(e) {
+    return String(e).startsWith(EXIT_SENTINEL);
+}
+
+// The values of the exported globals, like the stack pointer
+function exportedGlobals(instance) {
+    const globals = [];
+    for (const [name, value] of Object.entries(instance.exports)) {
+        if (value instanceof WebAssembly.Global) {
+            try {
+                globals.push([name, String(value.value)]);
+            }
+            catch (_) { } // Globals like v128 can't be read from js
+        }
+    }
+    return globals;
+}
+
+// Collect the crash report of a failed instance, with a snapshot of it's memory if the process keeps crash dumps
+export function crashReport(kernel, pid, e, instance, memory) {
+    const crash = { error: String(e), stack: e && e.stack ? String(e.stack) : undefined };
+    if (instance) {
+        crash.globals = exportedGlobals(instance);
+        if (kernel.process_crash_dumps(pid)) {
+            const exported = instance.exports.memory;
+            const buffer = (exported instanceof WebAssembly.Memory ? exported : memory).buffer;
+            crash.memory = new Uint8Array(buffer).slice();
+        }
+    }
+    return crash;
+}
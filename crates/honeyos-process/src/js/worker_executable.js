@@ -1,20 +1,36 @@
 import __init, * as __kernel from "BINDGEN_SHIM_URL";
+import { crashReport, isExit } from "WORKER_COMMON_URL";
 
 self.onmessage = async event => {
     self.onmessage = undefined;
-    const [pid, kernel, kernel_memory, memory] = event.data;
+    const [pid, kernel, kernel_memory, memory, module, libraries] = event.data;
 
     __init(kernel, kernel_memory).catch(err => {
         console.error("Failed to initialize module: " + err);
         throw err;
     }).then(async () => {
-        const table = new WebAssembly.Table({
-            initial: 4,
-            element: "anyfunc"
-        });
-        let instance = await __kernel.create_instance(pid, memory, table);
-        instance.exports._start();
-        postMessage({}); // Tell the kernel the process is dead
-        close();
+        let instance;
+        try {
+            const table = new WebAssembly.Table({
+                initial: 4,
+                element: "anyfunc"
+            });
+            instance = await __kernel.create_instance(pid, module, libraries, memory, table);
+            instance.exports[__kernel.process_entrypoint(pid)]();
+            postMessage({}); // Tell the kernel the process is dead
+            close();
+        }
+        catch (e) {
+            // A process that called exit did not crash, so it's memory is not snapshotted
+            if (isExit(e)) {
+                postMessage({ error: String(e) });
+                close();
+                return;
+            }
+
+            // Tell the kernel the process failed
+            postMessage(crashReport(__kernel, pid, e, instance, memory));
+            close();
+        }
     })
-}
\ No newline at end of file
+}
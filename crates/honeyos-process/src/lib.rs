@@ -1,29 +1,83 @@
 use std::sync::{Arc, Mutex, Once};
 
+use capabilities::Capabilities;
 use context::ApiBuilderFn;
+use crash::{CrashReport, CRASH_REPORT_CAPACITY};
+use error::SpawnError;
+use handle::KernelObject;
 use hashbrown::{
     hash_map::{Values, ValuesMut},
     HashMap,
 };
+use limits::ResourceLimits;
+use pipe::{Pipe, PipeEnd, PipeReader, PipeWriter};
 use process::Process;
-use thread::ThreadRequest;
+use thread::{ThreadError, ThreadRequest, MAIN_THREAD_ID};
 use uuid::Uuid;
 
+pub mod allocator;
+pub mod capabilities;
 pub mod context;
+pub mod crash;
+pub mod dylink;
+pub mod error;
+pub mod fd;
+pub mod handle;
+pub mod limits;
+pub mod manifest;
 pub mod memory;
+pub mod module_cache;
+pub mod pipe;
 pub mod process;
 pub mod requirements;
+pub mod ring;
+pub mod stdin;
 pub mod stdout;
+pub mod tests;
 pub mod thread;
+pub mod trace;
+pub mod weak;
 
 static mut PROCESS_MANAGER: Option<Arc<Mutex<ProcessManager>>> = None;
 
+/// The options a process gets spawned with
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// The command-line arguments passed to the process
+    pub args: Vec<String>,
+    /// The environment variables of the process
+    pub env: HashMap<String, String>,
+    /// The process that spawned the process, if any
+    pub parent: Option<Uuid>,
+    /// The pipe the process reads it's stdin from
+    pub stdin: Option<PipeReader>,
+    /// The pipe the process writes it's stdout to
+    pub stdout: Option<PipeWriter>,
+    /// The pipe the process writes it's stderr to
+    pub stderr: Option<PipeWriter>,
+    /// The resource limits of the process.
+    /// The memory limit lowers the maximum the binary declares
+    pub limits: ResourceLimits,
+    /// Whether the process may raise it's resource limits
+    pub privileged: bool,
+    /// The parts of the api the process may use
+    pub capabilities: Capabilities,
+    /// Whether the api calls of the process are traced from the start, also enabled by the `HAPI_TRACE` environment variable
+    pub trace: bool,
+}
+
 /// A manager for the seperate processes in honeyos
 pub struct ProcessManager {
     api_builder: ApiBuilderFn,
     processes: HashMap<Uuid, Process>,
-    spawn_requests: Vec<Uuid>,           // Spawns are handled by the kernel
+    pipes: HashMap<Uuid, (Uuid, PipeEnd)>, // The pipe ends mapped to their owning process
+    spawn_requests: Vec<Uuid>,             // Spawns are handled by the kernel
     thread_requests: Vec<ThreadRequest>, // Thread spawn requests are also handled by the kernel as chrome does not support nested web workers
+    thread_kill_requests: Vec<(Uuid, u32)>, // Workers can only be terminated from the kernel
+    exit_requests: Vec<Uuid>,
+    exit_codes: HashMap<Uuid, (Option<Uuid>, i32)>, // The exit codes of dead processes and their parents, until the parent takes them, closes it's handle or exits
+    crash_reports: Vec<(Uuid, Arc<CrashReport>)>, // The reports of the last crashed processes, oldest first
+    released: Vec<KernelObject>, // The objects of the handles of dead processes, until the kernel releases them
 }
 
 impl ProcessManager {
@@ -35,8 +89,14 @@ impl ProcessManager {
             PROCESS_MANAGER = Some(Arc::new(Mutex::new(ProcessManager {
                 api_builder,
                 processes: HashMap::new(),
+                pipes: HashMap::new(),
                 spawn_requests: Vec::new(),
                 thread_requests: Vec::new(),
+                thread_kill_requests: Vec::new(),
+                exit_requests: Vec::new(),
+                exit_codes: HashMap::new(),
+                crash_reports: Vec::new(),
+                released: Vec::new(),
             })));
         });
     }
@@ -51,22 +111,25 @@ impl ProcessManager {
         }
     }
 
-    /// Spawn a process
+    /// Spawn a process.
+    /// The binary is validated up front, so a broken binary fails here instead of in it's worker.
     pub fn spawn(
         &mut self,
         wasm_bin: Vec<u8>,
         title: Option<&str>,
         working_directory: &str,
-    ) -> anyhow::Result<Uuid> {
+        options: SpawnOptions,
+    ) -> Result<Uuid, SpawnError> {
         let id = Uuid::new_v4();
-        let title = if let Some(title) = title {
-            title.to_string()
-        } else {
-            id.to_string()
-        };
         // Insert the process into the hashmap
-        let process =
-            Process::new(id, wasm_bin, &title, working_directory, self.api_builder).unwrap();
+        let process = Process::new(
+            id,
+            wasm_bin,
+            title,
+            working_directory,
+            options,
+            self.api_builder,
+        )?;
         self.processes.insert(id, process);
 
         // Spawn the process
@@ -74,28 +137,162 @@ impl ProcessManager {
         Ok(id)
     }
 
-    /// Spawn a thread for a process
-    pub fn spawn_thread(&mut self, pid: Uuid, fptr: u32) {
-        self.thread_requests.push(ThreadRequest { pid, fptr });
+    /// Spawn a thread for a process, passing an argument to the entrypoint.
+    /// The thread id is reserved immediately, while the worker is spawned during the next update.
+    /// Fails if the process does not exist or has reached it's thread limit.
+    pub fn spawn_thread(&mut self, pid: Uuid, fptr: u32, arg: u32) -> Result<u32, ThreadError> {
+        let tid = self
+            .processes
+            .get_mut(&pid)
+            .ok_or(ThreadError::NoSuchProcess(pid))?
+            .reserve_thread()?;
+        self.thread_requests.push(ThreadRequest {
+            pid,
+            tid,
+            fptr,
+            arg,
+        });
+        Ok(tid)
+    }
+
+    /// Kill a thread of a process.
+    /// As workers can only be terminated by the kernel, the thread is killed during the next update.
+    /// Returns false if the thread does not exist.
+    pub fn kill_thread(&mut self, pid: Uuid, tid: u32) -> bool {
+        let Some(process) = self.processes.get(&pid) else {
+            return false;
+        };
+        if tid == MAIN_THREAD_ID || process.thread_state(tid).is_none() {
+            return false;
+        }
+        self.thread_kill_requests.push((pid, tid));
+        true
+    }
+
+    /// Terminate a process that has set it's exit code.
+    /// As workers can only be terminated by the kernel, the process is terminated during the next update.
+    pub fn request_exit(&mut self, pid: Uuid) {
+        self.exit_requests.push(pid);
+    }
+
+    /// Take the exit code of a dead process.
+    /// Returns `None` if the process is still alive, did not exit on it's own or the code has been taken.
+    pub fn take_exit_code(&mut self, pid: Uuid) -> Option<i32> {
+        self.exit_codes.remove(&pid).map(|(_, code)| code)
+    }
+
+    /// Forget the exit code of a dead process once it's parent closed it's handle to it
+    pub fn release_exit_code(&mut self, parent: Uuid, pid: Uuid) {
+        if self
+            .exit_codes
+            .get(&pid)
+            .is_some_and(|(p, _)| *p == Some(parent))
+        {
+            self.exit_codes.remove(&pid);
+        }
+    }
+
+    /// Get the crash report of a process.
+    /// The reports of the last crashed processes are kept after they are removed.
+    pub fn crash_report(&self, pid: Uuid) -> Option<Arc<CrashReport>> {
+        if let Some(process) = self.processes.get(&pid) {
+            return process.ctx().crash_report();
+        }
+        self.crash_reports
+            .iter()
+            .find(|(id, _)| *id == pid)
+            .map(|(_, report)| report.clone())
+    }
+
+    /// Take the objects the handles of dead processes referred to.
+    /// Objects owned by the process manager, like pipe ends, are already released.
+    pub fn take_released(&mut self) -> Vec<KernelObject> {
+        std::mem::take(&mut self.released)
+    }
+
+    /// Create a pipe owned by a process.
+    /// Returns the ids of the read and write end.
+    pub fn create_pipe(&mut self, owner: Uuid, capacity: usize) -> (Uuid, Uuid) {
+        let (reader, writer) = Pipe::create(capacity);
+        let reader_id = Uuid::new_v4();
+        let writer_id = Uuid::new_v4();
+        self.pipes
+            .insert(reader_id, (owner, PipeEnd::Reader(reader)));
+        self.pipes
+            .insert(writer_id, (owner, PipeEnd::Writer(writer)));
+        (reader_id, writer_id)
+    }
+
+    /// Get a pipe end owned by a process
+    pub fn pipe(&self, owner: Uuid, id: Uuid) -> Option<&PipeEnd> {
+        let (pipe_owner, end) = self.pipes.get(&id)?;
+        if *pipe_owner != owner {
+            return None;
+        }
+        Some(end)
+    }
+
+    /// Close a pipe end owned by a process.
+    /// Returns false if the process does not own a pipe end with the id.
+    pub fn close_pipe(&mut self, owner: Uuid, id: Uuid) -> bool {
+        if self.pipe(owner, id).is_none() {
+            return false;
+        }
+        self.pipes.remove(&id);
+        true
     }
 
     /// Check for the status of each process and remove those no longer running
     pub fn update(&mut self) {
-        // Remove dead processes
+        // Terminate processes that called exit
+        for pid in std::mem::take(&mut self.exit_requests) {
+            if let Some(process) = self.processes.get_mut(&pid) {
+                process.kill();
+            }
+        }
+
+        // Drop the workers of forgotten threads and remove dead processes
         let mut dead = Vec::new();
         for (id, process) in self.processes.iter_mut() {
+            process.reap_threads();
             if !process.is_alive() {
                 dead.push(*id);
             }
         }
         for id in dead {
-            self.processes.remove(&id);
+            if let Some(process) = self.processes.remove(&id) {
+                // The exit code is only kept while the parent can take it
+                let parent = process.ctx().parent();
+                if let Some(code) = process.exit_code() {
+                    if parent.is_some_and(|parent| self.processes.contains_key(&parent)) {
+                        self.exit_codes.insert(id, (parent, code));
+                    }
+                }
+                self.exit_codes.retain(|_, (parent, _)| *parent != Some(id));
+                if let Some(report) = process.ctx().crash_report() {
+                    if self.crash_reports.len() >= CRASH_REPORT_CAPACITY {
+                        self.crash_reports.remove(0);
+                    }
+                    self.crash_reports.push((id, report));
+                }
+                // Release the pipes connected to the process's stdio
+                process.stdin().release();
+                process.stdout().release();
+                process.stderr().release();
+                self.released.extend(process.ctx().handles().drain());
+            }
+            self.pipes.retain(|_, (owner, _)| *owner != id);
         }
 
         // Handle spawn requests
         for request in self.spawn_requests.iter() {
-            let process = self.processes.get_mut(request).unwrap();
-            process.spawn().unwrap();
+            let Some(process) = self.processes.get_mut(request) else {
+                continue;
+            };
+            if let Err(e) = process.spawn() {
+                log::error!("Failed to spawn process `{}`: {}", request, e);
+                process.fail();
+            }
         }
         self.spawn_requests.clear();
 
@@ -104,7 +301,7 @@ impl ProcessManager {
             let Some(process) = self.processes.get_mut(&request.pid) else {
                 continue;
             };
-            if let Err(e) = process.spawn_thread(request.fptr) {
+            if let Err(e) = process.spawn_thread(request) {
                 log::error!(
                     "Failed to spawn thread for process `{}`: {}",
                     request.pid,
@@ -113,6 +310,17 @@ impl ProcessManager {
             }
         }
         self.thread_requests.clear();
+
+        // Handle thread kill requests
+        for (pid, tid) in self.thread_kill_requests.iter() {
+            let Some(process) = self.processes.get_mut(pid) else {
+                continue;
+            };
+            if let Err(e) = process.kill_thread(*tid) {
+                log::error!("Failed to kill thread {} of process `{}`: {}", tid, pid, e);
+            }
+        }
+        self.thread_kill_requests.clear();
     }
 }
 
@@ -0,0 +1,167 @@
+//! Resource limits for processes.
+//! The limits are attached when a process is spawned and enforced by each subsystem.
+//! Unprivileged processes can only lower their limits.
+
+/// A resource a process can be limited in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// The amount of threads alive at once, excluding the main thread
+    Threads = 0,
+    /// The amount of pages the memory can grow to
+    MemoryPages = 1,
+    /// The amount of file handles open at once
+    OpenFiles = 2,
+    /// The amount of network requests that have not finished yet
+    NetworkRequests = 3,
+    /// The amount of bytes the stdout and stderr retain
+    StdoutRetention = 4,
+}
+
+/// The resource limits of a process.
+/// A limit of `None` means the resource is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub threads: Option<u32>,
+    pub memory_pages: Option<u32>,
+    pub open_files: Option<u32>,
+    pub network_requests: Option<u32>,
+    pub stdout_retention: Option<u32>,
+}
+
+/// The resource limits and privilege a process spawns it's subprocesses with.
+/// Subprocesses are unprivileged unless a privileged parent passes it's privilege on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChildLimits {
+    pub limits: ResourceLimits,
+    pub privileged: bool,
+}
+
+/// The error types for resource limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitError {
+    /// The process would exceed the limit of the resource
+    Exceeded(Resource),
+    /// The process is not allowed to raise the limit of the resource
+    NotPermitted(Resource),
+    /// The resource id is invalid
+    InvalidResource(u32),
+}
+
+impl Resource {
+    /// The error code returned by the api when the limit of the resource is exceeded.
+    /// The codes are distinct from the other error codes of the api.
+    pub fn exceeded_code(&self) -> i32 {
+        -20 - *self as i32
+    }
+}
+
+impl ResourceLimits {
+    /// Get the limit of a resource
+    pub fn get(&self, resource: Resource) -> Option<u32> {
+        match resource {
+            Resource::Threads => self.threads,
+            Resource::MemoryPages => self.memory_pages,
+            Resource::OpenFiles => self.open_files,
+            Resource::NetworkRequests => self.network_requests,
+            Resource::StdoutRetention => self.stdout_retention,
+        }
+    }
+
+    /// Set the limit of a resource
+    pub fn set(&mut self, resource: Resource, limit: Option<u32>) {
+        let slot = match resource {
+            Resource::Threads => &mut self.threads,
+            Resource::MemoryPages => &mut self.memory_pages,
+            Resource::OpenFiles => &mut self.open_files,
+            Resource::NetworkRequests => &mut self.network_requests,
+            Resource::StdoutRetention => &mut self.stdout_retention,
+        };
+        *slot = limit;
+    }
+
+    /// Set the limit of a resource, unless it would raise the current limit and the caller is unprivileged
+    pub fn try_set(
+        &mut self,
+        resource: Resource,
+        limit: Option<u32>,
+        privileged: bool,
+    ) -> Result<(), LimitError> {
+        if !privileged && lower(self.get(resource), limit) != limit {
+            return Err(LimitError::NotPermitted(resource));
+        }
+        self.set(resource, limit);
+        Ok(())
+    }
+
+    /// Get the lower of each limit
+    pub fn intersect(&self, other: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            threads: lower(self.threads, other.threads),
+            memory_pages: lower(self.memory_pages, other.memory_pages),
+            open_files: lower(self.open_files, other.open_files),
+            network_requests: lower(self.network_requests, other.network_requests),
+            stdout_retention: lower(self.stdout_retention, other.stdout_retention),
+        }
+    }
+
+    /// Check if using one more of a resource would exceed the limit
+    pub fn check(&self, resource: Resource, in_use: usize) -> Result<(), LimitError> {
+        match self.get(resource) {
+            Some(limit) if in_use >= limit as usize => Err(LimitError::Exceeded(resource)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ChildLimits {
+    /// Get the limits and privilege of a subprocess spawned by a parent with the requested limits.
+    /// A subprocess never gets higher limits than it's parent, or privilege the parent does not hold.
+    pub fn apply(
+        &self,
+        requested: &ResourceLimits,
+        parent: &ResourceLimits,
+        parent_privileged: bool,
+    ) -> (ResourceLimits, bool) {
+        let limits = requested.intersect(&self.limits).intersect(parent);
+        (limits, self.privileged && parent_privileged)
+    }
+}
+
+/// Get the lower of two limits, where `None` is unlimited
+fn lower(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+impl TryFrom<u32> for Resource {
+    type Error = LimitError;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Resource::Threads),
+            1 => Ok(Resource::MemoryPages),
+            2 => Ok(Resource::OpenFiles),
+            3 => Ok(Resource::NetworkRequests),
+            4 => Ok(Resource::StdoutRetention),
+            _ => Err(LimitError::InvalidResource(value)),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitError::Exceeded(resource) => {
+                writeln!(f, "Resource limit exceeded: {:?}", resource)
+            }
+            LimitError::NotPermitted(resource) => {
+                writeln!(f, "Not permitted to raise the limit of: {:?}", resource)
+            }
+            LimitError::InvalidResource(id) => writeln!(f, "Invalid resource: {}", id),
+        }
+    }
+}
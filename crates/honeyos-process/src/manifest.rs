@@ -0,0 +1,180 @@
+//! The manifest of an executable, embedded as json in a `honeyos` custom section of the wasm binary.
+//! The manifest is optional, binaries without one run with the capabilities their spawner grants.
+//! ```json
+//! {
+//!     "name": "editor",
+//!     "version": "1.2.0",
+//!     "description": "A text editor",
+//!     "capabilities": ["fs:a:read", "fs:a:write", "display"],
+//!     "min_hapi_version": "1.0",
+//!     "weak_imports": ["hapi.hapi_klog_read"],
+//!     "entrypoint": "_start"
+//! }
+//! ```
+use std::str::FromStr;
+
+use honeyos_fs::FsLabel;
+use serde::{Deserialize, Serialize};
+
+use crate::capabilities::{Capabilities, Capability};
+
+/// The name of the custom section holding the manifest
+pub const MANIFEST_SECTION: &str = "honeyos";
+
+/// The function called when no entrypoint is declared
+pub const DEFAULT_ENTRYPOINT: &str = "_start";
+
+/// The manifest of an executable
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    /// The capabilities the executable requests.
+    /// `None` requests whatever the spawner grants, an empty list requests nothing.
+    pub capabilities: Option<Vec<String>>,
+    /// The lowest version of the api the executable runs on, as `major.minor`
+    pub min_hapi_version: Option<String>,
+    /// The imported functions the executable runs without, as `module.name`.
+    /// The ones the kernel does not provide are replaced by shims, see [`crate::weak`].
+    pub weak_imports: Option<Vec<String>>,
+    /// The exported function the process starts at
+    pub entrypoint: Option<String>,
+}
+
+/// The error types for manifests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The section is not valid json or does not match the manifest
+    InvalidJson(String),
+    /// A requested capability is unknown
+    UnknownCapability(String),
+    /// The minimum api version is not formatted as `major.minor`
+    InvalidVersion(String),
+    /// A weak import is not formatted as `module.name`
+    InvalidWeakImport(String),
+}
+
+impl Manifest {
+    /// Parse the contents of the manifest section
+    pub fn parse(data: &[u8]) -> Result<Self, ManifestError> {
+        let manifest: Manifest =
+            serde_json::from_slice(data).map_err(|e| ManifestError::InvalidJson(e.to_string()))?;
+
+        // Validate the fields up front, so a broken manifest is rejected before the binary runs
+        manifest.requested_capabilities()?;
+        manifest.min_hapi_version()?;
+        if let Some(import) = manifest.weak_imports().find(|import| {
+            !import
+                .split_once('.')
+                .is_some_and(|(module, name)| !module.is_empty() && !name.is_empty())
+        }) {
+            return Err(ManifestError::InvalidWeakImport(import.to_string()));
+        }
+        Ok(manifest)
+    }
+
+    /// Serialize the manifest as json
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Get the requested capabilities.
+    /// Returns `None` if the manifest does not request any in particular.
+    pub fn requested_capabilities(&self) -> Result<Option<Capabilities>, ManifestError> {
+        let Some(requested) = &self.capabilities else {
+            return Ok(None);
+        };
+        let mut capabilities = Capabilities::none();
+        for capability in requested {
+            capabilities.grant(parse_capability(capability)?);
+        }
+        Ok(Some(capabilities))
+    }
+
+    /// Get the lowest version of the api the executable runs on as `(major, minor)`
+    pub fn min_hapi_version(&self) -> Result<Option<(u32, u32)>, ManifestError> {
+        let Some(version) = &self.min_hapi_version else {
+            return Ok(None);
+        };
+        let invalid = || ManifestError::InvalidVersion(version.clone());
+        let (major, minor) = version.split_once('.').ok_or_else(invalid)?;
+        let major = major.parse().map_err(|_| invalid())?;
+        let minor = minor.parse().map_err(|_| invalid())?;
+        Ok(Some((major, minor)))
+    }
+
+    /// Check if the executable runs on a version of the api as `(major, minor)`.
+    /// Versions are compatible within a major version, as minor versions only add to the api.
+    pub fn runs_on(&self, (major, minor): (u32, u32)) -> Result<bool, ManifestError> {
+        Ok(match self.min_hapi_version()? {
+            Some((required_major, required_minor)) => {
+                required_major == major && required_minor <= minor
+            }
+            None => true,
+        })
+    }
+
+    /// Get the imported functions the executable runs without, as `module.name`
+    pub fn weak_imports(&self) -> impl Iterator<Item = &str> {
+        self.weak_imports
+            .iter()
+            .flatten()
+            .map(|import| import.as_str())
+    }
+
+    /// Get the exported function the process starts at
+    pub fn entrypoint(&self) -> &str {
+        self.entrypoint.as_deref().unwrap_or(DEFAULT_ENTRYPOINT)
+    }
+}
+
+/// Parse a capability formatted as `fs:<label>:read`, `fs:<label>:write`,
+/// `network`, `display`, `js-eval`, `spawn` or `thread`
+fn parse_capability(capability: &str) -> Result<Capability, ManifestError> {
+    let unknown = || ManifestError::UnknownCapability(capability.to_string());
+    match capability {
+        "network" => Ok(Capability::Network),
+        "display" => Ok(Capability::Display),
+        "js-eval" => Ok(Capability::JsEval),
+        "spawn" => Ok(Capability::Spawn),
+        "thread" => Ok(Capability::Thread),
+        _ => {
+            let mut parts = capability.split(':');
+            let (Some("fs"), Some(label), Some(access), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(unknown());
+            };
+            if label.len() != 1 {
+                return Err(unknown());
+            }
+            let label = FsLabel::from_str(label).map_err(|_| unknown())?;
+            match access {
+                "read" => Ok(Capability::FsRead(label)),
+                "write" => Ok(Capability::FsWrite(label)),
+                _ => Err(unknown()),
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestError::InvalidJson(error) => writeln!(f, "Invalid manifest: {}", error),
+            ManifestError::UnknownCapability(capability) => {
+                writeln!(f, "Unknown capability in manifest: {}", capability)
+            }
+            ManifestError::InvalidVersion(version) => {
+                writeln!(f, "Invalid api version in manifest: {}", version)
+            }
+            ManifestError::InvalidWeakImport(import) => {
+                writeln!(f, "Invalid weak import in manifest: {}", import)
+            }
+        }
+    }
+}
@@ -1,50 +1,74 @@
-use std::ffi::CString;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex, MutexGuard,
+};
 
 use anyhow::anyhow;
 use wasm_bindgen::JsCast;
-use web_sys::js_sys::{Reflect, SharedArrayBuffer, Uint8Array, WebAssembly, JSON};
+use web_sys::js_sys::{Atomics, Int32Array, Reflect, Uint8Array, WebAssembly, JSON};
+
+use crate::{
+    allocator::{AllocError, Allocator, Realloc, MIN_ALIGN},
+    limits::{LimitError, Resource},
+};
 
 /// (64Kib) The size of one wasm page as specified in the spec:
 /// https://developer.mozilla.org/en-US/docs/WebAssembly/JavaScript_interface/Memory/grow
 const PAGE_SIZE: u32 = 65536;
 
-/// A region in memory
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryRegion {
-    pub start: u32,
-    pub end: u32,
-    pub free: bool,
+/// The amount of bytes scanned at once when searching for the end of a string
+const STR_CHUNK_SIZE: u32 = 256;
+
+/// The error types for accessing the memory of a process through a pointer it passed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The range does not fit in the memory
+    OutOfBounds { ptr: u32, len: u32, size: u32 },
+    /// The range reaches into memory the allocator has not handed out
+    Alloc(AllocError),
+    /// No null terminator was found within the maximum length of the string
+    Unterminated { ptr: u32, max_len: u32 },
 }
 
-impl MemoryRegion {
-    /// The size of the region
-    pub fn size(self) -> u32 {
-        self.end - self.start
-    }
+/// The result of waiting on an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The waiting thread was woken by a notify
+    Woken = 0,
+    /// The value at the address did not match the expected value
+    NotEqual = 1,
+    /// The timeout elapsed before the thread was woken
+    TimedOut = 2,
+}
 
-    /// Split the region.
-    /// Input the size of the first region.
-    pub fn split(self, size: u32) -> (MemoryRegion, MemoryRegion) {
-        let current_size = self.size();
-        if current_size <= size {
-            panic!("Size of first region must be smaller than the size of the current region");
-        }
-        if !self.free {
-            panic!("Only free memory regions should be split")
-        }
+/// A view of the memory used for atomic wait and notify operations.
+/// Obtained from the memory, so the memory doesn't need to stay locked while waiting.
+#[derive(Debug, Clone)]
+pub struct MemoryAtomics {
+    view: Int32Array,
+}
 
-        let first = MemoryRegion {
-            start: self.start,
-            end: self.end - current_size - size,
-            free: false,
-        };
-        let second = MemoryRegion {
-            start: first.end,
-            end: self.end,
-            free: false,
-        };
-        (first, second)
-    }
+/// A snapshot of the memory usage of a process
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The amount of bytes allocated through the kernel
+    pub allocated: u64,
+    /// The highest amount of bytes allocated through the kernel at once
+    pub peak_allocated: u64,
+    /// The amount of pages the memory spans
+    pub pages: u32,
+    /// The highest amount of pages the memory spanned
+    pub peak_pages: u32,
+    /// The maximum amount of pages the memory can grow to
+    pub maximum_pages: Option<u32>,
+}
+
+/// The page counters and kernel-side limit of a memory, shared between the copies of the memory in each worker
+#[derive(Debug)]
+struct PageUsage {
+    pages: AtomicU32,
+    peak_pages: AtomicU32,
+    limit: AtomicU32, // `u32::MAX` if unlimited
 }
 
 /// The sandboxed memory of a process
@@ -53,11 +77,36 @@ pub struct Memory {
     maximum: Option<u32>,
     inner: WebAssembly::Memory,
 
-    regions: Vec<MemoryRegion>,
+    allocator: Arc<Mutex<Allocator>>, // Shared between the copies of the memory in each worker
+    usage: Arc<PageUsage>,
 }
 
 impl Memory {
-    pub fn new(initial: u32, maximum: Option<u32>, shared: bool) -> anyhow::Result<Self> {
+    /// Create the memory of a process.
+    /// The kernel-side `limit` in pages lowers the maximum the binary declares.
+    pub fn new(
+        initial: u32,
+        maximum: Option<u32>,
+        shared: bool,
+        limit: Option<u32>,
+    ) -> anyhow::Result<Self> {
+        if limit.is_some_and(|limit| initial > limit) {
+            return Err(LimitError::Exceeded(Resource::MemoryPages).into());
+        }
+        let maximum = match (maximum, limit) {
+            (Some(maximum), Some(limit)) => Some(maximum.min(limit)),
+            (maximum, limit) => maximum.or(limit),
+        };
+        if let Some(maximum) = maximum {
+            if initial > maximum {
+                return Err(anyhow!(
+                    "The process requires {} pages of memory, which exceeds the limit of {} pages",
+                    initial,
+                    maximum
+                ));
+            }
+        }
+
         let memory_desc = JSON::parse("{}").unwrap();
         Reflect::set(&memory_desc, &"initial".into(), &initial.into()).unwrap();
         if let Some(maximum_memory) = maximum {
@@ -68,10 +117,17 @@ impl Memory {
         let inner = WebAssembly::Memory::new(memory_desc.unchecked_ref())
             .map_err(|e| anyhow!("Failed to allocate memory for process: {:?}", e))?;
 
+        let usage = PageUsage {
+            pages: AtomicU32::new(0),
+            peak_pages: AtomicU32::new(0),
+            limit: AtomicU32::new(limit.unwrap_or(u32::MAX)),
+        };
+        usage.record(initial);
         Ok(Self {
             inner,
             maximum,
-            regions: Vec::new(),
+            allocator: Arc::new(Mutex::new(Allocator::new())),
+            usage: Arc::new(usage),
         })
     }
 
@@ -82,122 +138,159 @@ impl Memory {
         clone
     }
 
-    /// Read from a certain block of memory
+    /// Replace the inner wasm memory object of this copy of the memory
+    pub fn replace_inner(&mut self, inner: WebAssembly::Memory) {
+        self.inner = inner;
+    }
+
+    /// Read from a certain block of memory.
+    /// The range is not checked, so this is only meant for pointers computed by the kernel itself.
     pub fn read(&self, ptr: u32, len: u32) -> Vec<u8> {
         let buffer = self.inner.buffer();
         let bytes = Uint8Array::new(&buffer);
         bytes.slice(ptr, ptr + len as u32).to_vec()
     }
 
-    /// Read a string from memory.
-    /// Takes a pointer and then keeps reading until it finds a null terminator
-    pub fn read_str(&self, ptr: u32) -> Option<String> {
-        let buffer = self.inner.buffer();
-        let bytes = Uint8Array::new(&buffer);
-        let byte_length = bytes.byte_length();
+    /// Write to a certain block of memory.
+    /// The range is not checked, so this is only meant for pointers computed by the kernel itself.
+    pub fn write(&mut self, ptr: u32, data: &[u8]) {
+        let bytes = Uint8Array::new(&self.inner.buffer());
+        let array = Uint8Array::from(data);
+        bytes.set(&array, ptr);
+    }
 
-        let mut string = Vec::new();
+    /// Read a block of memory at a pointer passed by the process.
+    /// Fails if the block does not fit in the memory or reaches into memory the allocator has not handed out.
+    pub fn read_checked(&self, ptr: u32, len: u32) -> Result<Vec<u8>, MemoryError> {
+        let bytes = Uint8Array::new(&self.inner.buffer());
+        self.check(ptr, len, bytes.byte_length())?;
+        Ok(bytes.slice(ptr, ptr + len).to_vec())
+    }
 
-        // Iterate from the pointer position until we hit a null terminator or exceed buffer length
-        for offset in 0..byte_length {
-            let current_ptr = ptr + offset;
-            if current_ptr >= byte_length {
-                break;
-            }
+    /// Write a block of memory at a pointer passed by the process.
+    /// Fails if the block does not fit in the memory or reaches into memory the allocator has not handed out,
+    /// in which case nothing is written.
+    pub fn write_checked(&mut self, ptr: u32, data: &[u8]) -> Result<(), MemoryError> {
+        let bytes = Uint8Array::new(&self.inner.buffer());
+        let size = bytes.byte_length();
+        let len = u32::try_from(data.len()).map_err(|_| MemoryError::OutOfBounds {
+            ptr,
+            len: u32::MAX,
+            size,
+        })?;
+        self.check(ptr, len, size)?;
+        bytes.set(&Uint8Array::from(data), ptr);
+        Ok(())
+    }
+
+    /// Read a null-terminated string at a pointer passed by the process.
+    /// The string may be at most `max_len` bytes long, including the null terminator.
+    /// Invalid utf-8 is replaced rather than rejected.
+    pub fn read_str_bounded(&self, ptr: u32, max_len: u32) -> Result<String, MemoryError> {
+        let bytes = Uint8Array::new(&self.inner.buffer());
+        let size = bytes.byte_length();
+        check_bounds(ptr, 0, size)?;
 
-            let byte = bytes.get_index(current_ptr);
-            string.push(byte);
-            if byte == 0 {
-                break;
+        // Scan in chunks, so a short string doesn't copy `max_len` bytes out of the memory
+        let end = ptr.saturating_add(max_len).min(size);
+        let mut string = Vec::new();
+        let mut position = ptr;
+        while position < end {
+            let chunk_end = position.saturating_add(STR_CHUNK_SIZE).min(end);
+            let chunk = bytes.slice(position, chunk_end).to_vec();
+            if let Some(nul) = chunk.iter().position(|byte| *byte == 0) {
+                string.extend_from_slice(&chunk[..nul]);
+                self.allocator().check_range(ptr, string.len() as u32 + 1)?;
+                return Ok(String::from_utf8_lossy(&string).into_owned());
             }
+            string.extend_from_slice(&chunk);
+            position = chunk_end;
         }
+        Err(MemoryError::Unterminated { ptr, max_len })
+    }
 
-        let cstring = CString::from_vec_with_nul(string).ok()?;
-        Some(cstring.to_string_lossy().to_string())
+    /// Check that a range passed by the process fits in the memory and the allocator allows accessing it,
+    /// so a buffer can be checked before data is consumed to fill it
+    pub fn check_range(&self, ptr: u32, len: u32) -> Result<(), MemoryError> {
+        let size = Uint8Array::new(&self.inner.buffer()).byte_length();
+        self.check(ptr, len, size)
     }
 
-    /// Write to a certain block of memory
-    pub fn write(&mut self, ptr: u32, data: &[u8]) {
-        let bytes = Uint8Array::new(&self.inner.buffer());
-        let array = Uint8Array::from(data);
-        bytes.set(&array, ptr);
+    /// Check a range against the size of the memory and the allocator
+    fn check(&self, ptr: u32, len: u32, size: u32) -> Result<(), MemoryError> {
+        check_bounds(ptr, len, size)?;
+        self.allocator().check_range(ptr, len)?;
+        Ok(())
     }
 
     /// Allocate a block of memory and return it's pointer.
-    /// Returns None if the memory exceeds the 32-bit maximum of 4gb
+    /// Returns None if the memory cannot grow large enough
     pub fn alloc(&mut self, size: u32) -> Option<u32> {
-        let buffer = self.inner.buffer().dyn_into::<SharedArrayBuffer>().ok()?;
-        let current_size = buffer.byte_length();
-        let ptr = current_size;
-
-        // Return an old region if it is free
-        let mut region_index = None;
-        let regions = self.regions.clone();
-        for (index, region) in regions.iter().enumerate() {
-            if !region.free {
-                continue;
-            }
-            let region_size = region.size();
-            if region_size == size {
-                region_index = Some(index);
-            }
-            if region_size > size {
-                let (first, second) = region.split(size);
-                self.regions.insert(index, second);
-                self.regions.insert(index, first);
-                region_index = Some(index);
-            }
+        self.alloc_aligned(size, MIN_ALIGN)
+    }
+
+    /// Allocate a block of memory aligned to `align` bytes and return it's pointer.
+    /// Returns None if the alignment is not a power of two or the memory cannot grow large enough
+    pub fn alloc_aligned(&mut self, size: u32, align: u32) -> Option<u32> {
+        let mut allocator = self.allocator();
+        if let Some(ptr) = allocator.alloc(size, align) {
+            return Some(ptr);
         }
-        if let Some(region_index) = region_index {
-            let region = self.regions.get_mut(region_index).unwrap();
-            region.free = false;
-            return Some(region.start);
+        if !align.is_power_of_two() {
+            return None;
         }
 
-        self.grow(&buffer, size);
-        self.regions.push(MemoryRegion {
-            start: ptr,
-            end: ptr + size,
-            free: false,
-        });
-        Some(ptr)
+        // Grow the memory by enough to fit the block at any alignment
+        let (start, size_grown) = self.grow(size.checked_add(align.max(MIN_ALIGN))?)?;
+        allocator.add_region(start, size_grown);
+        allocator.alloc(size, align)
     }
 
-    /// Reallocate a block of memory and returns the new pointer
+    /// Reallocate a block of memory and returns the new pointer.
+    /// The block is resized in place if possible, otherwise the contents are moved to a new block.
     pub fn realloc(&mut self, ptr: u32, new_size: u32) -> Option<u32> {
-        let new_ptr = self.alloc(new_size)?;
-        self.copy(ptr, new_ptr, new_size);
-        Some(new_ptr)
-    }
+        let mut allocator = self.allocator();
+        let result = match allocator.realloc(ptr, new_size) {
+            Ok(Some(result)) => result,
+            Ok(None) => {
+                let (start, size_grown) = self.grow(new_size.checked_add(MIN_ALIGN)?)?;
+                allocator.add_region(start, size_grown);
+                allocator.realloc(ptr, new_size).ok()??
+            }
+            Err(e) => {
+                log::error!("Failed to reallocate: {}", e);
+                return None;
+            }
+        };
 
-    /// Mark a region of memory as free
-    pub fn free(&mut self, ptr: u32) -> Option<()> {
-        let mut index = None;
-        for (i, region) in self.regions.iter().enumerate() {
-            if region.start == ptr {
-                index = Some(i);
-                break;
+        match result {
+            Realloc::InPlace(ptr) => Some(ptr),
+            Realloc::Moved { from, to, len } => {
+                // Copy while the allocator is locked, so the old block cannot be handed out yet
+                let bytes = Uint8Array::new(&self.inner.buffer());
+                bytes.set(&bytes.subarray(from, from + len), to);
+                Some(to)
             }
         }
-        let Some(index) = index else {
-            return None;
-        };
-        let region = self.regions.get_mut(index).unwrap();
-        if region.free {
-            log::error!("Double free!");
+    }
+
+    /// Mark a block of memory as free
+    pub fn free(&mut self, ptr: u32) -> Option<()> {
+        if let Err(e) = self.allocator().free(ptr) {
+            log::error!("Failed to free: {}", e);
             return None;
         }
-        region.free = true;
         Some(())
     }
 
-    /// Grow the memory
-    fn grow(&mut self, buffer: &SharedArrayBuffer, size: u32) -> Option<()> {
-        let current_size = buffer.byte_length();
-        let new_size = current_size + size;
+    /// Grow the memory by at least `size` bytes.
+    /// Returns the start and size of the new region.
+    fn grow(&self, size: u32) -> Option<(u32, u32)> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        let current_pages = Uint8Array::new(&self.inner.buffer()).byte_length() / PAGE_SIZE;
 
-        if let Some(maximum) = self.maximum {
-            if new_size / PAGE_SIZE > maximum {
+        if let Some(maximum) = self.maximum_pages() {
+            if current_pages + pages > maximum {
                 log::warn!(
                     "Process attempted to allocate more than the maximum of {} pages of ram",
                     maximum
@@ -205,18 +298,71 @@ impl Memory {
                 return None;
             }
         }
-        self.inner.grow(size / PAGE_SIZE + 1);
-        Some(())
+        // Use the previous size returned by grow, as the process might have grown the memory itself
+        let previous_pages = self.inner.grow(pages);
+        self.usage.record(previous_pages + pages);
+        Some((previous_pages.checked_mul(PAGE_SIZE)?, pages * PAGE_SIZE))
     }
 
-    /// Copy data from one memory region to another
-    fn copy(&self, src_ptr: u32, dest_ptr: u32, size: u32) {
-        let buffer = self.inner.buffer();
-        let bytes = Uint8Array::new(&buffer);
+    /// Get the memory usage, refreshing the page count from the memory.
+    pub fn stats(&self) -> MemoryStats {
+        let pages = Uint8Array::new(&self.inner.buffer()).byte_length() / PAGE_SIZE;
+        self.usage.record(pages);
+        self.cached_stats()
+    }
+
+    /// Get the memory usage without touching the memory itself,
+    /// so it is safe to call from any thread.
+    /// The page count is the last one observed by the kernel.
+    pub fn cached_stats(&self) -> MemoryStats {
+        let allocator = self.allocator();
+        MemoryStats {
+            allocated: allocator.allocated(),
+            peak_allocated: allocator.peak_allocated(),
+            pages: self.usage.pages.load(Ordering::Acquire),
+            peak_pages: self.usage.peak_pages.load(Ordering::Acquire),
+            maximum_pages: self.maximum_pages(),
+        }
+    }
+
+    /// The kernel-side limit of the memory in pages
+    pub fn limit(&self) -> Option<u32> {
+        match self.usage.limit.load(Ordering::Acquire) {
+            u32::MAX => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// Set the kernel-side limit of the memory in pages.
+    /// Only affects growth through the kernel, as the maximum of the memory itself is fixed once created.
+    pub fn set_limit(&self, limit: Option<u32>) {
+        self.usage
+            .limit
+            .store(limit.unwrap_or(u32::MAX), Ordering::Release);
+    }
+
+    /// The maximum amount of pages the memory can grow to through the kernel
+    fn maximum_pages(&self) -> Option<u32> {
+        match (self.maximum, self.limit()) {
+            (Some(maximum), Some(limit)) => Some(maximum.min(limit)),
+            (maximum, limit) => maximum.or(limit),
+        }
+    }
+
+    /// Lock the allocator
+    fn allocator(&self) -> MutexGuard<'_, Allocator> {
+        loop {
+            let Ok(allocator) = self.allocator.try_lock() else {
+                continue;
+            };
+            return allocator;
+        }
+    }
 
-        // Perform data copying
-        for i in 0..size {
-            bytes.set_index(dest_ptr + i, bytes.get_index(src_ptr + i));
+    /// Get a view of the memory for atomic wait and notify operations
+    pub fn atomics(&self) -> MemoryAtomics {
+        MemoryAtomics {
+            view: Int32Array::new(&self.inner.buffer()),
         }
     }
 
@@ -225,3 +371,92 @@ impl Memory {
         &self.inner
     }
 }
+
+impl MemoryAtomics {
+    /// Block until the 32-bit value at the address is notified, if it equals the expected value.
+    /// Waits indefinitely if no timeout is given.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn wait(
+        &self,
+        ptr: u32,
+        expected: i32,
+        timeout_ms: Option<f64>,
+    ) -> anyhow::Result<WaitResult> {
+        let index = self.index(ptr)?;
+        let result = match timeout_ms {
+            Some(timeout_ms) => Atomics::wait_with_timeout(&self.view, index, expected, timeout_ms),
+            None => Atomics::wait(&self.view, index, expected),
+        }
+        .map_err(|e| anyhow!("Failed to wait on address {}: {:?}", ptr, e))?;
+
+        match String::from(result).as_str() {
+            "ok" => Ok(WaitResult::Woken),
+            "not-equal" => Ok(WaitResult::NotEqual),
+            "timed-out" => Ok(WaitResult::TimedOut),
+            result => Err(anyhow!("Unexpected wait result: {}", result)),
+        }
+    }
+
+    /// Wake up to `count` threads waiting on the address.
+    /// Returns the amount of threads woken.
+    pub fn notify(&self, ptr: u32, count: u32) -> anyhow::Result<u32> {
+        let index = self.index(ptr)?;
+        Atomics::notify_with_count(&self.view, index, count)
+            .map_err(|e| anyhow!("Failed to notify address {}: {:?}", ptr, e))
+    }
+
+    /// Get the index of a 32-bit value in the view.
+    /// Fails if the address is unaligned or out of bounds.
+    fn index(&self, ptr: u32) -> anyhow::Result<u32> {
+        if !ptr.is_multiple_of(4) {
+            return Err(anyhow!("Address {} is not 4-byte aligned", ptr));
+        }
+        let index = ptr / 4;
+        if index >= self.view.length() {
+            return Err(anyhow!("Address {} is out of bounds", ptr));
+        }
+        Ok(index)
+    }
+}
+
+/// Check that a range fits in a memory of `size` bytes
+pub fn check_bounds(ptr: u32, len: u32, size: u32) -> Result<(), MemoryError> {
+    match ptr as u64 + len as u64 <= size as u64 {
+        true => Ok(()),
+        false => Err(MemoryError::OutOfBounds { ptr, len, size }),
+    }
+}
+
+impl PageUsage {
+    /// Record the current amount of pages
+    fn record(&self, pages: u32) {
+        self.pages.store(pages, Ordering::Release);
+        self.peak_pages.fetch_max(pages, Ordering::AcqRel);
+    }
+}
+
+impl From<AllocError> for MemoryError {
+    fn from(error: AllocError) -> Self {
+        MemoryError::Alloc(error)
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+impl std::fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryError::OutOfBounds { ptr, len, size } => writeln!(
+                f,
+                "{} bytes at pointer {} are outside the {} bytes of memory",
+                len, ptr, size
+            ),
+            MemoryError::Alloc(error) => write!(f, "{}", error),
+            MemoryError::Unterminated { ptr, max_len } => writeln!(
+                f,
+                "The string at pointer {} is not null-terminated within {} bytes",
+                ptr, max_len
+            ),
+        }
+    }
+}
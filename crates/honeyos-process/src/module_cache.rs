@@ -0,0 +1,120 @@
+//! A cache of compiled binaries, keyed by the hash of their contents.
+//! Compiling dominates the time it takes to spawn a process, so repeated launches of a binary
+//! and the threads of a process instantiate a cached module instead of recompiling it.
+//! Compiled modules are js objects owned by the kernel thread, workers receive them through `postMessage`.
+use std::{
+    cell::RefCell,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use hashbrown::HashMap;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::js_sys::{Uint8Array, WebAssembly};
+
+/// The amount of compiled modules kept by the kernel
+pub const MODULE_CACHE_CAPACITY: usize = 32;
+
+thread_local! {
+    static MODULE_CACHE: RefCell<ModuleCache<WebAssembly::Module>> =
+        RefCell::new(ModuleCache::new(MODULE_CACHE_CAPACITY));
+}
+
+/// A least recently used cache of compiled modules
+#[derive(Debug)]
+pub struct ModuleCache<M> {
+    modules: HashMap<u64, CachedModule<M>>,
+    capacity: usize,
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct CachedModule<M> {
+    bin: Arc<Vec<u8>>,
+    module: M,
+    last_used: u64,
+}
+
+impl<M: Clone> ModuleCache<M> {
+    /// Create a cache holding at most `capacity` modules
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            modules: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    /// Get the compiled module of a binary.
+    /// The contents are compared on a hit, so a hash collision never runs the wrong binary.
+    pub fn get(&mut self, bin: &Arc<Vec<u8>>) -> Option<M> {
+        self.clock += 1;
+        let cached = self.modules.get_mut(&module_hash(bin))?;
+        if !Arc::ptr_eq(&cached.bin, bin) && cached.bin != *bin {
+            return None;
+        }
+        cached.last_used = self.clock;
+        Some(cached.module.clone())
+    }
+
+    /// Cache the compiled module of a binary, evicting the least recently used module if the cache is full
+    pub fn insert(&mut self, bin: Arc<Vec<u8>>, module: M) {
+        if self.capacity == 0 {
+            return;
+        }
+        let hash = module_hash(&bin);
+        if !self.modules.contains_key(&hash) && self.modules.len() >= self.capacity {
+            let oldest = self
+                .modules
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.modules.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.modules.insert(
+            hash,
+            CachedModule {
+                bin,
+                module,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    /// Get the amount of cached modules
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// Check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+}
+
+/// Hash the contents of a binary
+pub fn module_hash(bin: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bin.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Get the compiled module of a binary, compiling and caching it if it's not cached.
+/// Must be called from the kernel thread.
+pub async fn compile(bin: Arc<Vec<u8>>) -> Result<WebAssembly::Module, String> {
+    if let Some(module) = MODULE_CACHE.with_borrow_mut(|cache| cache.get(&bin)) {
+        return Ok(module);
+    }
+
+    let buffer = Uint8Array::from(bin.as_slice());
+    let module: WebAssembly::Module = JsFuture::from(WebAssembly::compile(&buffer))
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .into();
+    MODULE_CACHE.with_borrow_mut(|cache| cache.insert(bin, module.clone()));
+    Ok(module)
+}
@@ -0,0 +1,263 @@
+//! Anonymous pipes for streaming bytes between processes
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// The default capacity of a pipe in bytes
+pub const DEFAULT_PIPE_CAPACITY: usize = 64 * 1024;
+
+/// The interval at which a blocking pipe operation checks the pipe again
+const POLL_INTERVAL_MS: u64 = 10;
+
+/// The error types for pipes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeError {
+    /// All read ends of the pipe have been closed
+    Broken,
+    /// The end of the pipe has been closed
+    Closed,
+}
+
+/// A bounded byte buffer shared between a read and a write end
+#[derive(Debug)]
+pub struct Pipe {
+    buffer: Mutex<VecDeque<u8>>,
+    capacity: usize,
+    readers: AtomicUsize,
+    writers: AtomicUsize,
+}
+
+/// The read end of a pipe.
+/// The pipe reports the end of the stream once all write ends are closed or dropped.
+#[derive(Debug)]
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+    closed: AtomicBool,
+}
+
+/// The write end of a pipe.
+/// The pipe is broken once all read ends are closed or dropped.
+#[derive(Debug)]
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+    closed: AtomicBool,
+}
+
+/// Either end of a pipe
+#[derive(Debug, Clone)]
+pub enum PipeEnd {
+    Reader(PipeReader),
+    Writer(PipeWriter),
+}
+
+impl Pipe {
+    /// Create a pipe that holds at most `capacity` bytes.
+    /// Returns the read and write end.
+    pub fn create(capacity: usize) -> (PipeReader, PipeWriter) {
+        let pipe = Arc::new(Pipe {
+            buffer: Mutex::new(VecDeque::new()),
+            capacity,
+            readers: AtomicUsize::new(1),
+            writers: AtomicUsize::new(1),
+        });
+        (
+            PipeReader {
+                pipe: pipe.clone(),
+                closed: AtomicBool::new(false),
+            },
+            PipeWriter {
+                pipe,
+                closed: AtomicBool::new(false),
+            },
+        )
+    }
+
+    /// Create a pipe without a capacity limit
+    pub fn unbounded() -> (PipeReader, PipeWriter) {
+        Self::create(usize::MAX)
+    }
+
+    /// The maximum amount of bytes the pipe holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The amount of bytes in the pipe
+    pub fn len(&self) -> usize {
+        loop {
+            let Ok(buffer) = self.buffer.try_lock() else {
+                continue;
+            };
+            return buffer.len();
+        }
+    }
+
+    /// Check if the pipe contains no bytes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PipeReader {
+    /// Read up to `max` bytes without blocking.
+    /// Returns an empty vec if the pipe is empty or this end has been closed.
+    pub fn read(&self, max: usize) -> Vec<u8> {
+        if self.closed.load(Ordering::Acquire) {
+            return Vec::new();
+        }
+        loop {
+            let Ok(mut buffer) = self.pipe.buffer.try_lock() else {
+                continue;
+            };
+            let amount = max.min(buffer.len());
+            return buffer.drain(..amount).collect();
+        }
+    }
+
+    /// Read up to `max` bytes.
+    /// Blocks until at least one byte is available or all write ends are closed.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn read_blocking(&self, max: usize) -> Vec<u8> {
+        loop {
+            let data = self.read(max);
+            if !data.is_empty() || max == 0 || self.is_eof() {
+                return data;
+            }
+            // Will panic on main thread
+            std::thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS));
+        }
+    }
+
+    /// The amount of bytes available to be read
+    pub fn len(&self) -> usize {
+        self.pipe.len()
+    }
+
+    /// Check if there are no bytes available to be read
+    pub fn is_empty(&self) -> bool {
+        self.pipe.is_empty()
+    }
+
+    /// Check if all write ends of the pipe have been closed
+    pub fn is_write_closed(&self) -> bool {
+        self.pipe.writers.load(Ordering::Acquire) == 0
+    }
+
+    /// Check if all write ends have been closed and all bytes have been read.
+    /// Always true once this end has been closed.
+    pub fn is_eof(&self) -> bool {
+        self.closed.load(Ordering::Acquire) || (self.is_write_closed() && self.is_empty())
+    }
+
+    /// Close this read end.
+    /// Does nothing if it has already been closed.
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            self.pipe.readers.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl PipeWriter {
+    /// Write as many bytes as fit into the pipe without blocking.
+    /// Returns the amount of bytes written.
+    pub fn write(&self, data: &[u8]) -> Result<usize, PipeError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(PipeError::Closed);
+        }
+        if self.is_read_closed() {
+            return Err(PipeError::Broken);
+        }
+        loop {
+            let Ok(mut buffer) = self.pipe.buffer.try_lock() else {
+                continue;
+            };
+            let amount = data
+                .len()
+                .min(self.pipe.capacity.saturating_sub(buffer.len()));
+            buffer.extend(&data[..amount]);
+            return Ok(amount);
+        }
+    }
+
+    /// Write all bytes to the pipe.
+    /// Blocks while the pipe is full.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn write_blocking(&self, mut data: &[u8]) -> Result<(), PipeError> {
+        while !data.is_empty() {
+            let written = self.write(data)?;
+            data = &data[written..];
+            if written == 0 {
+                // Will panic on main thread
+                std::thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if all read ends of the pipe have been closed
+    pub fn is_read_closed(&self) -> bool {
+        self.pipe.readers.load(Ordering::Acquire) == 0
+    }
+
+    /// Close this write end.
+    /// Does nothing if it has already been closed.
+    pub fn close(&self) {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            self.pipe.writers.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        let closed = self.closed.load(Ordering::Acquire);
+        if !closed {
+            self.pipe.readers.fetch_add(1, Ordering::AcqRel);
+        }
+        Self {
+            pipe: self.pipe.clone(),
+            closed: AtomicBool::new(closed),
+        }
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        let closed = self.closed.load(Ordering::Acquire);
+        if !closed {
+            self.pipe.writers.fetch_add(1, Ordering::AcqRel);
+        }
+        Self {
+            pipe: self.pipe.clone(),
+            closed: AtomicBool::new(closed),
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl std::error::Error for PipeError {}
+
+impl std::fmt::Display for PipeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipeError::Broken => writeln!(f, "All read ends of the pipe have been closed"),
+            PipeError::Closed => writeln!(f, "The end of the pipe has been closed"),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::anyhow;
+use hashbrown::HashMap;
 use honeyos_atomics::mutex::SpinMutex;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -6,71 +7,186 @@ use std::sync::{
 };
 use uuid::Uuid;
 use wasm_bindgen::{closure::Closure, prelude::wasm_bindgen, JsCast, JsValue};
-use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 use web_sys::{
-    js_sys::{Function, Reflect, WebAssembly, JSON},
-    Blob, Url, Worker, WorkerOptions, WorkerType,
+    js_sys::{Array, Function, Reflect, WebAssembly, JSON},
+    Blob, MessageEvent, Url, Worker, WorkerOptions, WorkerType,
 };
 
 use crate::{
-    context::{ApiBuilderFn, ProcessCtx},
+    context::{ApiBuilderFn, ProcessCtx, HAPI_VERSION},
+    crash::{self, CrashReport, EXIT_SENTINEL},
+    dylink::{self, LinkPlan, Linker, DEFAULT_LIBRARY_PATH, LIBRARY_PATH_VAR},
+    error::SpawnError,
+    limits::LimitError,
+    manifest::{Manifest, DEFAULT_ENTRYPOINT},
     memory::Memory,
+    module_cache,
     requirements::WasmRequirements,
+    stdin::ProcessStdIn,
     stdout::ProcessStdOut,
-    thread::ThreadPool,
-    ProcessManager,
+    thread::{ThreadError, ThreadPool, ThreadRequest, ThreadState, ThreadTable, MAIN_THREAD_ID},
+    weak::Shim,
+    ProcessManager, SpawnOptions,
 };
 
+/// The import module holding the memory, table and emscripten stubs
+const ENVIRONMENT_MODULE: &str = "env";
+
+/// The items of the environment module
+const ENVIRONMENT_IMPORTS: [&str; 11] = [
+    "memory",
+    "table",
+    "emscripten_notify_memory_growth",
+    "_emscripten_notify_mailbox_postmessage",
+    "emscripten_check_blocking_allowed",
+    "_emscripten_receive_on_main_thread_js",
+    "__emscripten_init_main_thread_js",
+    "_emscripten_thread_mailbox_await",
+    "_emscripten_thread_set_strongref",
+    "emscripten_exit_with_live_runtime",
+    "__emscripten_thread_cleanup",
+];
+
+/// The emscripten methods stubbed in the environment module
+const EMSCRIPTEN_STUBS: &[&str] = ENVIRONMENT_IMPORTS.split_at(2).1;
+
 /// A process in honeyos
 pub struct Process {
     // The process id
     id: Uuid,
     // The process title
     title: String,
+    // The manifest embedded in the binary
+    manifest: Option<Arc<Manifest>>,
     // The current working directory for the process
     cwd: Arc<RwLock<String>>,
     // The process context
     ctx: Arc<ProcessCtx>,
     // The worker for the process
     worker: Option<Worker>,
+    // The shared libraries linked with the binary
+    link: Arc<LinkPlan>,
+    // The shims for the weak imports the api and the libraries do not provide
+    shims: Arc<Vec<Shim>>,
+    // The compiled binary and libraries, set once they're compiled so threads instantiate them without recompiling
+    modules: Arc<Mutex<Option<(WebAssembly::Module, Array)>>>,
     // Flag for if the process is alive
     alive: Arc<AtomicBool>,
     // The threadpool
     thread_pool: ThreadPool,
+    // The stdin
+    stdin: Arc<ProcessStdIn>,
     // The stdout
     stdout: Arc<ProcessStdOut>,
+    // The stderr
+    stderr: Arc<ProcessStdOut>,
 }
 
 impl Process {
-    /// Create a process
+    /// Create a process.
+    /// Without a title the process is named after it's manifest, or it's id if it has none.
     pub fn new(
         id: Uuid,
         wasm_bin: Vec<u8>,
-        title: &str,
+        title: Option<&str>,
         working_directory: &str,
+        options: SpawnOptions,
         api_builder: ApiBuilderFn,
-    ) -> anyhow::Result<Self> {
-        let title = title.to_string();
+    ) -> Result<Self, SpawnError> {
+        // Validate and parse the wasm
+        let requirements = WasmRequirements::parse(&wasm_bin)?;
+        let entrypoint = requirements
+            .manifest
+            .as_ref()
+            .map_or(DEFAULT_ENTRYPOINT, |manifest| manifest.entrypoint());
+        if !requirements
+            .function_exports
+            .iter()
+            .any(|export| export == entrypoint)
+        {
+            return Err(SpawnError::MissingEntrypoint(entrypoint.to_string()));
+        }
+        if let Some(manifest) = &requirements.manifest {
+            if !manifest.runs_on(HAPI_VERSION)? {
+                let required = manifest.min_hapi_version()?.unwrap_or_default();
+                return Err(SpawnError::UnsupportedHapiVersion(required));
+            }
+        }
+
+        let manifest = requirements.manifest.clone().map(Arc::new);
+        let title = title
+            .map(|title| title.to_string())
+            .or_else(|| manifest.as_ref().and_then(|manifest| manifest.name.clone()))
+            .unwrap_or_else(|| id.to_string());
         // The running flag
         let alive = Arc::new(AtomicBool::new(true));
-        // The stdout
-        let stdout = Arc::new(ProcessStdOut::new());
         // The current working directory
         let cwd = Arc::new(RwLock::new(working_directory.to_string()));
+        let library_path = options
+            .env
+            .get(LIBRARY_PATH_VAR)
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_LIBRARY_PATH.to_string());
         // Create the process context
-        let ctx = create_context(id, &wasm_bin, stdout.clone(), cwd.clone(), api_builder)?;
+        let ctx = create_context(
+            id,
+            &wasm_bin,
+            &requirements,
+            cwd.clone(),
+            options,
+            api_builder,
+        )?;
+        // Resolve the shared libraries the binary needs
+        let capabilities = ctx.capabilities();
+        let mut link = LinkPlan::resolve(&requirements, &library_path, |path| {
+            dylink::read_library(path, capabilities)
+        })?;
+        // Check that the api and the libraries provide every import, so the binary doesn't fail once it's instantiated
+        let api = ctx.api_module_builder();
+        let provided = |module: &str, name: &str, position_independent: bool| {
+            (module == ENVIRONMENT_MODULE && ENVIRONMENT_IMPORTS.contains(&name))
+                || api.contains(module, name)
+                || link.provides(module, name, position_independent)
+        };
+        let position_independent = requirements.dylink.is_some();
+        let mut missing = requirements.missing_imports(|module, name| {
+            provided(module, name, position_independent) || requirements.is_weak(module, name)
+        });
+        for library in link.libraries.iter() {
+            let library_missing = library
+                .requirements
+                .missing_imports(|module, name| provided(module, name, true));
+            missing.extend(
+                library_missing
+                    .into_iter()
+                    .map(|import| format!("{}: {}", library.name, import)),
+            );
+        }
+        if !missing.is_empty() {
+            return Err(SpawnError::UnknownImports(missing));
+        }
+        let shims =
+            requirements.weak_shims(|module, name| provided(module, name, position_independent));
+        link.reserve_memory(&mut ctx.memory())?;
         // Create the thread pool
         let thread_pool = ThreadPool::new(id);
 
         Ok(Self {
             id,
             title,
+            manifest,
             alive,
-            stdout,
+            stdin: ctx.stdin(),
+            stdout: ctx.stdout(),
+            stderr: ctx.stderr(),
             cwd,
             ctx,
             thread_pool,
             worker: None,
+            link: Arc::new(link),
+            shims: Arc::new(shims),
+            modules: Arc::new(Mutex::new(None)),
         })
     }
 
@@ -81,52 +197,178 @@ impl Process {
 
         let worker = Worker::new_with_options(&get_worker_script(), &options)
             .map_err(|e| anyhow::anyhow!("Failed to create worker: {:?}", e))?;
-        let msg = web_sys::js_sys::Array::new();
-
-        // Send the pid
-        msg.push(&self.id.to_string().into());
-        // Send the kernel module
-        msg.push(&wasm_bindgen::module());
-        // Send the kernel memory
-        msg.push(&wasm_bindgen::memory());
-        // Send the process memory
-        msg.push(self.ctx().memory_nospin().inner());
-
-        worker
-            .post_message(&msg)
-            .map_err(|e| anyhow::anyhow!("Failed to send message to worker: {:?}", e))?;
-
         // Set callbacks
         let alive_callback = self.alive.clone();
-        let onmessage_callback =
-            Closure::wrap(
-                Box::new(move || alive_callback.store(false, Ordering::Relaxed))
-                    as Box<dyn FnMut()>,
-            );
+        let threads_callback = self.threads();
+        let ctx_callback = self.ctx();
+        let title_callback = self.title.clone();
+        let manifest_callback = self.manifest.clone();
+        let onmessage_callback = Closure::wrap(Box::new(move |event: MessageEvent| {
+            alive_callback.store(false, Ordering::Relaxed);
+            let error = Reflect::get(&event.data(), &"error".into())
+                .ok()
+                .filter(|error| !error.is_undefined());
+
+            // A process that called exit unwinds with an error, but has set it's exit code
+            match (error, ctx_callback.exit_code()) {
+                (_, Some(code)) => {
+                    threads_callback.finish(MAIN_THREAD_ID, ThreadState::Exited, code as u32);
+                }
+                (None, None) => {
+                    ctx_callback.set_exit_code(0);
+                    threads_callback.finish(MAIN_THREAD_ID, ThreadState::Exited, 0);
+                }
+                (Some(error), None) => {
+                    record_crash(
+                        &ctx_callback,
+                        &title_callback,
+                        manifest_callback.as_deref(),
+                        MAIN_THREAD_ID,
+                        error.as_string().unwrap_or_default(),
+                        Some(&event.data()),
+                    );
+                    threads_callback.finish(MAIN_THREAD_ID, ThreadState::Failed, 0);
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
         let alive_callback = self.alive.clone();
-        let onerror_callback =
-            Closure::wrap(
-                Box::new(move || alive_callback.store(false, Ordering::Relaxed))
-                    as Box<dyn FnMut()>,
+        let threads_callback = self.threads();
+        let ctx_callback = self.ctx();
+        let title_callback = self.title.clone();
+        let manifest_callback = self.manifest.clone();
+        let onerror_callback = Closure::wrap(Box::new(move |event: JsValue| {
+            alive_callback.store(false, Ordering::Relaxed);
+            let message = Reflect::get(&event, &"message".into())
+                .ok()
+                .and_then(|message| message.as_string())
+                .unwrap_or_else(|| "The worker failed".to_string());
+            record_crash(
+                &ctx_callback,
+                &title_callback,
+                manifest_callback.as_deref(),
+                MAIN_THREAD_ID,
+                message,
+                None,
             );
+            threads_callback.finish(MAIN_THREAD_ID, ThreadState::Failed, 0);
+        }) as Box<dyn FnMut(JsValue)>);
         worker.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
         worker.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
 
-        self.worker = Some(worker);
         self.alive.store(true, Ordering::Release);
 
         onmessage_callback.forget();
         onerror_callback.forget();
 
+        // Compile the binary, unless it's cached, and send it to the worker
+        let pid = self.id;
+        let bin = self.ctx.module();
+        let library_bins: Vec<_> = self
+            .link
+            .libraries
+            .iter()
+            .map(|library| library.bin.clone())
+            .collect();
+        let memory = self.ctx().memory_nospin().inner().clone();
+        let compiled = self.modules.clone();
+        let alive = self.alive.clone();
+        let threads = self.threads();
+        let process_worker = worker.clone();
+        spawn_local(async move {
+            let result = async {
+                let module = module_cache::compile(bin).await?;
+                let libraries = Array::new();
+                for bin in library_bins {
+                    let library = module_cache::compile(bin).await?;
+                    libraries.push(&library);
+                }
+                *compiled.spin_lock().unwrap() = Some((module.clone(), libraries.clone()));
+                let msg = web_sys::js_sys::Array::new();
+
+                // Send the pid
+                msg.push(&pid.to_string().into());
+                // Send the kernel module
+                msg.push(&wasm_bindgen::module());
+                // Send the kernel memory
+                msg.push(&wasm_bindgen::memory());
+                // Send the process memory
+                msg.push(&memory);
+                // Send the compiled binary
+                msg.push(&module);
+                // Send the compiled libraries
+                msg.push(&libraries);
+
+                process_worker
+                    .post_message(&msg)
+                    .map_err(|e| format!("Failed to send message to worker: {:?}", e))
+            }
+            .await;
+            if let Err(e) = result {
+                log::error!("Failed to start process `{}`: {}", pid, e);
+                process_worker.terminate();
+                alive.store(false, Ordering::Relaxed);
+                threads.finish(MAIN_THREAD_ID, ThreadState::Failed, 0);
+            }
+        });
+
+        self.worker = Some(worker);
+        Ok(())
+    }
+
+    /// Reserve an id for a thread that will be spawned later.
+    /// Fails if the process has reached it's thread limit.
+    pub fn reserve_thread(&mut self) -> Result<u32, ThreadError> {
+        let limit = self.ctx.limits().threads;
+        self.thread_pool.reserve(limit)
+    }
+
+    /// Spawn a thread with a reserved id.
+    /// The thread instantiates the binary and libraries compiled for the main thread.
+    pub fn spawn_thread(&mut self, request: &ThreadRequest) -> anyhow::Result<()> {
+        let modules = self.modules.spin_lock().unwrap().clone();
+        let Some((module, libraries)) = modules else {
+            self.thread_pool.fail(request.tid);
+            return Err(anyhow!(
+                "The binary of the process has not been compiled yet"
+            ));
+        };
+        let ctx = self.ctx();
+        let title = self.title.clone();
+        let manifest = self.manifest.clone();
+        let on_crash = move |tid, message, data: &JsValue| {
+            record_crash(&ctx, &title, manifest.as_deref(), tid, message, Some(data));
+        };
+        self.thread_pool.spawn(
+            request,
+            &module,
+            &libraries,
+            &self.ctx().memory_nospin(),
+            on_crash,
+        )?;
         Ok(())
     }
 
-    /// Spawn a thread and return it's id
-    pub fn spawn_thread(&mut self, f_ptr: u32) -> anyhow::Result<u32> {
-        let id = self
-            .thread_pool
-            .spawn(f_ptr, self.ctx().memory_nospin().inner())?;
-        Ok(id)
+    /// Kill a thread
+    pub fn kill_thread(&mut self, tid: u32) -> anyhow::Result<()> {
+        if let Some(stack) = self.thread_pool.kill(tid)? {
+            self.ctx.memory().free(stack);
+        }
+        Ok(())
+    }
+
+    /// Drop the workers of threads that have been joined or detached
+    pub fn reap_threads(&mut self) {
+        self.thread_pool.reap();
+    }
+
+    /// Get the state of a thread
+    pub fn thread_state(&self, tid: u32) -> Option<ThreadState> {
+        self.thread_pool.state(tid)
+    }
+
+    /// Get the shared thread table, which can be used to join threads without locking the process manager
+    pub fn threads(&self) -> ThreadTable {
+        self.thread_pool.table()
     }
 
     /// Kill the process
@@ -135,9 +377,34 @@ impl Process {
         if let Some(worker) = self.worker.as_mut() {
             worker.terminate();
         }
+        match self.exit_code() {
+            Some(code) => self
+                .threads()
+                .finish(MAIN_THREAD_ID, ThreadState::Exited, code as u32),
+            None => self
+                .threads()
+                .finish(MAIN_THREAD_ID, ThreadState::Killed, 0),
+        };
         self.alive.store(false, Ordering::Relaxed);
     }
 
+    /// Mark the process as failed, e.g. when it's worker could not be spawned
+    pub fn fail(&mut self) {
+        self.thread_pool.kill_all();
+        if let Some(worker) = self.worker.as_mut() {
+            worker.terminate();
+        }
+        self.threads()
+            .finish(MAIN_THREAD_ID, ThreadState::Failed, 0);
+        self.alive.store(false, Ordering::Relaxed);
+    }
+
+    /// Get the exit code of the process.
+    /// Only set once the process has exited on it's own.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.ctx.exit_code()
+    }
+
     /// Get the id
     pub fn id(&self) -> Uuid {
         self.id
@@ -148,6 +415,21 @@ impl Process {
         &self.title
     }
 
+    /// Get the manifest embedded in the binary, if any
+    pub fn manifest(&self) -> Option<Arc<Manifest>> {
+        self.manifest.clone()
+    }
+
+    /// Get the shared libraries linked with the binary
+    pub fn link(&self) -> Arc<LinkPlan> {
+        self.link.clone()
+    }
+
+    /// Get the shims for the weak imports the api and the libraries do not provide
+    pub fn shims(&self) -> Arc<Vec<Shim>> {
+        self.shims.clone()
+    }
+
     /// Get the context
     pub fn ctx(&self) -> Arc<ProcessCtx> {
         self.ctx.clone()
@@ -158,23 +440,65 @@ impl Process {
         self.alive.load(Ordering::Relaxed)
     }
 
+    /// Get the stdin
+    pub fn stdin(&self) -> Arc<ProcessStdIn> {
+        self.stdin.clone()
+    }
+
     /// Get the stdout
     pub fn stdout(&self) -> Arc<ProcessStdOut> {
         self.stdout.clone()
     }
 
+    /// Get the stderr
+    pub fn stderr(&self) -> Arc<ProcessStdOut> {
+        self.stderr.clone()
+    }
+
     /// Get the current working directory
     pub fn cwd(&self) -> String {
         self.cwd.read().unwrap().clone()
     }
+
+    /// Get the command-line arguments
+    pub fn args(&self) -> Arc<Vec<String>> {
+        self.ctx.args()
+    }
+
+    /// Get a copy of the environment variables
+    pub fn env(&self) -> HashMap<String, String> {
+        self.ctx.env()
+    }
+}
+
+/// Get the name of the function the process starts at.
+/// Throws in the worker if the process id is invalid.
+#[wasm_bindgen]
+pub fn process_entrypoint(pid: String) -> Result<String, JsValue> {
+    let pid = Uuid::parse_str(&pid)
+        .map_err(|_| JsValue::from_str(&format!("Invalid process id: {}", pid)))?;
+    let process_manager_lock = ProcessManager::get();
+    let Ok(process_manager) = process_manager_lock.spin_lock() else {
+        panic!("Process Manager Poisoned");
+    };
+
+    Ok(process_manager
+        .process(pid)
+        .and_then(|process| process.manifest())
+        .map_or(DEFAULT_ENTRYPOINT.to_string(), |manifest| {
+            manifest.entrypoint().to_string()
+        }))
 }
 
 /// Create the instance in the worker
 #[wasm_bindgen]
 pub async fn create_instance(
     pid: String,
+    module: &WebAssembly::Module,
+    libraries: &Array,
     memory: &WebAssembly::Memory,
     table: &WebAssembly::Table,
+    tid: Option<u32>,
 ) -> WebAssembly::Instance {
     let pid = Uuid::parse_str(&pid).unwrap();
     let process_manager_lock = ProcessManager::get();
@@ -185,47 +509,155 @@ pub async fn create_instance(
     let process = process_manager.process(pid).unwrap();
 
     let ctx = process.ctx();
-    let ctx = Arc::new(ctx.new_worker(memory.clone()));
+    let ctx = Arc::new(ctx.new_worker(memory.clone(), tid.unwrap_or(MAIN_THREAD_ID)));
 
     let environment = setup_environment(&ctx.memory().inner(), &table)
         .map_err(|e| log::error!("Failed to create environment: {}", e))
         .unwrap();
-    let api_module = ctx.build_api();
+    // Weak imports the kernel does not provide are replaced by shims
+    let mut api_module_builder = ctx.api_module_builder();
+    for shim in process.shims().iter() {
+        api_module_builder.register_in(&shim.module, &shim.name, shim.function().into());
+    }
+    let api_module = api_module_builder.build();
     let imports = setup_imports(environment, &api_module)
         .map_err(|e| log::error!("Failed to setup imports: {}", e))
         .unwrap();
 
-    init_binary(&ctx.module(), imports).await
+    // Instantiate the shared libraries first, so their exports can be imported by the binary
+    let mut linker = None;
+    let link = process.link();
+    let imports = if link.is_static() {
+        imports
+    } else {
+        let mut dynamic_linker = Linker::new(ctx.clone(), (*link).clone(), table.clone());
+        dynamic_linker
+            .link_libraries(libraries, &imports)
+            .await
+            .map_err(|e| log::error!("Failed to link libraries: {}", e))
+            .unwrap();
+        let imports = dynamic_linker
+            .binary_imports(module, &imports)
+            .map_err(|e| log::error!("Failed to link binary: {}", e))
+            .unwrap();
+        linker = Some(dynamic_linker);
+        imports
+    };
+
+    let instance = init_binary(module, imports).await;
+    if let Some(mut linker) = linker {
+        let tid = tid.unwrap_or(MAIN_THREAD_ID);
+        linker
+            .finish(&instance, tid == MAIN_THREAD_ID)
+            .map_err(|e| log::error!("Failed to link binary: {}", e))
+            .unwrap();
+        // The stack of the thread is freed once it finishes
+        if let Some(stack) = linker.stack() {
+            if !process.threads().set_stack(tid, stack) {
+                ctx.memory().free(stack);
+            }
+        }
+    }
+
+    // Binaries that define their own memory, like stock wasi binaries, are accessed through the exported memory
+    let exported_memory = Reflect::get(&instance.exports(), &"memory".into())
+        .ok()
+        .and_then(|memory| memory.dyn_into::<WebAssembly::Memory>().ok());
+    if let Some(exported_memory) = exported_memory {
+        let mut memory = ctx.memory();
+        if JsValue::from(memory.inner()) != JsValue::from(&exported_memory) {
+            memory.replace_inner(exported_memory);
+        }
+    }
+    instance
+}
+
+/// Check if a process writes a crash dump when it's worker throws.
+/// Called by the worker, as only the worker can take the snapshot of the memory.
+#[wasm_bindgen]
+pub fn process_crash_dumps(pid: String) -> bool {
+    let Ok(pid) = Uuid::parse_str(&pid) else {
+        return false;
+    };
+    let process_manager_lock = ProcessManager::get();
+    let Ok(process_manager) = process_manager_lock.spin_lock() else {
+        panic!("Process Manager Poisoned");
+    };
+
+    // A process that called exit unwinds with an error, but did not crash
+    process_manager.process(pid).is_some_and(|process| {
+        process.exit_code().is_none() && crash::dump_dir(&process.ctx()).is_some()
+    })
+}
+
+/// Record the crash of a thread of a process, writing a dump if the process has a dump directory
+fn record_crash(
+    ctx: &ProcessCtx,
+    title: &str,
+    manifest: Option<&Manifest>,
+    tid: u32,
+    message: String,
+    data: Option<&JsValue>,
+) {
+    // The thread pool logs the failure of other threads along with their name
+    if tid == MAIN_THREAD_ID {
+        log::error!("Process `{}` failed: {}", ctx.pid(), message);
+    }
+    let mut report = CrashReport::new(ctx, title, manifest.cloned(), tid, message);
+    let memory = data.and_then(|data| report.add_worker_data(data));
+    if let Some(dir) = crash::dump_dir(ctx) {
+        match crash::write_dump(&dir, &report, memory.as_deref()) {
+            Ok(path) => report.dump_path = Some(path),
+            Err(e) => log::error!(
+                "Failed to write crash dump of process `{}`: {}",
+                ctx.pid(),
+                e
+            ),
+        }
+    }
+    ctx.set_crash_report(report);
 }
 
 /// Create the context
 fn create_context(
     pid: Uuid,
     bin: &[u8],
-    stdout: Arc<ProcessStdOut>,
+    requirements: &WasmRequirements,
     cwd: Arc<RwLock<String>>,
+    mut options: SpawnOptions,
     api_builder: ApiBuilderFn,
-) -> anyhow::Result<Arc<ProcessCtx>> {
-    // Parse the wasm
-    let requirements = WasmRequirements::parse(&bin).unwrap();
+) -> Result<Arc<ProcessCtx>, SpawnError> {
+    // A process only gets the capabilities it's manifest requests, if it requests any in particular
+    let requested = requirements
+        .manifest
+        .as_ref()
+        .map(|manifest| manifest.requested_capabilities())
+        .transpose()?
+        .flatten();
+    if let Some(requested) = requested {
+        options.capabilities = options.capabilities.intersect(&requested);
+    }
 
     // Create the memory
-    let memory = Arc::new(Mutex::new(
-        Memory::new(
-            requirements.initial_memory,
-            requirements.maximum_memory,
-            requirements.shared_memory,
-        )
-        .expect("Failed to initialize instance's memory"),
-    ));
+    let memory = Memory::new(
+        requirements.initial_memory,
+        requirements.maximum_memory,
+        requirements.shared_memory,
+        options.limits.memory_pages,
+    )
+    .map_err(|e| match e.downcast::<LimitError>() {
+        Ok(error) => SpawnError::Limit(error),
+        Err(e) => SpawnError::Memory(e.to_string()),
+    })?;
+    let memory = Arc::new(Mutex::new(memory));
 
     let bin = Arc::new(bin.to_vec());
     Ok(Arc::new(ProcessCtx::new(
         pid,
         memory.clone(),
-        stdout,
         cwd,
         bin.clone(),
+        options,
         api_builder,
     )))
 }
@@ -245,132 +677,26 @@ pub fn setup_environment(
     Ok(env)
 }
 
-/// Setup the imports object
-pub fn setup_imports(environment: JsValue, api_module: &JsValue) -> anyhow::Result<JsValue> {
-    let imports_object = JSON::parse("{}").unwrap();
-    Reflect::set(&imports_object, &"env".into(), &environment)
+/// Setup the imports object from the env and the api import modules
+pub fn setup_imports(environment: JsValue, api_modules: &JsValue) -> anyhow::Result<JsValue> {
+    Reflect::set(api_modules, &ENVIRONMENT_MODULE.into(), &environment)
         .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-    Reflect::set(&imports_object, &"hapi".into(), &api_module)
-        .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-
-    // This does nothing. But emscripten expects this to be there. This might be removed in the future
-    setup_emscripten_imports(&imports_object)?;
-    Ok(imports_object)
+    Ok(api_modules.clone())
 }
 
 /// Add dummy methods to the env for emscripten suppoort.
 /// These methods remain unimplemented as they are not needed, but emscripten still expects them
 fn setup_emscripten_environment(env: &JsValue) -> anyhow::Result<()> {
-    Reflect::set(
-        &env,
-        &"emscripten_notify_memory_growth".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"_emscripten_notify_mailbox_postmessage".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"emscripten_check_blocking_allowed".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"_emscripten_notify_mailbox_postmessage".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"_emscripten_receive_on_main_thread_js".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"__emscripten_init_main_thread_js".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"_emscripten_thread_mailbox_await".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"_emscripten_thread_set_strongref".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"emscripten_exit_with_live_runtime".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-    Reflect::set(
-        &env,
-        &"__emscripten_thread_cleanup".into(),
-        &Function::new_no_args("{}"),
-    )
-    .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
-
-    Ok(())
-}
-
-/// Add dummy methods to the import for emscripten suppoort.
-/// These methods remain unimplemented as they are not needed, but emscripten still expects them
-fn setup_emscripten_imports(imports_object: &JsValue) -> anyhow::Result<()> {
-    let wasi_snapshot_preview1 = JSON::parse("{}").unwrap();
-    Reflect::set(
-        &wasi_snapshot_preview1,
-        &"proc_exit".into(),
-        &Function::new_no_args("{}").into(),
-    )
-    .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-    Reflect::set(
-        &wasi_snapshot_preview1,
-        &"clock_time_get".into(),
-        &Function::new_no_args("{}").into(),
-    )
-    .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-    Reflect::set(
-        &wasi_snapshot_preview1,
-        &"fd_close".into(),
-        &Function::new_no_args("{}").into(),
-    )
-    .map_err(|e| anyhow::anyhow!("Failed     to setup imports: {:?}", e))?;
-    Reflect::set(
-        &wasi_snapshot_preview1,
-        &"fd_write".into(),
-        &Function::new_no_args("{}").into(),
-    )
-    .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-    Reflect::set(
-        &wasi_snapshot_preview1,
-        &"fd_seek".into(),
-        &Function::new_no_args("{}").into(),
-    )
-    .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
-    Reflect::set(
-        &imports_object,
-        &"wasi_snapshot_preview1".into(),
-        &wasi_snapshot_preview1,
-    )
-    .map_err(|e| anyhow::anyhow!("Failed to setup imports: {:?}", e))?;
+    for &name in EMSCRIPTEN_STUBS {
+        Reflect::set(env, &name.into(), &Function::new_no_args("{}"))
+            .map_err(|e| anyhow!("Failed to setup env: {:?}", e))?;
+    }
     Ok(())
 }
 
-/// Initialize the wasm instance
-pub async fn init_binary(bin: &[u8], imports: JsValue) -> WebAssembly::Instance {
-    let promise = WebAssembly::instantiate_buffer(bin, &imports.unchecked_into());
+/// Instantiate the compiled binary
+pub async fn init_binary(module: &WebAssembly::Module, imports: JsValue) -> WebAssembly::Instance {
+    let promise = WebAssembly::instantiate_module(module, &imports.unchecked_into());
     let promise = JsFuture::from(promise);
     let instance = promise
         .await
@@ -379,10 +705,7 @@ pub async fn init_binary(bin: &[u8], imports: JsValue) -> WebAssembly::Instance
         })
         .unwrap();
 
-    Reflect::get(&instance, &"instance".into())
-        .unwrap()
-        .dyn_into()
-        .unwrap()
+    instance.dyn_into().unwrap()
 }
 
 /// Generate the worker script encoded blob url. (Cached for performance)
@@ -403,18 +726,10 @@ fn get_worker_script() -> String {
         .unwrap();
 
     let template = include_str!("js/worker_executable.js");
-    let script = template.replace("BINDGEN_SHIM_URL", &wasm_shim_url);
-
-    // Create url encoded blob
-    let arr = web_sys::js_sys::Array::new();
-    arr.set(0, JsValue::from_str(&script));
-    let blob = Blob::new_with_str_sequence(&arr).unwrap();
-    let url = Url::create_object_url_with_blob(
-        &blob
-            .slice_with_f64_and_f64_and_content_type(0.0, blob.size(), "text/javascript")
-            .unwrap(),
-    )
-    .unwrap();
+    let script = template
+        .replace("BINDGEN_SHIM_URL", &wasm_shim_url)
+        .replace("WORKER_COMMON_URL", &get_worker_common_script());
+    let url = script_url(&script);
 
     // Cache the url
     if let Ok(mut cached) = CACHED_SCRIPT.try_lock() {
@@ -425,3 +740,40 @@ fn get_worker_script() -> String {
 
     url
 }
+
+/// Generate the blob url of the module shared by the worker scripts. (Cached for performance)
+pub(crate) fn get_worker_common_script() -> String {
+    static CACHED_SCRIPT: Mutex<Option<String>> = Mutex::new(None);
+
+    // Cache the url
+    if let Ok(cached) = CACHED_SCRIPT.try_lock() {
+        if let Some(cached) = cached.as_ref() {
+            return cached.clone();
+        }
+    }
+
+    let template = include_str!("js/worker_common.js");
+    let script = template.replace("EXIT_SENTINEL_PREFIX", EXIT_SENTINEL);
+    let url = script_url(&script);
+
+    // Cache the url
+    if let Ok(mut cached) = CACHED_SCRIPT.try_lock() {
+        *cached = Some(url.clone());
+    }
+
+    url
+}
+
+/// Create a url encoded blob of a javascript module
+pub(crate) fn script_url(script: &str) -> String {
+    // Create url encoded blob
+    let arr = web_sys::js_sys::Array::new();
+    arr.set(0, JsValue::from_str(script));
+    let blob = Blob::new_with_str_sequence(&arr).unwrap();
+    Url::create_object_url_with_blob(
+        &blob
+            .slice_with_f64_and_f64_and_content_type(0.0, blob.size(), "text/javascript")
+            .unwrap(),
+    )
+    .unwrap()
+}
@@ -1,4 +1,14 @@
-use wasmparser::{Parser, Payload, TypeRef};
+use hashbrown::HashMap;
+use wasmparser::{
+    CompositeType, Encoding, ExternalKind, Parser, Payload, TypeRef, ValType, Validator,
+};
+
+use crate::{
+    dylink::{DylinkInfo, DYLINK_SECTION},
+    error::SpawnError,
+    manifest::{Manifest, MANIFEST_SECTION},
+    weak::Shim,
+};
 
 /// The runtime requirements for a wasm binary
 #[derive(Debug)]
@@ -6,37 +16,82 @@ pub struct WasmRequirements {
     pub initial_memory: u32,
     pub maximum_memory: Option<u32>,
     pub shared_memory: bool,
+    pub manifest: Option<Manifest>,
+    /// The imports of the binary as `(module, name)`
+    pub imports: Vec<(String, String)>,
+    /// The result types of the imported functions, by `module.name`
+    pub import_results: HashMap<String, Vec<ValType>>,
+    /// The names of the exported functions
+    pub function_exports: Vec<String>,
+    /// The names of every export
+    pub exports: Vec<String>,
+    /// The minimum size of the imported table
+    pub table_minimum: u32,
+    /// The dynamic linking information, if the binary is position independent
+    pub dylink: Option<DylinkInfo>,
 }
 
 impl WasmRequirements {
-    /// Parse a binary
-    pub fn parse(bin: &[u8]) -> anyhow::Result<Self> {
+    /// Validate and parse a binary
+    pub fn parse(bin: &[u8]) -> Result<Self, SpawnError> {
+        Validator::new().validate_all(bin)?;
+
         let parser = Parser::new(0);
 
         // Initialize variables to track memory requirements
         let mut initial_memory = 0;
         let mut maximum_memory = Some(0);
         let mut shared_memory = false;
+        let mut manifest = None;
+        let mut imports = Vec::new();
+        let mut types = Vec::new();
+        let mut import_results = HashMap::new();
+        let mut function_exports = Vec::new();
+        let mut exports = Vec::new();
+        let mut table_minimum = 0;
+        let mut dylink = None;
 
-        for payload in parser.parse_all(&bin) {
+        for payload in parser.parse_all(bin) {
             match payload? {
+                Payload::Version {
+                    encoding: Encoding::Component,
+                    ..
+                } => {
+                    return Err(SpawnError::InvalidBinary(
+                        "Components are not supported".to_string(),
+                    ));
+                }
+                Payload::TypeSection(section) => {
+                    for group in section {
+                        types.extend(group?.into_types().map(|ty| match ty.composite_type {
+                            CompositeType::Func(func) => func.results().to_vec(),
+                            _ => Vec::new(),
+                        }));
+                    }
+                }
                 Payload::ImportSection(section) => {
                     for import in section {
                         let import = import?;
+                        imports.push((import.module.to_string(), import.name.to_string()));
+                        if let TypeRef::Func(index) = import.ty {
+                            let results = types.get(index as usize).cloned().unwrap_or_default();
+                            import_results
+                                .insert(format!("{}.{}", import.module, import.name), results);
+                        }
+                        if let TypeRef::Table(table) = import.ty {
+                            table_minimum = table.initial as u32;
+                        }
                         if import.name == "memory" && import.module == "env" {
-                            match import.ty {
-                                TypeRef::Memory(memory) => {
-                                    initial_memory = memory.initial;
-                                    shared_memory = memory.shared;
-                                    maximum_memory = memory.maximum.map(|v| v as u32);
+                            if let TypeRef::Memory(memory) = import.ty {
+                                initial_memory = memory.initial;
+                                shared_memory = memory.shared;
+                                maximum_memory = memory.maximum.map(|v| v as u32);
 
-                                    if shared_memory && maximum_memory.is_none() {
-                                        return Err(anyhow::anyhow!(
-                                            "Wasm cannot request shared memory without specifying a maximum memory size"
-                                        ));
-                                    }
+                                if shared_memory && maximum_memory.is_none() {
+                                    return Err(SpawnError::InvalidBinary(
+                                        "Wasm cannot request shared memory without specifying a maximum memory size".to_string(),
+                                    ));
                                 }
-                                _ => {}
                             }
                         }
                     }
@@ -49,12 +104,28 @@ impl WasmRequirements {
                         maximum_memory = memory.maximum.map(|v| v as u32);
 
                         if shared_memory && maximum_memory.is_none() {
-                            return Err(anyhow::anyhow!(
+                            return Err(SpawnError::InvalidBinary(
                                 "Wasm cannot request shared memory without specifying a maximum"
+                                    .to_string(),
                             ));
                         }
                     }
                 }
+                Payload::ExportSection(section) => {
+                    for export in section {
+                        let export = export?;
+                        exports.push(export.name.to_string());
+                        if export.kind == ExternalKind::Func {
+                            function_exports.push(export.name.to_string());
+                        }
+                    }
+                }
+                Payload::CustomSection(section) if section.name() == MANIFEST_SECTION => {
+                    manifest = Some(Manifest::parse(section.data())?);
+                }
+                Payload::CustomSection(section) if section.name() == DYLINK_SECTION => {
+                    dylink = Some(DylinkInfo::parse(section.data(), section.data_offset())?);
+                }
                 _ => {}
             }
         }
@@ -63,6 +134,45 @@ impl WasmRequirements {
             maximum_memory,
             initial_memory: initial_memory as u32,
             shared_memory,
+            manifest,
+            imports,
+            import_results,
+            function_exports,
+            exports,
+            table_minimum,
+            dylink,
         })
     }
+
+    /// Get the imports that are not provided, formatted as `module.name`
+    pub fn missing_imports(&self, provided: impl Fn(&str, &str) -> bool) -> Vec<String> {
+        self.imports
+            .iter()
+            .filter(|(module, name)| !provided(module, name))
+            .map(|(module, name)| format!("{}.{}", module, name))
+            .collect()
+    }
+
+    /// Check if an import is a function the manifest marks as weak
+    pub fn is_weak(&self, module: &str, name: &str) -> bool {
+        let import = format!("{}.{}", module, name);
+        self.import_results.contains_key(&import)
+            && self
+                .manifest
+                .as_ref()
+                .is_some_and(|manifest| manifest.weak_imports().any(|weak| weak == import))
+    }
+
+    /// Get the shims for the weak imports that are not provided
+    pub fn weak_shims(&self, provided: impl Fn(&str, &str) -> bool) -> Vec<Shim> {
+        self.imports
+            .iter()
+            .filter(|(module, name)| self.is_weak(module, name) && !provided(module, name))
+            .map(|(module, name)| Shim {
+                module: module.clone(),
+                name: name.clone(),
+                results: self.import_results[&format!("{}.{}", module, name)].clone(),
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,436 @@
+//! The submission and completion rings of a process, modeled after io_uring.
+//! A process sets up a ring in it's own memory, queues operations in the submission ring and submits them at once.
+//! Operations that can finish right away complete during the submit, others complete once the kernel sees them finish.
+//! Completions are kept by the kernel until they are copied into the completion ring,
+//! which happens whenever the process submits or waits, so finished operations are reaped without further calls.
+//! The operations in flight and the completions kept by the kernel are limited to the amount of completion entries,
+//! further submissions are rejected until the process reaps completions.
+//!
+//! The ring starts with a header of little endian `u32` values, followed by the submission and completion entries:
+//! - `0` The head of the submission ring, advanced by the kernel
+//! - `4` The tail of the submission ring, advanced by the process
+//! - `8` The head of the completion ring, advanced by the process
+//! - `12` The tail of the completion ring, advanced by the kernel
+//! - `16` The amount of submission entries
+//! - `20` The amount of completion entries, twice the amount of submission entries
+//! - `24` The amount of completions kept by the kernel because the completion ring was full
+//! - `28` Reserved
+//!
+//! Heads and tails increase freely and wrap around, the entry of an index is at `index % entries`.
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use honeyos_atomics::mutex::SpinMutex;
+use uuid::Uuid;
+use web_sys::js_sys::Date;
+
+/// The size of the header of a ring
+pub const RING_HEADER_SIZE: u32 = 32;
+
+/// The size of a submission entry
+pub const SUBMISSION_SIZE: u32 = 32;
+
+/// The size of a completion entry
+pub const COMPLETION_SIZE: u32 = 16;
+
+/// The maximum amount of submission entries of a ring
+pub const MAX_RING_ENTRIES: u32 = 4096;
+
+/// The offsets of the fields in the header
+pub const SQ_HEAD: u32 = 0;
+pub const SQ_TAIL: u32 = 4;
+pub const CQ_HEAD: u32 = 8;
+pub const CQ_TAIL: u32 = 12;
+pub const SQ_ENTRIES: u32 = 16;
+pub const CQ_ENTRIES: u32 = 20;
+pub const CQ_OVERFLOW: u32 = 24;
+
+/// The operations a process can submit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    /// Complete right away, for waking a waiting thread
+    Nop = 0,
+    /// Read `len` bytes of a file at the offset into the buffer
+    FileRead = 1,
+    /// Write `len` bytes of the buffer to a file at the offset
+    FileWrite = 2,
+    /// Wait for a network request to finish
+    NetworkRequest = 3,
+    /// Wait for the duration in nanoseconds to elapse
+    Timeout = 4,
+    /// Wait for a process to exit
+    ProcessWait = 5,
+}
+
+/// An operation queued by the process.
+/// It's entry is laid out as follows, in little endian:
+/// - `u8` The opcode, followed by 3 bytes of padding
+/// - `u32` The handle the operation acts on
+/// - `u64` The user data, passed back in the completion
+/// - `u32` The pointer to the buffer
+/// - `u32` The length of the buffer
+/// - `u64` The offset of file operations, or the duration of a timeout in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Submission {
+    pub opcode: u8,
+    pub handle: u32,
+    pub user_data: u64,
+    pub buffer: u32,
+    pub len: u32,
+    pub arg: u64,
+}
+
+/// A finished operation.
+/// It's entry is laid out as follows, in little endian:
+/// - `u64` The user data of the submission
+/// - `i32` The result, the amount of bytes transferred by file operations, or a negative error code
+/// - `u32` Reserved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Completion {
+    pub user_data: u64,
+    pub result: i32,
+}
+
+/// An operation waiting on the kernel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PendingOp {
+    /// A network request to finish
+    NetworkRequest(Uuid),
+    /// The deadline of a timeout, in milliseconds since the unix epoch
+    Timeout(f64),
+    /// A process to exit
+    ProcessWait(Uuid),
+}
+
+/// The placement of a ring in the memory of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingLayout {
+    pub ptr: u32,
+    /// The amount of submission entries, a power of two
+    pub entries: u32,
+}
+
+/// The kernel side of the ring of a process, shared by it's threads
+#[derive(Debug, Default)]
+pub struct CompletionRing {
+    state: Mutex<RingState>,
+    completed: Condvar,
+    flushing: Mutex<()>,
+}
+
+/// The operations in flight and the completions not copied into the completion ring yet
+#[derive(Debug, Default)]
+struct RingState {
+    layout: Option<RingLayout>,
+    pending: Vec<(u64, PendingOp)>,
+    completions: VecDeque<Completion>,
+}
+
+/// The error types for rings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingError {
+    /// The process already set up it's ring
+    AlreadySetUp,
+    /// The process has not set up a ring
+    NotSetUp,
+    /// The amount of entries is zero or above [`MAX_RING_ENTRIES`]
+    InvalidEntries(u32),
+    /// A head and tail are further apart than the ring is long
+    Corrupted,
+    /// The operations in flight and the completions kept by the kernel fill the completion ring
+    Busy,
+}
+
+impl Opcode {
+    pub fn from_u8(opcode: u8) -> Option<Self> {
+        match opcode {
+            0 => Some(Opcode::Nop),
+            1 => Some(Opcode::FileRead),
+            2 => Some(Opcode::FileWrite),
+            3 => Some(Opcode::NetworkRequest),
+            4 => Some(Opcode::Timeout),
+            5 => Some(Opcode::ProcessWait),
+            _ => None,
+        }
+    }
+}
+
+impl Submission {
+    /// Parse a submission from it's entry
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; SUBMISSION_SIZE as usize] = bytes.try_into().ok()?;
+        let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        Some(Self {
+            opcode: bytes[0],
+            handle: u32_at(4),
+            user_data: u64_at(8),
+            buffer: u32_at(16),
+            len: u32_at(20),
+            arg: u64_at(24),
+        })
+    }
+
+    /// Get the entry of the submission
+    pub fn to_bytes(&self) -> [u8; SUBMISSION_SIZE as usize] {
+        let mut bytes = [0u8; SUBMISSION_SIZE as usize];
+        bytes[0] = self.opcode;
+        bytes[4..8].copy_from_slice(&self.handle.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.user_data.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.buffer.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.len.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.arg.to_le_bytes());
+        bytes
+    }
+}
+
+impl Completion {
+    /// Get the entry of the completion
+    pub fn to_bytes(&self) -> [u8; COMPLETION_SIZE as usize] {
+        let mut bytes = [0u8; COMPLETION_SIZE as usize];
+        bytes[0..8].copy_from_slice(&self.user_data.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.result.to_le_bytes());
+        bytes
+    }
+}
+
+impl RingLayout {
+    /// Get the amount of submission entries of a ring with at least the requested amount of entries,
+    /// rounded up to a power of two
+    pub fn entries_for(requested: u32) -> Result<u32, RingError> {
+        match requested {
+            1..=MAX_RING_ENTRIES => Ok(requested.next_power_of_two()),
+            _ => Err(RingError::InvalidEntries(requested)),
+        }
+    }
+
+    /// Get the size in bytes of a ring with an amount of submission entries
+    pub fn size_for(entries: u32) -> u32 {
+        RING_HEADER_SIZE + entries * SUBMISSION_SIZE + entries * 2 * COMPLETION_SIZE
+    }
+
+    /// Get the size in bytes of the ring
+    pub fn size(&self) -> u32 {
+        Self::size_for(self.entries)
+    }
+
+    /// Get the amount of completion entries
+    pub fn completion_entries(&self) -> u32 {
+        self.entries * 2
+    }
+
+    /// Get the header of a new ring
+    pub fn header(&self) -> [u8; RING_HEADER_SIZE as usize] {
+        let mut header = [0u8; RING_HEADER_SIZE as usize];
+        let (sq_entries, cq_entries) = (SQ_ENTRIES as usize, CQ_ENTRIES as usize);
+        header[sq_entries..sq_entries + 4].copy_from_slice(&self.entries.to_le_bytes());
+        header[cq_entries..cq_entries + 4]
+            .copy_from_slice(&self.completion_entries().to_le_bytes());
+        header
+    }
+
+    /// Get the pointer to a field of the header
+    pub fn field(&self, offset: u32) -> u32 {
+        self.ptr + offset
+    }
+
+    /// Get the pointer to the submission entry of an index
+    pub fn submission(&self, index: u32) -> u32 {
+        self.ptr + RING_HEADER_SIZE + (index % self.entries) * SUBMISSION_SIZE
+    }
+
+    /// Get the pointer to the completion entry of an index
+    pub fn completion(&self, index: u32) -> u32 {
+        let completions = self.ptr + RING_HEADER_SIZE + self.entries * SUBMISSION_SIZE;
+        completions + (index % self.completion_entries()) * COMPLETION_SIZE
+    }
+
+    /// Get the amount of submissions queued between the head and tail of the submission ring
+    pub fn queued(&self, head: u32, tail: u32) -> Result<u32, RingError> {
+        match tail.wrapping_sub(head) {
+            queued if queued <= self.entries => Ok(queued),
+            _ => Err(RingError::Corrupted),
+        }
+    }
+
+    /// Get the amount of free entries between the head and tail of the completion ring
+    pub fn free(&self, head: u32, tail: u32) -> Result<u32, RingError> {
+        match tail.wrapping_sub(head) {
+            used if used <= self.completion_entries() => Ok(self.completion_entries() - used),
+            _ => Err(RingError::Corrupted),
+        }
+    }
+}
+
+impl CompletionRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the placement of the ring, if the process set it up
+    pub fn layout(&self) -> Option<RingLayout> {
+        self.state().layout
+    }
+
+    /// Set the placement of the ring, which can only be done once
+    pub fn set_layout(&self, layout: RingLayout) -> Result<(), RingError> {
+        let mut state = self.state();
+        if state.layout.is_some() {
+            return Err(RingError::AlreadySetUp);
+        }
+        state.layout = Some(layout);
+        Ok(())
+    }
+
+    /// Keep an operation until the kernel sees it finish
+    pub fn add_pending(&self, user_data: u64, op: PendingOp) {
+        self.state().pending.push((user_data, op));
+    }
+
+    /// Check if the ring has room for another operation.
+    /// The operations in flight and the completions kept by the kernel are limited to the amount of completion entries.
+    pub fn check_capacity(&self) -> Result<(), RingError> {
+        let state = self.state();
+        let layout = state.layout.ok_or(RingError::NotSetUp)?;
+        let used = state.pending.len() + state.completions.len();
+        match used < layout.completion_entries() as usize {
+            true => Ok(()),
+            false => Err(RingError::Busy),
+        }
+    }
+
+    /// Complete an operation, waking the threads waiting on the ring
+    pub fn complete(&self, user_data: u64, result: i32) {
+        self.state()
+            .completions
+            .push_back(Completion { user_data, result });
+        self.completed.notify_all();
+    }
+
+    /// Complete the operations in flight `ready` returns a result for.
+    /// Returns false without polling if the ring is in use, so this never blocks the kernel.
+    pub fn poll(&self, ready: impl FnMut(&PendingOp) -> Option<i32>) -> bool {
+        let Ok(mut state) = self.state.try_lock() else {
+            return false;
+        };
+        if state.complete_ready(ready) {
+            self.completed.notify_all();
+        }
+        true
+    }
+
+    /// Take up to `max` completions, oldest first
+    pub fn take_completions(&self, max: u32) -> Vec<Completion> {
+        let mut state = self.state();
+        let amount = state.completions.len().min(max as usize);
+        state.completions.drain(..amount).collect()
+    }
+
+    /// Get the amount of completions not copied into the completion ring yet
+    pub fn completions(&self) -> usize {
+        self.state().completions.len()
+    }
+
+    /// Get the amount of operations in flight
+    pub fn pending(&self) -> usize {
+        self.state().pending.len()
+    }
+
+    /// Block until there is a completion, or the timeout in milliseconds elapsed.
+    /// Timeouts in flight are completed by the waiting thread, so they don't wait on the kernel.
+    /// Returns whether there is a completion.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn wait(&self, timeout: Option<f64>) -> bool {
+        let deadline = timeout.map(|timeout| Date::now() + timeout);
+        let mut state = self.state();
+        loop {
+            let now = Date::now();
+            state.complete_ready(|op| match op {
+                PendingOp::Timeout(deadline) => (*deadline <= now).then_some(0),
+                _ => None,
+            });
+            if !state.completions.is_empty() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return false;
+            }
+
+            // Sleep until the first of the timeout and the timeouts in flight
+            let wake = state
+                .pending
+                .iter()
+                .filter_map(|(_, op)| match op {
+                    PendingOp::Timeout(deadline) => Some(*deadline),
+                    _ => None,
+                })
+                .chain(deadline)
+                .min_by(f64::total_cmp);
+            state = match wake {
+                Some(wake) => {
+                    let duration = Duration::from_secs_f64((wake - now).max(0.0) / 1_000.0);
+                    self.completed.wait_timeout(state, duration).unwrap().0
+                }
+                None => self.completed.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Lock copying completions into the completion ring, so threads of the process don't copy at the same time.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn flush_lock(&self) -> MutexGuard<'_, ()> {
+        self.flushing.spin_lock().unwrap()
+    }
+
+    /// Lock the state of the ring
+    fn state(&self) -> MutexGuard<'_, RingState> {
+        self.state.spin_lock().unwrap()
+    }
+}
+
+impl RingState {
+    /// Move the operations in flight `ready` returns a result for to the completions.
+    /// Returns whether any operation completed.
+    fn complete_ready(&mut self, mut ready: impl FnMut(&PendingOp) -> Option<i32>) -> bool {
+        let before = self.completions.len();
+        let completions = &mut self.completions;
+        self.pending.retain(|(user_data, op)| match ready(op) {
+            Some(result) => {
+                completions.push_back(Completion {
+                    user_data: *user_data,
+                    result,
+                });
+                false
+            }
+            None => true,
+        });
+        self.completions.len() > before
+    }
+}
+
+impl std::error::Error for RingError {}
+
+impl std::fmt::Display for RingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RingError::AlreadySetUp => writeln!(f, "The process already set up it's ring"),
+            RingError::NotSetUp => writeln!(f, "The process has not set up a ring"),
+            RingError::InvalidEntries(entries) => writeln!(
+                f,
+                "A ring must have between 1 and {} entries, not {}",
+                MAX_RING_ENTRIES, entries
+            ),
+            RingError::Corrupted => writeln!(
+                f,
+                "A head and tail of the ring are further apart than the ring is long"
+            ),
+            RingError::Busy => writeln!(
+                f,
+                "The operations in flight and the completions kept fill the completion ring"
+            ),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::anyhow;
+
+use crate::pipe::{Pipe, PipeReader, PipeWriter};
+
+/// StdIn is a struct that represents the standard input stream of a process
+#[derive(Debug)]
+pub struct ProcessStdIn {
+    reader: PipeReader,         // The process-side end of the stream
+    writer: Option<PipeWriter>, // The kernel-side end. None if the stream is connected to a pipe
+}
+
+impl ProcessStdIn {
+    pub fn new() -> Self {
+        let (reader, writer) = Pipe::unbounded();
+        Self {
+            reader,
+            writer: Some(writer),
+        }
+    }
+
+    /// Create a stdin that reads from a pipe.
+    /// Input can only be written through the write ends of the pipe.
+    pub fn from_pipe(reader: PipeReader) -> Self {
+        Self {
+            reader,
+            writer: None,
+        }
+    }
+
+    /// Write bytes to the input buffer.
+    /// Fails if the stream has been closed or is connected to a pipe.
+    pub fn write(&self, data: &[u8]) -> anyhow::Result<()> {
+        let Some(writer) = self.writer.as_ref() else {
+            return Err(anyhow!("Cannot write to a stdin connected to a pipe"));
+        };
+        writer
+            .write_blocking(data)
+            .map_err(|e| anyhow!("Failed to write to stdin: {}", e))?;
+        Ok(())
+    }
+
+    /// Read up to `max` bytes from the input buffer without blocking.
+    /// Returns an empty vec if no input is available.
+    pub fn read(&self, max: usize) -> Vec<u8> {
+        self.reader.read(max)
+    }
+
+    /// Read up to `max` bytes from the input buffer.
+    /// Blocks until at least one byte is available or the stream is closed.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn read_blocking(&self, max: usize) -> Vec<u8> {
+        self.reader.read_blocking(max)
+    }
+
+    /// The amount of bytes available to be read
+    pub fn len(&self) -> usize {
+        self.reader.len()
+    }
+
+    /// Check if there are no bytes available to be read
+    pub fn is_empty(&self) -> bool {
+        self.reader.is_empty()
+    }
+
+    /// Close the stream. The process can still read the remaining input.
+    pub fn close(&self) {
+        if let Some(writer) = self.writer.as_ref() {
+            writer.close();
+        }
+    }
+
+    /// Check if the stream has been closed
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_write_closed()
+    }
+
+    /// Check if the stream is closed and all input has been read
+    pub fn is_eof(&self) -> bool {
+        self.reader.is_eof()
+    }
+
+    /// Release the process-side end of the stream.
+    /// Writers connected through a pipe will see the pipe as broken.
+    pub fn release(&self) {
+        self.reader.close();
+    }
+}
+
+impl Default for ProcessStdIn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,147 +1,415 @@
 use anyhow::anyhow;
-use std::sync::{Arc, Mutex, RwLock};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::pipe::PipeWriter;
+
+/// The default amount of bytes the stdout retains
+pub const DEFAULT_MAX_RETENTION: usize = 1024 * 1024;
 
 /// A message sent to stdout
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StdoutMessage {
     String(String),
+    Bytes(Vec<u8>),
     Clear,
     ClearLine,
     ClearLines(u32),
 }
 
-/// StdOut is a struct that represents a standard output stream of a shell
+/// The output stream of a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// StdOut is a struct that represents a standard output stream of a shell.
+/// It is used for both the stdout and the stderr of a process.
+///
+/// The output is kept in a ring buffer addressed by monotonically increasing offsets,
+/// so readers can keep a cursor and only read the bytes written since.
 #[derive(Debug)]
 pub struct ProcessStdOut {
-    process_buffer: Arc<Mutex<Vec<StdoutMessage>>>, // The process-side buffer
-    eventual_buffer: RwLock<String>,                // The eventual buffer
+    buffer: Mutex<StdoutBuffer>,                  // The retained output
+    pipe: Option<PipeWriter>,                     // The pipe the output is redirected to
+    log: Option<(Arc<Mutex<OutputLog>>, Stream)>, // The log the output is also recorded in
+}
+
+/// The retained output of a stream.
+/// The byte at index `i` of the data lives at offset `start + i`.
+#[derive(Debug)]
+pub struct StdoutBuffer {
+    data: VecDeque<u8>,
+    start: u64,
+    max_retention: usize,
+}
+
+/// The output of the stdout and stderr of a process, in the order it was written.
+/// The output is addressed by offsets like a [`StdoutBuffer`], and split into runs of the stream each byte was written to.
+#[derive(Debug)]
+pub struct OutputLog {
+    buffer: StdoutBuffer,
+    streams: VecDeque<(u64, Stream)>, // The offsets at which the output switches streams, oldest first
 }
 
 impl ProcessStdOut {
     pub fn new() -> Self {
         Self {
-            process_buffer: Arc::new(Mutex::new(Vec::new())),
-            eventual_buffer: RwLock::new(String::new()),
+            buffer: Mutex::new(StdoutBuffer::new(DEFAULT_MAX_RETENTION)),
+            pipe: None,
+            log: None,
+        }
+    }
+
+    /// Create a stdout that also records the written output in a log shared with the other stream of the process
+    pub fn logged(log: Arc<Mutex<OutputLog>>, stream: Stream) -> Self {
+        Self {
+            log: Some((log, stream)),
+            ..Self::new()
         }
     }
 
-    /// Write a string to the kernel buffer. Locks the buffer
+    /// Create a stdout that redirects all written output to a pipe.
+    /// Clear messages are ignored, as the output has already been consumed by the reader.
+    pub fn piped(pipe: PipeWriter) -> Self {
+        Self {
+            pipe: Some(pipe),
+            ..Self::new()
+        }
+    }
+
+    /// Write a string to the buffer
     pub fn write(&self, string: impl Into<String>) -> anyhow::Result<()> {
-        let string = string.into();
-        let mut process_buffer = self
-            .process_buffer
-            .try_lock()
-            .map_err(|e| anyhow!("Failed to lock stdout buffer: {}", e))?;
-        process_buffer.push(StdoutMessage::String(string));
-        Ok(())
+        self.push(StdoutMessage::String(string.into()))
     }
 
-    /// Write a line to the kernel buffer
+    /// Write a line to the buffer
     pub fn writeln(&self, string: impl Into<String>) -> anyhow::Result<()> {
-        let string = string.into();
-        let string = format!("{}\n", string);
-        let mut process_buffer = self
-            .process_buffer
-            .try_lock()
-            .map_err(|e| anyhow!("Failed to lock stdout buffer: {}", e))?;
-        process_buffer.push(StdoutMessage::String(string));
-        Ok(())
+        self.push(StdoutMessage::String(format!("{}\n", string.into())))
     }
 
-    /// Sync buffer to the local copy
-    pub fn sync(&self) {
-        // Get the eventual buffer
-        let mut eventual_buffer;
-        loop {
-            let Ok(e) = self.eventual_buffer.try_write() else {
-                continue;
+    /// Write raw bytes to the buffer
+    pub fn write_bytes(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.push(StdoutMessage::Bytes(bytes.to_vec()))
+    }
+
+    /// Apply a message to the buffer, or send the output to the pipe if the stream is piped.
+    pub fn push(&self, message: StdoutMessage) -> anyhow::Result<()> {
+        if let Some(pipe) = self.pipe.as_ref() {
+            let bytes = match &message {
+                StdoutMessage::String(s) => s.as_bytes(),
+                StdoutMessage::Bytes(b) => b.as_slice(),
+                _ => return Ok(()),
             };
-            eventual_buffer = e;
-            break;
-        }
-
-        if let Ok(mut process_buffer) = self.process_buffer.try_lock() {
-            for message in process_buffer.iter() {
-                match message {
-                    StdoutMessage::String(s) => {
-                        *eventual_buffer = format!("{}{}", eventual_buffer, &s)
-                    }
-                    StdoutMessage::Clear => eventual_buffer.clear(),
-                    // Clear the last line
-                    StdoutMessage::ClearLine => {
-                        let mut lines = eventual_buffer
-                            .split("\n")
-                            .filter(|c| *c != "")
-                            .collect::<Vec<&str>>();
-                        if lines.len() >= 1 {
-                            lines.remove(lines.len() - 1);
-                            let mut result = String::new();
-                            for line in lines {
-                                result = format!("{}{}\n", result, line);
-                            }
-                            *eventual_buffer = result;
-                        }
-                    }
-                    StdoutMessage::ClearLines(num) => {
-                        let mut lines = eventual_buffer
-                            .split("\n")
-                            .filter(|c| *c != "")
-                            .collect::<Vec<&str>>();
-
-                        for _ in 0..*num {
-                            if lines.len() <= 0 {
-                                break;
-                            }
-                            lines.remove(lines.len() - 1);
-                        }
-
-                        let mut result = String::new();
-                        for line in lines {
-                            result = format!("{}{}\n", result, line);
-                        }
-                        *eventual_buffer = result;
-                    }
+            return pipe
+                .write_blocking(bytes)
+                .map_err(|e| anyhow!("Failed to write output to pipe: {}", e));
+        }
+
+        if let Some((log, stream)) = self.log.as_ref() {
+            let mut log = loop {
+                if let Ok(log) = log.try_lock() {
+                    break log;
                 }
+            };
+            match &message {
+                StdoutMessage::String(s) => log.push(*stream, s.as_bytes()),
+                StdoutMessage::Bytes(b) => log.push(*stream, b),
+                StdoutMessage::Clear => log.clear(),
+                StdoutMessage::ClearLine => log.clear_lines(1),
+                StdoutMessage::ClearLines(num) => log.clear_lines(*num),
             }
-            process_buffer.clear();
         }
+
+        let mut buffer = self.lock();
+        match message {
+            StdoutMessage::String(s) => buffer.push(s.as_bytes()),
+            StdoutMessage::Bytes(b) => buffer.push(&b),
+            StdoutMessage::Clear => buffer.clear(),
+            StdoutMessage::ClearLine => buffer.clear_lines(1),
+            StdoutMessage::ClearLines(num) => buffer.clear_lines(num),
+        }
+        Ok(())
     }
 
-    /// Clear the local buffer
+    /// Clear the buffer
     pub fn clear(&self) {
-        loop {
-            let Ok(mut eventual_buffer) = self.eventual_buffer.try_write() else {
-                continue;
-            };
-            eventual_buffer.clear();
-            break;
-        }
+        let _ = self.push(StdoutMessage::Clear);
     }
 
     /// Clear N number of lines in the processes's stdout.
     /// Will only clear up to the amount of lines.
     pub fn clear_lines(&self, num: u32) {
+        let _ = self.push(StdoutMessage::ClearLines(num));
+    }
+
+    /// Return the retained output as a string
+    pub fn buffer(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
+
+    /// Return the retained output
+    pub fn bytes(&self) -> Vec<u8> {
+        self.lock().data.iter().copied().collect()
+    }
+
+    /// The amount of bytes retained
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Check if no bytes are retained
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Read up to `max` bytes starting at an offset.
+    /// Returns `None` if the offset is before the start of the buffer,
+    /// as the output has been cleared or discarded since.
+    pub fn read(&self, from: u64, max: usize) -> Option<Vec<u8>> {
+        self.lock().read(from, max)
+    }
+
+    /// The offset of the first retained byte
+    pub fn start(&self) -> u64 {
+        self.lock().start()
+    }
+
+    /// The offset after the last written byte
+    pub fn end(&self) -> u64 {
+        self.lock().end()
+    }
+
+    /// The maximum amount of bytes retained
+    pub fn max_retention(&self) -> usize {
+        self.lock().max_retention()
+    }
+
+    /// Set the maximum amount of bytes retained.
+    /// Discards the oldest bytes if the buffer is larger.
+    pub fn set_max_retention(&self, max_retention: usize) {
+        self.lock().set_max_retention(max_retention);
+    }
+
+    /// Check if the output is redirected to a pipe
+    pub fn is_piped(&self) -> bool {
+        self.pipe.is_some()
+    }
+
+    /// Release the pipe the output is redirected to.
+    /// Readers of the pipe will reach the end of the stream once all other write ends are closed.
+    pub fn release(&self) {
+        if let Some(pipe) = self.pipe.as_ref() {
+            pipe.close();
+        }
+    }
+
+    /// Lock the buffer
+    fn lock(&self) -> MutexGuard<'_, StdoutBuffer> {
         loop {
-            let Ok(mut process_buffer) = self.process_buffer.try_lock() else {
+            let Ok(buffer) = self.buffer.try_lock() else {
                 continue;
             };
-            process_buffer.push(StdoutMessage::ClearLines(num));
-            break;
+            return buffer;
+        }
+    }
+}
+
+impl StdoutBuffer {
+    /// Create an empty buffer that retains at most `max_retention` bytes
+    pub fn new(max_retention: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            start: 0,
+            max_retention,
         }
     }
 
-    /// Return the local buffer
-    pub fn buffer(&self) -> String {
-        loop {
-            let Ok(eventual_buffer) = self.eventual_buffer.try_read() else {
+    /// The offset of the first retained byte
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The offset after the last written byte
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// The amount of bytes retained
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Check if no bytes are retained
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The maximum amount of bytes retained
+    pub fn max_retention(&self) -> usize {
+        self.max_retention
+    }
+
+    /// Set the maximum amount of bytes retained.
+    /// Discards the oldest bytes if the buffer is larger.
+    pub fn set_max_retention(&mut self, max_retention: usize) {
+        self.max_retention = max_retention;
+        self.trim();
+    }
+
+    /// Append bytes to the buffer, discarding the oldest bytes past the retention limit
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        self.trim();
+    }
+
+    /// Read up to `max` bytes starting at an offset.
+    /// Returns `None` if the offset is before the start of the buffer.
+    pub fn read(&self, from: u64, max: usize) -> Option<Vec<u8>> {
+        if from < self.start {
+            return None;
+        }
+        let index = (from - self.start).min(self.data.len() as u64) as usize;
+        let amount = max.min(self.data.len() - index);
+        Some(self.data.range(index..index + amount).copied().collect())
+    }
+
+    /// Clear the buffer.
+    /// The start moves past every offset handed out before, so readers notice the clear.
+    pub fn clear(&mut self) {
+        self.start = self.end() + 1;
+        self.data.clear();
+    }
+
+    /// Clear the last `num` lines, ignoring empty lines.
+    /// Will only clear up to the amount of lines.
+    /// As this rewrites the output, the remaining bytes are moved past every offset handed out before.
+    pub fn clear_lines(&mut self, num: u32) {
+        let end = self.end();
+        let mut position = self.data.len();
+        for _ in 0..num {
+            while position > 0 && self.data[position - 1] == b'\n' {
+                position -= 1;
+            }
+            if position == 0 {
+                break;
+            }
+            while position > 0 && self.data[position - 1] != b'\n' {
+                position -= 1;
+            }
+        }
+        if position == self.data.len() {
+            return;
+        }
+        self.data.truncate(position);
+        self.start = end + 1;
+    }
+
+    /// Discard the oldest bytes past the retention limit
+    fn trim(&mut self) {
+        let excess = self.data.len().saturating_sub(self.max_retention);
+        if excess > 0 {
+            self.data.drain(..excess);
+            self.start += excess as u64;
+        }
+    }
+}
+
+impl OutputLog {
+    /// Create an empty log that retains at most `max_retention` bytes
+    pub fn new(max_retention: usize) -> Self {
+        Self {
+            buffer: StdoutBuffer::new(max_retention),
+            streams: VecDeque::new(),
+        }
+    }
+
+    /// The offset of the first retained byte
+    pub fn start(&self) -> u64 {
+        self.buffer.start()
+    }
+
+    /// The offset after the last written byte
+    pub fn end(&self) -> u64 {
+        self.buffer.end()
+    }
+
+    /// The maximum amount of bytes retained
+    pub fn max_retention(&self) -> usize {
+        self.buffer.max_retention()
+    }
+
+    /// Set the maximum amount of bytes retained.
+    /// Discards the oldest bytes if the log is larger.
+    pub fn set_max_retention(&mut self, max_retention: usize) {
+        self.buffer.set_max_retention(max_retention);
+        self.trim();
+    }
+
+    /// Append bytes written to a stream
+    pub fn push(&mut self, stream: Stream, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        if self.streams.back().map(|(_, s)| *s) != Some(stream) {
+            self.streams.push_back((self.buffer.end(), stream));
+        }
+        self.buffer.push(bytes);
+        self.trim();
+    }
+
+    /// Read the output starting at an offset, as the runs of bytes written to each stream in order.
+    /// Returns `None` if the offset is before the start of the log.
+    pub fn read(&self, from: u64) -> Option<Vec<(Stream, Vec<u8>)>> {
+        let bytes = self.buffer.read(from, usize::MAX)?;
+        let from = from.min(self.end());
+        let mut runs = Vec::new();
+        for (index, (_, stream)) in self.streams.iter().enumerate() {
+            let run_end = self
+                .streams
+                .get(index + 1)
+                .map_or(self.end(), |(offset, _)| *offset);
+            if run_end <= from {
                 continue;
-            };
-            return eventual_buffer.clone();
+            }
+            let run_start = self.streams[index].0.max(from);
+            let run = &bytes[(run_start - from) as usize..(run_end - from) as usize];
+            runs.push((*stream, run.to_vec()));
         }
+        Some(runs)
+    }
+
+    /// Clear the log
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.streams.clear();
     }
 
-    /// Return an arc reference to the process buffer
-    pub fn process_buffer(&self) -> Arc<Mutex<Vec<StdoutMessage>>> {
-        self.process_buffer.clone()
+    /// Clear the last `num` lines of the output, regardless of the stream they were written to
+    pub fn clear_lines(&mut self, num: u32) {
+        let start = self.buffer.start();
+        self.buffer.clear_lines(num);
+
+        // The remaining output moved to new offsets
+        let moved = self.buffer.start() - start;
+        let end = self.buffer.end();
+        self.streams.retain_mut(|(offset, _)| {
+            *offset += moved;
+            *offset < end
+        });
+    }
+
+    /// Forget the streams of output that has been discarded
+    fn trim(&mut self) {
+        while self
+            .streams
+            .get(1)
+            .is_some_and(|(offset, _)| *offset <= self.buffer.start())
+        {
+            self.streams.pop_front();
+        }
     }
 }
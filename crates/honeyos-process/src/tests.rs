@@ -0,0 +1,1219 @@
+#[cfg(test)]
+mod pipe_tests {
+    use crate::pipe::{Pipe, PipeError};
+
+    #[test]
+    fn read_write() {
+        let (reader, writer) = Pipe::create(16);
+
+        assert_eq!(writer.write(b"hello").unwrap(), 5);
+        assert_eq!(reader.len(), 5);
+        assert_eq!(reader.read(3), b"hel");
+        assert_eq!(reader.read(16), b"lo");
+        assert!(reader.read(16).is_empty());
+        assert!(!reader.is_eof());
+    }
+
+    #[test]
+    fn capacity() {
+        let (reader, writer) = Pipe::create(4);
+
+        assert_eq!(writer.write(b"abcdef").unwrap(), 4);
+        assert_eq!(writer.write(b"ef").unwrap(), 0);
+        assert_eq!(reader.read(2), b"ab");
+        assert_eq!(writer.write(b"ef").unwrap(), 2);
+        assert_eq!(reader.read(16), b"cdef");
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let (reader, writer) = Pipe::create(16);
+        let other_writer = writer.clone();
+
+        writer.write(b"foo").unwrap();
+        drop(writer);
+        assert!(!reader.is_write_closed());
+
+        other_writer.close();
+        assert!(reader.is_write_closed());
+        assert!(!reader.is_eof());
+        assert_eq!(reader.read_blocking(16), b"foo");
+        assert!(reader.is_eof());
+        assert!(reader.read_blocking(16).is_empty());
+    }
+
+    #[test]
+    fn broken_pipe() {
+        let (reader, writer) = Pipe::create(16);
+
+        reader.close();
+        reader.close();
+        assert!(writer.is_read_closed());
+        assert_eq!(writer.write(b"foo"), Err(PipeError::Broken));
+
+        writer.close();
+        assert_eq!(writer.write(b"foo"), Err(PipeError::Closed));
+    }
+}
+
+#[cfg(test)]
+mod stdout_tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::stdout::{OutputLog, ProcessStdOut, StdoutBuffer, Stream};
+
+    #[test]
+    fn offsets() {
+        let stdout = ProcessStdOut::new();
+
+        stdout.write("foo").unwrap();
+        stdout.writeln("bar").unwrap();
+        assert_eq!(stdout.start(), 0);
+        assert_eq!(stdout.end(), 7);
+        assert_eq!(stdout.read(0, 3).unwrap(), b"foo");
+        assert_eq!(stdout.read(3, 16).unwrap(), b"bar\n");
+        assert!(stdout.read(7, 16).unwrap().is_empty());
+        assert_eq!(stdout.buffer(), "foobar\n");
+    }
+
+    #[test]
+    fn retention() {
+        let mut buffer = StdoutBuffer::new(4);
+
+        buffer.push(b"abcdef");
+        assert_eq!(buffer.start(), 2);
+        assert_eq!(buffer.end(), 6);
+        assert_eq!(buffer.read(0, 16), None);
+        assert_eq!(buffer.read(4, 16).unwrap(), b"ef");
+
+        buffer.set_max_retention(2);
+        assert_eq!(buffer.start(), 4);
+        assert_eq!(buffer.read(4, 16).unwrap(), b"ef");
+    }
+
+    #[test]
+    fn clear() {
+        let mut buffer = StdoutBuffer::new(1024);
+
+        buffer.push(b"foo\n");
+        buffer.clear();
+        assert!(buffer.is_empty());
+        assert!(buffer.start() > 4);
+        assert_eq!(buffer.read(4, 16), None);
+
+        let start = buffer.start();
+        buffer.push(b"bar");
+        assert_eq!(buffer.read(start, 16).unwrap(), b"bar");
+    }
+
+    #[test]
+    fn clear_lines() {
+        let mut buffer = StdoutBuffer::new(1024);
+
+        buffer.push(b"foo\n\nbar\nspam\neggs");
+        buffer.clear_lines(1);
+        assert_eq!(
+            buffer.read(buffer.start(), 64).unwrap(),
+            b"foo\n\nbar\nspam\n"
+        );
+
+        buffer.clear_lines(2);
+        assert_eq!(buffer.read(buffer.start(), 64).unwrap(), b"foo\n\n");
+
+        buffer.clear_lines(5);
+        assert!(buffer.is_empty());
+
+        // Nothing was cleared, so the offsets stay the same
+        let start = buffer.start();
+        buffer.clear_lines(1);
+        assert_eq!(buffer.start(), start);
+    }
+
+    #[test]
+    fn interleaved() {
+        let log = Arc::new(Mutex::new(OutputLog::new(1024)));
+        let stdout = ProcessStdOut::logged(log.clone(), Stream::Stdout);
+        let stderr = ProcessStdOut::logged(log.clone(), Stream::Stderr);
+
+        stdout.write("foo\n").unwrap();
+        stderr.write("error\n").unwrap();
+        stdout.write("bar").unwrap();
+        stdout.writeln("baz").unwrap();
+
+        let log = log.lock().unwrap();
+        assert_eq!(
+            log.read(log.start()).unwrap(),
+            vec![
+                (Stream::Stdout, b"foo\n".to_vec()),
+                (Stream::Stderr, b"error\n".to_vec()),
+                (Stream::Stdout, b"barbaz\n".to_vec()),
+            ]
+        );
+        assert_eq!(
+            log.read(6).unwrap(),
+            vec![
+                (Stream::Stderr, b"ror\n".to_vec()),
+                (Stream::Stdout, b"barbaz\n".to_vec()),
+            ]
+        );
+        assert!(log.read(log.end()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn log_retention() {
+        let mut log = OutputLog::new(4);
+
+        log.push(Stream::Stdout, b"ab");
+        log.push(Stream::Stderr, b"cd");
+        log.push(Stream::Stdout, b"ef");
+        assert_eq!(log.read(1), None);
+        assert_eq!(
+            log.read(log.start()).unwrap(),
+            vec![
+                (Stream::Stderr, b"cd".to_vec()),
+                (Stream::Stdout, b"ef".to_vec())
+            ]
+        );
+
+        log.clear_lines(1);
+        assert!(log.read(log.start()).unwrap().is_empty());
+
+        log.set_max_retention(8);
+        log.push(Stream::Stderr, b"foo\nbar");
+        log.clear_lines(1);
+        assert_eq!(
+            log.read(log.start()).unwrap(),
+            vec![(Stream::Stderr, b"foo\n".to_vec())]
+        );
+    }
+}
+
+#[cfg(test)]
+mod thread_tests {
+    use uuid::Uuid;
+
+    use crate::thread::{ThreadError, ThreadPool, ThreadState, MAIN_THREAD_ID};
+
+    #[test]
+    fn join_forgets() {
+        let mut pool = ThreadPool::new(Uuid::nil());
+        let threads = pool.table();
+        let tid = pool.reserve(None).unwrap();
+
+        threads.finish(tid, ThreadState::Exited, 7);
+        let thread = threads.join(tid).unwrap();
+        assert_eq!(thread.state, ThreadState::Exited);
+        assert_eq!(thread.retval, 7);
+        assert!(threads.get(tid).is_none());
+        assert!(matches!(
+            threads.join(tid),
+            Err(ThreadError::NoSuchThread(_))
+        ));
+
+        // The main thread is kept, as the process reads it's state
+        threads.finish(MAIN_THREAD_ID, ThreadState::Exited, 0);
+        threads.join(MAIN_THREAD_ID).unwrap();
+        assert!(threads.get(MAIN_THREAD_ID).is_some());
+    }
+
+    #[test]
+    fn detach() {
+        let mut pool = ThreadPool::new(Uuid::nil());
+        let threads = pool.table();
+        let running = pool.reserve(None).unwrap();
+        let finished = pool.reserve(None).unwrap();
+
+        threads.detach(running).unwrap();
+        assert!(threads.get(running).is_some());
+        threads.finish(running, ThreadState::Exited, 0);
+        assert!(threads.get(running).is_none());
+
+        threads.finish(finished, ThreadState::Failed, 0);
+        threads.detach(finished).unwrap();
+        assert!(threads.get(finished).is_none());
+        assert!(matches!(
+            threads.detach(finished),
+            Err(ThreadError::NoSuchThread(_))
+        ));
+    }
+
+    #[test]
+    fn stack_freed_on_finish() {
+        let mut pool = ThreadPool::new(Uuid::nil());
+        let threads = pool.table();
+        let tid = pool.reserve(None).unwrap();
+
+        assert!(threads.set_stack(tid, 4096));
+        assert_eq!(threads.finish(tid, ThreadState::Exited, 0), Some(4096));
+        assert_eq!(threads.get(tid).unwrap().stack, None);
+        assert_eq!(threads.finish(tid, ThreadState::Failed, 0), None);
+
+        // A thread that already finished leaves the stack to the caller
+        assert!(!threads.set_stack(tid, 8192));
+        assert!(!threads.set_stack(tid + 1, 8192));
+    }
+}
+
+#[cfg(test)]
+mod allocator_tests {
+    use crate::allocator::{AllocError, Allocator, Realloc};
+
+    #[test]
+    fn alloc_free() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(1024, 1024);
+
+        let a = allocator.alloc(100, 8).unwrap();
+        let b = allocator.alloc(100, 8).unwrap();
+        assert_eq!(a, 1024);
+        assert_eq!(b, 1128);
+        assert_eq!(allocator.allocated(), 208);
+
+        allocator.free(a).unwrap();
+        assert_eq!(allocator.allocated(), 104);
+        assert_eq!(allocator.peak_allocated(), 208);
+        assert_eq!(allocator.free(a), Err(AllocError::DoubleFree(a)));
+        assert_eq!(allocator.free(1), Err(AllocError::InvalidPointer(1)));
+
+        // The freed block gets reused
+        assert_eq!(allocator.alloc(50, 8).unwrap(), a);
+    }
+
+    #[test]
+    fn double_free_after_merge() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(1024, 1024);
+
+        let a = allocator.alloc(64, 8).unwrap();
+        let b = allocator.alloc(64, 8).unwrap();
+        allocator.free(a).unwrap();
+        allocator.free(b).unwrap();
+
+        // `b` has been merged into the free block starting at `a`
+        assert!(allocator.block(b).is_none());
+        assert_eq!(allocator.free(b), Err(AllocError::DoubleFree(b)));
+        assert_eq!(
+            allocator.free(b + 8),
+            Err(AllocError::InvalidPointer(b + 8))
+        );
+
+        // Once the memory is handed out again, the old pointer no longer counts as freed
+        let c = allocator.alloc(128, 8).unwrap();
+        assert_eq!(c, a);
+        assert_eq!(allocator.free(b), Err(AllocError::InvalidPointer(b)));
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(0, 300);
+
+        let a = allocator.alloc(96, 8).unwrap();
+        let b = allocator.alloc(96, 8).unwrap();
+        let c = allocator.alloc(104, 8).unwrap();
+        assert_eq!(allocator.alloc(8, 8), None);
+
+        allocator.free(a).unwrap();
+        allocator.free(c).unwrap();
+        assert_eq!(allocator.alloc(200, 8), None);
+
+        // Freeing the middle block merges all three
+        allocator.free(b).unwrap();
+        assert_eq!(allocator.available(), 296);
+        assert_eq!(allocator.alloc(296, 8).unwrap(), 0);
+    }
+
+    #[test]
+    fn alignment() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(8, 4096);
+
+        let a = allocator.alloc(10, 256).unwrap();
+        assert_eq!(a % 256, 0);
+        assert_eq!(allocator.alloc(10, 3), None);
+
+        // The padding in front of the aligned block stays available
+        let b = allocator.alloc(8, 8).unwrap();
+        assert_eq!(b, 8);
+    }
+
+    #[test]
+    fn separate_regions() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(0, 64);
+        allocator.add_region(128, 64);
+
+        assert_eq!(allocator.alloc(100, 8), None);
+        allocator.add_region(64, 64);
+        assert_eq!(allocator.alloc(192, 8).unwrap(), 0);
+    }
+
+    #[test]
+    fn realloc() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(0, 1024);
+
+        let a = allocator.alloc(64, 8).unwrap();
+        assert_eq!(
+            allocator.realloc(a, 128).unwrap(),
+            Some(Realloc::InPlace(a))
+        );
+        assert_eq!(allocator.realloc(a, 32).unwrap(), Some(Realloc::InPlace(a)));
+        assert_eq!(allocator.block(a).unwrap().size, 32);
+        assert_eq!(allocator.allocated(), 32);
+
+        // A block behind it forces a move
+        let b = allocator.alloc(32, 8).unwrap();
+        let Some(Realloc::Moved { from, to, len }) = allocator.realloc(a, 256).unwrap() else {
+            panic!("Expected the block to move");
+        };
+        assert_eq!((from, len), (a, 32));
+        assert!(to > b);
+        assert!(allocator.block(a).unwrap().free);
+
+        assert_eq!(allocator.realloc(a, 8), Err(AllocError::InvalidPointer(a)));
+        assert_eq!(allocator.realloc(to, 4096).unwrap(), None);
+    }
+
+    #[test]
+    fn check_range() {
+        let mut allocator = Allocator::new();
+        allocator.add_region(1024, 1024);
+        let a = allocator.alloc(100, 8).unwrap();
+
+        // Memory outside the regions belongs to the process
+        assert_eq!(allocator.check_range(0, 1024), Ok(()));
+        assert_eq!(allocator.check_range(4096, 16), Ok(()));
+        assert_eq!(
+            allocator.check_range(1000, 100),
+            Err(AllocError::NotAllocated {
+                ptr: 1000,
+                len: 100
+            })
+        );
+
+        // Inside the regions only within an allocated block
+        assert_eq!(allocator.check_range(a, 100), Ok(()));
+        assert_eq!(allocator.check_range(a + 50, 50), Ok(()));
+        assert!(allocator.check_range(a, 200).is_err());
+        assert!(allocator.check_range(1500, 8).is_err());
+        assert_eq!(allocator.check_range(1500, 0), Ok(()));
+
+        allocator.free(a).unwrap();
+        assert!(allocator.check_range(a, 8).is_err());
+    }
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use crate::limits::{ChildLimits, LimitError, Resource, ResourceLimits};
+
+    #[test]
+    fn lower_only() {
+        let mut limits = ResourceLimits {
+            threads: Some(4),
+            ..Default::default()
+        };
+
+        assert_eq!(limits.try_set(Resource::Threads, Some(2), false), Ok(()));
+        assert_eq!(
+            limits.try_set(Resource::Threads, Some(3), false),
+            Err(LimitError::NotPermitted(Resource::Threads))
+        );
+        assert_eq!(
+            limits.try_set(Resource::Threads, None, false),
+            Err(LimitError::NotPermitted(Resource::Threads))
+        );
+        assert_eq!(
+            limits.try_set(Resource::MemoryPages, Some(16), false),
+            Ok(())
+        );
+        assert_eq!(limits.try_set(Resource::Threads, None, true), Ok(()));
+        assert_eq!(limits.threads, None);
+        assert_eq!(limits.memory_pages, Some(16));
+    }
+
+    #[test]
+    fn intersect_and_check() {
+        let parent = ResourceLimits {
+            threads: Some(2),
+            open_files: Some(8),
+            ..Default::default()
+        };
+        let child = ResourceLimits {
+            open_files: Some(16),
+            network_requests: Some(1),
+            ..Default::default()
+        };
+        let limits = child.intersect(&parent);
+
+        assert_eq!(limits.threads, Some(2));
+        assert_eq!(limits.open_files, Some(8));
+        assert_eq!(limits.network_requests, Some(1));
+        assert_eq!(limits.memory_pages, None);
+
+        assert_eq!(limits.check(Resource::Threads, 1), Ok(()));
+        assert_eq!(
+            limits.check(Resource::Threads, 2),
+            Err(LimitError::Exceeded(Resource::Threads))
+        );
+        assert_eq!(limits.check(Resource::MemoryPages, usize::MAX), Ok(()));
+        assert_eq!(Resource::Threads.exceeded_code(), -20);
+        assert_eq!(Resource::try_from(3), Ok(Resource::NetworkRequests));
+        assert_eq!(Resource::try_from(5), Err(LimitError::InvalidResource(5)));
+    }
+
+    #[test]
+    fn unprivileged_child() {
+        let parent = ResourceLimits {
+            threads: Some(4),
+            ..Default::default()
+        };
+        let mut child_limits = ChildLimits::default();
+        child_limits.limits.set(Resource::OpenFiles, Some(8));
+
+        // Children are unprivileged by default, even if their parent is privileged
+        let (mut limits, privileged) =
+            child_limits.apply(&ResourceLimits::default(), &parent, true);
+        assert!(!privileged);
+        assert_eq!(limits.threads, Some(4));
+        assert_eq!(limits.open_files, Some(8));
+        assert_eq!(
+            limits.try_set(Resource::Threads, Some(8), privileged),
+            Err(LimitError::NotPermitted(Resource::Threads))
+        );
+        assert_eq!(
+            limits.try_set(Resource::OpenFiles, None, privileged),
+            Err(LimitError::NotPermitted(Resource::OpenFiles))
+        );
+        assert_eq!(
+            limits.try_set(Resource::Threads, Some(2), privileged),
+            Ok(())
+        );
+
+        // Only a privileged parent can pass it's privilege on
+        child_limits.privileged = true;
+        assert!(!child_limits.apply(&limits, &parent, false).1);
+        assert!(child_limits.apply(&limits, &parent, true).1);
+    }
+}
+
+#[cfg(test)]
+mod fd_tests {
+    use crate::fd::{FdTable, FileDescriptor, STDOUT_FD};
+
+    #[test]
+    fn lowest_free_number() {
+        let mut table = FdTable::new([("/".to_string(), "a:/".to_string())]);
+        assert_eq!(
+            table.get(3),
+            Some(&FileDescriptor::Preopen {
+                name: "/".to_string(),
+                path: "a:/".to_string()
+            })
+        );
+
+        let dir = FileDescriptor::Directory {
+            path: "a:/home".to_string(),
+        };
+        assert_eq!(table.insert(dir.clone()), 4);
+        assert_eq!(table.insert(dir.clone()), 5);
+        assert!(table.remove(4).is_some());
+        assert_eq!(table.insert(dir.clone()), 4);
+        assert_eq!(table.open_files(), 2);
+    }
+
+    #[test]
+    fn renumber() {
+        let mut table = FdTable::new([]);
+        let fd = table.insert(FileDescriptor::Directory {
+            path: "a:/".to_string(),
+        });
+
+        assert!(!table.renumber(fd, 10));
+        assert!(table.renumber(fd, STDOUT_FD));
+        assert!(table.get(fd).is_none());
+        assert!(matches!(
+            table.get(STDOUT_FD),
+            Some(FileDescriptor::Directory { .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+mod capabilities_tests {
+    use honeyos_fs::FsLabel;
+
+    use crate::capabilities::{Capabilities, Capability};
+
+    #[test]
+    fn grant_and_revoke() {
+        let mut capabilities = Capabilities::none();
+        capabilities.grant(Capability::FsRead(FsLabel::A));
+        capabilities.grant(Capability::Network);
+
+        assert!(capabilities.allows(Capability::FsRead(FsLabel::A)));
+        assert!(!capabilities.allows(Capability::FsWrite(FsLabel::A)));
+        assert!(!capabilities.allows(Capability::FsRead(FsLabel::B)));
+        assert!(capabilities.allows(Capability::Network));
+
+        capabilities.revoke(Capability::Network);
+        assert!(!capabilities.allows(Capability::Network));
+        assert_eq!(capabilities.bits(), 1);
+    }
+
+    #[test]
+    fn intersect() {
+        let mut parent = Capabilities::all();
+        parent.revoke(Capability::JsEval);
+        let mut child = Capabilities::none();
+        child.grant(Capability::JsEval);
+        child.grant(Capability::Spawn);
+
+        let capabilities = child.intersect(&parent);
+        assert!(!capabilities.allows(Capability::JsEval));
+        assert!(capabilities.allows(Capability::Spawn));
+        assert_eq!(Capabilities::from_bits(u64::MAX), Capabilities::all());
+        assert!(Capabilities::all().allows(Capability::FsWrite(FsLabel::Z)));
+        assert!(Capabilities::all().allows(Capability::Thread));
+    }
+}
+
+#[cfg(test)]
+mod manifest_tests {
+    use honeyos_fs::FsLabel;
+
+    use crate::{
+        capabilities::Capability,
+        manifest::{Manifest, ManifestError, DEFAULT_ENTRYPOINT},
+        requirements::WasmRequirements,
+    };
+
+    /// Build an empty wasm binary with a custom section
+    fn binary_with_section(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut section = vec![name.len() as u8];
+        section.extend_from_slice(name.as_bytes());
+        section.extend_from_slice(data);
+
+        let mut bin = b"\0asm\x01\0\0\0".to_vec();
+        bin.push(0);
+        bin.push(section.len() as u8);
+        bin.extend(section);
+        bin
+    }
+
+    #[test]
+    fn parse_from_binary() {
+        let json = br#"{"name":"editor","version":"1.0","capabilities":["fs:a:read","display"],"min_hapi_version":"0.2"}"#;
+        let requirements = WasmRequirements::parse(&binary_with_section("honeyos", json)).unwrap();
+        let manifest = requirements.manifest.unwrap();
+
+        assert_eq!(manifest.name.as_deref(), Some("editor"));
+        assert_eq!(manifest.entrypoint(), DEFAULT_ENTRYPOINT);
+        assert_eq!(manifest.min_hapi_version(), Ok(Some((0, 2))));
+        assert_eq!(manifest.runs_on((0, 2)), Ok(true));
+        assert_eq!(manifest.runs_on((0, 3)), Ok(true));
+        assert_eq!(manifest.runs_on((0, 1)), Ok(false));
+        assert_eq!(manifest.runs_on((1, 2)), Ok(false));
+
+        let capabilities = manifest.requested_capabilities().unwrap().unwrap();
+        assert!(capabilities.allows(Capability::FsRead(FsLabel::A)));
+        assert!(!capabilities.allows(Capability::FsWrite(FsLabel::A)));
+        assert!(capabilities.allows(Capability::Display));
+        assert!(!capabilities.allows(Capability::JsEval));
+
+        let other = WasmRequirements::parse(&binary_with_section("name", b"")).unwrap();
+        assert_eq!(other.manifest, None);
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(matches!(
+            Manifest::parse(b"{"),
+            Err(ManifestError::InvalidJson(_))
+        ));
+        assert_eq!(
+            Manifest::parse(br#"{"capabilities":["fs:a:execute"]}"#),
+            Err(ManifestError::UnknownCapability("fs:a:execute".to_string()))
+        );
+        assert_eq!(
+            Manifest::parse(br#"{"min_hapi_version":"1"}"#),
+            Err(ManifestError::InvalidVersion("1".to_string()))
+        );
+        assert_eq!(
+            Manifest::parse(br#"{"weak_imports":["hapi_foo"]}"#),
+            Err(ManifestError::InvalidWeakImport("hapi_foo".to_string()))
+        );
+        assert_eq!(
+            Manifest::parse(b"{}").unwrap().requested_capabilities(),
+            Ok(None)
+        );
+    }
+}
+
+/// Helpers to encode the parts of wasm binaries for the tests
+#[cfg(test)]
+mod wasm_encoding {
+    /// Encode a section or subsection with a payload shorter than 128 bytes
+    pub fn section(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, payload.len() as u8];
+        section.extend_from_slice(payload);
+        section
+    }
+
+    /// Encode a string with it's length
+    pub fn string(value: &str) -> Vec<u8> {
+        let mut encoded = vec![value.len() as u8];
+        encoded.extend_from_slice(value.as_bytes());
+        encoded
+    }
+}
+
+#[cfg(test)]
+mod requirements_tests {
+    use super::wasm_encoding::section;
+    use crate::{error::SpawnError, requirements::WasmRequirements, weak::Shim};
+
+    /// Build a binary importing `hapi.hapi_foo` and exporting an empty `_start`
+    fn binary() -> Vec<u8> {
+        let mut bin = b"\0asm\x01\0\0\0".to_vec();
+        bin.extend(section(1, b"\x01\x60\x00\x00"));
+        bin.extend(section(2, b"\x01\x04hapi\x08hapi_foo\x00\x00"));
+        bin.extend(section(3, b"\x01\x00"));
+        bin.extend(section(7, b"\x01\x06_start\x00\x01"));
+        bin.extend(section(10, b"\x01\x02\x00\x0b"));
+        bin
+    }
+
+    #[test]
+    fn imports_and_exports() {
+        let requirements = WasmRequirements::parse(&binary()).unwrap();
+        assert_eq!(
+            requirements.imports,
+            vec![("hapi".to_string(), "hapi_foo".to_string())]
+        );
+        assert_eq!(requirements.function_exports, vec!["_start".to_string()]);
+
+        assert!(requirements.missing_imports(|_, _| true).is_empty());
+        assert_eq!(
+            requirements.missing_imports(|module, _| module != "hapi"),
+            vec!["hapi.hapi_foo".to_string()]
+        );
+    }
+
+    #[test]
+    fn weak_imports() {
+        let manifest = br#"{"weak_imports":["hapi.hapi_foo"]}"#;
+        let mut custom = vec![7];
+        custom.extend_from_slice(b"honeyos");
+        custom.extend_from_slice(manifest);
+        let mut bin = binary();
+        bin.extend(section(0, &custom));
+
+        let requirements = WasmRequirements::parse(&bin).unwrap();
+        assert!(requirements.is_weak("hapi", "hapi_foo"));
+        assert!(!requirements.is_weak("hapi", "hapi_bar"));
+        assert!(requirements.weak_shims(|_, _| true).is_empty());
+        assert_eq!(
+            requirements.weak_shims(|_, _| false),
+            vec![Shim {
+                module: "hapi".to_string(),
+                name: "hapi_foo".to_string(),
+                results: Vec::new(),
+            }]
+        );
+
+        // Imports are only weak when the manifest says so
+        assert!(!WasmRequirements::parse(&binary())
+            .unwrap()
+            .is_weak("hapi", "hapi_foo"));
+    }
+
+    #[test]
+    fn invalid_binary() {
+        let error = WasmRequirements::parse(b"not wasm").unwrap_err();
+        assert!(matches!(error, SpawnError::InvalidBinary(_)));
+        assert_eq!(error.code(), -30);
+
+        // The function refers to a type that does not exist, which only validation catches
+        let mut bin = b"\0asm\x01\0\0\0".to_vec();
+        bin.extend(section(3, b"\x01\x05"));
+        bin.extend(section(10, b"\x01\x02\x00\x0b"));
+        assert!(matches!(
+            WasmRequirements::parse(&bin),
+            Err(SpawnError::InvalidBinary(_))
+        ));
+    }
+}
+
+#[cfg(test)]
+mod module_cache_tests {
+    use std::sync::Arc;
+
+    use crate::module_cache::{module_hash, ModuleCache};
+
+    #[test]
+    fn hit_and_miss() {
+        let mut cache = ModuleCache::new(4);
+        let bin = Arc::new(b"\0asm\x01\0\0\0".to_vec());
+        assert_eq!(cache.get(&bin), None);
+
+        cache.insert(bin.clone(), 1);
+        assert_eq!(cache.get(&bin), Some(1));
+        // The same contents hit, even from a different allocation
+        assert_eq!(cache.get(&Arc::new(bin.to_vec())), Some(1));
+        assert_eq!(cache.get(&Arc::new(b"other".to_vec())), None);
+        assert_eq!(module_hash(&bin), module_hash(&bin.to_vec()));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ModuleCache::new(2);
+        let a = Arc::new(vec![0]);
+        let b = Arc::new(vec![1]);
+        let c = Arc::new(vec![2]);
+
+        cache.insert(a.clone(), 'a');
+        cache.insert(b.clone(), 'b');
+        assert_eq!(cache.get(&a), Some('a'));
+
+        cache.insert(c.clone(), 'c');
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&a), Some('a'));
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&c), Some('c'));
+
+        let mut disabled = ModuleCache::new(0);
+        disabled.insert(a.clone(), 'a');
+        assert!(disabled.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod dylink_tests {
+    use hashbrown::HashMap;
+
+    use super::wasm_encoding::{section, string};
+    use crate::{
+        dylink::{DylinkInfo, LinkPlan},
+        error::SpawnError,
+        requirements::WasmRequirements,
+    };
+
+    /// Build a position independent module needing libraries and exporting empty functions
+    fn library(needed: &[&str], exports: &[&str]) -> Vec<u8> {
+        let mut dylink = string("dylink.0");
+        dylink.extend(section(1, &[16, 2, 1, 0]));
+        let mut needed_payload = vec![needed.len() as u8];
+        for name in needed {
+            needed_payload.extend(string(name));
+        }
+        dylink.extend(section(2, &needed_payload));
+
+        let mut bin = b"\0asm\x01\0\0\0".to_vec();
+        bin.extend(section(0, &dylink));
+        if !exports.is_empty() {
+            let count = exports.len() as u8;
+            let mut export_payload = vec![count];
+            let mut code_payload = vec![count];
+            for (index, name) in exports.iter().enumerate() {
+                export_payload.extend(string(name));
+                export_payload.extend([0, index as u8]);
+                code_payload.extend([2, 0, 0x0b]);
+            }
+            bin.extend(section(1, b"\x01\x60\x00\x00"));
+            let mut function_payload = vec![count];
+            function_payload.extend(std::iter::repeat_n(0, exports.len()));
+            bin.extend(section(3, &function_payload));
+            bin.extend(section(7, &export_payload));
+            bin.extend(section(10, &code_payload));
+        }
+        bin
+    }
+
+    #[test]
+    fn parse() {
+        let requirements = WasmRequirements::parse(&library(&["libc.wasm"], &["puts"])).unwrap();
+        assert_eq!(
+            requirements.dylink,
+            Some(DylinkInfo {
+                memory_size: 16,
+                memory_alignment: 2,
+                table_size: 1,
+                table_alignment: 0,
+                needed: vec!["libc.wasm".to_string()],
+            })
+        );
+        assert_eq!(requirements.exports, vec!["puts".to_string()]);
+    }
+
+    #[test]
+    fn resolve() {
+        let files: HashMap<&str, Vec<u8>> = HashMap::from([
+            ("b:/lib/libc.wasm", library(&[], &["puts"])),
+            ("b:/lib/libui.wasm", library(&["libc.wasm"], &["draw"])),
+            ("b:/lib/static.wasm", b"\0asm\x01\0\0\0".to_vec()),
+        ]);
+        let read = |path: &str| files.get(path).cloned();
+
+        // Dependencies are linked first and each library only once
+        let binary = WasmRequirements::parse(&library(&["libui.wasm", "libc.wasm"], &[])).unwrap();
+        let plan = LinkPlan::resolve(&binary, "a:/missing;b:/lib/", read).unwrap();
+        let names: Vec<_> = plan.libraries.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["libc.wasm", "libui.wasm"]);
+        assert_eq!(plan.libraries[0].path, "b:/lib/libc.wasm");
+        assert!(!plan.is_static());
+
+        assert!(plan.provides("env", "puts", false));
+        assert!(plan.provides("GOT.func", "draw", false));
+        assert!(!plan.provides("env", "printf", true));
+        assert!(plan.provides("env", "__memory_base", true));
+        assert!(!plan.provides("env", "__memory_base", false));
+
+        let binary = WasmRequirements::parse(&library(&["libgl.wasm"], &[])).unwrap();
+        let error = LinkPlan::resolve(&binary, "b:/lib", read).unwrap_err();
+        assert_eq!(error, SpawnError::MissingLibrary("libgl.wasm".to_string()));
+        assert_eq!(error.code(), -35);
+
+        let binary = WasmRequirements::parse(&library(&["static.wasm"], &[])).unwrap();
+        assert!(matches!(
+            LinkPlan::resolve(&binary, "b:/lib", read),
+            Err(SpawnError::InvalidLibrary(..))
+        ));
+
+        let binary = WasmRequirements::parse(b"\0asm\x01\0\0\0").unwrap();
+        assert!(LinkPlan::resolve(&binary, "b:/lib", read)
+            .unwrap()
+            .is_static());
+    }
+}
+
+#[cfg(test)]
+mod crash_tests {
+    use crate::{
+        crash::{tail, CrashReport},
+        manifest::Manifest,
+    };
+
+    #[test]
+    fn output_tail() {
+        assert_eq!(tail(b"hello world", 5), "world");
+        assert_eq!(tail(b"hello", 16), "hello");
+        assert_eq!(tail(b"", 16), "");
+    }
+
+    #[test]
+    fn report_json() {
+        let report = CrashReport {
+            pid: "pid".to_string(),
+            title: "editor".to_string(),
+            message: "RuntimeError: unreachable".to_string(),
+            stack: Some("at editor.wasm.main".to_string()),
+            globals: vec![("__stack_pointer".to_string(), "65536".to_string())],
+            manifest: Some(Manifest {
+                name: Some("editor".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let json = report.to_json();
+        assert!(json.contains(r#""globals":[["__stack_pointer","65536"]]"#));
+        assert_eq!(serde_json::from_str::<CrashReport>(&json).unwrap(), report);
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use crate::trace::{ProcessTrace, TraceEntry, TRACE_CAPACITY};
+
+    fn entry(name: &str, result: &str) -> TraceEntry {
+        TraceEntry {
+            tid: 0,
+            name: name.to_string(),
+            args: vec!["3".to_string(), "1024".to_string()],
+            result: result.to_string(),
+            duration: 0.25,
+        }
+    }
+
+    #[test]
+    fn entry_line() {
+        assert_eq!(
+            entry("fd_read", "0").to_string(),
+            "[0] fd_read(3, 1024) = 0 <0.250ms>\n"
+        );
+    }
+
+    #[test]
+    fn read_lines() {
+        let trace = ProcessTrace::new(true);
+        assert!(trace.enabled());
+        trace.record(entry("fd_read", "0"));
+        trace.record(entry("fd_write", "-1"));
+        let line = entry("fd_read", "0").to_string();
+
+        // Only whole lines are read
+        let data = trace.read(line.len() + 4);
+        assert_eq!(data, line.as_bytes());
+        assert_eq!(trace.len(), 1);
+
+        // A line longer than the buffer is cut off
+        assert_eq!(trace.read(4), b"[0] ");
+        assert!(trace.is_empty());
+        assert!(trace.read(64).is_empty());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let trace = ProcessTrace::new(false);
+        for _ in 0..TRACE_CAPACITY {
+            trace.record(entry("fd_read", "0"));
+        }
+        trace.record(entry("fd_write", "0"));
+        assert_eq!(trace.len(), TRACE_CAPACITY);
+        assert_eq!(trace.dropped(), 1);
+
+        trace.set_enabled(true);
+        assert!(trace.enabled());
+    }
+}
+
+#[cfg(test)]
+mod weak_tests {
+    use wasmparser::ValType;
+
+    use crate::weak::Shim;
+
+    fn shim(results: Vec<ValType>) -> Shim {
+        Shim {
+            module: "hapi".to_string(),
+            name: "hapi_foo".to_string(),
+            results,
+        }
+    }
+
+    #[test]
+    fn body() {
+        assert_eq!(shim(vec![]).body(), "return;");
+        assert_eq!(shim(vec![ValType::I32]).body(), "return -17;");
+        assert_eq!(shim(vec![ValType::I64]).body(), "return -17n;");
+        assert_eq!(
+            shim(vec![ValType::F64, ValType::I32]).body(),
+            "return [NaN, -17];"
+        );
+    }
+}
+
+#[cfg(test)]
+mod handle_tests {
+    use honeyos_fs::FsLabel;
+    use uuid::Uuid;
+
+    use crate::handle::{HandleError, HandleKind, HandleTable, KernelObject};
+
+    #[test]
+    fn lowest_free_handle() {
+        let mut handles = HandleTable::new();
+        assert_eq!(handles.insert(KernelObject::Process(Uuid::new_v4())), 1);
+        assert_eq!(handles.insert(KernelObject::Pipe(Uuid::new_v4())), 2);
+        assert_eq!(handles.insert(KernelObject::Pipe(Uuid::new_v4())), 3);
+
+        handles.remove(2).unwrap();
+        assert_eq!(handles.insert(KernelObject::Request(Uuid::new_v4())), 2);
+        assert_eq!(handles.insert(KernelObject::Request(Uuid::new_v4())), 4);
+        assert_eq!(handles.len(), 4);
+    }
+
+    #[test]
+    fn kind_checked() {
+        let mut handles = HandleTable::new();
+        let id = Uuid::new_v4();
+        let pipe = handles.insert(KernelObject::Pipe(id));
+        let file = handles.insert(KernelObject::File(FsLabel::A, id));
+        let directory = handles.insert(KernelObject::Directory(FsLabel::A, id));
+        let thread = handles.insert(KernelObject::Thread(3));
+
+        assert_eq!(handles.pipe(pipe), Ok(id));
+        assert_eq!(handles.file(file), Ok((FsLabel::A, id)));
+        assert_eq!(handles.directory(directory), Ok((FsLabel::A, id)));
+        assert_eq!(handles.thread(thread), Ok(3));
+        assert_eq!(
+            handles.thread(directory),
+            Err(HandleError::WrongKind {
+                handle: directory,
+                expected: HandleKind::Thread,
+                found: HandleKind::Directory,
+            })
+        );
+        assert_eq!(
+            handles.process(pipe),
+            Err(HandleError::WrongKind {
+                handle: pipe,
+                expected: HandleKind::Process,
+                found: HandleKind::Pipe,
+            })
+        );
+        assert_eq!(handles.request(7), Err(HandleError::NoSuchHandle(7)));
+    }
+
+    #[test]
+    fn remove_and_drain() {
+        let mut handles = HandleTable::new();
+        let id = Uuid::new_v4();
+        let handle = handles.insert(KernelObject::Request(id));
+        handles.insert(KernelObject::Process(id));
+
+        assert_eq!(handles.remove(handle), Ok(KernelObject::Request(id)));
+        assert_eq!(
+            handles.remove(handle),
+            Err(HandleError::NoSuchHandle(handle))
+        );
+        assert_eq!(handles.drain(), vec![KernelObject::Process(id)]);
+        assert!(handles.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod memory_tests {
+    use crate::memory::{check_bounds, MemoryError};
+
+    #[test]
+    fn bounds() {
+        assert_eq!(check_bounds(0, 1024, 1024), Ok(()));
+        assert_eq!(check_bounds(1024, 0, 1024), Ok(()));
+        assert_eq!(
+            check_bounds(1000, 25, 1024),
+            Err(MemoryError::OutOfBounds {
+                ptr: 1000,
+                len: 25,
+                size: 1024
+            })
+        );
+
+        // The end of the range does not wrap around
+        assert!(check_bounds(u32::MAX, 2, u32::MAX).is_err());
+    }
+}
+
+#[cfg(test)]
+mod ring_tests {
+    use uuid::Uuid;
+
+    use crate::ring::{
+        Completion, CompletionRing, PendingOp, RingError, RingLayout, Submission, CQ_ENTRIES,
+        MAX_RING_ENTRIES, RING_HEADER_SIZE, SQ_ENTRIES, SUBMISSION_SIZE,
+    };
+
+    #[test]
+    fn layout() {
+        assert_eq!(RingLayout::entries_for(5), Ok(8));
+        assert_eq!(
+            RingLayout::entries_for(0),
+            Err(RingError::InvalidEntries(0))
+        );
+        assert!(RingLayout::entries_for(MAX_RING_ENTRIES + 1).is_err());
+
+        let layout = RingLayout {
+            ptr: 1024,
+            entries: 4,
+        };
+        assert_eq!(layout.size(), 32 + 4 * 32 + 8 * 16);
+        assert_eq!(
+            layout.submission(5),
+            1024 + RING_HEADER_SIZE + SUBMISSION_SIZE
+        );
+        assert_eq!(
+            layout.completion(8),
+            1024 + RING_HEADER_SIZE + 4 * SUBMISSION_SIZE
+        );
+
+        let header = layout.header();
+        let field = |offset: u32| &header[offset as usize..offset as usize + 4];
+        assert_eq!(field(SQ_ENTRIES), 4u32.to_le_bytes());
+        assert_eq!(field(CQ_ENTRIES), 8u32.to_le_bytes());
+    }
+
+    #[test]
+    fn heads_and_tails() {
+        let layout = RingLayout { ptr: 0, entries: 4 };
+        assert_eq!(layout.queued(2, 5), Ok(3));
+        assert_eq!(layout.queued(u32::MAX, 1), Ok(2));
+        assert_eq!(layout.queued(0, 5), Err(RingError::Corrupted));
+
+        assert_eq!(layout.free(0, 0), Ok(8));
+        assert_eq!(layout.free(u32::MAX - 1, 2), Ok(4));
+        assert_eq!(layout.free(1, 0), Err(RingError::Corrupted));
+    }
+
+    #[test]
+    fn entries() {
+        let submission = Submission {
+            opcode: 1,
+            handle: 3,
+            user_data: u64::MAX - 7,
+            buffer: 4096,
+            len: 512,
+            arg: 1 << 40,
+        };
+        let bytes = submission.to_bytes();
+        assert_eq!(Submission::from_bytes(&bytes), Some(submission));
+        assert_eq!(Submission::from_bytes(&bytes[..16]), None);
+
+        let completion = Completion {
+            user_data: 42,
+            result: -19,
+        }
+        .to_bytes();
+        assert_eq!(completion[0..8], 42u64.to_le_bytes());
+        assert_eq!(completion[8..12], (-19i32).to_le_bytes());
+    }
+
+    #[test]
+    fn poll_and_take() {
+        let ring = CompletionRing::new();
+        let layout = RingLayout { ptr: 0, entries: 4 };
+        assert_eq!(ring.set_layout(layout), Ok(()));
+        assert_eq!(ring.set_layout(layout), Err(RingError::AlreadySetUp));
+
+        let request = Uuid::new_v4();
+        ring.add_pending(1, PendingOp::NetworkRequest(request));
+        ring.add_pending(2, PendingOp::Timeout(100.0));
+        ring.complete(3, 0);
+
+        // Only the operations that are ready complete
+        assert!(ring.poll(|op| match op {
+            PendingOp::Timeout(deadline) => (*deadline <= 150.0).then_some(0),
+            _ => None,
+        }));
+        assert_eq!(ring.pending(), 1);
+        assert_eq!(ring.completions(), 2);
+
+        assert_eq!(
+            ring.take_completions(1),
+            vec![Completion {
+                user_data: 3,
+                result: 0
+            }]
+        );
+        assert_eq!(ring.take_completions(8).len(), 1);
+        assert!(ring.take_completions(8).is_empty());
+    }
+
+    #[test]
+    fn capacity() {
+        let ring = CompletionRing::new();
+        assert_eq!(ring.check_capacity(), Err(RingError::NotSetUp));
+        ring.set_layout(RingLayout { ptr: 0, entries: 2 }).unwrap();
+
+        // Operations in flight and completions kept share the completion entries
+        for user_data in 0..2 {
+            ring.add_pending(user_data, PendingOp::Timeout(100.0));
+            ring.complete(user_data + 2, 0);
+        }
+        assert_eq!(ring.check_capacity(), Err(RingError::Busy));
+
+        ring.take_completions(1);
+        assert_eq!(ring.check_capacity(), Ok(()));
+    }
+}
@@ -4,12 +4,29 @@ use std::sync::{Arc, Mutex};
 use hashbrown::HashMap;
 use uuid::Uuid;
 use wasm_bindgen::{closure::Closure, prelude::JsValue, JsCast};
-use web_sys::{js_sys::WebAssembly, Blob, Url, Worker, WorkerOptions, WorkerType};
+
+use crate::{
+    limits::{LimitError, Resource},
+    memory::Memory,
+    process::{get_worker_common_script, script_url},
+};
+use web_sys::{
+    js_sys::{Array, Reflect, WebAssembly},
+    MessageEvent, Worker, WorkerOptions, WorkerType,
+};
+
+/// The id of the main thread of a process
+pub const MAIN_THREAD_ID: u32 = 0;
+
+/// The interval at which a joining thread checks the state of the thread again
+const JOIN_POLL_INTERVAL_MS: u64 = 10;
 
 /// The error types for threads
 #[derive(Debug)]
 pub enum ThreadError {
     NoSuchThread(u32),
+    NoSuchProcess(Uuid),
+    Limit(LimitError),
     WorkerCreation(String),
     WorkerMessaging(String),
 }
@@ -18,136 +35,368 @@ pub enum ThreadError {
 #[derive(Debug)]
 pub struct ThreadRequest {
     pub pid: Uuid,
+    pub tid: u32,
     pub fptr: u32,
+    pub arg: u32,
 }
 
-/// Represents a thread
-#[derive(Debug)]
-pub struct Thread {
-    worker: Worker,
-    alive: bool,
+/// The state of a thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// The thread has been requested, but the worker has not been spawned yet
+    Pending = 0,
+    /// The thread is running
+    Running = 1,
+    /// The thread returned from it's entrypoint
+    Exited = 2,
+    /// The thread threw an error
+    Failed = 3,
+    /// The thread has been killed
+    Killed = 4,
+}
+
+/// The information the kernel keeps about a thread
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    /// The state of the thread
+    pub state: ThreadState,
+    /// The value the entrypoint returned. Only set once the thread has exited
+    pub retval: u32,
+    /// The name of the thread, used for diagnostics
+    pub name: String,
+    /// Whether the thread is forgotten once it finishes, instead of being kept until it's joined
+    pub detached: bool,
+    /// The stack the linker allocated for the position independent modules of the thread.
+    /// It's freed once the thread finishes
+    pub stack: Option<u32>,
+}
+
+/// The threads of a process.
+/// Shared between the kernel and the workers of the process.
+#[derive(Debug, Clone, Default)]
+pub struct ThreadTable {
+    threads: Arc<Mutex<HashMap<u32, ThreadInfo>>>,
 }
 
 /// The threadpool for a process
 #[derive(Debug)]
 pub struct ThreadPool {
     pid: Uuid,
-    threads: Arc<Mutex<HashMap<u32, Thread>>>,
-    thread_amount: u32,
+    table: ThreadTable,
+    workers: HashMap<u32, Worker>,
+    next_id: u32,
+}
+
+impl ThreadState {
+    /// Check if the thread has not finished yet
+    pub fn is_alive(&self) -> bool {
+        matches!(self, ThreadState::Pending | ThreadState::Running)
+    }
+}
+
+impl ThreadTable {
+    /// Get the information about a thread
+    pub fn get(&self, id: u32) -> Option<ThreadInfo> {
+        self.with(|threads| threads.get(&id).cloned())
+    }
+
+    /// Get the state of a thread
+    pub fn state(&self, id: u32) -> Option<ThreadState> {
+        self.with(|threads| threads.get(&id).map(|thread| thread.state))
+    }
+
+    /// Set the name of a thread.
+    /// Returns false if the thread does not exist.
+    pub fn set_name(&self, id: u32, name: &str) -> bool {
+        self.with(|threads| {
+            let Some(thread) = threads.get_mut(&id) else {
+                return false;
+            };
+            thread.name = name.to_string();
+            true
+        })
+    }
+
+    /// Add a thread
+    fn insert(&self, id: u32, state: ThreadState, name: String) {
+        self.with(|threads| {
+            threads.insert(
+                id,
+                ThreadInfo {
+                    state,
+                    retval: 0,
+                    name,
+                    detached: false,
+                    stack: None,
+                },
+            )
+        });
+    }
+
+    /// Set the state of a thread
+    fn set_state(&self, id: u32, state: ThreadState) {
+        self.with(|threads| {
+            if let Some(thread) = threads.get_mut(&id) {
+                thread.state = state;
+            }
+        });
+    }
+
+    /// Record the stack allocated for a thread, so it can be freed once the thread finishes.
+    /// Returns false if the thread already finished, in which case the caller frees the stack.
+    pub fn set_stack(&self, id: u32, stack: u32) -> bool {
+        self.with(|threads| match threads.get_mut(&id) {
+            Some(thread) if thread.state.is_alive() => {
+                thread.stack = Some(stack);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Set the final state and return value of a thread, unless it has already finished.
+    /// Detached threads are forgotten instead.
+    /// Returns the stack of the thread, which the caller frees.
+    pub(crate) fn finish(&self, id: u32, state: ThreadState, retval: u32) -> Option<u32> {
+        self.with(|threads| {
+            let thread = threads.get_mut(&id)?;
+            if !thread.state.is_alive() {
+                return None;
+            }
+            let stack = thread.stack.take();
+            if thread.detached && id != MAIN_THREAD_ID {
+                threads.remove(&id);
+                return stack;
+            }
+            thread.state = state;
+            thread.retval = retval;
+            stack
+        })
+    }
+
+    /// Wait until a thread has finished and return it's final information.
+    /// The thread is forgotten once joined, except for the main thread, whose state the process keeps.
+    /// This will block the thread, so this cannot be called in the kernel.
+    pub fn join(&self, id: u32) -> Result<ThreadInfo, ThreadError> {
+        loop {
+            let thread = self.with(|threads| {
+                let thread = threads.get(&id).cloned()?;
+                if !thread.state.is_alive() && id != MAIN_THREAD_ID {
+                    threads.remove(&id);
+                }
+                Some(thread)
+            });
+            let thread = thread.ok_or(ThreadError::NoSuchThread(id))?;
+            if !thread.state.is_alive() {
+                return Ok(thread);
+            }
+            // Will panic on main thread
+            std::thread::sleep(std::time::Duration::from_millis(JOIN_POLL_INTERVAL_MS));
+        }
+    }
+
+    /// Detach a thread, so it's forgotten once it finishes instead of being kept until it's joined.
+    /// A thread that already finished is forgotten right away.
+    pub fn detach(&self, id: u32) -> Result<(), ThreadError> {
+        self.with(|threads| {
+            let thread = threads.get_mut(&id).ok_or(ThreadError::NoSuchThread(id))?;
+            if thread.state.is_alive() {
+                thread.detached = true;
+            } else {
+                threads.remove(&id);
+            }
+            Ok(())
+        })
+    }
+
+    /// Run a closure with the locked threads
+    fn with<T>(&self, f: impl FnOnce(&mut HashMap<u32, ThreadInfo>) -> T) -> T {
+        loop {
+            let Ok(mut threads) = self.threads.try_lock() else {
+                continue;
+            };
+            return f(&mut threads);
+        }
+    }
 }
 
 impl ThreadPool {
     pub fn new(pid: Uuid) -> Self {
+        let table = ThreadTable::default();
+        table.insert(MAIN_THREAD_ID, ThreadState::Running, "main".to_string());
         Self {
             pid,
-            threads: Arc::new(Mutex::new(HashMap::new())),
-            thread_amount: 0,
+            table,
+            workers: HashMap::new(),
+            next_id: MAIN_THREAD_ID + 1,
         }
     }
 
-    /// Spawn a thread
-    pub fn spawn(&mut self, f_ptr: u32, memory: &WebAssembly::Memory) -> Result<u32, ThreadError> {
-        let id = self.thread_amount;
-        let worker = spawn_worker(self.pid, f_ptr, &memory)?;
+    /// Reserve an id for a thread that will be spawned later.
+    /// Fails if the amount of threads alive, excluding the main thread, would exceed the limit.
+    pub fn reserve(&mut self, limit: Option<u32>) -> Result<u32, ThreadError> {
+        let alive = self.table.with(|threads| {
+            threads
+                .iter()
+                .filter(|(id, thread)| **id != MAIN_THREAD_ID && thread.state.is_alive())
+                .count()
+        });
+        if limit.is_some_and(|limit| alive >= limit as usize) {
+            return Err(ThreadError::Limit(LimitError::Exceeded(Resource::Threads)));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.table
+            .insert(id, ThreadState::Pending, format!("thread-{}", id));
+        Ok(id)
+    }
 
-        let threads = self.threads.clone();
+    /// Spawn the thread of a request with a reserved id.
+    /// Does nothing if the thread has been killed before it was spawned.
+    /// `on_crash` is called with the error and the data the worker sent when the thread throws.
+    pub fn spawn(
+        &mut self,
+        request: &ThreadRequest,
+        module: &WebAssembly::Module,
+        libraries: &Array,
+        memory: &Memory,
+        on_crash: impl Fn(u32, String, &JsValue) + 'static,
+    ) -> Result<(), ThreadError> {
+        let id = request.tid;
+        let thread = self.table.get(id).ok_or(ThreadError::NoSuchThread(id))?;
+        if thread.state != ThreadState::Pending {
+            return Ok(());
+        }
+
+        let worker = match spawn_worker(request, &thread.name, module, libraries, memory.inner()) {
+            Ok(worker) => worker,
+            Err(e) => {
+                self.table.set_state(id, ThreadState::Failed);
+                return Err(e);
+            }
+        };
 
         // Register callbacks
-        let threads_callback = threads.clone();
-        let onmessage_callback = Closure::wrap(Box::new(move || loop {
-            let Ok(mut threads) = threads_callback.try_lock() else {
-                continue;
-            };
-            let thread = threads.get_mut(&id).unwrap();
-            thread.alive = false;
-            break;
-        }) as Box<dyn FnMut()>);
-        let threads_callback = threads.clone();
-        let onerror_callback = Closure::wrap(Box::new(move || loop {
-            let Ok(mut threads) = threads_callback.try_lock() else {
-                continue;
-            };
-            let thread = threads.get_mut(&id).unwrap();
-            thread.alive = false;
-            break;
+        let pid = self.pid;
+        let table = self.table.clone();
+        let mut memory_f = memory.clone();
+        let onmessage_callback = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let data = event.data();
+            if let Ok(error) = Reflect::get(&data, &"error".into()) {
+                if !error.is_undefined() {
+                    let name = table.get(id).map(|thread| thread.name).unwrap_or_default();
+                    let message = error.as_string().unwrap_or_default();
+                    log::error!(
+                        "Thread {} `{}` of process `{}` failed: {}",
+                        id,
+                        name,
+                        pid,
+                        message
+                    );
+                    on_crash(id, message, &data);
+                    let stack = table.finish(id, ThreadState::Failed, 0);
+                    free_stack(&mut memory_f, stack);
+                    return;
+                }
+            }
+            let retval = Reflect::get(&data, &"retval".into())
+                .ok()
+                .and_then(|retval| retval.as_f64())
+                .unwrap_or_default();
+            let stack = table.finish(id, ThreadState::Exited, retval as i32 as u32);
+            free_stack(&mut memory_f, stack);
+        }) as Box<dyn FnMut(MessageEvent)>);
+        let table = self.table.clone();
+        let mut memory_f = memory.clone();
+        let onerror_callback = Closure::wrap(Box::new(move || {
+            let stack = table.finish(id, ThreadState::Failed, 0);
+            free_stack(&mut memory_f, stack);
         }) as Box<dyn FnMut()>);
 
         worker.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
-        worker.set_onmessage(Some(onerror_callback.as_ref().unchecked_ref()));
+        worker.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
 
-        // Wait till the lock is free
-        loop {
-            let Ok(mut threads) = threads.try_lock() else {
-                log::info!("Waiting for thread lock");
-                continue;
-            };
-            threads.insert(
-                id,
-                Thread {
-                    worker,
-                    alive: true,
-                },
-            );
-            break;
-        }
-        self.thread_amount += 1;
+        self.table.set_state(id, ThreadState::Running);
+        self.workers.insert(id, worker);
 
         onmessage_callback.forget();
         onerror_callback.forget();
-        Ok(id)
+        Ok(())
+    }
+
+    /// Get the state of a thread
+    pub fn state(&self, id: u32) -> Option<ThreadState> {
+        self.table.state(id)
+    }
+
+    /// Get the shared thread table
+    pub fn table(&self) -> ThreadTable {
+        self.table.clone()
     }
 
     /// Check if a thread is alive.
     /// Also returns false if the id is invalid
     pub fn alive(&self, id: u32) -> bool {
-        loop {
-            let Ok(threads) = self.threads.try_lock() else {
-                log::info!("Wating for thread lock");
-                continue;
-            };
-            let thread = threads.get(&id).unwrap();
-            return thread.alive;
-        }
+        self.table.state(id).is_some_and(|state| state.is_alive())
     }
 
-    /// Kill a thread
-    pub fn kill(&mut self, id: u32) -> Result<(), ThreadError> {
-        loop {
-            let Ok(threads) = self.threads.try_lock() else {
-                log::info!("Wating for thread lock");
-                continue;
-            };
-            let thread = threads.get(&id).unwrap();
-            thread.worker.terminate();
-            break;
+    /// Kill a thread.
+    /// Returns the stack of the thread, which the caller frees.
+    pub fn kill(&mut self, id: u32) -> Result<Option<u32>, ThreadError> {
+        if self.table.state(id).is_none() {
+            return Err(ThreadError::NoSuchThread(id));
         }
-        Ok(())
+        if let Some(worker) = self.workers.remove(&id) {
+            worker.terminate();
+        }
+        Ok(self.table.finish(id, ThreadState::Killed, 0))
+    }
+
+    /// Drop the workers of threads that have been forgotten, because they were joined or detached
+    pub fn reap(&mut self) {
+        let table = &self.table;
+        self.workers.retain(|id, worker| {
+            let known = table.state(*id).is_some();
+            if !known {
+                worker.terminate();
+            }
+            known
+        });
+    }
+
+    /// Mark a reserved thread as failed, when it can't be spawned
+    pub fn fail(&mut self, id: u32) {
+        self.table.finish(id, ThreadState::Failed, 0);
     }
 
     /// Kill all threads
     pub fn kill_all(&mut self) {
-        loop {
-            let Ok(mut threads) = self.threads.try_lock() else {
-                log::info!("Wating for thread lock");
-                continue;
-            };
-            for (_, thread) in threads.iter_mut() {
-                thread.worker.terminate();
-                thread.alive = false;
-            }
-            break;
+        for (id, worker) in self.workers.drain() {
+            worker.terminate();
+            self.table.finish(id, ThreadState::Killed, 0);
         }
     }
 }
 
+/// Free the stack of a finished thread, if the linker allocated one
+fn free_stack(memory: &mut Memory, stack: Option<u32>) {
+    if let Some(stack) = stack {
+        memory.free(stack);
+    }
+}
+
 /// Spawn a thread as a subprocess
 fn spawn_worker(
-    pid: Uuid,
-    f_ptr: u32,
+    request: &ThreadRequest,
+    name: &str,
+    module: &WebAssembly::Module,
+    libraries: &Array,
     memory: &WebAssembly::Memory,
 ) -> Result<Worker, ThreadError> {
-    let mut options = WorkerOptions::new();
-    options.type_(WorkerType::Module);
+    let options = WorkerOptions::new();
+    options.set_type(WorkerType::Module);
+    options.set_name(name);
 
     let script = generate_worker_script();
     let worker = Worker::new_with_options(&script, &options)
@@ -155,15 +404,23 @@ fn spawn_worker(
     let msg = web_sys::js_sys::Array::new();
 
     // Send the pid
-    msg.push(&JsValue::from(pid.to_string()));
+    msg.push(&JsValue::from(request.pid.to_string()));
     // Send the kernel module
     msg.push(&wasm_bindgen::module());
     // Send the kernel memory
     msg.push(&wasm_bindgen::memory());
     // Send the instance memory
-    msg.push(&memory);
+    msg.push(memory);
+    // Send the compiled binary
+    msg.push(module);
+    // Send the compiled libraries
+    msg.push(libraries);
     // The function pointer
-    msg.push(&JsValue::from(f_ptr));
+    msg.push(&JsValue::from(request.fptr));
+    // The thread id
+    msg.push(&JsValue::from(request.tid));
+    // The argument passed to the entrypoint
+    msg.push(&JsValue::from(request.arg));
 
     worker
         .post_message(&msg)
@@ -190,18 +447,10 @@ fn generate_worker_script() -> String {
         .unwrap();
 
     let template = include_str!("js/worker.js");
-    let script = template.replace("BINDGEN_SHIM_URL", &wasm_shim_url);
-
-    // Create url encoded blob
-    let arr = web_sys::js_sys::Array::new();
-    arr.set(0, JsValue::from_str(&script));
-    let blob = Blob::new_with_str_sequence(&arr).unwrap();
-    let url = Url::create_object_url_with_blob(
-        &blob
-            .slice_with_f64_and_f64_and_content_type(0.0, blob.size(), "text/javascript")
-            .unwrap(),
-    )
-    .unwrap();
+    let script = template
+        .replace("BINDGEN_SHIM_URL", &wasm_shim_url)
+        .replace("WORKER_COMMON_URL", &get_worker_common_script());
+    let url = script_url(&script);
 
     // Cache the url
     if let Ok(mut cached) = CACHED_SCRIPT.try_lock() {
@@ -219,6 +468,8 @@ impl std::fmt::Display for ThreadError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ThreadError::NoSuchThread(id) => writeln!(f, "No such thread with id: {}", id),
+            ThreadError::NoSuchProcess(pid) => writeln!(f, "No such process with id: {}", pid),
+            ThreadError::Limit(e) => writeln!(f, "Failed to reserve thread: {}", e),
             ThreadError::WorkerCreation(e) => writeln!(f, "Failed to create worker: {:?}", e),
             ThreadError::WorkerMessaging(e) => {
                 writeln!(f, "Failed to post message to worker: {:?}", e)
@@ -0,0 +1,202 @@
+//! Tracing of the api calls of a process, like strace.
+//! While tracing is enabled, every call a process makes into it's import modules is recorded
+//! with it's arguments, return value and duration in the trace buffer of the process.
+//! The buffer is shared by the threads of the process and read as text lines by other processes.
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use honeyos_atomics::mutex::SpinMutex;
+use wasm_bindgen::{closure::Closure, JsCast, JsValue};
+use web_sys::js_sys::{Array, Function, Uint8Array, WebAssembly};
+
+/// The environment variable enabling tracing when a process is spawned
+pub const TRACE_VAR: &str = "HAPI_TRACE";
+
+/// The amount of calls kept in the trace buffer of a process, older calls are dropped
+pub const TRACE_CAPACITY: usize = 4096;
+
+/// A recorded api call
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    /// The thread that made the call
+    pub tid: u32,
+    pub name: String,
+    pub args: Vec<String>,
+    /// The return value, or the error the call threw
+    pub result: String,
+    /// The duration of the call in milliseconds
+    pub duration: f64,
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "[{}] {}({}) = {} <{:.3}ms>",
+            self.tid,
+            self.name,
+            self.args.join(", "),
+            self.result,
+            self.duration
+        )
+    }
+}
+
+/// The trace buffer of a process
+#[derive(Debug, Default)]
+pub struct ProcessTrace {
+    enabled: AtomicBool,
+    entries: Mutex<VecDeque<TraceEntry>>,
+    dropped: AtomicU64,
+}
+
+impl ProcessTrace {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            ..Default::default()
+        }
+    }
+
+    /// Check if the calls of the process are recorded
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Get the address of the enabled flag in the memory of the kernel.
+    /// The wrapped functions read the flag from there, without calling into the kernel.
+    fn enabled_ptr(&self) -> u32 {
+        self.enabled.as_ptr() as u32
+    }
+
+    /// Start or stop recording the calls of the process.
+    /// Stopping keeps the recorded calls until they are read.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record a call, dropping the oldest call if the buffer is full
+    pub fn record(&self, entry: TraceEntry) {
+        let mut entries = self.entries.busy_lock();
+        if entries.len() >= TRACE_CAPACITY {
+            entries.pop_front();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        entries.push_back(entry);
+    }
+
+    /// Take the oldest recorded calls as text lines, as many as fit in `max` bytes.
+    /// A line longer than `max` is cut off, so a small buffer never stalls the reader.
+    pub fn read(&self, max: usize) -> Vec<u8> {
+        let mut entries = self.entries.busy_lock();
+        let mut data = Vec::new();
+        while let Some(entry) = entries.front() {
+            let line = entry.to_string();
+            if data.len() + line.len() > max {
+                if data.is_empty() && max > 0 {
+                    data.extend_from_slice(&line.as_bytes()[..max]);
+                    entries.pop_front();
+                }
+                break;
+            }
+            data.extend_from_slice(line.as_bytes());
+            entries.pop_front();
+        }
+        data
+    }
+
+    /// Get the amount of recorded calls that were not read yet
+    pub fn len(&self) -> usize {
+        self.entries.busy_lock().len()
+    }
+
+    /// Check if there are no recorded calls left to read
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the amount of calls dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps the functions of the import modules of a thread, recording their calls in the trace buffer.
+/// The check for whether tracing is enabled happens on each call, so tracing can be toggled while the process runs.
+/// The flag is read atomically from the shared memory of the kernel, so untraced calls don't pay for a call into the kernel.
+pub struct Tracer {
+    flags: JsValue,
+    enabled_ptr: JsValue,
+    record: JsValue,
+    factory: Function,
+}
+
+impl Tracer {
+    pub fn new(trace: Arc<ProcessTrace>, tid: u32) -> Self {
+        let kernel_memory: WebAssembly::Memory = wasm_bindgen::memory().unchecked_into();
+        let flags = Uint8Array::new(&kernel_memory.buffer()).into();
+        let enabled_ptr = trace.enabled_ptr().into();
+        let record = Closure::<dyn Fn(String, Array, String, f64)>::new(
+            move |name, args: Array, result, duration| {
+                trace.record(TraceEntry {
+                    tid,
+                    name,
+                    args: args.iter().filter_map(|arg| arg.as_string()).collect(),
+                    result,
+                    duration,
+                })
+            },
+        )
+        .into_js_value();
+        let factory = Function::new_with_args(
+            "name, f, flags, enabled_ptr, record",
+            r#"
+            const fmt = (value) => value === undefined ? "?" : String(value);
+            return function () {
+                if (Atomics.load(flags, enabled_ptr) === 0) {
+                    return f.apply(null, arguments);
+                }
+                const start = performance.now();
+                let result;
+                try {
+                    result = f.apply(null, arguments);
+                } catch (e) {
+                    record(name, Array.from(arguments, fmt), "<" + String(e) + ">", performance.now() - start);
+                    throw e;
+                }
+                record(name, Array.from(arguments, fmt), fmt(result), performance.now() - start);
+                return result;
+            };
+            "#,
+        );
+        Self {
+            flags,
+            enabled_ptr,
+            record,
+            factory,
+        }
+    }
+
+    /// Wrap an item of an import module, items that aren't functions are returned as is
+    pub fn wrap(&self, name: &str, value: &JsValue) -> JsValue {
+        if !value.is_function() {
+            return value.clone();
+        }
+        let args = Array::of5(
+            &name.into(),
+            value,
+            &self.flags,
+            &self.enabled_ptr,
+            &self.record,
+        );
+        self.factory
+            .apply(&JsValue::NULL, &args)
+            .unwrap_or_else(|_| value.clone())
+    }
+}
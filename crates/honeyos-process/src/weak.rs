@@ -0,0 +1,46 @@
+//! Weak imports, functions a binary can run without.
+//! A binary marks the functions it imports as weak in the `weak_imports` of it's manifest, as `module.name`.
+//! Weak imports the kernel does not provide are replaced by shims when the binary is instantiated,
+//! so the binary can check for them with `hapi_has_feature` and degrade gracefully on older kernels.
+use wasmparser::ValType;
+use web_sys::js_sys::Function;
+
+/// The error code returned by the shims of weak imports.
+/// The code is distinct from the other error codes of the api.
+pub const NOT_SUPPORTED: i32 = -17;
+
+/// The shim replacing a weak import the kernel does not provide
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shim {
+    pub module: String,
+    pub name: String,
+    /// The result types of the imported function
+    pub results: Vec<ValType>,
+}
+
+impl Shim {
+    /// Get the body of the shim function.
+    /// Integer results are `NOT_SUPPORTED`, floats are `NaN` and references are null.
+    pub fn body(&self) -> String {
+        let results = self
+            .results
+            .iter()
+            .map(|result| match result {
+                ValType::I32 => NOT_SUPPORTED.to_string(),
+                ValType::I64 => format!("{}n", NOT_SUPPORTED),
+                ValType::F32 | ValType::F64 => "NaN".to_string(),
+                ValType::V128 | ValType::Ref(_) => "null".to_string(),
+            })
+            .collect::<Vec<_>>();
+        match results.as_slice() {
+            [] => "return;".to_string(),
+            [result] => format!("return {};", result),
+            results => format!("return [{}];", results.join(", ")),
+        }
+    }
+
+    /// Create the shim function
+    pub fn function(&self) -> Function {
+        Function::new_no_args(&self.body())
+    }
+}
@@ -3,6 +3,8 @@ use std::{ffi::CString, sync::Arc};
 use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
 use wasm_bindgen::{closure::Closure, prelude::wasm_bindgen};
 
+use super::error::{fail, HapiError};
+
 /// Gets returned to describe a file
 #[repr(C)]
 #[wasm_bindgen]
@@ -17,7 +19,7 @@ pub fn register_browser_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // Returns a pointer to the user agent.
     // ### Returns
     // - The user agent on success
-    // - `NULL`` if failed allocate the string.
+    // - `NULL` if failed allocate the string, `hapi_last_error_code` is `-14`
     let ctx_f = ctx.clone();
     let user_agent = web_sys::js_sys::eval("self.navigator.userAgent")
         .unwrap()
@@ -33,6 +35,12 @@ pub fn register_browser_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
             let Some(ptr) =
                 memory.alloc(user_agent_len as u32 * std::mem::size_of::<char>() as u32)
             else {
+                drop(memory);
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    "Failed to allocate the user agent",
+                );
                 return std::ptr::null();
             };
             memory.write(ptr, user_agent.as_bytes());
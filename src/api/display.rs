@@ -1,42 +1,57 @@
 use std::sync::Arc;
 
-use honeyos_atomics::{mutex::SpinMutex, rwlock::SpinRwLock};
-use honeyos_display::{error::Error, Display, KeyBuffer};
+use honeyos_atomics::rwlock::SpinRwLock;
+use honeyos_display::{Display, KeyBuffer};
 use honeyos_process::{
+    capabilities::Capability,
     context::{ApiModuleBuilder, ProcessCtx},
-    ProcessManager,
+    stdout::Stream,
 };
 use wasm_bindgen::closure::Closure;
 
+use super::error::{fail, fail_with, read_str, HapiError};
+
 /// Register the display api
 pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_display_assume_control
+    // Take control over the display, unless another process controls it.
+    // ### Returns
+    // - `0` On success
+    // - `-8` If another process controls the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_assume_control",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
-            if let Err(Error::DisplayOccupied) = display.assume_control(ctx_f.pid()) {
-                return -1;
+            match display.assume_control(ctx_f.pid()) {
+                Ok(_) => 0,
+                Err(e) => fail_with(&ctx_f, &e),
             }
-            return 0;
         })
         .into_js_value(),
     );
 
     // hapi_display_loosen_control
+    // Loosen the control over the display, so other processes may assume control.
+    // ### Returns
+    // - `0` On success
+    // - `-9` If the process does not control the display, or the control is already loose
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_loosen_control",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             match display.loosen_control() {
-                Err(_) => -2,
+                Err(e) => fail_with(&ctx_f, &e),
                 Ok(_) => 0,
             }
         })
@@ -45,8 +60,9 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
 
     // hapi_display_override_control
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_override_control",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
@@ -57,14 +73,20 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_release_control
+    // Release the control over the display.
+    // ### Returns
+    // - `0` On success
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_release_control",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             display.release_control();
             0
@@ -73,8 +95,9 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_release_control
-    builder.register(
+    builder.register_restricted(
         "hapi_display_displace_control",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
@@ -85,35 +108,54 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_push_stdout
+    // Show the stdout and stderr of the process on the display, in the order they were written.
+    // Only the output written since the last push is appended, unless the output has been cleared
+    // or the display has been written to since, in which case the display is redrawn.
+    // ### Returns
+    // - `0` On success
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_push_stdout",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
 
-            // Get stdout from the process manager
-            let stdout_str = {
-                let process_manager_lock = ProcessManager::get();
-                let Ok(mut process_manager) = process_manager_lock.spin_lock() else {
-                    return -1;
-                };
-                let process = process_manager.process_mut(ctx_f.pid()).unwrap();
-                let stdout = process.stdout();
-
-                // Sync stdout
-                stdout.sync();
-                stdout.buffer()
+            // Get the output of both streams written since the last push
+            let (runs, end, redraw) = {
+                let output = ctx_f.output();
+                let cursor = display.output_cursor(ctx_f.pid());
+                match cursor.and_then(|from| output.read(from)) {
+                    Some(runs) => (runs, output.end(), false),
+                    None => (
+                        output.read(output.start()).unwrap_or_default(),
+                        output.end(),
+                        true,
+                    ),
+                }
             };
+            if runs.is_empty() && !redraw {
+                return 0;
+            }
 
-            // Send stdout to the display
+            // Send the output to the display, with stderr in red
             {
-                let text_mode = display.text_mode_mut();
-                text_mode.clear();
-                text_mode.append_str(&stdout_str);
+                let text_mode = display.text_mode_output(ctx_f.pid(), end);
+                if redraw {
+                    text_mode.clear();
+                }
+                for (stream, bytes) in runs {
+                    let text = String::from_utf8_lossy(&bytes);
+                    match stream {
+                        Stream::Stdout => text_mode.append_str(&text),
+                        Stream::Stderr => text_mode.append_str(&format!("\x1b[91m{}\x1b[0m", text)),
+                    }
+                }
             }
 
             display.notify_update();
@@ -124,24 +166,31 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_set_text
+    // Show a string on the display.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the string is invalid
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_set_text",
+        Capability::Display,
         Closure::<dyn Fn(*const u8) -> i32>::new(move |ptr: *const u8| {
-            let memory = ctx_f.memory();
-            let Some(string) = &memory.read_str(ptr as u32) else {
-                return -2;
+            let string = match read_str(&ctx_f, ptr, "text") {
+                Ok(string) => string,
+                Err(code) => return code,
             };
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
 
             {
                 let text_mode = display.text_mode_mut();
                 text_mode.clear();
-                text_mode.append_str(string);
+                text_mode.append_str(&string);
             }
 
             display.notify_update();
@@ -152,17 +201,24 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_get_key_buffer
+    // Get the key last pressed on the display.
+    // ### Returns
+    // - The key code on success
+    // - `-1` If no key has been pressed since the key was cleared
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_get_key_buffer",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let display = display_lock.spin_read().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             if display.keybuffer.key < 0 {
-                return -2;
+                return fail(&ctx_f, HapiError::NotFound, "No key has been pressed");
             }
             return display.keybuffer.key;
         })
@@ -170,14 +226,20 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_get_key_shift
+    // Check if shift was held when the key was pressed.
+    // ### Returns
+    // - `1` If shift was held, `0` if not
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_get_key_shift",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let display = display_lock.spin_read().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             return display.keybuffer.shift as i32;
         })
@@ -185,14 +247,20 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_get_key_ctrl
+    // Check if ctrl was held when the key was pressed.
+    // ### Returns
+    // - `1` If ctrl was held, `0` if not
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_get_key_ctrl",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let display = display_lock.spin_read().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             return display.keybuffer.ctrl as i32;
         })
@@ -200,14 +268,20 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_display_clear_key
+    // Clear the key last pressed on the display.
+    // ### Returns
+    // - `0` On success
+    // - `-9` If the process does not control the display
+    // - `-10` If the process lacks the display capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_display_clear_key",
+        Capability::Display,
         Closure::<dyn Fn() -> i32>::new(move || {
             let display_lock = Display::get();
             let mut display = display_lock.spin_write().unwrap();
             if !display.has_control(ctx_f.pid()) {
-                return -1;
+                return not_in_control(&ctx_f);
             }
             display.keybuffer = KeyBuffer {
                 key: -1,
@@ -219,3 +293,12 @@ pub fn register_display_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
         .into_js_value(),
     );
 }
+
+/// Fail a call that requires control over the display
+fn not_in_control(ctx: &ProcessCtx) -> i32 {
+    fail(
+        ctx,
+        HapiError::InvalidState,
+        "The process does not control the display",
+    )
+}
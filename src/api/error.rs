@@ -0,0 +1,391 @@
+//! The error codes of the api.
+//! Every hapi function that fails returns the negative code of a [`HapiError`],
+//! and records a message describing the failure as the last error of the calling thread.
+//! The message can be read with `hapi_last_error` until the next failing call.
+//!
+//! | Code | Error |
+//! |------|-------|
+//! | `-1` | [`HapiError::NotFound`] |
+//! | `-2` | [`HapiError::InvalidArgument`] |
+//! | `-3` | [`HapiError::AlreadyExists`] |
+//! | `-4` | [`HapiError::NotMounted`] |
+//! | `-5` | [`HapiError::IsDirectory`] |
+//! | `-6` | [`HapiError::NotADirectory`] |
+//! | `-7` | [`HapiError::OutOfRange`] |
+//! | `-8` | [`HapiError::Busy`] |
+//! | `-9` | [`HapiError::InvalidState`] |
+//! | `-10` | [`HapiError::PermissionDenied`] |
+//! | `-11` | [`HapiError::Closed`] |
+//! | `-12` | [`HapiError::Network`] |
+//! | `-13` | [`HapiError::Worker`] |
+//! | `-14` | [`HapiError::OutOfMemory`] |
+//! | `-15` | [`HapiError::Deadlock`] |
+//! | `-16` | [`HapiError::Internal`] |
+//! | `-17` | [`HapiError::NotSupported`], returned by the shims of weak imports |
+//! | `-18` | [`HapiError::InvalidHandle`] |
+//! | `-19` | [`HapiError::InvalidPointer`] |
+//! | `-20..-25` | [`HapiError::LimitExceeded`], `-20` minus the resource |
+//! | `-30..-37` | The spawn errors, see [`SpawnError::code`] |
+//!
+//! The wasi functions return the error numbers of wasi instead.
+use std::{ffi::CString, fmt::Display, str::FromStr, sync::Arc};
+
+use honeyos_process::{
+    capabilities::PERMISSION_DENIED,
+    context::{ApiModuleBuilder, ProcessCtx},
+    error::SpawnError,
+    handle::HandleError,
+    limits::{LimitError, Resource},
+    memory::MemoryError,
+    pipe::PipeError,
+    ring::RingError,
+    thread::ThreadError,
+    weak::NOT_SUPPORTED,
+};
+use uuid::Uuid;
+use wasm_bindgen::closure::Closure;
+
+/// The longest string argument read from memory, including the null terminator
+pub const MAX_STR_LEN: u32 = 1 << 20;
+
+/// An error of the api, with a stable code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HapiError {
+    /// The file, directory, process, thread, pipe, request or variable does not exist
+    NotFound,
+    /// An argument is invalid, like a string that is not utf-8 or a malformed id
+    InvalidArgument,
+    /// The file or directory already exists
+    AlreadyExists,
+    /// No file system is mounted at the label
+    NotMounted,
+    /// The path is a directory, where a file was expected
+    IsDirectory,
+    /// The path is a file, where a directory was expected
+    NotADirectory,
+    /// An index or offset is out of range
+    OutOfRange,
+    /// The resource is held by another process, like the display or a file system label
+    Busy,
+    /// The call is not valid in the current state, like releasing the display without controlling it
+    InvalidState,
+    /// The process lacks the capability for the call
+    PermissionDenied,
+    /// The stream or pipe has been closed
+    Closed,
+    /// A network request could not be made
+    Network,
+    /// The worker of a thread could not be created or messaged
+    Worker,
+    /// Memory could not be allocated in the process
+    OutOfMemory,
+    /// The call would never return, like a thread joining itself
+    Deadlock,
+    /// The kernel failed, like a poisoned lock
+    Internal,
+    /// The kernel does not provide the call
+    NotSupported,
+    /// The handle does not exist or refers to another kind of object
+    InvalidHandle,
+    /// A pointer or buffer is outside the memory of the process, or reaches into memory it has not allocated
+    InvalidPointer,
+    /// The process would exceed the limit of a resource
+    LimitExceeded(Resource),
+    /// Spawning a process failed
+    Spawn(i32),
+}
+
+impl HapiError {
+    /// Get the code returned by the api
+    pub fn code(self) -> i32 {
+        match self {
+            HapiError::NotFound => -1,
+            HapiError::InvalidArgument => -2,
+            HapiError::AlreadyExists => -3,
+            HapiError::NotMounted => -4,
+            HapiError::IsDirectory => -5,
+            HapiError::NotADirectory => -6,
+            HapiError::OutOfRange => -7,
+            HapiError::Busy => -8,
+            HapiError::InvalidState => -9,
+            HapiError::PermissionDenied => PERMISSION_DENIED,
+            HapiError::Closed => -11,
+            HapiError::Network => -12,
+            HapiError::Worker => -13,
+            HapiError::OutOfMemory => -14,
+            HapiError::Deadlock => -15,
+            HapiError::Internal => -16,
+            HapiError::NotSupported => NOT_SUPPORTED,
+            HapiError::InvalidHandle => -18,
+            HapiError::InvalidPointer => -19,
+            HapiError::LimitExceeded(resource) => resource.exceeded_code(),
+            HapiError::Spawn(code) => code,
+        }
+    }
+}
+
+impl Display for HapiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HapiError::NotFound => write!(f, "Not found"),
+            HapiError::InvalidArgument => write!(f, "Invalid argument"),
+            HapiError::AlreadyExists => write!(f, "Already exists"),
+            HapiError::NotMounted => write!(f, "No file system mounted"),
+            HapiError::IsDirectory => write!(f, "Is a directory"),
+            HapiError::NotADirectory => write!(f, "Not a directory"),
+            HapiError::OutOfRange => write!(f, "Out of range"),
+            HapiError::Busy => write!(f, "Resource busy"),
+            HapiError::InvalidState => write!(f, "Invalid state"),
+            HapiError::PermissionDenied => write!(f, "Permission denied"),
+            HapiError::Closed => write!(f, "Closed"),
+            HapiError::Network => write!(f, "Network error"),
+            HapiError::Worker => write!(f, "Worker error"),
+            HapiError::OutOfMemory => write!(f, "Out of memory"),
+            HapiError::Deadlock => write!(f, "Deadlock"),
+            HapiError::Internal => write!(f, "Internal error"),
+            HapiError::NotSupported => write!(f, "Not supported"),
+            HapiError::InvalidHandle => write!(f, "Invalid handle"),
+            HapiError::InvalidPointer => write!(f, "Invalid pointer"),
+            HapiError::LimitExceeded(resource) => {
+                write!(f, "Resource limit exceeded: {:?}", resource)
+            }
+            HapiError::Spawn(code) => write!(f, "Spawn failed: {}", code),
+        }
+    }
+}
+
+impl From<&honeyos_fs::error::Error> for HapiError {
+    fn from(error: &honeyos_fs::error::Error) -> Self {
+        use honeyos_fs::error::Error;
+        match error {
+            Error::NoSuchFile(_)
+            | Error::NoSuchFileWithId(_)
+            | Error::NoSuchDirectoryWithId(_)
+            | Error::NoSuchDirectory(_)
+            | Error::NoSuchFileInDirectory { .. }
+            | Error::NoSuchDirectoryInDirectory { .. }
+            | Error::NoSuchFileOrDirectory(_)
+            | Error::DirectoryOrphaned(_)
+            | Error::FileOrphaned(_) => HapiError::NotFound,
+            Error::IsFile(_) => HapiError::NotADirectory,
+            Error::IsDirectory(_) => HapiError::IsDirectory,
+            Error::FileAlreadyExists(_) | Error::DirectoryAlreadyExists(_) => {
+                HapiError::AlreadyExists
+            }
+            Error::IndexOutOfRange { .. } => HapiError::OutOfRange,
+            Error::LabelInUse(_) => HapiError::Busy,
+            Error::NoFsMounted(_) => HapiError::NotMounted,
+            Error::NotAFsLabel(_) | Error::NoFsLabel(_) => HapiError::InvalidArgument,
+            Error::FsManagerPoisoned => HapiError::Internal,
+        }
+    }
+}
+
+impl From<&honeyos_display::error::Error> for HapiError {
+    fn from(error: &honeyos_display::error::Error) -> Self {
+        use honeyos_display::error::Error;
+        match error {
+            Error::DisplayOccupied => HapiError::Busy,
+            Error::CannotLoosen | Error::AlreadyLoose => HapiError::InvalidState,
+        }
+    }
+}
+
+impl From<&honeyos_networking::error::Error> for HapiError {
+    fn from(error: &honeyos_networking::error::Error) -> Self {
+        use honeyos_networking::error::Error;
+        match error {
+            Error::RequestInitFailure(_) | Error::HeaderParseFailure(_) => HapiError::Network,
+            Error::InvalidRequestMethod => HapiError::InvalidArgument,
+            Error::RequestLimitExceeded(_) => HapiError::LimitExceeded(Resource::NetworkRequests),
+        }
+    }
+}
+
+impl From<&ThreadError> for HapiError {
+    fn from(error: &ThreadError) -> Self {
+        match error {
+            ThreadError::NoSuchThread(_) | ThreadError::NoSuchProcess(_) => HapiError::NotFound,
+            ThreadError::Limit(error) => error.into(),
+            ThreadError::WorkerCreation(_) | ThreadError::WorkerMessaging(_) => HapiError::Worker,
+        }
+    }
+}
+
+impl From<&LimitError> for HapiError {
+    fn from(error: &LimitError) -> Self {
+        match error {
+            LimitError::Exceeded(resource) => HapiError::LimitExceeded(*resource),
+            LimitError::NotPermitted(_) => HapiError::PermissionDenied,
+            LimitError::InvalidResource(_) => HapiError::InvalidArgument,
+        }
+    }
+}
+
+impl From<&PipeError> for HapiError {
+    fn from(_: &PipeError) -> Self {
+        HapiError::Closed
+    }
+}
+
+impl From<&HandleError> for HapiError {
+    fn from(_: &HandleError) -> Self {
+        HapiError::InvalidHandle
+    }
+}
+
+impl From<&MemoryError> for HapiError {
+    fn from(error: &MemoryError) -> Self {
+        match error {
+            MemoryError::OutOfBounds { .. } | MemoryError::Alloc(_) => HapiError::InvalidPointer,
+            MemoryError::Unterminated { .. } => HapiError::InvalidArgument,
+        }
+    }
+}
+
+impl From<&RingError> for HapiError {
+    fn from(error: &RingError) -> Self {
+        match error {
+            RingError::AlreadySetUp => HapiError::AlreadyExists,
+            RingError::InvalidEntries(_) => HapiError::InvalidArgument,
+            RingError::NotSetUp | RingError::Corrupted => HapiError::InvalidState,
+            RingError::Busy => HapiError::Busy,
+        }
+    }
+}
+
+impl From<&SpawnError> for HapiError {
+    fn from(error: &SpawnError) -> Self {
+        match error {
+            SpawnError::Limit(error) => error.into(),
+            error => HapiError::Spawn(error.code()),
+        }
+    }
+}
+
+/// Record an error as the last error of the calling thread and return it's code
+pub fn fail(ctx: &ProcessCtx, error: HapiError, message: impl Display) -> i32 {
+    let code = error.code();
+    ctx.set_last_error(code, message.to_string().trim_end().to_string());
+    code
+}
+
+/// Record an error of one of the kernel crates as the last error of the calling thread and return it's code
+pub fn fail_with<E: Display>(ctx: &ProcessCtx, error: &E) -> i32
+where
+    for<'a> HapiError: From<&'a E>,
+{
+    fail(ctx, HapiError::from(error), error)
+}
+
+/// Read a string argument from memory, at most [`MAX_STR_LEN`] bytes long.
+/// Fails with the recorded code of [`HapiError::InvalidPointer`] if the string is outside the memory of the process,
+/// or [`HapiError::InvalidArgument`] if it is not null-terminated.
+pub fn read_str(ctx: &ProcessCtx, ptr: *const u8, name: &str) -> Result<String, i32> {
+    let result = ctx.memory().read_str_bounded(ptr as u32, MAX_STR_LEN);
+    result.map_err(|e| {
+        fail(
+            ctx,
+            HapiError::from(&e),
+            format!("The {} is not a valid string: {}", name, e),
+        )
+    })
+}
+
+/// Read a buffer argument from memory.
+/// The memory must not be locked by the caller.
+/// Fails with the recorded code of [`HapiError::InvalidPointer`].
+pub fn read_mem(ctx: &ProcessCtx, ptr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    let result = ctx.memory().read_checked(ptr, len);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Check a buffer argument before consuming the data to fill it.
+/// The memory must not be locked by the caller.
+/// Fails with the recorded code of [`HapiError::InvalidPointer`].
+pub fn check_mem(ctx: &ProcessCtx, ptr: u32, len: u32) -> Result<(), i32> {
+    let result = ctx.memory().check_range(ptr, len);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Write to a buffer argument in memory.
+/// The memory must not be locked by the caller.
+/// Fails with the recorded code of [`HapiError::InvalidPointer`], in which case nothing is written.
+pub fn write_mem(ctx: &ProcessCtx, ptr: u32, data: &[u8]) -> Result<(), i32> {
+    let result = ctx.memory().write_checked(ptr, data);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Read an id argument from memory.
+/// Fails with the recorded code of [`HapiError::InvalidArgument`].
+pub fn read_id(ctx: &ProcessCtx, ptr: *const u8, name: &str) -> Result<Uuid, i32> {
+    let id = read_str(ctx, ptr, name)?;
+    Uuid::from_str(&id).map_err(|_| {
+        fail(
+            ctx,
+            HapiError::InvalidArgument,
+            format!("The {} is not a valid id: {}", name, id),
+        )
+    })
+}
+
+/// Register the error api
+pub fn register_error_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_last_error_code
+    // Get the code of the last failed api call of the calling thread.
+    // ### Returns
+    // - The code of the error, `0` if no call of the thread has failed
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_last_error_code",
+        Closure::<dyn Fn() -> i32>::new(move || match ctx_f.last_error() {
+            Some((code, _)) => code,
+            None => 0,
+        })
+        .into_js_value(),
+    );
+
+    // hapi_last_error_length
+    // Get the size of the buffer needed for `hapi_last_error`.
+    // ### Returns
+    // - The size of the message as a null-terminated string
+    // - `-1` If no call of the thread has failed
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_last_error_length",
+        Closure::<dyn Fn() -> i32>::new(move || match ctx_f.last_error() {
+            Some((_, message)) => message.len() as i32 + 1,
+            None => HapiError::NotFound.code(),
+        })
+        .into_js_value(),
+    );
+
+    // hapi_last_error
+    // Write the message describing the last failed api call of the calling thread to the buffer as a null-terminated string.
+    // The message is kept until another call of the thread fails, reading it does not fail itself.
+    // Calls replaced by a stub because the process lacks their capability return `-10` without recording a message.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If no call of the thread has failed
+    // - `-19` If the buffer is invalid, the message is kept
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_last_error_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_last_error",
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
+            let Some((_, message)) = ctx_f.last_error() else {
+                return HapiError::NotFound.code();
+            };
+            let cstring = CString::new(message).unwrap_or_default();
+            let result = ctx_f
+                .memory()
+                .write_checked(buffer as u32, cstring.as_bytes_with_nul());
+            match result {
+                Ok(_) => 0,
+                Err(_) => HapiError::InvalidPointer.code(),
+            }
+        })
+        .into_js_value(),
+    );
+}
@@ -1,300 +1,242 @@
-use std::{ffi::CString, str::FromStr, sync::Arc};
-
-use honeyos_fs::{error::Error, ramfs::RamFsHandler, FileResult, FsLabel, FsManager};
-use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
-use uuid::Uuid;
+use std::{str::FromStr, sync::Arc};
+
+use honeyos_fs::{ramfs::RamFsHandler, FsLabel, FsManager};
+use honeyos_process::{
+    capabilities::Capability,
+    context::{ApiModuleBuilder, ProcessCtx},
+    handle::KernelObject,
+};
 use wasm_bindgen::closure::Closure;
 
+use super::{
+    error::{fail, fail_with, read_mem, read_str, write_mem, HapiError},
+    handle::{directory_handle, file_handle, insert_handle},
+};
+
 /// Register the fs api
 pub fn register_fs_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_fs_init_ramfs
     // Register a ram filesystem with the provided label.
     // ### Returns
     // - `0` On success
-    // - `-1` If the label char is invalid
-    // - `-2` If the label is already occupied
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - `-2` If the label char is invalid
+    // - `-8` If the label is already occupied
+    // - `-10` If the process can't write the file system
+    // - `-16` If the file system manager has been poisoned
+    let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_init_ramfs",
         Closure::<dyn Fn(u8) -> i32>::new(move |fs_label: u8| {
-            let fs_manager = FsManager::get();
-            let Ok(fs_label) = FsLabel::from_str(&(fs_label as char).to_string()) else {
-                return -1;
+            let fs_label = match label(&ctx_f, fs_label) {
+                Ok(label) => label,
+                Err(code) => return code,
             };
+            if let Err(code) = check_access(&ctx_f, Capability::FsWrite(fs_label)) {
+                return code;
+            }
 
-            match fs_manager.register_fs(fs_label, RamFsHandler::new()) {
+            match FsManager::get().register_fs(fs_label, RamFsHandler::new()) {
                 Ok(_) => 0,
-                Err(e) => match e {
-                    honeyos_fs::error::Error::FsManagerPoisoned => {
-                        panic!("The file system manager has been poisoned");
-                    }
-                    _ => -2,
-                },
+                Err(e) => fail_with(&ctx_f, &e),
             }
         })
         .into_js_value(),
     );
 
     // hapi_fs_file_create
-    // Create a file at the path.
+    // Create a file at the path, relative to the working directory unless it has a fs label.
     // ### Returns
     // - `0` On success
     // - `-1` If the directory doesn't exist
-    // - `-2` If a file with the name already exists
-    // - `-3` If the path string is invalid
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - `-2` If the path string is invalid
+    // - `-3` If a file with the name already exists
+    // - `-4` If the fs label does not correspond to an active fs
+    // - `-10` If the process can't write the file system
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_file_create",
         Closure::<dyn Fn(*const u8) -> i32>::new(move |path| {
-            let memory = ctx_f.memory();
-            let Some(mut path) = memory.read_str(path as u32) else {
-                return -2;
-            };
-
-            let fs_manager = FsManager::get();
-
-            // If the path does not contain a fs label, append the dir to the current working directory
-            let fs_label = match FsLabel::extract_from_path(&path) {
-                Ok(label) => label,
-                Err(_) => {
-                    let cwd = ctx_f.cwd();
-                    path = format!("{}/{}", cwd, path);
-                    let Ok(label) = FsLabel::extract_from_path(&path) else {
-                        return -2;
-                    };
-                    label
-                }
+            let (fs_label, path) = match resolve_path(&ctx_f, path) {
+                Ok(path) => path,
+                Err(code) => return code,
             };
+            if let Err(code) = check_access(&ctx_f, Capability::FsWrite(fs_label)) {
+                return code;
+            }
 
-            let Ok(fs_manager) = fs_manager.get_fs(fs_label) else {
-                log::info!("Failed to get fs: {}", fs_label);
-                return -1;
+            let fs = match FsManager::get().get_fs(fs_label) {
+                Ok(fs) => fs,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
-            let Ok(mut fs_manager) = fs_manager.write() else {
-                panic!("The file system manager has been poisoned");
+            let Ok(mut fs) = fs.write() else {
+                return fail(
+                    &ctx_f,
+                    HapiError::Internal,
+                    "The file system has been poisoned",
+                );
             };
-
-            let path = path.split_off(3);
-
-            match fs_manager.create_file(&path) {
+            match fs.create_file(&path) {
                 Ok(_) => 0,
-                Err(e) => match e {
-                    honeyos_fs::error::Error::FileAlreadyExists(_) => -2,
-                    _ => -1,
-                },
+                Err(e) => fail_with(&ctx_f, &e),
             }
         })
         .into_js_value(),
     );
 
-    // hapi_fs_file_get
-    // Find a file at disk and return it's id
-    // ### Returns
-    // - `0` On success
-    // - `-1` if the file does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // - `-3` If the path is a directory
-    // ### Panics
-    // Panics if the filesystem is poisoned.
-    // ### Safety
-    // The destination buffer must be the size of a UUID (37 bytes),
-    // otherwise the remaining bytes will be written to unallocated memory and can cause UB.
-    let ctx_f = ctx.clone();
-    builder.register(
-        "hapi_fs_file_get",
-        Closure::<dyn Fn(*const u8, *mut u8) -> i32>::new(move |path, buffer| {
-            let mut memory = ctx_f.memory();
-            let Some(path) = memory.read_str(path as u32) else {
-                return -1;
-            };
-
-            let fs_manager = FsManager::get();
-
-            let file_id = match fs_manager.lookup(&path) {
-                Ok(result) => match result {
-                    FileResult::File(file) => file,
-                    FileResult::Directory(_) => return -3,
-                },
-                Err(e) => match e {
-                    Error::NoFsMounted(_) => return -2,
-                    Error::NoSuchFileOrDirectory(_) => return -1,
-                    _ => return -1,
-                },
-            };
-            let file_id = CString::new(file_id.to_string()).unwrap();
-            memory.write(buffer as u32, file_id.as_bytes());
-            0
-        })
-        .into_js_value(),
-    );
-
     // hapi_fs_directory_create
-    // Create a directory at the path.
+    // Create a directory at the path, relative to the working directory unless it has a fs label.
     // ### Returns
     // - `0` On success
-    // - `-1` If the directory doesn't exist
-    // - `-2` If a directory with the name already exists
-    // - `-3` If the path string is invalid
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - `-1` If the parent directory doesn't exist
+    // - `-2` If the path string is invalid
+    // - `-3` If a directory with the name already exists
+    // - `-4` If the fs label does not correspond to an active fs
+    // - `-10` If the process can't write the file system
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_directory_create",
         Closure::<dyn Fn(*const u8) -> i32>::new(move |path| {
-            let memory = ctx_f.memory();
-            let Some(mut path) = memory.read_str(path as u32) else {
-                return -2;
-            };
-
-            let fs_manager = FsManager::get();
-
-            // If the path does not contain a fs label, append the dir to the current working directory
-            let fs_label = match FsLabel::extract_from_path(&path) {
-                Ok(label) => label,
-                Err(_) => {
-                    let cwd = ctx_f.cwd();
-                    path = format!("{}/{}", cwd, path);
-                    let Ok(label) = FsLabel::extract_from_path(&path) else {
-                        return -2;
-                    };
-                    label
-                }
+            let (fs_label, path) = match resolve_path(&ctx_f, path) {
+                Ok(path) => path,
+                Err(code) => return code,
             };
+            if let Err(code) = check_access(&ctx_f, Capability::FsWrite(fs_label)) {
+                return code;
+            }
 
-            let Ok(fs_manager) = fs_manager.get_fs(fs_label) else {
-                log::info!("Failed to get fs: {}", fs_label);
-                return -1;
+            let fs = match FsManager::get().get_fs(fs_label) {
+                Ok(fs) => fs,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
-            let Ok(mut fs_manager) = fs_manager.write() else {
-                panic!("The file system manager has been poisoned");
+            let Ok(mut fs) = fs.write() else {
+                return fail(
+                    &ctx_f,
+                    HapiError::Internal,
+                    "The file system has been poisoned",
+                );
             };
-
-            let path = path.split_off(3);
-
-            match fs_manager.create_directory(&path) {
+            match fs.create_directory(&path) {
                 Ok(_) => 0,
-                Err(e) => match e {
-                    honeyos_fs::error::Error::DirectoryAlreadyExists(_) => -2,
-                    _ => -1,
-                },
+                Err(e) => fail_with(&ctx_f, &e),
             }
         })
         .into_js_value(),
     );
 
     // hapi_fs_directory_get
-    // Find a directory at disk and return it's id
+    // Find a directory at disk and return a handle to it.
+    // The path is relative to the working directory unless it has a fs label.
     // ### Returns
-    // - `0` On success
-    // - `-1` if the directory does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // ### Panics
-    // Panics if the filesystem is poisoned.
-    // ### Safety
-    // The destination buffer must be the size of a UUID (37 bytes),
-    // otherwise the remaining bytes will be written to unallocated memory and can cause UB.
+    // - The handle on success
+    // - `-1` If the directory does not exist
+    // - `-2` If the path string is invalid
+    // - `-4` If the fs label does not correspond to an active fs
+    // - `-10` If the process can't read the file system
+    // - `-22` If the process has reached it's open file limit
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_directory_get",
-        Closure::<dyn Fn(*const u8, *mut u8) -> i32>::new(move |path, buffer| {
-            let mut memory = ctx_f.memory();
-            let Some(mut path) = memory.read_str(path as u32) else {
-                return -1;
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |path| {
+            let (fs_label, path) = match resolve_path(&ctx_f, path) {
+                Ok(path) => path,
+                Err(code) => return code,
             };
+            if let Err(code) = check_access(&ctx_f, Capability::FsRead(fs_label)) {
+                return code;
+            }
 
-            // If the path does not contain a fs label, append the dir to the current working directory
-            let label = match FsLabel::extract_from_path(&path) {
-                Ok(label) => label,
-                Err(_) => {
-                    let cwd = ctx_f.cwd();
-                    path = format!("{}/{}", cwd, path);
-                    let Ok(label) = FsLabel::extract_from_path(&path) else {
-                        return -2;
-                    };
-                    label
-                }
+            let fs = match FsManager::get().get_fs(fs_label) {
+                Ok(fs) => fs,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
-
-            let fs_manager = FsManager::get();
-            let Ok(fs) = fs_manager.get_fs(label) else {
-                return -2;
+            let Ok(fs) = fs.read() else {
+                return fail(
+                    &ctx_f,
+                    HapiError::Internal,
+                    "The file system has been poisoned",
+                );
             };
-
-            let fs_reader = fs.read().expect(&format!(
-                "The lock for file system {}:/ has been poisoned",
-                label
-            ));
-
-            // Remove the label from the path
-            let path = path.split_off(3);
-
-            let Ok(dir_id) = fs_reader.get_directory(&path) else {
-                return -1;
+            let dir_id = match fs.get_directory(&path) {
+                Ok(id) => id,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
+            drop(fs);
 
-            let dir_id = CString::new(dir_id.to_string()).unwrap();
-            memory.write(buffer as u32, dir_id.as_bytes());
-            0
+            match insert_handle(&ctx_f, KernelObject::Directory(fs_label, dir_id)) {
+                Ok(handle) => handle as i32,
+                Err(code) => code,
+            }
         })
         .into_js_value(),
     );
 
     // hapi_fs_file_get
-    // Find a file at disk and return it's id
+    // Find a file at disk and return a handle to it.
+    // The path is relative to the working directory unless it has a fs label.
     // ### Returns
-    // - `0` On success
-    // - `-1` if the file does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // ### Panics
-    // Panics if the filesystem is poisoned.
-    // ### Safety
-    // The destination buffer must be the size of a UUID (37 bytes),
-    // otherwise the remaining bytes will be written to unallocated memory and can cause UB.
+    // - The handle on success
+    // - `-1` If the file does not exist
+    // - `-2` If the path string is invalid
+    // - `-4` If the fs label does not correspond to an active fs
+    // - `-5` If the path is a directory
+    // - `-10` If the process can't read the file system
+    // - `-22` If the process has reached it's open file limit
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_file_get",
-        Closure::<dyn Fn(*const u8, *mut u8) -> i32>::new(move |path, buffer| {
-            let mut memory = ctx_f.memory();
-            let Some(mut path) = memory.read_str(path as u32) else {
-                return -1;
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |path| {
+            let (fs_label, path) = match resolve_path(&ctx_f, path) {
+                Ok(path) => path,
+                Err(code) => return code,
             };
+            get_file(&ctx_f, fs_label, &path)
+        })
+        .into_js_value(),
+    );
 
-            // If the path does not contain a fs label, append the dir to the current working directory
-            let label = match FsLabel::extract_from_path(&path) {
-                Ok(label) => label,
-                Err(_) => {
-                    let cwd = ctx_f.cwd();
-                    path = format!("{}/{}", cwd, path);
-                    let Ok(label) = FsLabel::extract_from_path(&path) else {
-                        return -2;
-                    };
-                    label
-                }
+    // hapi_fs_file_get_at
+    // Find a file in a directory and return a handle to it.
+    // The path is relative to the directory of the handle.
+    // ### Returns
+    // - The handle on success
+    // - `-1` If the file does not exist
+    // - `-2` If the path string is invalid
+    // - `-4` If the file system of the directory is no longer mounted
+    // - `-5` If the path is a directory
+    // - `-10` If the process can't read the file system
+    // - `-18` If the handle is not a directory handle
+    // - `-22` If the process has reached it's open file limit
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_fs_file_get_at",
+        Closure::<dyn Fn(u32, *const u8) -> i32>::new(move |handle, path| {
+            let (fs_label, dir_id) = match directory_handle(&ctx_f, handle) {
+                Ok(directory) => directory,
+                Err(code) => return code,
             };
-
-            let fs_manager = FsManager::get();
-            let Ok(fs) = fs_manager.get_fs(label) else {
-                return -2;
+            let path = match read_str(&ctx_f, path, "path") {
+                Ok(path) => path,
+                Err(code) => return code,
             };
 
-            let fs_reader = fs.read().expect(&format!(
-                "The lock for file system {}:/ has been poisoned",
-                label
-            ));
-
-            // Remove the label from the path
-            let path = path.split_off(3);
-
-            let Ok(file_id) = fs_reader.get_file(&path) else {
-                return -1;
+            let fs = match FsManager::get().get_fs(fs_label) {
+                Ok(fs) => fs,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            let Ok(fs) = fs.read() else {
+                return fail(
+                    &ctx_f,
+                    HapiError::Internal,
+                    "The file system has been poisoned",
+                );
+            };
+            let dir_path = match fs.directory_path(dir_id) {
+                Ok(dir_path) => dir_path,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
+            drop(fs);
 
-            let file_id = CString::new(file_id.to_string()).unwrap();
-            memory.write(buffer as u32, file_id.as_bytes());
-            0
+            get_file(&ctx_f, fs_label, &format!("{}/{}", dir_path, path))
         })
         .into_js_value(),
     );
@@ -302,40 +244,38 @@ pub fn register_fs_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_fs_file_size
     // Return a file's length
     // ### Returns
-    // - `0` On success
-    // - `-1` if the file does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - The size on success
+    // - `-1` If the file does not exist
+    // - `-4` If the file system of the file is no longer mounted
+    // - `-10` If the process can't read the file system
+    // - `-18` If the handle is not a file handle
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_file_size",
-        Closure::<dyn Fn(u8, *const u8) -> i32>::new(move |fs_label, file_id| {
-            let memory = ctx_f.memory();
-            let Some(file_id) = memory.read_str(file_id as u32) else {
-                return -1;
-            };
-            let Ok(file_id) = Uuid::parse_str(&file_id) else {
-                return -1;
-            };
-            let Ok(fs_label) = FsLabel::from_str(&(fs_label as char).to_string()) else {
-                return -2;
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let (fs_label, file_id) = match file_handle(&ctx_f, handle) {
+                Ok(file) => file,
+                Err(code) => return code,
             };
+            if let Err(code) = check_access(&ctx_f, Capability::FsRead(fs_label)) {
+                return code;
+            }
 
-            let fs_manager = FsManager::get();
-            let Ok(fs) = fs_manager.get_fs(fs_label) else {
-                return -2;
+            let fs = match FsManager::get().get_fs(fs_label) {
+                Ok(fs) => fs,
+                Err(e) => return fail_with(&ctx_f, &e),
             };
-            let fs_reader = fs.read().expect(&format!(
-                "The lock for file system {}:/ has been poisoned",
-                fs_label
-            ));
-
-            let Ok(size) = fs_reader.file_size(file_id) else {
-                return -1;
+            let Ok(fs) = fs.read() else {
+                return fail(
+                    &ctx_f,
+                    HapiError::Internal,
+                    "The file system has been poisoned",
+                );
             };
-
-            size as i32
+            match fs.file_size(file_id) {
+                Ok(size) => size as i32,
+                Err(e) => fail_with(&ctx_f, &e),
+            }
         })
         .into_js_value(),
     );
@@ -344,45 +284,20 @@ pub fn register_fs_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // Write a set amount of bytes to a file
     // ### Returns
     // - `0` On success
-    // - `-1` if the file does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // - `-3` If there is not enough space
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - `-1` If the file does not exist
+    // - `-4` If the file system of the file is no longer mounted
+    // - `-7` If the offset is past the end of the file
+    // - `-10` If the process can't write the file system
+    // - `-18` If the handle is not a file handle
+    // - `-19` If the buffer is invalid
     // ### Safety
     // If the size of the buffer is smaller than the reported, unallocated memory will be read from and can cause UB.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_file_write",
-        Closure::<dyn Fn(u8, *const u8, u32, u32, *const u8) -> i32>::new(
-            move |fs_label, file_id, offset, size, buffer| {
-                let memory = ctx_f.memory();
-                let Some(file_id) = memory.read_str(file_id as u32) else {
-                    return -1;
-                };
-                let Ok(file_id) = Uuid::parse_str(&file_id) else {
-                    return -1;
-                };
-                let Ok(fs_label) = FsLabel::from_str(&(fs_label as char).to_string()) else {
-                    return -2;
-                };
-
-                let fs_manager = FsManager::get();
-                let Ok(fs) = fs_manager.get_fs(fs_label) else {
-                    return -2;
-                };
-                let mut fs_writer = fs.write().expect(&format!(
-                    "The lock for file system {}:/ has been poisoned",
-                    fs_label
-                ));
-
-                let bytes = memory.read(buffer as u32, size);
-
-                let Ok(_) = fs_writer.write(file_id, offset as usize, &bytes) else {
-                    return -3;
-                };
-
-                0
+        Closure::<dyn Fn(u32, u32, u32, *const u8) -> i32>::new(
+            move |handle, offset, size, buffer| {
+                write_file(&ctx_f, handle, offset, size, buffer as u32)
             },
         )
         .into_js_value(),
@@ -392,49 +307,183 @@ pub fn register_fs_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // Read a set amount of bytes from the file and write it to a buffer
     // ### Returns
     // - `0` On success
-    // - `-1` if the file does not exist or if the path is incorrect.
-    // - `-2` If the fs label does not correspond to an active fs
-    // ### Panics
-    // Panics if the filesystem is poisoned.
+    // - `-1` If the file does not exist
+    // - `-4` If the file system of the file is no longer mounted
+    // - `-7` If the range is past the end of the file
+    // - `-10` If the process can't read the file system
+    // - `-18` If the handle is not a file handle
+    // - `-19` If the buffer is invalid
     // ### Safety
     // If the size of the buffer is smaller than the reported, unallocated memory will be written to and can cause UB.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_fs_file_read",
-        Closure::<dyn Fn(u8, *const u8, u32, u32, *mut u8) -> i32>::new(
-            move |fs_label, file_id, offset, size, buffer| {
-                let mut memory = ctx_f.memory();
-                let Some(file_id) = memory.read_str(file_id as u32) else {
-                    return -1;
-                };
-                let Ok(file_id) = Uuid::parse_str(&file_id) else {
-                    return -1;
-                };
-                let Ok(fs_label) = FsLabel::from_str(&(fs_label as char).to_string()) else {
-                    return -2;
-                };
-
-                let fs_manager = FsManager::get();
-                let Ok(fs) = fs_manager.get_fs(fs_label) else {
-                    return -2;
-                };
-                let fs_reader = fs.read().expect(&format!(
-                    "The lock for file system {}:/ has been poisoned",
-                    fs_label
-                ));
-
-                // NOTE(GetAGripGal): We should probably refactor this to not load the entire file in memory each time.
-                // But for now its fine.
-                let Ok(bytes) = fs_reader.read(file_id) else {
-                    return -1;
-                };
-
-                let slice = &bytes[offset as usize..offset as usize + size as usize];
-                memory.write(buffer as u32, slice);
-
-                0
+        Closure::<dyn Fn(u32, u32, u32, *mut u8) -> i32>::new(
+            move |handle, offset, size, buffer| {
+                read_file(&ctx_f, handle, offset, size, buffer as u32)
             },
         )
         .into_js_value(),
     );
 }
+
+/// Write `size` bytes of a buffer to a file at an offset.
+/// Returns `0` or the recorded error code of `hapi_fs_file_write`.
+pub(crate) fn write_file(
+    ctx: &ProcessCtx,
+    handle: u32,
+    offset: u32,
+    size: u32,
+    buffer: u32,
+) -> i32 {
+    let (fs_label, file_id) = match file_handle(ctx, handle) {
+        Ok(file) => file,
+        Err(code) => return code,
+    };
+    if let Err(code) = check_access(ctx, Capability::FsWrite(fs_label)) {
+        return code;
+    }
+
+    let fs = match FsManager::get().get_fs(fs_label) {
+        Ok(fs) => fs,
+        Err(e) => return fail_with(ctx, &e),
+    };
+    let Ok(mut fs) = fs.write() else {
+        return fail(
+            ctx,
+            HapiError::Internal,
+            "The file system has been poisoned",
+        );
+    };
+
+    let bytes = match read_mem(ctx, buffer, size) {
+        Ok(bytes) => bytes,
+        Err(code) => return code,
+    };
+    match fs.write(file_id, offset as usize, &bytes) {
+        Ok(_) => 0,
+        Err(e) => fail_with(ctx, &e),
+    }
+}
+
+/// Read `size` bytes of a file at an offset into a buffer.
+/// Returns `0` or the recorded error code of `hapi_fs_file_read`.
+pub(crate) fn read_file(ctx: &ProcessCtx, handle: u32, offset: u32, size: u32, buffer: u32) -> i32 {
+    let (fs_label, file_id) = match file_handle(ctx, handle) {
+        Ok(file) => file,
+        Err(code) => return code,
+    };
+    if let Err(code) = check_access(ctx, Capability::FsRead(fs_label)) {
+        return code;
+    }
+
+    let fs = match FsManager::get().get_fs(fs_label) {
+        Ok(fs) => fs,
+        Err(e) => return fail_with(ctx, &e),
+    };
+    let Ok(fs) = fs.read() else {
+        return fail(
+            ctx,
+            HapiError::Internal,
+            "The file system has been poisoned",
+        );
+    };
+
+    // NOTE(GetAGripGal): We should probably refactor this to not load the entire file in memory each time.
+    // But for now its fine.
+    let bytes = match fs.read(file_id) {
+        Ok(bytes) => bytes,
+        Err(e) => return fail_with(ctx, &e),
+    };
+
+    let Some(slice) = bytes.get(offset as usize..offset as usize + size as usize) else {
+        return fail(
+            ctx,
+            HapiError::OutOfRange,
+            format!(
+                "Reading {} bytes at {} is past the end of the {} bytes of file {}",
+                size,
+                offset,
+                bytes.len(),
+                file_id
+            ),
+        );
+    };
+    match write_mem(ctx, buffer, slice) {
+        Ok(_) => 0,
+        Err(code) => code,
+    }
+}
+
+/// Find a file on a file system and return a handle to it.
+/// Returns the handle or the recorded error code of `hapi_fs_file_get`.
+fn get_file(ctx: &ProcessCtx, fs_label: FsLabel, path: &str) -> i32 {
+    if let Err(code) = check_access(ctx, Capability::FsRead(fs_label)) {
+        return code;
+    }
+
+    let fs = match FsManager::get().get_fs(fs_label) {
+        Ok(fs) => fs,
+        Err(e) => return fail_with(ctx, &e),
+    };
+    let Ok(fs) = fs.read() else {
+        return fail(
+            ctx,
+            HapiError::Internal,
+            "The file system has been poisoned",
+        );
+    };
+    let file_id = match fs.get_file(path) {
+        Ok(id) => id,
+        Err(_) if fs.get_directory(path).is_ok() => {
+            return fail(
+                ctx,
+                HapiError::IsDirectory,
+                format!("{} is a directory", path),
+            )
+        }
+        Err(e) => return fail_with(ctx, &e),
+    };
+    drop(fs);
+
+    match insert_handle(ctx, KernelObject::File(fs_label, file_id)) {
+        Ok(handle) => handle as i32,
+        Err(code) => code,
+    }
+}
+
+/// Parse a fs label argument
+fn label(ctx: &ProcessCtx, label: u8) -> Result<FsLabel, i32> {
+    let label = (label as char).to_string();
+    FsLabel::from_str(&label).map_err(|e| fail_with(ctx, &e))
+}
+
+/// Check if the process may access a file system
+fn check_access(ctx: &ProcessCtx, capability: Capability) -> Result<(), i32> {
+    match ctx.capabilities().allows(capability) {
+        true => Ok(()),
+        false => Err(fail(
+            ctx,
+            HapiError::PermissionDenied,
+            format!("The process lacks the capability: {:?}", capability),
+        )),
+    }
+}
+
+/// Read a path argument, relative to the working directory unless it has a fs label.
+/// Returns the label and the path on the file system.
+fn resolve_path(ctx: &ProcessCtx, path: *const u8) -> Result<(FsLabel, String), i32> {
+    let mut path = read_str(ctx, path, "path")?;
+
+    // If the path does not contain a fs label, append the dir to the current working directory
+    let label = match FsLabel::extract_from_path(&path) {
+        Ok(label) => label,
+        Err(_) => {
+            path = format!("{}/{}", ctx.cwd(), path);
+            FsLabel::extract_from_path(&path).map_err(|e| fail_with(ctx, &e))?
+        }
+    };
+
+    // Remove the label from the path
+    Ok((label, path.split_off(3)))
+}
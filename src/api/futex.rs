@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
+use wasm_bindgen::closure::Closure;
+
+use super::error::{fail, HapiError};
+
+/// Register the futex api
+pub fn register_futex_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_futex_wait
+    // Block the calling thread until the 32-bit value at the address is woken with `hapi_futex_wake`.
+    // Returns immediately if the value does not equal the expected value.
+    // A negative timeout waits indefinitely.
+    // ### Returns
+    // - `0` If the thread was woken
+    // - `1` If the value did not equal the expected value
+    // - `2` If the timeout elapsed
+    // - `-2` If the address is unaligned or out of bounds, or the memory is not shared
+    // ### Safety
+    // - This blocks the calling thread, so it cannot be used on the main thread of the browser
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_futex_wait",
+        Closure::<dyn Fn(*const i32, i32, i64) -> i32>::new(move |addr, expected, timeout_ns| {
+            // Release the memory before blocking
+            let atomics = ctx_f.memory().atomics();
+            let timeout_ms = match timeout_ns < 0 {
+                true => None,
+                false => Some(timeout_ns as f64 / 1_000_000.0),
+            };
+            match atomics.wait(addr as u32, expected, timeout_ms) {
+                Ok(result) => result as i32,
+                Err(e) => fail(&ctx_f, HapiError::InvalidArgument, e),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_futex_wake
+    // Wake up to `count` threads waiting on the address
+    // ### Returns
+    // - The amount of threads woken
+    // - `-2` If the address is unaligned or out of bounds
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_futex_wake",
+        Closure::<dyn Fn(*const i32, u32) -> i32>::new(move |addr, count| {
+            let atomics = ctx_f.memory().atomics();
+            match atomics.notify(addr as u32, count) {
+                Ok(woken) => woken as i32,
+                Err(e) => fail(&ctx_f, HapiError::InvalidArgument, e),
+            }
+        })
+        .into_js_value(),
+    );
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use honeyos_atomics::{mutex::SpinMutex, rwlock::SpinRwLock};
+use honeyos_fs::FsLabel;
+use honeyos_networking::NetworkingManager;
+use honeyos_process::{
+    context::{ApiModuleBuilder, ProcessCtx},
+    handle::{KernelObject, SELF_HANDLE},
+    ProcessManager,
+};
+use uuid::Uuid;
+use wasm_bindgen::closure::Closure;
+
+use super::error::fail_with;
+
+/// Register the handle api
+pub fn register_handle_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_handle_close
+    // Close a handle and release the object it refers to.
+    // Requests are dropped and pipe ends are closed, processes, files, directories and threads are left as they are.
+    // Closing the handle of a child process forgets it's exit code.
+    // The handle number may be reused by the next object the process opens.
+    // ### Returns
+    // - `0` On success
+    // - `-18` If the handle does not exist
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_handle_close",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let object = match ctx_f.handles().remove(handle) {
+                Ok(object) => object,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            release(&ctx_f, object);
+            0
+        })
+        .into_js_value(),
+    );
+
+    // hapi_handle_type
+    // Get the kind of object a handle refers to.
+    // ### Returns
+    // - `1` For a process, `2` for a network request, `3` for a pipe end, `4` for a file, `5` for a directory and `6` for a thread
+    // - `-18` If the handle does not exist
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_handle_type",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let handles = ctx_f.handles();
+            match handles.get(handle) {
+                Ok(object) => object.kind() as i32,
+                Err(e) => {
+                    drop(handles);
+                    fail_with(&ctx_f, &e)
+                }
+            }
+        })
+        .into_js_value(),
+    );
+}
+
+/// Release the object of a closed handle
+pub(crate) fn release(ctx: &ProcessCtx, object: KernelObject) {
+    match object {
+        KernelObject::Request(id) => release_request(id),
+        KernelObject::Pipe(id) => {
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            process_manager.close_pipe(ctx.pid(), id);
+        }
+        KernelObject::Process(id) => {
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            process_manager.release_exit_code(ctx.pid(), id);
+        }
+        KernelObject::File(..) | KernelObject::Directory(..) | KernelObject::Thread(_) => {}
+    }
+}
+
+/// Drop a request from the networking manager
+pub(crate) fn release_request(id: Uuid) {
+    let networking_manager_lock = NetworkingManager::get();
+    let mut networking_manager = networking_manager_lock.spin_write().unwrap();
+    networking_manager.remove(id);
+}
+
+/// Add an object to the handle table of the process and return the handle.
+/// The object is released if the process has reached it's open file limit.
+/// Fails with the recorded code of [`super::error::HapiError::LimitExceeded`].
+pub(crate) fn insert_handle(ctx: &ProcessCtx, object: KernelObject) -> Result<u32, i32> {
+    match ctx.insert_handle(object.clone()) {
+        Ok(handle) => Ok(handle),
+        Err(e) => {
+            release(ctx, object);
+            Err(fail_with(ctx, &e))
+        }
+    }
+}
+
+/// Check if the process can open `amount` more handles, before creating the objects they refer to.
+/// Fails with the recorded code of [`super::error::HapiError::LimitExceeded`].
+pub(crate) fn check_open_files(ctx: &ProcessCtx, amount: usize) -> Result<(), i32> {
+    ctx.check_open_files(amount).map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the process of a process handle, [`SELF_HANDLE`] being the process itself.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn process_handle(ctx: &ProcessCtx, handle: u32) -> Result<Uuid, i32> {
+    if handle == SELF_HANDLE {
+        return Ok(ctx.pid());
+    }
+    let result = ctx.handles().process(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the request of a request handle.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn request_handle(ctx: &ProcessCtx, handle: u32) -> Result<Uuid, i32> {
+    let result = ctx.handles().request(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the pipe end of a pipe handle.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn pipe_handle(ctx: &ProcessCtx, handle: u32) -> Result<Uuid, i32> {
+    let result = ctx.handles().pipe(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the file of a file handle.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn file_handle(ctx: &ProcessCtx, handle: u32) -> Result<(FsLabel, Uuid), i32> {
+    let result = ctx.handles().file(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the directory of a directory handle.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn directory_handle(ctx: &ProcessCtx, handle: u32) -> Result<(FsLabel, Uuid), i32> {
+    let result = ctx.handles().directory(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
+
+/// Get the thread of a thread handle.
+/// Fails with the recorded code of [`super::error::HapiError::InvalidHandle`].
+pub(crate) fn thread_handle(ctx: &ProcessCtx, handle: u32) -> Result<u32, i32> {
+    let result = ctx.handles().thread(handle);
+    result.map_err(|e| fail_with(ctx, &e))
+}
@@ -1,92 +1,114 @@
 use std::{ffi::CString, sync::Arc};
 
-use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
+use honeyos_klog::log_process;
+use honeyos_process::{
+    capabilities::Capability,
+    context::{ApiModuleBuilder, ProcessCtx},
+};
+use log::Level;
 use wasm_bindgen::closure::Closure;
 use web_sys::js_sys::JSON;
 
+use super::error::{fail, read_str, HapiError};
+
 /// Register the js-console api
 pub fn register_js_console_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_js_console_log_info
-    // Logs a string to the js console as info
+    // Logs a string to the js console and the kernel log as info
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_js_console_log_info",
         Closure::<dyn Fn(*const u8)>::new(move |ptr| {
-            let memory = ctx_f.memory();
-            let string = memory.read_str(ptr as u32);
-            let Some(string) = string else {
+            let Ok(string) = read_str(&ctx_f, ptr, "message") else {
                 log::warn!("PID: {} - Memory: Failed to read string", ctx_f.pid());
                 return;
             };
-            log::info!("PID: {} - {}", ctx_f.pid(), string);
+            log_process(Level::Info, ctx_f.pid(), &string);
         })
         .into_js_value(),
     );
 
     // hapi_js_console_log_warn
-    // Logs a string to the js console as a warning
+    // Logs a string to the js console and the kernel log as a warning
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_js_console_log_warn",
         Closure::<dyn Fn(*const u8)>::new(move |ptr| {
-            let memory = ctx_f.memory();
-            let string = memory.read_str(ptr as u32);
-            let Some(string) = string else {
+            let Ok(string) = read_str(&ctx_f, ptr, "message") else {
                 log::warn!("PID: {} - Memory: Failed to read string", ctx_f.pid());
                 return;
             };
-            log::warn!("PID: {} - {}", ctx_f.pid(), string);
+            log_process(Level::Warn, ctx_f.pid(), &string);
         })
         .into_js_value(),
     );
 
     // hapi_js_console_log_error
-    // Logs a string to the js console as an error
+    // Logs a string to the js console and the kernel log as an error
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_js_console_log_error",
         Closure::<dyn Fn(*const u8)>::new(move |ptr| {
-            let memory = ctx_f.memory();
-            let string = memory.read_str(ptr as u32);
-            let Some(string) = string else {
+            let Ok(string) = read_str(&ctx_f, ptr, "message") else {
                 log::warn!("PID: {} - Memory: Failed to read string", ctx_f.pid());
                 return;
             };
-            log::error!("PID: {} - {}", ctx_f.pid(), string);
+            log_process(Level::Error, ctx_f.pid(), &string);
         })
         .into_js_value(),
     );
 
     // hapi_js_console_eval
+    // Evaluate js code and return the result as a json string.
+    // ### Returns
+    // - The result on success
+    // - NULL On failure, `hapi_last_error_code` is `-2` if the code is invalid or threw,
+    //   or the result can't be converted to json, `-14` if the result could not be allocated
+    //   and `-19` if the code is an invalid pointer
+    // - NULL if the process lacks the js eval capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted_ptr(
         "hapi_js_console_eval",
+        Capability::JsEval,
         Closure::<dyn Fn(*const u8) -> *const u8>::new(move |ptr| {
-            let string = {
-                let memory = ctx_f.memory();
-                let string = memory.read_str(ptr as u32);
-                let Some(string) = string else {
-                    return std::ptr::null();
-                };
-                string
+            let Ok(string) = read_str(&ctx_f, ptr, "code") else {
+                return std::ptr::null();
             };
 
             // Evaluate the js code
-            let result = web_sys::js_sys::eval(&string).unwrap();
-            let Ok(result) = JSON::stringify(&result) else {
-                return std::ptr::null();
+            let result = match web_sys::js_sys::eval(&string) {
+                Ok(result) => result,
+                Err(e) => {
+                    fail(
+                        &ctx_f,
+                        HapiError::InvalidArgument,
+                        format!("The code threw: {:?}", e),
+                    );
+                    return std::ptr::null();
+                }
             };
-            let Some(result) = result.as_string() else {
+            let result = JSON::stringify(&result)
+                .ok()
+                .and_then(|result| result.as_string());
+            let Some(cstring) = result.and_then(|result| CString::new(result).ok()) else {
+                fail(
+                    &ctx_f,
+                    HapiError::InvalidArgument,
+                    "The result can't be converted to json",
+                );
                 return std::ptr::null();
             };
 
             // Return the result as a string
             let mut memory = ctx_f.memory();
-            let Ok(cstring) = CString::new(result) else {
-                return std::ptr::null();
-            };
             let bytes = cstring.as_bytes_with_nul();
             let Some(ptr) = memory.alloc(bytes.len() as u32) else {
+                drop(memory);
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    "Failed to allocate the result",
+                );
                 return std::ptr::null();
             };
 
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use honeyos_klog::KernelLog;
+use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
+use log::LevelFilter;
+use wasm_bindgen::closure::Closure;
+
+use super::error::{fail, write_mem, HapiError};
+
+/// Register the kernel log api
+pub fn register_klog_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_klog_read
+    // Read the records of the kernel log from the sequence number `from` into the buffer, like dmesg.
+    // Each record is written as a line like `[seconds since boot] LEVEL subsystem pid: message`,
+    // only whole lines are written unless a single line does not fit in the buffer. The output is not null-terminated.
+    // Only records at or above the level are read, the levels are:
+    // - `1` Error
+    // - `2` Warn
+    // - `3` Info
+    // - `4` Debug
+    // - `5` Trace
+    // The kernel log keeps the last records only, older records are skipped.
+    // The sequence number to continue reading from is written to `next_out`, unless null.
+    // ### Returns
+    // - The amount of bytes written, `0` if there are no new records
+    // - `-2` If the level is invalid
+    // - `-19` If the buffer or the out pointer is invalid
+    // ### Safety
+    // - The buffer size must be at least `len` bytes or unallocated memory will be written to.
+    // - The out pointer must be valid for 8 bytes or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_klog_read",
+        Closure::<dyn Fn(u64, u32, *mut u8, u32, *mut u64) -> i32>::new(
+            move |from, level, buffer, len, next_out: *mut u64| {
+                let level = match level {
+                    1 => LevelFilter::Error,
+                    2 => LevelFilter::Warn,
+                    3 => LevelFilter::Info,
+                    4 => LevelFilter::Debug,
+                    5 => LevelFilter::Trace,
+                    level => {
+                        return fail(
+                            &ctx_f,
+                            HapiError::InvalidArgument,
+                            format!("Invalid log level: {}", level),
+                        )
+                    }
+                };
+
+                let (data, next) = KernelLog::get().read(from, level, len as usize);
+                if let Err(code) = write_mem(&ctx_f, buffer as u32, &data) {
+                    return code;
+                }
+                if !next_out.is_null() {
+                    if let Err(code) = write_mem(&ctx_f, next_out as u32, &next.to_le_bytes()) {
+                        return code;
+                    }
+                }
+                data.len() as i32
+            },
+        )
+        .into_js_value(),
+    );
+
+    // hapi_klog_end
+    // Get the sequence number the next record of the kernel log will be assigned.
+    // Reading from it only reads records logged after this call, like `dmesg --follow-new`.
+    builder.register(
+        "hapi_klog_end",
+        Closure::<dyn Fn() -> u64>::new(move || KernelLog::get().end()).into_js_value(),
+    );
+}
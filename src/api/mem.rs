@@ -1,21 +1,68 @@
 use std::{os::raw::c_void, sync::Arc};
 
-use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
+use honeyos_atomics::mutex::SpinMutex;
+use honeyos_process::{
+    context::{ApiModuleBuilder, ProcessCtx},
+    memory::MemoryStats,
+    ProcessManager,
+};
 use wasm_bindgen::closure::Closure;
 
+use super::{
+    error::{fail, write_mem, HapiError},
+    handle::process_handle,
+};
+
 /// Register the memory api
 pub fn register_mem_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_mem_alloc
     // Allocate a block of memory and return it's pointer.
     // ### Returns
     // - The pointer to the block
-    // - `NULL` if the memory allocation failed
+    // - `NULL` if the memory allocation failed, `hapi_last_error_code` is `-14`
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_mem_alloc",
         Closure::<dyn Fn(u32) -> *mut c_void>::new(move |size| {
-            let mut memory = ctx_f.memory();
-            let Some(ptr) = memory.alloc(size) else {
+            let ptr = ctx_f.memory().alloc(size);
+            let Some(ptr) = ptr else {
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    format!("Failed to allocate {} bytes", size),
+                );
+                return std::ptr::null_mut();
+            };
+            ptr as *mut c_void
+        })
+        .into_js_value(),
+    );
+
+    // hapi_mem_alloc_aligned
+    // Allocate a block of memory aligned to `align` bytes and return it's pointer.
+    // The alignment must be a power of two. Blocks are always aligned to at least 8 bytes.
+    // ### Returns
+    // - The pointer to the block
+    // - `NULL` if the alignment is invalid or the memory allocation failed, `hapi_last_error_code` is `-2` or `-14`
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_mem_alloc_aligned",
+        Closure::<dyn Fn(u32, u32) -> *mut c_void>::new(move |size, align: u32| {
+            if !align.is_power_of_two() {
+                fail(
+                    &ctx_f,
+                    HapiError::InvalidArgument,
+                    format!("The alignment {} is not a power of two", align),
+                );
+                return std::ptr::null_mut();
+            }
+            let ptr = ctx_f.memory().alloc_aligned(size, align);
+            let Some(ptr) = ptr else {
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    format!("Failed to allocate {} bytes", size),
+                );
                 return std::ptr::null_mut();
             };
             ptr as *mut c_void
@@ -25,15 +72,25 @@ pub fn register_mem_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
 
     // hapi_mem_realloc
     // Reallocate a block of memory and return the new pointer.
+    // The block is resized in place if possible, otherwise the contents are moved to a new block and the old one is freed.
+    // Behaves like `hapi_mem_alloc` if the pointer is `NULL`.
     // ### Returns
     // - The pointer to the block
-    // - `NULL` if the memory allocation failed
+    // - `NULL` if the memory allocation failed, `hapi_last_error_code` is `-14`
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_mem_realloc",
-        Closure::<dyn Fn(*mut c_void, u32) -> *mut c_void>::new(move |ptr, size| {
-            let mut memory = ctx_f.memory();
-            let Some(new_ptr) = memory.realloc(ptr as u32, size) else {
+        Closure::<dyn Fn(*mut c_void, u32) -> *mut c_void>::new(move |ptr: *mut c_void, size| {
+            let new_ptr = match ptr.is_null() {
+                true => ctx_f.memory().alloc(size),
+                false => ctx_f.memory().realloc(ptr as u32, size),
+            };
+            let Some(new_ptr) = new_ptr else {
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    format!("Failed to allocate {} bytes", size),
+                );
                 return std::ptr::null_mut();
             };
             new_ptr as *mut c_void
@@ -42,14 +99,144 @@ pub fn register_mem_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     );
 
     // hapi_mem_free
-    // Free a block of memory
+    // Free a block of memory. Does nothing if the pointer is `NULL`.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_mem_free",
-        Closure::<dyn Fn(*mut c_void)>::new(move |ptr| {
+        Closure::<dyn Fn(*mut c_void)>::new(move |ptr: *mut c_void| {
+            if ptr.is_null() {
+                return;
+            }
             let mut memory = ctx_f.memory();
             memory.free(ptr as u32);
         })
         .into_js_value(),
     );
+
+    // hapi_mem_stats
+    // Write the memory usage of the process to the buffer.
+    // The buffer is laid out as follows, in little endian:
+    // - `u64` The amount of bytes allocated through `hapi_mem_*`
+    // - `u64` The highest amount of bytes allocated at once
+    // - `u32` The amount of pages the memory spans
+    // - `u32` The highest amount of pages the memory spanned
+    // - `u32` The maximum amount of pages the memory can grow to, `0` if unlimited
+    // - `u32` Padding
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer must be at least 32 bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_mem_stats",
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
+            let stats = ctx_f.memory().stats();
+            match write_mem(&ctx_f, buffer as u32, &stats_bytes(&stats)) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_mem_stats
+    // Write the memory usage of a process to the buffer, laid out like `hapi_mem_stats`.
+    // The page count of other processes is the last one observed by the kernel.
+    // The handle may be `0`, in which case the memory usage of this process is written.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer must be at least 32 bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_mem_stats",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |handle, buffer| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+
+            let stats = match id == ctx_f.pid() {
+                true => ctx_f.memory().stats(),
+                false => {
+                    let process_manager_lock = ProcessManager::get();
+                    let process_manager = process_manager_lock.spin_lock().unwrap();
+                    let Some(process) = process_manager.process(id) else {
+                        return fail(
+                            &ctx_f,
+                            HapiError::NotFound,
+                            format!("No such process: {}", id),
+                        );
+                    };
+                    let ctx = process.ctx();
+                    let stats = ctx.memory_nospin().cached_stats();
+                    stats
+                }
+            };
+
+            match write_mem(&ctx_f, buffer as u32, &stats_bytes(&stats)) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_mem_stats_total
+    // Write the memory usage of all processes to the buffer.
+    // The buffer is laid out as follows, in little endian:
+    // - `u64` The amount of bytes allocated through `hapi_mem_*`
+    // - `u32` The amount of pages the memory of all processes spans
+    // - `u32` The amount of processes
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer must be at least 16 bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_mem_stats_total",
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
+            let (allocated, pages, processes) = {
+                let process_manager_lock = ProcessManager::get();
+                let process_manager = process_manager_lock.spin_lock().unwrap();
+                let mut allocated = 0u64;
+                let mut pages = 0u32;
+                let mut processes = 0u32;
+                for process in process_manager.processes() {
+                    let ctx = process.ctx();
+                    let stats = ctx.memory_nospin().cached_stats();
+                    allocated += stats.allocated;
+                    pages = pages.saturating_add(stats.pages);
+                    processes += 1;
+                }
+                (allocated, pages, processes)
+            };
+
+            let mut bytes = Vec::with_capacity(16);
+            bytes.extend_from_slice(&allocated.to_le_bytes());
+            bytes.extend_from_slice(&pages.to_le_bytes());
+            bytes.extend_from_slice(&processes.to_le_bytes());
+            match write_mem(&ctx_f, buffer as u32, &bytes) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+}
+
+/// Lay out the memory stats as described in `hapi_mem_stats`
+fn stats_bytes(stats: &MemoryStats) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    bytes[0..8].copy_from_slice(&stats.allocated.to_le_bytes());
+    bytes[8..16].copy_from_slice(&stats.peak_allocated.to_le_bytes());
+    bytes[16..20].copy_from_slice(&stats.pages.to_le_bytes());
+    bytes[20..24].copy_from_slice(&stats.peak_pages.to_le_bytes());
+    bytes[24..28].copy_from_slice(&stats.maximum_pages.unwrap_or(0).to_le_bytes());
+    bytes
 }
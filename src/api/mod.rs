@@ -3,38 +3,137 @@
 //! This is done by a callback that gets called everytime a process gets initialized.
 pub mod browser;
 pub mod display;
+pub mod error;
 pub mod fs;
+pub mod futex;
+pub mod handle;
 pub mod js;
+pub mod klog;
 pub mod mem;
 pub mod network;
+pub mod pipe;
 pub mod process;
+pub mod ring;
 pub mod thread;
 pub mod time;
+pub mod version;
+pub mod wasi;
 
 use std::sync::Arc;
 
-use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
+use honeyos_process::context::{ApiBuilderFn, ApiModuleBuilder, ProcessCtx};
 use wasm_bindgen::closure::Closure;
 
 use self::{
-    browser::register_browser_api, display::register_display_api, fs::register_fs_api,
-    js::register_js_console_api, mem::register_mem_api, network::register_network_api,
-    process::register_process_api, thread::register_thread_api, time::register_time_api,
+    browser::register_browser_api, display::register_display_api, error::register_error_api,
+    fs::register_fs_api, futex::register_futex_api, handle::register_handle_api,
+    js::register_js_console_api, klog::register_klog_api, mem::register_mem_api,
+    network::register_network_api, pipe::register_pipe_api, process::register_process_api,
+    ring::register_ring_api, thread::register_thread_api, time::register_time_api,
+    version::register_version_api, wasi::register_wasi_api,
 };
+use error::{check_mem, fail, read_str, write_mem, HapiError};
+
+/// The parts of the api with the feature they're discovered by through `hapi_has_feature`
+const API: &[(&str, ApiBuilderFn)] = &[
+    ("error", register_error_api),
+    ("handle", register_handle_api),
+    ("js-console", register_js_console_api),
+    ("klog", register_klog_api),
+    ("stdin", register_stdin_api),
+    ("stdout", register_stdout_api),
+    ("stderr", register_stderr_api),
+    ("display", register_display_api),
+    ("time", register_time_api),
+    ("process", register_process_api),
+    ("pipe", register_pipe_api),
+    ("browser", register_browser_api),
+    ("mem", register_mem_api),
+    ("network", register_network_api),
+    ("fs", register_fs_api),
+    ("thread", register_thread_api),
+    ("futex", register_futex_api),
+    ("ring", register_ring_api),
+    ("wasi", register_wasi_api),
+];
 
 /// Register the api.
 /// This gets called for every process that gets initialized
 pub fn register_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
-    register_js_console_api(ctx.clone(), builder);
-    register_stdout_api(ctx.clone(), builder);
-    register_display_api(ctx.clone(), builder);
-    register_time_api(ctx.clone(), builder);
-    register_process_api(ctx.clone(), builder);
-    register_browser_api(ctx.clone(), builder);
-    register_mem_api(ctx.clone(), builder);
-    register_network_api(ctx.clone(), builder);
-    register_fs_api(ctx.clone(), builder);
-    register_thread_api(ctx.clone(), builder);
+    for (feature, register) in API {
+        builder.feature(*feature);
+        register(ctx.clone(), builder);
+    }
+    // Registered last, so the feature registry holds everything installed above
+    register_version_api(ctx, builder);
+}
+
+/// Register the stdin api
+fn register_stdin_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_stdin_read
+    // Read up to `len` bytes from the process's stdin into the buffer without blocking.
+    // ### Returns
+    // - The amount of bytes read, `0` if no input is available
+    // - `-11` If stdin has been closed and all input has been read
+    // - `-19` If the buffer is invalid, the input is not consumed
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_stdin_read",
+        Closure::<dyn Fn(*mut u8, u32) -> i32>::new(move |buffer, len| {
+            if let Err(code) = check_mem(&ctx_f, buffer as u32, len) {
+                return code;
+            }
+            let stdin = ctx_f.stdin();
+            let data = stdin.read(len as usize);
+            if data.is_empty() && stdin.is_eof() {
+                return fail(&ctx_f, HapiError::Closed, "Stdin has been closed");
+            }
+            match write_mem(&ctx_f, buffer as u32, &data) {
+                Ok(_) => data.len() as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_stdin_read_blocking
+    // Read up to `len` bytes from the process's stdin into the buffer.
+    // Blocks until at least one byte is available.
+    // ### Returns
+    // - The amount of bytes read
+    // - `-11` If stdin has been closed and all input has been read
+    // - `-19` If the buffer is invalid, the input is not consumed
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_stdin_read_blocking",
+        Closure::<dyn Fn(*mut u8, u32) -> i32>::new(move |buffer, len| {
+            if let Err(code) = check_mem(&ctx_f, buffer as u32, len) {
+                return code;
+            }
+            let stdin = ctx_f.stdin();
+            let data = stdin.read_blocking(len as usize);
+            if data.is_empty() && stdin.is_eof() {
+                return fail(&ctx_f, HapiError::Closed, "Stdin has been closed");
+            }
+            match write_mem(&ctx_f, buffer as u32, &data) {
+                Ok(_) => data.len() as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_stdin_length
+    // Get the amount of bytes available to be read from stdin
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_stdin_length",
+        Closure::<dyn Fn() -> u32>::new(move || ctx_f.stdin().len() as u32).into_js_value(),
+    );
 }
 
 /// Register the stdout api
@@ -73,17 +172,70 @@ fn register_stdout_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
         .into_js_value(),
     );
 
+    // stdout_set_retention
+    // Set the maximum amount of bytes retained in the process's stdout.
+    // The oldest output is discarded once the limit is reached.
+    // The retention is capped at the stdout retention limit of the process.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_stdout_set_retention",
+        Closure::<dyn Fn(u32)>::new(move |bytes: u32| {
+            let bytes = match ctx_f.limits().stdout_retention {
+                Some(limit) => bytes.min(limit),
+                None => bytes,
+            };
+            let stdout = ctx_f.stdout();
+            stdout.set_max_retention(bytes as usize);
+        })
+        .into_js_value(),
+    );
+
     // stdout_write
+    // Write a string to the process's stdout.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the string is not null-terminated
+    // - `-11` If stdout is piped and the read end of the pipe has been closed
+    // - `-19` If the string is outside the memory of the process
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_stdout_write",
-        Closure::<dyn Fn(*const u8)>::new(move |ptr: *const u8| {
-            let stdout = ctx_f.stdout();
-            let string = ctx_f.memory().read_str(ptr as u32);
-            let Some(string) = string else {
-                return;
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |ptr: *const u8| {
+            let string = match read_str(&ctx_f, ptr, "string") {
+                Ok(string) => string,
+                Err(code) => return code,
+            };
+            match ctx_f.stdout().write(string) {
+                Ok(_) => 0,
+                Err(e) => fail(&ctx_f, HapiError::Closed, e),
+            }
+        })
+        .into_js_value(),
+    );
+}
+
+/// Register the stderr api
+fn register_stderr_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // stderr_write
+    // Write a string to the process's stderr.
+    // Diagnostics should be written here, so they can be told apart from the regular output.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the string is not null-terminated
+    // - `-11` If stderr is piped and the read end of the pipe has been closed
+    // - `-19` If the string is outside the memory of the process
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_stderr_write",
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |ptr: *const u8| {
+            let string = match read_str(&ctx_f, ptr, "string") {
+                Ok(string) => string,
+                Err(code) => return code,
             };
-            stdout.write(string).unwrap();
+            match ctx_f.stderr().write(string) {
+                Ok(_) => 0,
+                Err(e) => fail(&ctx_f, HapiError::Closed, e),
+            }
         })
         .into_js_value(),
     );
@@ -1,112 +1,172 @@
-use std::{ffi::CString, str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use honeyos_atomics::rwlock::SpinRwLock;
 use honeyos_networking::{
     request::{RequestMethod, RequestMode, RequestStatus},
     NetworkingManager,
 };
-use honeyos_process::context::{ApiModuleBuilder, ProcessCtx};
-use uuid::Uuid;
+use honeyos_process::{
+    capabilities::Capability,
+    context::{ApiModuleBuilder, ProcessCtx},
+    handle::KernelObject,
+};
 use wasm_bindgen::closure::Closure;
 
+use super::{
+    error::{fail, fail_with, read_str, HapiError},
+    handle::{check_open_files, insert_handle, release_request, request_handle},
+};
+
 /// Register the network api
 pub fn register_network_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_network_request
+    // Create a cross-origin request and return it's handle.
+    // The request is dropped when the handle is closed.
+    // ### Returns
+    // - The handle on success
+    // - `-2` If a parameter is invalid
+    // - `-12` If the request could not be made
+    // - `-22` If the process has reached it's open file limit
+    // - `-23` If the process has reached it's limit of outstanding requests
+    // - `-10` If the process lacks the network capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_network_request",
-        Closure::<dyn Fn(*const u8, u32, *const u8) -> *const u8>::new(
-            move |url, method, headers| {
-                // Read params
-                let mut memory = ctx_f.memory();
-                let url = memory.read_str(url as u32);
-                let Some(url) = url else {
-                    return std::ptr::null();
-                };
-                let Ok(method) = RequestMethod::try_from(method) else {
-                    return std::ptr::null();
-                };
-                let headers = memory.read_str(headers as u32);
-                let Some(headers) = headers else {
-                    return std::ptr::null();
-                };
-
-                // Setup request
-                let networking_manager_lock = NetworkingManager::get();
-                let mut networking_manager = networking_manager_lock.spin_write().unwrap();
-                let id = networking_manager.request(url, method, RequestMode::Cors, headers);
-
-                // Write id to memory
-                let id = id.to_string();
-                let Some(id_ptr) = memory.alloc(id.len() as u32) else {
-                    return std::ptr::null();
-                };
-                let cstring = CString::new(id).unwrap();
-                memory.write(id_ptr, cstring.as_bytes());
-
-                id_ptr as *const u8
-            },
-        )
+        Capability::Network,
+        Closure::<dyn Fn(*const u8, u32, *const u8) -> i32>::new(move |url, method, headers| {
+            // Read params
+            let url = match read_str(&ctx_f, url, "url") {
+                Ok(url) => url,
+                Err(code) => return code,
+            };
+            let method = match RequestMethod::try_from(method) {
+                Ok(method) => method,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            let headers = match read_str(&ctx_f, headers, "headers") {
+                Ok(headers) => headers,
+                Err(code) => return code,
+            };
+
+            // Setup request, the handle has to fit in the open file limit
+            if let Err(code) = check_open_files(&ctx_f, 1) {
+                return code;
+            }
+            let networking_manager_lock = NetworkingManager::get();
+            let mut networking_manager = networking_manager_lock.spin_write().unwrap();
+            let limit = ctx_f.limits().network_requests.map(|limit| limit as usize);
+            let id = match networking_manager.request(
+                ctx_f.pid(),
+                limit,
+                url,
+                method,
+                RequestMode::Cors,
+                headers,
+            ) {
+                Ok(id) => id,
+                Err(e) => {
+                    log::warn!("Process `{}` failed to create request: {}", ctx_f.pid(), e);
+                    return fail_with(&ctx_f, &e);
+                }
+            };
+            drop(networking_manager);
+
+            match insert_handle(&ctx_f, KernelObject::Request(id)) {
+                Ok(handle) => handle as i32,
+                Err(code) => code,
+            }
+        })
         .into_js_value(),
     );
 
     // hapi_network_request_local
+    // Create a same-origin request and return it's handle.
+    // The request is dropped when the handle is closed.
+    // ### Returns
+    // - The handle on success
+    // - `-2` If a parameter is invalid
+    // - `-12` If the request could not be made
+    // - `-22` If the process has reached it's open file limit
+    // - `-23` If the process has reached it's limit of outstanding requests
+    // - `-10` If the process lacks the network capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_network_request_local",
-        Closure::<dyn Fn(*const u8, u32, *const u8) -> *const u8>::new(
-            move |url, method, headers| {
-                // Read params
-                let mut memory = ctx_f.memory();
-                let url = memory.read_str(url as u32);
-                let Some(url) = url else {
-                    return std::ptr::null();
-                };
-                let Ok(method) = RequestMethod::try_from(method) else {
-                    return std::ptr::null();
-                };
-                let headers = memory.read_str(headers as u32);
-                let Some(headers) = headers else {
-                    return std::ptr::null();
-                };
-
-                // Setup request
-                let networking_manager_lock = NetworkingManager::get();
-                let mut networking_manager = networking_manager_lock.spin_write().unwrap();
-                let id = networking_manager.request(url, method, RequestMode::SameOrigin, headers);
-
-                // Write id to memory
-                let id = id.to_string();
-                let Some(id_ptr) = memory.alloc(id.len() as u32) else {
-                    return std::ptr::null();
-                };
-                let cstring = CString::new(id).unwrap();
-                memory.write(id_ptr, cstring.as_bytes());
-
-                id_ptr as *const u8
-            },
-        )
+        Capability::Network,
+        Closure::<dyn Fn(*const u8, u32, *const u8) -> i32>::new(move |url, method, headers| {
+            // Read params
+            let url = match read_str(&ctx_f, url, "url") {
+                Ok(url) => url,
+                Err(code) => return code,
+            };
+            let method = match RequestMethod::try_from(method) {
+                Ok(method) => method,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            let headers = match read_str(&ctx_f, headers, "headers") {
+                Ok(headers) => headers,
+                Err(code) => return code,
+            };
+
+            // Setup request, the handle has to fit in the open file limit
+            if let Err(code) = check_open_files(&ctx_f, 1) {
+                return code;
+            }
+            let networking_manager_lock = NetworkingManager::get();
+            let mut networking_manager = networking_manager_lock.spin_write().unwrap();
+            let limit = ctx_f.limits().network_requests.map(|limit| limit as usize);
+            let id = match networking_manager.request(
+                ctx_f.pid(),
+                limit,
+                url,
+                method,
+                RequestMode::SameOrigin,
+                headers,
+            ) {
+                Ok(id) => id,
+                Err(e) => {
+                    log::warn!("Process `{}` failed to create request: {}", ctx_f.pid(), e);
+                    return fail_with(&ctx_f, &e);
+                }
+            };
+            drop(networking_manager);
+
+            match insert_handle(&ctx_f, KernelObject::Request(id)) {
+                Ok(handle) => handle as i32,
+                Err(code) => code,
+            }
+        })
         .into_js_value(),
     );
 
     // hapi_network_request_status
+    // Get the status of a request.
+    // ### Returns
+    // - `0` If the request is processing
+    // - `1` If the request succeeded
+    // - `2` If the request failed
+    // - `3` If the request is pending
+    // - `-1` If the request does not exist
+    // - `-18` If the handle is not a request handle
+    // - `-10` If the process lacks the network capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_network_request_status",
-        Closure::<dyn Fn(*const u8) -> i32>::new(move |id| {
-            let memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return -1;
-            };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return -1;
+        Capability::Network,
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match request_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
             };
 
             let networking_manager_lock = NetworkingManager::get();
             let networking_manager = networking_manager_lock.spin_read().unwrap();
             let Some(status) = networking_manager.status(id) else {
-                return -1;
+                return fail(
+                    &ctx_f,
+                    HapiError::NotFound,
+                    format!("No such request: {}", id),
+                );
             };
             match status {
                 RequestStatus::Processing => 0,
@@ -122,24 +182,27 @@ pub fn register_network_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // Check the lenght of the data in bytes.
     // ### Returns
     // - The data length on success
-    // - -1 if the request does not exist.
+    // - `-1` If the request has no data yet
+    // - `-18` If the handle is not a request handle
+    // - `-10` If the process lacks the network capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_network_request_data_length",
-        Closure::<dyn Fn(*const u8) -> i32>::new(move |id| {
-            let memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return -1;
-            };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return -1;
+        Capability::Network,
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match request_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
             };
 
             let networking_manager_lock = NetworkingManager::get();
             let networking_manager = networking_manager_lock.spin_read().unwrap();
             let Some(length) = networking_manager.data_length(id) else {
-                return -1;
+                return fail(
+                    &ctx_f,
+                    HapiError::NotFound,
+                    format!("No data for request: {}", id),
+                );
             };
 
             length as i32
@@ -151,29 +214,37 @@ pub fn register_network_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // Check the data in a request
     // ### Returns
     // - The data on success
-    // - NULL if the request does not exist,
-    // - NULL if the request has failed or is still pending,
-    // - NULL if the memory allocation failed.
+    // - NULL On failure, `hapi_last_error_code` is `-1` if the request has failed or is still pending,
+    //   `-18` if the handle is not a request handle and `-14` if the data could not be allocated
+    // - NULL if the process lacks the network capability
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted_ptr(
         "hapi_network_request_data",
-        Closure::<dyn Fn(*const u8) -> *const u8>::new(move |id| {
-            let mut memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return std::ptr::null();
-            };
-            let Ok(id) = Uuid::from_str(&id) else {
+        Capability::Network,
+        Closure::<dyn Fn(u32) -> *const u8>::new(move |handle| {
+            let Ok(id) = request_handle(&ctx_f, handle) else {
                 return std::ptr::null();
             };
 
             let networking_manager_lock = NetworkingManager::get();
             let networking_manager = networking_manager_lock.spin_read().unwrap();
             let Some(data) = networking_manager.data(id) else {
+                fail(
+                    &ctx_f,
+                    HapiError::NotFound,
+                    format!("No data for request: {}", id),
+                );
                 return std::ptr::null();
             };
 
+            let mut memory = ctx_f.memory();
             let Some(ptr) = memory.alloc(data.len() as u32) else {
+                drop(memory);
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    "Failed to allocate the request data",
+                );
                 return std::ptr::null();
             };
             memory.write(ptr, &data);
@@ -184,24 +255,18 @@ pub fn register_network_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     );
 
     // hapi_network_request_drop
-    // Drop the request from memory.
-    // Does nothing if the request does not exist
+    // Drop the request from memory and close it's handle.
+    // Does nothing if the handle is not a request handle
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_network_request_drop",
-        Closure::<dyn Fn(*const u8)>::new(move |id| {
-            let memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
+        Capability::Network,
+        Closure::<dyn Fn(u32)>::new(move |handle| {
+            let Ok(id) = request_handle(&ctx_f, handle) else {
                 return;
             };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return;
-            };
-
-            let networking_manager_lock = NetworkingManager::get();
-            let mut networking_manager = networking_manager_lock.spin_write().unwrap();
-            networking_manager.remove(id);
+            ctx_f.handles().remove(handle).ok();
+            release_request(id);
         })
         .into_js_value(),
     );
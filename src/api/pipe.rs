@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use honeyos_atomics::mutex::SpinMutex;
+use honeyos_process::{
+    context::{ApiModuleBuilder, ProcessCtx},
+    handle::KernelObject,
+    pipe::{PipeEnd, DEFAULT_PIPE_CAPACITY},
+    ProcessManager,
+};
+use wasm_bindgen::closure::Closure;
+
+use super::{
+    error::{check_mem, fail, fail_with, read_mem, write_mem, HapiError},
+    handle::{check_open_files, insert_handle, pipe_handle, release},
+};
+
+/// Register the pipe api
+pub fn register_pipe_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_pipe_create
+    // Create an anonymous pipe.
+    // Writes the handle of the read end and the write end to the provided pointers.
+    // The pipe ends are owned by the process and get closed once it exits, or when their handle is closed.
+    // Both pipe ends count towards the open file limit of the process.
+    // ### Returns
+    // - `0` On success
+    // - `-19` If one of the pointers is invalid
+    // - `-22` If the process has reached it's open file limit
+    // ### Safety
+    // - Both pointers must point to at least 4 bytes or unallocated memory will be written to
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_pipe_create",
+        Closure::<dyn Fn(*mut u32, *mut u32) -> i32>::new(move |read_out, write_out| {
+            if let Err(code) = check_mem(&ctx_f, read_out as u32, 4)
+                .and_then(|_| check_mem(&ctx_f, write_out as u32, 4))
+            {
+                return code;
+            }
+            if let Err(code) = check_open_files(&ctx_f, 2) {
+                return code;
+            }
+            let (reader, writer) = {
+                let process_manager_lock = ProcessManager::get();
+                let mut process_manager = process_manager_lock.spin_lock().unwrap();
+                process_manager.create_pipe(ctx_f.pid(), DEFAULT_PIPE_CAPACITY)
+            };
+
+            // Another thread may have opened a handle in the meantime, closing both ends if it did
+            let reader = match insert_handle(&ctx_f, KernelObject::Pipe(reader)) {
+                Ok(reader) => reader,
+                Err(code) => {
+                    release(&ctx_f, KernelObject::Pipe(writer));
+                    return code;
+                }
+            };
+            let writer = match insert_handle(&ctx_f, KernelObject::Pipe(writer)) {
+                Ok(writer) => writer,
+                Err(code) => {
+                    let object = ctx_f.handles().remove(reader);
+                    if let Ok(object) = object {
+                        release(&ctx_f, object);
+                    }
+                    return code;
+                }
+            };
+            match write_mem(&ctx_f, read_out as u32, &reader.to_le_bytes())
+                .and_then(|_| write_mem(&ctx_f, write_out as u32, &writer.to_le_bytes()))
+            {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_pipe_read
+    // Read up to `len` bytes from the read end of a pipe without blocking.
+    // ### Returns
+    // - The amount of bytes read, `0` if no data is available
+    // - `-1` If the handle is not the read end of a pipe
+    // - `-11` If all write ends have been closed and all data has been read
+    // - `-18` If the handle is not a pipe handle
+    // - `-19` If the buffer is invalid, no data is consumed
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_pipe_read",
+        Closure::<dyn Fn(u32, *mut u8, u32) -> i32>::new(move |handle, buffer, len| {
+            if let Err(code) = check_mem(&ctx_f, buffer as u32, len) {
+                return code;
+            }
+            let reader = match pipe_end(&ctx_f, handle) {
+                Ok(PipeEnd::Reader(reader)) => reader,
+                Ok(_) => return not_a_pipe_end(&ctx_f, "read"),
+                Err(code) => return code,
+            };
+            let data = reader.read(len as usize);
+            if data.is_empty() && reader.is_eof() {
+                return fail(
+                    &ctx_f,
+                    HapiError::Closed,
+                    "All write ends of the pipe have been closed",
+                );
+            }
+            match write_mem(&ctx_f, buffer as u32, &data) {
+                Ok(_) => data.len() as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_pipe_read_blocking
+    // Read up to `len` bytes from the read end of a pipe.
+    // Blocks until data is available or all write ends have been closed.
+    // ### Returns
+    // - The amount of bytes read
+    // - `-1` If the handle is not the read end of a pipe
+    // - `-11` If all write ends have been closed and all data has been read
+    // - `-18` If the handle is not a pipe handle
+    // - `-19` If the buffer is invalid, no data is consumed
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_pipe_read_blocking",
+        Closure::<dyn Fn(u32, *mut u8, u32) -> i32>::new(move |handle, buffer, len| {
+            if let Err(code) = check_mem(&ctx_f, buffer as u32, len) {
+                return code;
+            }
+            let reader = match pipe_end(&ctx_f, handle) {
+                Ok(PipeEnd::Reader(reader)) => reader,
+                Ok(_) => return not_a_pipe_end(&ctx_f, "read"),
+                Err(code) => return code,
+            };
+            let data = reader.read_blocking(len as usize);
+            if data.is_empty() && reader.is_eof() {
+                return fail(
+                    &ctx_f,
+                    HapiError::Closed,
+                    "All write ends of the pipe have been closed",
+                );
+            }
+            match write_mem(&ctx_f, buffer as u32, &data) {
+                Ok(_) => data.len() as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_pipe_write
+    // Write `len` bytes to the write end of a pipe.
+    // Blocks while the pipe is full.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the handle is not the write end of a pipe
+    // - `-11` If the write end or all read ends have been closed
+    // - `-18` If the handle is not a pipe handle
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be read from.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_pipe_write",
+        Closure::<dyn Fn(u32, *const u8, u32) -> i32>::new(move |handle, buffer, len| {
+            let writer = match pipe_end(&ctx_f, handle) {
+                Ok(PipeEnd::Writer(writer)) => writer,
+                Ok(_) => return not_a_pipe_end(&ctx_f, "write"),
+                Err(code) => return code,
+            };
+            let data = match read_mem(&ctx_f, buffer as u32, len) {
+                Ok(data) => data,
+                Err(code) => return code,
+            };
+            match writer.write_blocking(&data) {
+                Ok(_) => 0,
+                Err(e) => fail_with(&ctx_f, &e),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_pipe_close
+    // Close a pipe end owned by the process and it's handle.
+    // Once all write ends are closed, readers reach the end of the stream.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the pipe end has already been closed
+    // - `-18` If the handle is not a pipe handle
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_pipe_close",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match pipe_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            ctx_f.handles().remove(handle).ok();
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            match process_manager.close_pipe(ctx_f.pid(), id) {
+                true => 0,
+                false => fail(
+                    &ctx_f,
+                    HapiError::NotFound,
+                    format!("No such pipe end: {}", id),
+                ),
+            }
+        })
+        .into_js_value(),
+    );
+}
+
+/// Get a clone of the pipe end of a pipe handle.
+/// The end is cloned so the process manager isn't locked while blocking on it.
+/// Fails with the recorded code of the error.
+pub(crate) fn pipe_end(ctx: &ProcessCtx, handle: u32) -> Result<PipeEnd, i32> {
+    let id = pipe_handle(ctx, handle)?;
+    let process_manager_lock = ProcessManager::get();
+    let process_manager = process_manager_lock.spin_lock().unwrap();
+    process_manager.pipe(ctx.pid(), id).cloned().ok_or_else(|| {
+        fail(
+            ctx,
+            HapiError::NotFound,
+            format!("No such pipe end: {}", id),
+        )
+    })
+}
+
+/// Fail a call on a pipe end of the wrong kind
+pub(crate) fn not_a_pipe_end(ctx: &ProcessCtx, kind: &str) -> i32 {
+    fail(
+        ctx,
+        HapiError::NotFound,
+        format!("The handle is not the {} end of a pipe", kind),
+    )
+}
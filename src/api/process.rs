@@ -1,43 +1,124 @@
-use std::{ffi::CString, str::FromStr, sync::Arc};
+use std::{ffi::CString, sync::Arc};
 
+use hashbrown::HashMap;
 use honeyos_atomics::mutex::SpinMutex;
 use honeyos_process::{
+    capabilities::{Capabilities, Capability},
     context::{ApiModuleBuilder, ProcessCtx},
-    ProcessManager,
+    handle::KernelObject,
+    limits::Resource,
+    pipe::PipeEnd,
+    requirements::WasmRequirements,
+    trace::ProcessTrace,
+    ProcessManager, SpawnOptions,
 };
 use uuid::Uuid;
 use wasm_bindgen::closure::Closure;
 
+use super::{
+    error::{check_mem, fail, fail_with, read_id, read_mem, read_str, write_mem, HapiError},
+    handle::{insert_handle, process_handle},
+    pipe::{not_a_pipe_end, pipe_end},
+};
+
 /// Register the process api
 pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     // hapi_process_get_pid
     // Write the proccess id to the buffer
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
     // ### Safety
     // - The buffer size must be at least 37-bytes or unallocated memory will be written to.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_get_pid",
-        Closure::<dyn Fn(*const u8)>::new(move |buffer| {
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |buffer| {
             let pid = ctx_f.pid().to_string();
-            let mut memory = ctx_f.memory();
             let cstring = CString::new(pid).unwrap();
-            memory.write(buffer as u32, &cstring.as_bytes());
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_open
+    // Open a handle to a running process by it's id, like one written by `hapi_process_id`.
+    // ### Returns
+    // - The handle on success
+    // - `-1` If the process does not exist
+    // - `-2` If the id is invalid
+    // - `-22` If the process has reached it's open file limit
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_open",
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |id| {
+            let id = match read_id(&ctx_f, id, "process id") {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+
+            let exists = {
+                let process_manager_lock = ProcessManager::get();
+                let process_manager = process_manager_lock.spin_lock().unwrap();
+                process_manager.process(id).is_some()
+            };
+            if !exists {
+                return no_such_process(&ctx_f, id);
+            }
+            match insert_handle(&ctx_f, KernelObject::Process(id)) {
+                Ok(handle) => handle as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_id
+    // Write the id of the process of a process handle to the buffer as a null-terminated string.
+    // The handle may be `0`, in which case the id of this process is written.
+    // ### Returns
+    // - `0` On success
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer size must be at least 37-bytes or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_id",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |handle, buffer| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            let cstring = CString::new(id.to_string()).unwrap();
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
         })
         .into_js_value(),
     );
 
     // hapi_process_get_cwd
     // Write the current working directory to the buffer
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
     // ### Safety
     // - The buffer size must be at least the size of `hapi_process_get_cwd_length` or unallocated memory will be written to.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_get_cwd",
-        Closure::<dyn Fn(*mut u8)>::new(move |buffer| {
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
             let cwd = ctx_f.cwd().clone();
-            let mut memory = ctx_f.memory();
             let cstring = CString::new(cwd).unwrap();
-            memory.write(buffer as u32, &cstring.as_bytes());
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
         })
         .into_js_value(),
     );
@@ -62,14 +143,14 @@ pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // - The dir string must be a valid string or unallocated memory will be written to
     // ### Returns
     // - `0` On success
-    // - `-1` If the path is invalid
+    // - `-2` If the path is invalid
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_set_cwd",
         Closure::<dyn Fn(*const u8) -> i32>::new(move |dir| {
-            let memory = ctx_f.memory();
-            let Some(path) = memory.read_str(dir as u32) else {
-                return -1;
+            let path = match read_str(&ctx_f, dir, "path") {
+                Ok(path) => path,
+                Err(code) => return code,
             };
 
             ctx_f.set_cwd(&path);
@@ -80,74 +161,441 @@ pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
 
     // hapi_process_spawn_subprocess
     // Spawn a wasm binary as a subprocess.
-    // Writes the pid of the process to the provided buffer, unless null.
+    // The subprocess inherits the environment variables of this process.
+    // Writes the handle of the process to the provided pointer, unless null.
     // ### Safety
-    // - The provided buffer must be at least 37-bytes of length or unallocated memory will be written to
+    // - The provided pointer must point to at least 4 bytes or unallocated memory will be written to
     // ### Returns
     // - `0` On success
-    // - `-1` On failure
+    // - `-10` If the process lacks the spawn capability
+    // - `-19` If the binary, an array or the out pointer is invalid
+    // - `-21` If the subprocess requires more memory than it's memory limit allows
+    // - `-22` If the process has reached it's open file limit, as the subprocess takes up a handle
+    // - `-30` If the binary is not a valid wasm module
+    // - `-31` If the binary imports functions the api does not provide
+    // - `-32` If the binary does not export it's entrypoint
+    // - `-33` If the manifest of the binary is invalid
+    // - `-34` If the memory of the subprocess could not be created
+    // - `-35` If a library the binary needs is not found in the `LIBRARY_PATH`
+    // - `-36` If a library the binary needs is invalid
+    // - `-37` If the binary requires a newer or incompatible version of the api
+    // `hapi_process_spawn_error` describes why spawning the binary failed
     let ctx_f = ctx.clone();
-    builder.register(
+    builder.register_restricted(
         "hapi_process_spawn_subprocess",
-        Closure::<dyn Fn(*const u8, u32, *mut u8) -> i32>::new(move |bin, bin_len, pid_out| {
-            let mut memory = ctx_f.memory();
-            let wasm_bin = memory.read(bin as u32, bin_len);
+        Capability::Spawn,
+        Closure::<dyn Fn(*const u8, u32, *mut u32) -> i32>::new(move |bin, bin_len, pid_out| {
+            let options = SpawnOptions {
+                env: ctx_f.env(),
+                ..Default::default()
+            };
+            spawn_subprocess(&ctx_f, bin, bin_len, options, pid_out)
+        })
+        .into_js_value(),
+    );
 
-            let process_manager_lock = ProcessManager::get();
-            let mut process_manager = process_manager_lock.spin_lock().unwrap();
-            let cwd = ctx_f.cwd();
-            let pid = match process_manager.spawn(wasm_bin, None, &cwd) {
-                Ok(pid) => pid,
-                Err(e) => {
-                    log::error!("Failed to spawn subprocess: {}", e);
-                    return -1;
-                }
+    // hapi_process_spawn_subprocess_with_args
+    // Spawn a wasm binary as a subprocess with command-line arguments.
+    // The arguments are passed as an array of `argc` null-terminated strings.
+    // The subprocess inherits the environment variables of this process.
+    // Writes the handle of the process to the provided pointer, unless null.
+    // ### Safety
+    // - The provided pointer must point to at least 4 bytes or unallocated memory will be written to
+    // - `argv` must point to at least `argc` string pointers or unallocated memory will be read from
+    // ### Returns
+    // - `0` On success
+    // - `-2` If one of the arguments is not a valid string
+    // - `-10` If the process lacks the spawn capability
+    // - `-19` If the binary, an array or the out pointer is invalid
+    // - `-21` If the subprocess requires more memory than it's memory limit allows
+    // - `-22` If the process has reached it's open file limit, as the subprocess takes up a handle
+    // - `-30` If the binary is not a valid wasm module
+    // - `-31` If the binary imports functions the api does not provide
+    // - `-32` If the binary does not export it's entrypoint
+    // - `-33` If the manifest of the binary is invalid
+    // - `-34` If the memory of the subprocess could not be created
+    // - `-35` If a library the binary needs is not found in the `LIBRARY_PATH`
+    // - `-36` If a library the binary needs is invalid
+    // - `-37` If the binary requires a newer or incompatible version of the api
+    // `hapi_process_spawn_error` describes why spawning the binary failed
+    let ctx_f = ctx.clone();
+    builder.register_restricted(
+        "hapi_process_spawn_subprocess_with_args",
+        Capability::Spawn,
+        Closure::<dyn Fn(*const u8, u32, *const *const u8, u32, *mut u32) -> i32>::new(
+            move |bin, bin_len, argv, argc, pid_out| {
+                let args = match read_str_array(&ctx_f, argv, argc, "argument") {
+                    Ok(args) => args,
+                    Err(code) => return code,
+                };
+                let options = SpawnOptions {
+                    args,
+                    env: ctx_f.env(),
+                    ..Default::default()
+                };
+                spawn_subprocess(&ctx_f, bin, bin_len, options, pid_out)
+            },
+        )
+        .into_js_value(),
+    );
+
+    // hapi_process_spawn_subprocess_with_env
+    // Spawn a wasm binary as a subprocess with command-line arguments and additional environment variables.
+    // The arguments are passed as an array of `argc` null-terminated strings.
+    // The environment variables are passed as an array of `envc` null-terminated `KEY=VALUE` strings,
+    // which are added on top of the inherited environment of this process.
+    // Writes the handle of the process to the provided pointer, unless null.
+    // ### Safety
+    // - The provided pointer must point to at least 4 bytes or unallocated memory will be written to
+    // - `argv` must point to at least `argc` string pointers or unallocated memory will be read from
+    // - `envp` must point to at least `envc` string pointers or unallocated memory will be read from
+    // ### Returns
+    // - `0` On success
+    // - `-2` If one of the arguments or environment variables is not a valid string or `KEY=VALUE` string
+    // - `-10` If the process lacks the spawn capability
+    // - `-19` If the binary, an array or the out pointer is invalid
+    // - `-21` If the subprocess requires more memory than it's memory limit allows
+    // - `-22` If the process has reached it's open file limit, as the subprocess takes up a handle
+    // - `-30` If the binary is not a valid wasm module
+    // - `-31` If the binary imports functions the api does not provide
+    // - `-32` If the binary does not export it's entrypoint
+    // - `-33` If the manifest of the binary is invalid
+    // - `-34` If the memory of the subprocess could not be created
+    // - `-35` If a library the binary needs is not found in the `LIBRARY_PATH`
+    // - `-36` If a library the binary needs is invalid
+    // - `-37` If the binary requires a newer or incompatible version of the api
+    // `hapi_process_spawn_error` describes why spawning the binary failed
+    let ctx_f = ctx.clone();
+    builder.register_restricted(
+        "hapi_process_spawn_subprocess_with_env",
+        Capability::Spawn,
+        Closure::<
+            dyn Fn(*const u8, u32, *const *const u8, u32, *const *const u8, u32, *mut u32) -> i32,
+        >::new(move |bin, bin_len, argv, argc, envp, envc, pid_out| {
+            let args = match read_str_array(&ctx_f, argv, argc, "argument") {
+                Ok(args) => args,
+                Err(code) => return code,
+            };
+            let vars = match read_str_array(&ctx_f, envp, envc, "environment variable") {
+                Ok(vars) => vars,
+                Err(code) => return code,
             };
 
-            if pid_out == std::ptr::null_mut() {
-                return 0;
+            let mut env = ctx_f.env();
+            for var in vars {
+                let Some((key, value)) = var.split_once('=') else {
+                    return fail(
+                        &ctx_f,
+                        HapiError::InvalidArgument,
+                        format!(
+                            "The environment variable is not a KEY=VALUE string: {}",
+                            var
+                        ),
+                    );
+                };
+                env.insert(key.to_string(), value.to_string());
             }
 
-            let pid = pid.to_string();
-            let cstring = CString::new(pid).unwrap();
-            memory.write(pid_out as u32, &cstring.as_bytes());
+            let options = SpawnOptions {
+                args,
+                env,
+                ..Default::default()
+            };
+            spawn_subprocess(&ctx_f, bin, bin_len, options, pid_out)
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_spawn_subprocess_piped
+    // Spawn a wasm binary as a subprocess with command-line arguments, connecting it's stdin, stdout and stderr to pipes.
+    // `stdin` must be the handle of a pipe read end, `stdout` and `stderr` the handles of pipe write ends.
+    // Any handle may be `0`, in which case the subprocess gets it's own stream.
+    // The subprocess receives it's own copy of the pipe ends, so this process should close it's ends once it no longer needs them.
+    // Writes the handle of the process to the provided pointer, unless null.
+    // ### Safety
+    // - The provided pointer must point to at least 4 bytes or unallocated memory will be written to
+    // - `argv` must point to at least `argc` string pointers or unallocated memory will be read from
+    // ### Returns
+    // - `0` On success
+    // - `-1` If one of the pipe handles is not a pipe end of the right kind
+    // - `-2` If one of the arguments is not a valid string
+    // - `-10` If the process lacks the spawn capability
+    // - `-18` If one of the pipe handles is not a pipe handle
+    // - `-19` If the binary, an array or the out pointer is invalid
+    // - `-21` If the subprocess requires more memory than it's memory limit allows
+    // - `-22` If the process has reached it's open file limit, as the subprocess takes up a handle
+    // - `-30` If the binary is not a valid wasm module
+    // - `-31` If the binary imports functions the api does not provide
+    // - `-32` If the binary does not export it's entrypoint
+    // - `-33` If the manifest of the binary is invalid
+    // - `-34` If the memory of the subprocess could not be created
+    // - `-35` If a library the binary needs is not found in the `LIBRARY_PATH`
+    // - `-36` If a library the binary needs is invalid
+    // - `-37` If the binary requires a newer or incompatible version of the api
+    // `hapi_process_spawn_error` describes why spawning the binary failed
+    let ctx_f = ctx.clone();
+    builder.register_restricted(
+        "hapi_process_spawn_subprocess_piped",
+        Capability::Spawn,
+        Closure::<
+            dyn Fn(
+                *const u8,
+                u32,
+                *const *const u8,
+                u32,
+                u32,
+                u32,
+                u32,
+                *mut u32,
+            ) -> i32,
+        >::new(
+            move |bin,
+                  bin_len,
+                  argv,
+                  argc,
+                  stdin: u32,
+                  stdout: u32,
+                  stderr: u32,
+                  pid_out| {
+                let args = match read_str_array(&ctx_f, argv, argc, "argument") {
+                    Ok(args) => args,
+                    Err(code) => return code,
+                };
+
+                let stdin = match stdin {
+                    0 => None,
+                    stdin => match pipe_end(&ctx_f, stdin) {
+                        Ok(PipeEnd::Reader(reader)) => Some(reader),
+                        Ok(_) => return not_a_pipe_end(&ctx_f, "read"),
+                        Err(code) => return code,
+                    },
+                };
+                let stdout = match stdout {
+                    0 => None,
+                    stdout => match pipe_end(&ctx_f, stdout) {
+                        Ok(PipeEnd::Writer(writer)) => Some(writer),
+                        Ok(_) => return not_a_pipe_end(&ctx_f, "write"),
+                        Err(code) => return code,
+                    },
+                };
+                let stderr = match stderr {
+                    0 => None,
+                    stderr => match pipe_end(&ctx_f, stderr) {
+                        Ok(PipeEnd::Writer(writer)) => Some(writer),
+                        Ok(_) => return not_a_pipe_end(&ctx_f, "write"),
+                        Err(code) => return code,
+                    },
+                };
+
+                let options = SpawnOptions {
+                    args,
+                    env: ctx_f.env(),
+                    stdin,
+                    stdout,
+                    stderr,
+                    ..Default::default()
+                };
+                spawn_subprocess(&ctx_f, bin, bin_len, options, pid_out)
+            },
+        )
+        .into_js_value(),
+    );
+
+    // hapi_process_args_count
+    // Get the amount of command-line arguments passed to the process
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_args_count",
+        Closure::<dyn Fn() -> u32>::new(move || ctx_f.args().len() as u32).into_js_value(),
+    );
+
+    // hapi_process_args_length
+    // Get the string length of the argument at the index, including the null terminator
+    // ### Returns
+    // - The length on success
+    // - `-7` If the index is out of range
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_args_length",
+        Closure::<dyn Fn(u32) -> i32>::new(move |index| {
+            let args = ctx_f.args();
+            let Some(arg) = args.get(index as usize) else {
+                return argument_out_of_range(&ctx_f, index);
+            };
+            arg.len() as i32 + 1
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_args_get
+    // Write the argument at the index to the buffer
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_process_args_length` or unallocated memory will be written to.
+    // ### Returns
+    // - `0` On success
+    // - `-7` If the index is out of range
+    // - `-19` If the buffer is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_args_get",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |index, buffer| {
+            let args = ctx_f.args();
+            let Some(arg) = args.get(index as usize) else {
+                return argument_out_of_range(&ctx_f, index);
+            };
+            let cstring = CString::new(arg.as_str()).unwrap_or_default();
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_env_get_length
+    // Get the string length of an environment variable, including the null terminator
+    // ### Returns
+    // - The length on success
+    // - `-1` If the variable is not set
+    // - `-2` If the key is not a valid string
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_env_get_length",
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |key| {
+            let key = match read_str(&ctx_f, key, "key") {
+                Ok(key) => key,
+                Err(code) => return code,
+            };
+            let Some(value) = ctx_f.env_var(&key) else {
+                return variable_not_set(&ctx_f, &key);
+            };
+            value.len() as i32 + 1
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_env_get
+    // Write the value of an environment variable to the buffer
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_process_env_get_length` or unallocated memory will be written to.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the variable is not set
+    // - `-2` If the key is not a valid string
+    // - `-19` If the buffer is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_env_get",
+        Closure::<dyn Fn(*const u8, *mut u8) -> i32>::new(move |key, buffer| {
+            let key = match read_str(&ctx_f, key, "key") {
+                Ok(key) => key,
+                Err(code) => return code,
+            };
+            let Some(value) = ctx_f.env_var(&key) else {
+                return variable_not_set(&ctx_f, &key);
+            };
+            let cstring = CString::new(value).unwrap_or_default();
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_env_set
+    // Set an environment variable for this process.
+    // Removes the variable if the value is null.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the key or value is not a valid string, or the key is empty or contains a `=`
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_env_set",
+        Closure::<dyn Fn(*const u8, *const u8) -> i32>::new(move |key, value: *const u8| {
+            let key = match read_str(&ctx_f, key, "key") {
+                Ok(key) => key,
+                Err(code) => return code,
+            };
+            let value = match value.is_null() {
+                true => None,
+                false => match read_str(&ctx_f, value, "value") {
+                    Ok(value) => Some(value),
+                    Err(code) => return code,
+                },
+            };
+            if key.is_empty() || key.contains('=') {
+                return fail(
+                    &ctx_f,
+                    HapiError::InvalidArgument,
+                    format!("The key is empty or contains a `=`: {}", key),
+                );
+            }
+
+            ctx_f.set_env_var(&key, value.as_deref());
             0
         })
         .into_js_value(),
     );
 
+    // hapi_process_env_list_length
+    // Get the size of the buffer needed for `hapi_process_env_list`
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_env_list_length",
+        Closure::<dyn Fn() -> u32>::new(move || env_list(&ctx_f.env()).len() as u32)
+            .into_js_value(),
+    );
+
+    // hapi_process_env_list
+    // Write all environment variables to the buffer as consecutive null-terminated `KEY=VALUE` strings.
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_process_env_list_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_env_list",
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
+            let list = env_list(&ctx_f.env());
+            match write_mem(&ctx_f, buffer as u32, &list) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
     // hapi_process_stdout
     // Write the stoud of a process to a buffer
     // ### Safety
     // - The out buffer must be equal to `hapi_process_stdout_length` or unallocated memory will be written to.
-    // - The id must be at least 37-bytes in length and a valid string or unallocated memory will be read from.
     // ### Returns
     // - `0` On success
-    // - `-1` on failure
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_stdout",
-        Closure::<dyn Fn(*const u8, *mut u8) -> i32>::new(move |id, out_buffer| {
-            let mut memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return -1;
-            };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return -1;
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |handle, out_buffer| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
             };
 
             let process_manager_lock = ProcessManager::get();
             let mut process_manager = process_manager_lock.spin_lock().unwrap();
             let Some(process) = process_manager.process_mut(id) else {
-                return -1;
+                return no_such_process(&ctx_f, id);
             };
 
-            let stdout = process.stdout();
-            stdout.sync();
-            let buffer = stdout.buffer();
-            memory.write(out_buffer as u32, &buffer.as_bytes());
-            0
+            let buffer = process.stdout().bytes();
+            match write_mem(&ctx_f, out_buffer as u32, &buffer) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
         })
         .into_js_value(),
     );
@@ -155,33 +603,269 @@ pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // hapi_process_stdout_length
     // Returns the current length of the stdout buffer
     // ### Returns
-    // - `0` On success
-    // - `-1` If the id cannot be read from memory
+    // - The length on success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
     // ### Safety
-    // - The id must be at least 37-bytes in length and a valid string or unallocated memory will be read from.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_stdout_length",
-        Closure::<dyn Fn(*const u8) -> i32>::new(move |id| {
-            let memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return -1;
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
             };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return -1;
+
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process_mut(id) else {
+                return no_such_process(&ctx_f, id);
+            };
+
+            process.stdout().len() as i32
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stdout_read
+    // Read up to `len` bytes of the stdout of a process, starting at an offset.
+    // Offsets increase monotonically, so a reader can keep a cursor and only read new output.
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be written to.
+    // ### Returns
+    // - The amount of bytes read
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-7` If the offset is before `hapi_process_stdout_start`, as the output was cleared or discarded since
+    // - `-19` If the buffer is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stdout_read",
+        Closure::<dyn Fn(u32, u64, *mut u8, u32) -> i32>::new(
+            move |handle, from_offset, buffer, len| {
+                let id = match process_handle(&ctx_f, handle) {
+                    Ok(id) => id,
+                    Err(code) => return code,
+                };
+
+                let stdout = {
+                    let process_manager_lock = ProcessManager::get();
+                    let process_manager = process_manager_lock.spin_lock().unwrap();
+                    let Some(process) = process_manager.process(id) else {
+                        return no_such_process(&ctx_f, id);
+                    };
+                    process.stdout()
+                };
+
+                let Some(data) = stdout.read(from_offset, len as usize) else {
+                    return fail(
+                        &ctx_f,
+                        HapiError::OutOfRange,
+                        format!(
+                            "The offset {} is before the start of the output: {}",
+                            from_offset,
+                            stdout.start()
+                        ),
+                    );
+                };
+                match write_mem(&ctx_f, buffer as u32, &data) {
+                    Ok(_) => data.len() as i32,
+                    Err(code) => code,
+                }
+            },
+        )
+        .into_js_value(),
+    );
+
+    // hapi_process_stdout_start
+    // Get the offset of the first byte retained in the stdout of a process.
+    // The start moves forward when old output is discarded or the output is cleared.
+    // ### Safety
+    // ### Returns
+    // - The offset on success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stdout_start",
+        Closure::<dyn Fn(u32) -> i64>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code as i64,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(id) else {
+                return no_such_process(&ctx_f, id) as i64;
+            };
+            process.stdout().start() as i64
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stdout_end
+    // Get the offset after the last byte written to the stdout of a process
+    // ### Safety
+    // ### Returns
+    // - The offset on success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stdout_end",
+        Closure::<dyn Fn(u32) -> i64>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code as i64,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(id) else {
+                return no_such_process(&ctx_f, id) as i64;
+            };
+            process.stdout().end() as i64
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stderr
+    // Write the stderr of a process to a buffer
+    // ### Safety
+    // - The out buffer must be equal to `hapi_process_stderr_length` or unallocated memory will be written to.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stderr",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |handle, out_buffer| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process_mut(id) else {
+                return no_such_process(&ctx_f, id);
+            };
+
+            let buffer = process.stderr().bytes();
+            match write_mem(&ctx_f, out_buffer as u32, &buffer) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stderr_length
+    // Returns the current length of the stderr buffer
+    // ### Returns
+    // - The length on success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // ### Safety
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stderr_length",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
             };
 
             let process_manager_lock = ProcessManager::get();
             let mut process_manager = process_manager_lock.spin_lock().unwrap();
             let Some(process) = process_manager.process_mut(id) else {
-                return -1;
+                return no_such_process(&ctx_f, id);
             };
 
-            let stdout = process.stdout();
-            stdout.sync();
-            let buffer = stdout.buffer();
-            buffer.len() as i32
+            process.stderr().len() as i32
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stdin_write
+    // Write bytes to the stdin of a process.
+    // Only the parent of the process can write to it's stdin, others write to the pipe it reads from.
+    // ### Safety
+    // - The buffer must be at least `len` bytes in size or unallocated memory will be read from.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process does not exist
+    // - `-10` If this process is not the parent of the process
+    // - `-11` If the stdin of the process has been closed
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stdin_write",
+        Closure::<dyn Fn(u32, *const u8, u32) -> i32>::new(move |handle, buffer, len| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+            let data = match read_mem(&ctx_f, buffer as u32, len) {
+                Ok(data) => data,
+                Err(code) => return code,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(id) else {
+                return no_such_process(&ctx_f, id);
+            };
+            if process.ctx().parent() != Some(ctx_f.pid()) {
+                return not_the_parent(&ctx_f, id);
+            }
+
+            match process.stdin().write(&data) {
+                Ok(_) => 0,
+                Err(_) => fail(
+                    &ctx_f,
+                    HapiError::Closed,
+                    "The stdin of the process has been closed",
+                ),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_stdin_close
+    // Close the stdin of a process.
+    // The process can still read the remaining input, after which reads report the end of the stream.
+    // Only the parent of the process can close it's stdin.
+    // ### Safety
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process does not exist
+    // - `-10` If this process is not the parent of the process
+    // - `-18` If the handle is not a process handle
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_stdin_close",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(id) else {
+                return no_such_process(&ctx_f, id);
+            };
+            if process.ctx().parent() != Some(ctx_f.pid()) {
+                return not_the_parent(&ctx_f, id);
+            }
+
+            process.stdin().close();
+            0
         })
         .into_js_value(),
     );
@@ -189,18 +873,13 @@ pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
     // hapi_process_alive
     // Returns true if the process is alive
     // ### Safety
-    // - The id must be at least 37-bytes in length and a valid string or unallocated memory will be read from.
     let ctx_f = ctx.clone();
     builder.register(
         "hapi_process_alive",
-        Closure::<dyn Fn(*const u8) -> i32>::new(move |id| {
-            let memory = ctx_f.memory();
-            let id = memory.read_str(id as u32);
-            let Some(id) = id else {
-                return 0;
-            };
-            let Ok(id) = Uuid::from_str(&id) else {
-                return 0;
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(_) => return 0,
             };
 
             let process_manager_lock = ProcessManager::get();
@@ -213,4 +892,541 @@ pub fn register_process_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder
         })
         .into_js_value(),
     );
+
+    // hapi_process_getrlimit
+    // Get the limit of a resource of the process.
+    // The resources are:
+    // - `0` The amount of threads alive at once, excluding the main thread
+    // - `1` The amount of pages the memory can grow to
+    // - `2` The amount of handles and files open at once
+    // - `3` The amount of network requests that have not finished yet
+    // - `4` The amount of bytes the stdout and stderr retain
+    // ### Returns
+    // - The limit on success
+    // - `-1` If the resource is unlimited
+    // - `-2` If the resource is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_getrlimit",
+        Closure::<dyn Fn(u32) -> i64>::new(move |resource| {
+            let resource = match Resource::try_from(resource) {
+                Ok(resource) => resource,
+                Err(e) => return fail_with(&ctx_f, &e) as i64,
+            };
+            match ctx_f.limits().get(resource) {
+                Some(limit) => limit as i64,
+                None => -1,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_setrlimit
+    // Set the limit of a resource of the process, see `hapi_process_getrlimit` for the resources.
+    // A negative limit makes the resource unlimited.
+    // Unprivileged processes can only lower their limits.
+    // Lowering a limit does not reclaim resources already in use.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the resource is invalid
+    // - `-10` If the process is not permitted to raise the limit
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_setrlimit",
+        Closure::<dyn Fn(u32, i64) -> i32>::new(move |resource, limit: i64| {
+            let resource = match Resource::try_from(resource) {
+                Ok(resource) => resource,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            let limit = match limit {
+                limit if limit < 0 => None,
+                limit => Some(limit.min(u32::MAX as i64) as u32),
+            };
+            match ctx_f.set_limit(resource, limit) {
+                Ok(_) => 0,
+                Err(e) => fail_with(&ctx_f, &e),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_exit_code
+    // Take the exit code of a process that exited through `proc_exit` or by returning from it's entry point.
+    // The code can only be taken once. It's kept until the parent takes it, closes it's handle to the process or exits.
+    // ### Returns
+    // - `0` If the exit code was written to `code_out`
+    // - `1` If the process is still running
+    // - `-1` If the process does not exist or has no exit code, for example because it was killed
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the out pointer is invalid, the exit code is kept
+    // ### Safety
+    // - The out pointer must be valid for 4 bytes or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_exit_code",
+        Closure::<dyn Fn(u32, *mut i32) -> i32>::new(move |handle, code_out| {
+            let id = match process_handle(&ctx_f, handle) {
+                Ok(id) => id,
+                Err(code) => return code,
+            };
+
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            if process_manager.process(id).is_some() {
+                return 1;
+            }
+            if let Err(code) = check_mem(&ctx_f, code_out as u32, 4) {
+                return code;
+            }
+            let Some(code) = process_manager.take_exit_code(id) else {
+                return fail(
+                    &ctx_f,
+                    HapiError::NotFound,
+                    format!("The process has no exit code: {}", id),
+                );
+            };
+
+            match write_mem(&ctx_f, code_out as u32, &code.to_le_bytes()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_crash_info_length
+    // Get the size of the buffer needed for `hapi_process_crash_info`.
+    // ### Returns
+    // - The size of the crash report as a null-terminated json string
+    // - `-1` If the process did not crash, or it's report is no longer kept
+    // - `-18` If the handle is not a process handle
+    // ### Safety
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_crash_info_length",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| match read_crash_info(&ctx_f, handle) {
+            Ok(info) => info.as_bytes_with_nul().len() as i32,
+            Err(code) => code,
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_crash_info
+    // Write the report of a crashed process to the buffer as a null-terminated json string.
+    // The report holds the error the process died of, it's stack trace, the exported globals,
+    // the tail of it's stdout and stderr, it's manifest and the path of it's crash dump.
+    // A dump is written when the process has a `CRASH_DUMP_DIR` it may write to.
+    // The reports of the last crashed processes are kept after they die.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process did not crash, or it's report is no longer kept
+    // - `-18` If the handle is not a process handle
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_process_crash_info_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_crash_info",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |handle, buffer| {
+            match read_crash_info(&ctx_f, handle) {
+                Ok(info) => match write_mem(&ctx_f, buffer as u32, info.as_bytes_with_nul()) {
+                    Ok(_) => 0,
+                    Err(code) => code,
+                },
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_set_tracing
+    // Start or stop tracing the api calls of a process.
+    // While tracing, each call is recorded with it's arguments, return value and duration until read with `hapi_process_trace_read`.
+    // Processes spawned with `HAPI_TRACE=1` in their environment are traced from the start.
+    // A process may only trace itself and the subprocesses it spawned.
+    // The handle may be `0`, in which case this process is traced.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-10` If the other process is not a subprocess of this process
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_set_tracing",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |handle, enabled| {
+            match process_trace(&ctx_f, handle) {
+                Ok(trace) => {
+                    trace.set_enabled(enabled != 0);
+                    0
+                }
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_trace_read
+    // Read the oldest traced api calls of a process into the buffer, removing them from it's trace buffer.
+    // Each call is written as a line like `[tid] name(args) = result <duration>`, only whole lines are written
+    // unless a single line does not fit in the buffer. The output is not null-terminated.
+    // The handle may be `0`, in which case the calls of this process are read.
+    // ### Returns
+    // - The amount of bytes written, `0` if there are no traced calls
+    // - `-1` If the process does not exist
+    // - `-18` If the handle is not a process handle
+    // - `-10` If the other process is not a subprocess of this process
+    // - `-19` If the buffer is invalid, no calls are removed
+    // ### Safety
+    // - The buffer size must be at least `len` bytes or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_trace_read",
+        Closure::<dyn Fn(u32, *mut u8, u32) -> i32>::new(move |handle, buffer, len| {
+            match process_trace(&ctx_f, handle) {
+                Ok(trace) => {
+                    if let Err(code) = check_mem(&ctx_f, buffer as u32, len) {
+                        return code;
+                    }
+                    let data = trace.read(len as usize);
+                    match write_mem(&ctx_f, buffer as u32, &data) {
+                        Ok(_) => data.len() as i32,
+                        Err(code) => code,
+                    }
+                }
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_manifest_length
+    // Get the size of the buffer needed for `hapi_process_manifest`, without running the binary.
+    // ### Returns
+    // - The size of the manifest as a null-terminated json string
+    // - `-1` If the binary has no manifest
+    // - `-2` If the binary or it's manifest is invalid
+    // - `-19` If the binary is invalid
+    // ### Safety
+    // - The binary must be at least `bin_len` bytes in size or unallocated memory will be read from.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_manifest_length",
+        Closure::<dyn Fn(*const u8, u32) -> i32>::new(move |bin, bin_len| {
+            match read_manifest(&ctx_f, bin, bin_len) {
+                Ok(manifest) => manifest.len() as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_manifest
+    // Write the manifest of a binary to the buffer as a null-terminated json string, without running the binary.
+    // Launchers use this to show what a binary is and which capabilities it requests before spawning it.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the binary has no manifest
+    // - `-2` If the binary or it's manifest is invalid
+    // - `-19` If the binary or the buffer is invalid
+    // ### Safety
+    // - The binary must be at least `bin_len` bytes in size or unallocated memory will be read from.
+    // - The buffer size must be at least the size of `hapi_process_manifest_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_manifest",
+        Closure::<dyn Fn(*const u8, u32, *mut u8) -> i32>::new(move |bin, bin_len, buffer| {
+            match read_manifest(&ctx_f, bin, bin_len) {
+                Ok(manifest) => match write_mem(&ctx_f, buffer as u32, &manifest) {
+                    Ok(_) => 0,
+                    Err(code) => code,
+                },
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_spawn_error_length
+    // Get the size of the buffer needed for `hapi_process_spawn_error`.
+    // ### Returns
+    // - The size of the message as a null-terminated string
+    // - `-1` If the last spawn of this process did not fail
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_spawn_error_length",
+        Closure::<dyn Fn() -> i32>::new(move || match ctx_f.spawn_error() {
+            Some(error) => error.len() as i32 + 1,
+            None => no_spawn_error(&ctx_f),
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_spawn_error
+    // Write the message describing why the last spawn of this process failed to the buffer as a null-terminated string.
+    // The message is cleared by the next successful spawn.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the last spawn of this process did not fail
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_process_spawn_error_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_spawn_error",
+        Closure::<dyn Fn(*mut u8) -> i32>::new(move |buffer| {
+            let Some(error) = ctx_f.spawn_error() else {
+                return no_spawn_error(&ctx_f);
+            };
+            let cstring = CString::new(error).unwrap_or_default();
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_capabilities
+    // Get the capabilities of the process as a bitmask.
+    // Bits `0..26` grant reading the file systems `a` to `z` and bits `26..52` writing them,
+    // followed by bit `52` network, `53` display, `54` js eval, `55` spawn and `56` thread.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_capabilities",
+        Closure::<dyn Fn() -> i64>::new(move || ctx_f.capabilities().bits() as i64).into_js_value(),
+    );
+
+    // hapi_process_set_child_capabilities
+    // Set the capabilities subprocesses are spawned with, see `hapi_process_capabilities` for the bitmask.
+    // Capabilities this process does not hold are dropped, so a subprocess never has more capabilities than it's parent.
+    // ### Returns
+    // - The bitmask of the capabilities subprocesses will be spawned with
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_set_child_capabilities",
+        Closure::<dyn Fn(i64) -> i64>::new(move |capabilities: i64| {
+            ctx_f.set_child_capabilities(Capabilities::from_bits(capabilities as u64));
+            ctx_f.child_capabilities().bits() as i64
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_set_child_rlimit
+    // Set the limit of a resource subprocesses are spawned with, see `hapi_process_getrlimit` for the resources.
+    // A negative limit leaves the resource unlimited, a subprocess never gets higher limits than it's parent.
+    // ### Returns
+    // - `0` On success
+    // - `-2` If the resource is invalid
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_set_child_rlimit",
+        Closure::<dyn Fn(u32, i64) -> i32>::new(move |resource, limit: i64| {
+            let resource = match Resource::try_from(resource) {
+                Ok(resource) => resource,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            let limit = match limit {
+                limit if limit < 0 => None,
+                limit => Some(limit.min(u32::MAX as i64) as u32),
+            };
+            ctx_f.set_child_limit(resource, limit);
+            0
+        })
+        .into_js_value(),
+    );
+
+    // hapi_process_set_child_privileged
+    // Set whether subprocesses are spawned privileged, letting them raise their resource limits.
+    // Subprocesses are spawned unprivileged by default, only a privileged process can pass it's privilege on.
+    // ### Returns
+    // - `1` If subprocesses will be spawned privileged, `0` otherwise
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_process_set_child_privileged",
+        Closure::<dyn Fn(u32) -> i32>::new(move |privileged| {
+            ctx_f.set_child_privileged(privileged != 0);
+            ctx_f.child_limits().privileged.into()
+        })
+        .into_js_value(),
+    );
+}
+
+/// Spawn a subprocess and write it's handle to the pointer, unless null
+fn spawn_subprocess(
+    ctx: &ProcessCtx,
+    bin: *const u8,
+    bin_len: u32,
+    mut options: SpawnOptions,
+    pid_out: *mut u32,
+) -> i32 {
+    let wasm_bin = match read_mem(ctx, bin as u32, bin_len) {
+        Ok(wasm_bin) => wasm_bin,
+        Err(code) => return code,
+    };
+    if !pid_out.is_null() {
+        if let Err(code) = check_mem(ctx, pid_out as u32, 4) {
+            return code;
+        }
+    }
+
+    // The handle of the subprocess has to fit in the open file limit before it's spawned
+    if let Err(e) = ctx.check_open_files(1) {
+        return fail_with(ctx, &e);
+    }
+
+    // Subprocesses are unprivileged unless the parent passes it's privilege on,
+    // and can't escape it's resource limits or capabilities
+    let (limits, privileged) =
+        ctx.child_limits()
+            .apply(&options.limits, &ctx.limits(), ctx.privileged());
+    options.parent = Some(ctx.pid());
+    options.limits = limits;
+    options.privileged = privileged;
+    options.capabilities = ctx.child_capabilities();
+
+    let process_manager_lock = ProcessManager::get();
+    let mut process_manager = process_manager_lock.spin_lock().unwrap();
+    let cwd = ctx.cwd();
+    let pid = match process_manager.spawn(wasm_bin, None, &cwd, options) {
+        Ok(pid) => pid,
+        Err(e) => {
+            log::error!("Failed to spawn subprocess: {}", e);
+            ctx.set_spawn_error(Some(e.to_string().trim_end().to_string()));
+            return fail_with(ctx, &e);
+        }
+    };
+    ctx.set_spawn_error(None);
+
+    drop(process_manager);
+
+    let handle = match insert_handle(ctx, KernelObject::Process(pid)) {
+        Ok(handle) => handle,
+        Err(code) => return code,
+    };
+    if !pid_out.is_null() {
+        ctx.memory().write(pid_out as u32, &handle.to_le_bytes());
+    }
+    0
+}
+
+/// Fail a call on a command-line argument that does not exist
+fn argument_out_of_range(ctx: &ProcessCtx, index: u32) -> i32 {
+    fail(
+        ctx,
+        HapiError::OutOfRange,
+        format!("No command-line argument at index: {}", index),
+    )
+}
+
+/// Fail a call on an environment variable that is not set
+fn variable_not_set(ctx: &ProcessCtx, key: &str) -> i32 {
+    fail(
+        ctx,
+        HapiError::NotFound,
+        format!("The environment variable is not set: {}", key),
+    )
+}
+
+/// Fail a call on the spawn error when the last spawn did not fail
+fn no_spawn_error(ctx: &ProcessCtx) -> i32 {
+    fail(
+        ctx,
+        HapiError::NotFound,
+        "The last spawn of this process did not fail",
+    )
+}
+
+/// Fail a call on a process that does not exist
+fn no_such_process(ctx: &ProcessCtx, id: Uuid) -> i32 {
+    fail(ctx, HapiError::NotFound, format!("No such process: {}", id))
+}
+
+/// Fail a call on a process this process is not the parent of
+fn not_the_parent(ctx: &ProcessCtx, id: Uuid) -> i32 {
+    fail(
+        ctx,
+        HapiError::PermissionDenied,
+        format!("Not the parent of process: {}", id),
+    )
+}
+
+/// Get the crash report of a process as a json string.
+/// Fails with the recorded error code of the crash info api.
+fn read_crash_info(ctx: &ProcessCtx, handle: u32) -> Result<CString, i32> {
+    let id = process_handle(ctx, handle)?;
+
+    let process_manager_lock = ProcessManager::get();
+    let process_manager = process_manager_lock.spin_lock().unwrap();
+    let report = process_manager.crash_report(id).ok_or_else(|| {
+        fail(
+            ctx,
+            HapiError::NotFound,
+            format!("No crash report of the process: {}", id),
+        )
+    })?;
+    Ok(CString::new(report.to_json()).unwrap_or_default())
+}
+
+/// Get the trace buffer of a process, or of this process for the self handle.
+/// Only the process itself and it's parent may access the trace.
+/// Fails with the recorded error code of the tracing api.
+fn process_trace(ctx: &ProcessCtx, handle: u32) -> Result<Arc<ProcessTrace>, i32> {
+    let id = process_handle(ctx, handle)?;
+    if id == ctx.pid() {
+        return Ok(ctx.trace());
+    }
+
+    let process_manager_lock = ProcessManager::get();
+    let process_manager = process_manager_lock.spin_lock().unwrap();
+    let process = process_manager
+        .process(id)
+        .ok_or_else(|| no_such_process(ctx, id))?;
+    if process.ctx().parent() != Some(ctx.pid()) {
+        drop(process_manager);
+        return Err(not_the_parent(ctx, id));
+    }
+    Ok(process.ctx().trace())
+}
+
+/// Read the manifest of a binary in memory as a null-terminated json string.
+/// Fails with the recorded error code of the manifest api.
+fn read_manifest(ctx: &ProcessCtx, bin: *const u8, bin_len: u32) -> Result<Vec<u8>, i32> {
+    let bin = read_mem(ctx, bin as u32, bin_len)?;
+    let requirements =
+        WasmRequirements::parse(&bin).map_err(|e| fail(ctx, HapiError::InvalidArgument, e))?;
+    let manifest = requirements
+        .manifest
+        .ok_or_else(|| fail(ctx, HapiError::NotFound, "The binary has no manifest"))?;
+    let manifest = CString::new(manifest.to_json()).unwrap_or_default();
+    Ok(manifest.into_bytes_with_nul())
+}
+
+/// Read an array of `count` string pointers from memory.
+/// Fails with the recorded code of [`HapiError::InvalidPointer`] or [`HapiError::InvalidArgument`].
+fn read_str_array(
+    ctx: &ProcessCtx,
+    ptr: *const *const u8,
+    count: u32,
+    name: &str,
+) -> Result<Vec<String>, i32> {
+    let pointers = read_mem(ctx, ptr as u32, count.saturating_mul(4))?;
+    pointers
+        .chunks_exact(4)
+        .map(|p| {
+            let ptr = u32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+            read_str(ctx, ptr as *const u8, name)
+        })
+        .collect()
+}
+
+/// Serialize environment variables as consecutive null-terminated `KEY=VALUE` strings
+fn env_list(env: &HashMap<String, String>) -> Vec<u8> {
+    let mut list = Vec::new();
+    for (key, value) in env.iter() {
+        list.extend_from_slice(key.as_bytes());
+        list.push(b'=');
+        list.extend_from_slice(value.as_bytes());
+        list.push(0);
+    }
+    list
 }
@@ -0,0 +1,335 @@
+//! The ring api, letting processes submit operations and reap their completions without polling.
+//! The layout of the ring in the memory of the process is described in [`honeyos_process::ring`].
+use std::sync::Arc;
+
+use hashbrown::HashSet;
+use honeyos_networking::{request::RequestStatus, NetworkingManager};
+use honeyos_process::{
+    context::{ApiModuleBuilder, ProcessCtx},
+    ring::{
+        Opcode, PendingOp, RingError, RingLayout, Submission, CQ_HEAD, CQ_OVERFLOW, CQ_TAIL,
+        RING_HEADER_SIZE, SQ_HEAD, SQ_TAIL, SUBMISSION_SIZE,
+    },
+    ProcessManager,
+};
+use wasm_bindgen::closure::Closure;
+use web_sys::js_sys::Date;
+
+use super::{
+    error::{check_mem, fail, fail_with, read_mem, write_mem, HapiError},
+    fs::{read_file, write_file},
+    handle::{process_handle, request_handle},
+};
+
+/// Register the ring api
+pub fn register_ring_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // hapi_ring_setup
+    // Set up the ring of the process with at least `entries` submission entries and return it's pointer.
+    // The amount of entries is rounded up to a power of two, the completion ring has twice as many entries.
+    // The ring is allocated in the memory of the process and shared by it's threads, it can only be set up once.
+    // ### Returns
+    // - The pointer to the ring
+    // - `NULL` on failure, `hapi_last_error_code` is
+    //   `-2` if the amount of entries is zero or above `4096`,
+    //   `-3` if the ring is already set up or
+    //   `-14` if the ring could not be allocated
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_ring_setup",
+        Closure::<dyn Fn(u32) -> *mut u8>::new(move |entries| {
+            let ring = ctx_f.ring();
+            if ring.layout().is_some() {
+                fail_with(&ctx_f, &RingError::AlreadySetUp);
+                return std::ptr::null_mut();
+            }
+            let entries = match RingLayout::entries_for(entries) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    fail_with(&ctx_f, &e);
+                    return std::ptr::null_mut();
+                }
+            };
+
+            let size = RingLayout::size_for(entries);
+            let mut memory = ctx_f.memory();
+            let Some(ptr) = memory.alloc_aligned(size, 8) else {
+                drop(memory);
+                fail(
+                    &ctx_f,
+                    HapiError::OutOfMemory,
+                    format!("Failed to allocate the ring of {} bytes", size),
+                );
+                return std::ptr::null_mut();
+            };
+            let layout = RingLayout { ptr, entries };
+            let mut bytes = vec![0u8; size as usize];
+            bytes[..RING_HEADER_SIZE as usize].copy_from_slice(&layout.header());
+            memory.write(ptr, &bytes);
+            drop(memory);
+
+            // Another thread may have set up the ring in the meantime
+            if let Err(e) = ring.set_layout(layout) {
+                ctx_f.memory().free(ptr);
+                fail_with(&ctx_f, &e);
+                return std::ptr::null_mut();
+            }
+            ptr as *mut u8
+        })
+        .into_js_value(),
+    );
+
+    // hapi_ring_submit
+    // Submit the operations queued in the submission ring and advance it's head past them.
+    // Operations that can finish right away complete during the submit, others complete once the kernel sees them finish.
+    // Completions are copied into the completion ring as far as it has room, the rest is kept until the next submit or wait.
+    // The result of a completion is the amount of bytes transferred by file operations and `0` for other operations,
+    // or the negative error code of the failed operation, `-12` for a failed network request.
+    // The operations in flight and the completions kept by the kernel are limited to the amount of completion entries,
+    // submitting stops once they are reached and the remaining operations stay queued.
+    // ### Returns
+    // - The amount of operations submitted
+    // - `-8` If no operation could be submitted, as the completions have to be reaped first
+    // - `-9` If the ring is not set up, or it's heads and tails are corrupted
+    // - `-19` If the ring is no longer in the memory of the process
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_ring_submit",
+        Closure::<dyn Fn() -> i32>::new(move || {
+            let layout = match ring_layout(&ctx_f) {
+                Ok(layout) => layout,
+                Err(code) => return code,
+            };
+            let head = match read_field(&ctx_f, layout, SQ_HEAD) {
+                Ok(head) => head,
+                Err(code) => return code,
+            };
+            let tail = match read_field(&ctx_f, layout, SQ_TAIL) {
+                Ok(tail) => tail,
+                Err(code) => return code,
+            };
+            let queued = match layout.queued(head, tail) {
+                Ok(queued) => queued,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+
+            let ring = ctx_f.ring();
+            let mut submitted = 0;
+            for index in 0..queued {
+                // Make room by copying the completions kept into the completion ring
+                if ring.check_capacity().is_err() {
+                    if let Err(code) = flush(&ctx_f, layout) {
+                        return code;
+                    }
+                }
+                if let Err(e) = ring.check_capacity() {
+                    if submitted == 0 {
+                        return fail_with(&ctx_f, &e);
+                    }
+                    break;
+                }
+
+                let ptr = layout.submission(head.wrapping_add(index));
+                let entry = match read_mem(&ctx_f, ptr, SUBMISSION_SIZE) {
+                    Ok(entry) => entry,
+                    Err(code) => return code,
+                };
+                submit(&ctx_f, Submission::from_bytes(&entry).unwrap_or_default());
+                if let Err(code) =
+                    write_field(&ctx_f, layout, SQ_HEAD, head.wrapping_add(index + 1))
+                {
+                    return code;
+                }
+                submitted += 1;
+            }
+
+            match flush(&ctx_f, layout) {
+                Ok(_) => submitted,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_wait_any
+    // Block the calling thread until an operation completed, or the timeout in nanoseconds elapsed.
+    // A negative timeout waits indefinitely. Completions are copied into the completion ring before returning.
+    // ### Returns
+    // - The amount of completions in the completion ring, `0` if the timeout elapsed
+    // - `-9` If the ring is not set up, or it's heads and tails are corrupted
+    // - `-15` If the timeout is negative while no operation is in flight
+    // - `-19` If the ring is no longer in the memory of the process
+    // ### Safety
+    // - This blocks the calling thread, so it cannot be used on the main thread of the browser
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_wait_any",
+        Closure::<dyn Fn(i64) -> i32>::new(move |timeout_ns| {
+            let layout = match ring_layout(&ctx_f) {
+                Ok(layout) => layout,
+                Err(code) => return code,
+            };
+            match flush(&ctx_f, layout) {
+                Ok(0) => {}
+                Ok(count) => return count as i32,
+                Err(code) => return code,
+            }
+
+            let ring = ctx_f.ring();
+            if timeout_ns < 0 && ring.pending() == 0 {
+                return fail(
+                    &ctx_f,
+                    HapiError::Deadlock,
+                    "No operation is in flight to wait on",
+                );
+            }
+            let timeout_ms = (timeout_ns >= 0).then(|| timeout_ns as f64 / 1_000_000.0);
+            ring.wait(timeout_ms);
+
+            match flush(&ctx_f, layout) {
+                Ok(count) => count as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+}
+
+/// Complete the operations in flight the kernel saw finish.
+/// This never blocks, rings that are in use are polled in the next cycle.
+pub fn update_rings() {
+    let (rings, alive) = {
+        let process_manager_lock = ProcessManager::get();
+        let Ok(process_manager) = process_manager_lock.try_lock() else {
+            return;
+        };
+        let rings = process_manager
+            .processes()
+            .map(|process| process.ctx().ring())
+            .collect::<Vec<_>>();
+        let alive = process_manager
+            .processes()
+            .map(|process| process.id())
+            .collect::<HashSet<_>>();
+        (rings, alive)
+    };
+
+    let networking_manager_lock = NetworkingManager::get();
+    let Ok(networking_manager) = networking_manager_lock.try_read() else {
+        return;
+    };
+    let now = Date::now();
+    for ring in rings {
+        ring.poll(|op| match op {
+            PendingOp::NetworkRequest(id) => match networking_manager.status(*id) {
+                Some(RequestStatus::Success) => Some(0),
+                Some(RequestStatus::Fail) => Some(HapiError::Network.code()),
+                Some(RequestStatus::Pending | RequestStatus::Processing) => None,
+                None => Some(HapiError::NotFound.code()),
+            },
+            PendingOp::Timeout(deadline) => (*deadline <= now).then_some(0),
+            PendingOp::ProcessWait(id) => (!alive.contains(id)).then_some(0),
+        });
+    }
+}
+
+/// Submit an operation, completing it right away unless it waits on the kernel
+fn submit(ctx: &ProcessCtx, submission: Submission) {
+    let ring = ctx.ring();
+    let pending = |op| ring.add_pending(submission.user_data, op);
+    let result = match Opcode::from_u8(submission.opcode) {
+        Some(Opcode::Nop) => 0,
+        Some(Opcode::FileRead) => transfer(ctx, submission, read_file),
+        Some(Opcode::FileWrite) => transfer(ctx, submission, write_file),
+        Some(Opcode::NetworkRequest) => match request_handle(ctx, submission.handle) {
+            Ok(id) => return pending(PendingOp::NetworkRequest(id)),
+            Err(code) => code,
+        },
+        Some(Opcode::Timeout) => {
+            let deadline = Date::now() + submission.arg as f64 / 1_000_000.0;
+            return pending(PendingOp::Timeout(deadline));
+        }
+        Some(Opcode::ProcessWait) => match process_handle(ctx, submission.handle) {
+            Ok(id) if id == ctx.pid() => {
+                fail(ctx, HapiError::Deadlock, "A process can't wait on itself")
+            }
+            Ok(id) => return pending(PendingOp::ProcessWait(id)),
+            Err(code) => code,
+        },
+        None => fail(
+            ctx,
+            HapiError::InvalidArgument,
+            format!("Unknown ring opcode: {}", submission.opcode),
+        ),
+    };
+    ring.complete(submission.user_data, result);
+}
+
+/// Run the file operation of a submission.
+/// Results in the amount of bytes transferred or the recorded error code.
+fn transfer(
+    ctx: &ProcessCtx,
+    submission: Submission,
+    operation: fn(&ProcessCtx, u32, u32, u32, u32) -> i32,
+) -> i32 {
+    let Ok(offset) = u32::try_from(submission.arg) else {
+        return fail(
+            ctx,
+            HapiError::OutOfRange,
+            format!("The offset is past the end of the file: {}", submission.arg),
+        );
+    };
+    let Submission {
+        handle,
+        buffer,
+        len,
+        ..
+    } = submission;
+    match operation(ctx, handle, offset, len, buffer) {
+        0 => i32::try_from(len).unwrap_or(i32::MAX),
+        code => code,
+    }
+}
+
+/// Copy the completions kept by the kernel into the completion ring, as far as it has room.
+/// Returns the amount of completions in the completion ring.
+fn flush(ctx: &ProcessCtx, layout: RingLayout) -> Result<u32, i32> {
+    let ring = ctx.ring();
+    let _flushing = ring.flush_lock();
+    let head = read_field(ctx, layout, CQ_HEAD)?;
+    let tail = read_field(ctx, layout, CQ_TAIL)?;
+    let free = layout.free(head, tail).map_err(|e| fail_with(ctx, &e))?;
+
+    // Check the ring before taking the completions, so none are lost
+    check_mem(ctx, layout.ptr, layout.size())?;
+    let completions = ring.take_completions(free);
+    let overflow = ring.completions() as u32;
+
+    let mut memory = ctx.memory();
+    let mut tail = tail;
+    for completion in completions {
+        memory.write(layout.completion(tail), &completion.to_bytes());
+        tail = tail.wrapping_add(1);
+    }
+    memory.write(layout.field(CQ_OVERFLOW), &overflow.to_le_bytes());
+    memory.write(layout.field(CQ_TAIL), &tail.to_le_bytes());
+    Ok(tail.wrapping_sub(head))
+}
+
+/// Get the layout of the ring of the process.
+/// Fails with the recorded code of [`HapiError::InvalidState`].
+fn ring_layout(ctx: &ProcessCtx) -> Result<RingLayout, i32> {
+    ctx.ring()
+        .layout()
+        .ok_or_else(|| fail_with(ctx, &RingError::NotSetUp))
+}
+
+/// Read a field of the header of the ring
+fn read_field(ctx: &ProcessCtx, layout: RingLayout, offset: u32) -> Result<u32, i32> {
+    let bytes = read_mem(ctx, layout.field(offset), 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+/// Write a field of the header of the ring
+fn write_field(ctx: &ProcessCtx, layout: RingLayout, offset: u32, value: u32) -> Result<(), i32> {
+    write_mem(ctx, layout.field(offset), &value.to_le_bytes())
+}
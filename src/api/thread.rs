@@ -1,27 +1,381 @@
-use std::{ffi::c_void, sync::Arc};
+use std::{
+    ffi::{c_void, CString},
+    sync::Arc,
+};
 
 use honeyos_atomics::mutex::SpinMutex;
 use honeyos_process::{
+    capabilities::Capability,
     context::{ApiModuleBuilder, ProcessCtx},
+    handle::KernelObject,
+    thread::{ThreadError, ThreadState, MAIN_THREAD_ID},
     ProcessManager,
 };
 use wasm_bindgen::closure::Closure;
 
+use super::{
+    error::{check_mem, fail, fail_with, read_str, write_mem, HapiError},
+    handle::{insert_handle, thread_handle},
+};
+
 /// Register the thread api
 pub fn register_thread_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
     let ctx_f = ctx.clone();
     // hapi_thread_spawn
-    // Spawn a function pointer on a new thread
-    builder.register(
+    // Spawn a function pointer on a new thread.
+    // The id is reserved immediately, while the thread starts shortly after.
+    // ### Returns
+    // - The id of the thread on success
+    // - `-13` If the worker of the thread could not be created
+    // - `-20` If the process has reached it's thread limit
+    // - `-10` If the process lacks the thread capability
+    builder.register_restricted(
         "hapi_thread_spawn",
-        Closure::<dyn Fn(*const c_void)>::new(move |f_ptr| {
+        Capability::Thread,
+        Closure::<dyn Fn(*const c_void) -> i32>::new(move |f_ptr| {
             let f_ptr = f_ptr as u32;
             let process_manager_lock = ProcessManager::get();
             let Ok(mut process_manager) = process_manager_lock.spin_lock() else {
-                return;
+                return poisoned(&ctx_f);
+            };
+            spawn_result(&ctx_f, process_manager.spawn_thread(ctx_f.pid(), f_ptr, 0))
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_spawn_with_arg
+    // Spawn a function pointer on a new thread, passing an argument to it.
+    // The function gets called with the argument and it's return value can be retrieved with `hapi_thread_join`.
+    // The id is reserved immediately, while the thread starts shortly after.
+    // ### Returns
+    // - The id of the thread on success
+    // - `-13` If the worker of the thread could not be created
+    // - `-20` If the process has reached it's thread limit
+    // - `-10` If the process lacks the thread capability
+    let ctx_f = ctx.clone();
+    builder.register_restricted(
+        "hapi_thread_spawn_with_arg",
+        Capability::Thread,
+        Closure::<dyn Fn(*const c_void, *mut c_void) -> i32>::new(move |f_ptr, arg| {
+            let process_manager_lock = ProcessManager::get();
+            let Ok(mut process_manager) = process_manager_lock.spin_lock() else {
+                return poisoned(&ctx_f);
+            };
+            spawn_result(
+                &ctx_f,
+                process_manager.spawn_thread(ctx_f.pid(), f_ptr as u32, arg as u32),
+            )
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_current_id
+    // Get the id of the calling thread. The main thread of a process has the id `0`.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_current_id",
+        Closure::<dyn Fn() -> u32>::new(move || ctx_f.tid()).into_js_value(),
+    );
+
+    // hapi_thread_state
+    // Get the state of a thread
+    // ### Returns
+    // - `0` If the thread has not started yet
+    // - `1` If the thread is running
+    // - `2` If the thread returned from it's entrypoint
+    // - `3` If the thread threw an error
+    // - `4` If the thread has been killed
+    // - `-1` If the thread does not exist
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_state",
+        Closure::<dyn Fn(u32) -> i32>::new(move |tid| {
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(ctx_f.pid()) else {
+                return no_such_thread(&ctx_f, tid);
+            };
+            match process.thread_state(tid) {
+                Some(state) => state as i32,
+                None => no_such_thread(&ctx_f, tid),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_alive
+    // Check if a thread is still alive
+    // ### Returns
+    // - `1` If the thread has not started yet or is running
+    // - `0` If the thread has finished
+    // - `-1` If the thread does not exist
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_alive",
+        Closure::<dyn Fn(u32) -> i32>::new(move |tid| {
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(ctx_f.pid()) else {
+                return no_such_thread(&ctx_f, tid);
+            };
+            match process.thread_state(tid) {
+                Some(state) => state.is_alive() as i32,
+                None => no_such_thread(&ctx_f, tid),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_open
+    // Open a handle to a thread of the process, used to join or kill it.
+    // ### Returns
+    // - The handle on success
+    // - `-1` If the thread does not exist
+    // - `-22` If the process has reached it's open file limit
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_open",
+        Closure::<dyn Fn(u32) -> i32>::new(move |tid| {
+            let exists = {
+                let process_manager_lock = ProcessManager::get();
+                let process_manager = process_manager_lock.spin_lock().unwrap();
+                let process = process_manager.process(ctx_f.pid());
+                process.is_some_and(|process| process.thread_state(tid).is_some())
+            };
+            if !exists {
+                return no_such_thread(&ctx_f, tid);
+            }
+            match insert_handle(&ctx_f, KernelObject::Thread(tid)) {
+                Ok(handle) => handle as i32,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_join
+    // Block until the thread of a thread handle has finished and return it's final state.
+    // If the thread returned from it's entrypoint, the return value is written to `retval_out`, unless null.
+    // The thread is forgotten once joined, so it can only be joined once. The main thread is never forgotten.
+    // The handle stays open until it's closed.
+    // ### Returns
+    // - `2` If the thread returned from it's entrypoint
+    // - `3` If the thread threw an error
+    // - `4` If the thread has been killed
+    // - `-1` If the thread does not exist
+    // - `-15` If the thread tried to join itself
+    // - `-18` If the handle is not a thread handle
+    // - `-19` If `retval_out` is invalid
+    // ### Safety
+    // - This blocks the calling thread, so it cannot be used on the main thread of the browser
+    // - `retval_out` must be at least 4 bytes in size or unallocated memory will be written to
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_join",
+        Closure::<dyn Fn(u32, *mut u32) -> i32>::new(move |handle, retval_out: *mut u32| {
+            let tid = match thread_handle(&ctx_f, handle) {
+                Ok(tid) => tid,
+                Err(code) => return code,
+            };
+            if tid == ctx_f.tid() {
+                return fail(&ctx_f, HapiError::Deadlock, "A thread can't join itself");
+            }
+            if !retval_out.is_null() {
+                if let Err(code) = check_mem(&ctx_f, retval_out as u32, 4) {
+                    return code;
+                }
+            }
+
+            // Release the process manager before blocking
+            let threads = {
+                let process_manager_lock = ProcessManager::get();
+                let process_manager = process_manager_lock.spin_lock().unwrap();
+                let Some(process) = process_manager.process(ctx_f.pid()) else {
+                    return no_such_thread(&ctx_f, tid);
+                };
+                process.threads()
             };
-            process_manager.spawn_thread(ctx_f.pid(), f_ptr);
+
+            let thread = match threads.join(tid) {
+                Ok(thread) => thread,
+                Err(e) => return fail_with(&ctx_f, &e),
+            };
+            // The memory may have been freed while blocking, so it's checked again
+            if thread.state == ThreadState::Exited && !retval_out.is_null() {
+                let retval = thread.retval.to_le_bytes();
+                if let Err(code) = write_mem(&ctx_f, retval_out as u32, &retval) {
+                    return code;
+                }
+            }
+            thread.state as i32
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_kill
+    // Kill the thread of a thread handle.
+    // The thread is terminated by the kernel shortly after, use `hapi_thread_join` to wait for it.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the thread does not exist
+    // - `-2` If the thread is the main thread
+    // - `-18` If the handle is not a thread handle
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_kill",
+        Closure::<dyn Fn(u32) -> i32>::new(move |handle| {
+            let tid = match thread_handle(&ctx_f, handle) {
+                Ok(tid) => tid,
+                Err(code) => return code,
+            };
+            if tid == MAIN_THREAD_ID {
+                return fail(
+                    &ctx_f,
+                    HapiError::InvalidArgument,
+                    "The main thread can't be killed",
+                );
+            }
+            let process_manager_lock = ProcessManager::get();
+            let mut process_manager = process_manager_lock.spin_lock().unwrap();
+            match process_manager.kill_thread(ctx_f.pid(), tid) {
+                true => 0,
+                false => no_such_thread(&ctx_f, tid),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_detach
+    // Detach a thread, so it's forgotten once it finishes instead of being kept until it's joined.
+    // A detached thread can no longer be joined.
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the thread does not exist
+    // - `-2` If the thread is the main thread
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_detach",
+        Closure::<dyn Fn(u32) -> i32>::new(move |tid| {
+            if tid == MAIN_THREAD_ID {
+                return fail(
+                    &ctx_f,
+                    HapiError::InvalidArgument,
+                    "The main thread can't be detached",
+                );
+            }
+            let threads = {
+                let process_manager_lock = ProcessManager::get();
+                let process_manager = process_manager_lock.spin_lock().unwrap();
+                let Some(process) = process_manager.process(ctx_f.pid()) else {
+                    return no_such_thread(&ctx_f, tid);
+                };
+                process.threads()
+            };
+            match threads.detach(tid) {
+                Ok(_) => 0,
+                Err(e) => fail_with(&ctx_f, &e),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_set_name
+    // Set the name of a thread of the process, used for diagnostics
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the thread does not exist
+    // - `-2` If the name is not a valid string
+    // ### Safety
+    // - The name must be a valid string or unallocated memory will be read from
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_set_name",
+        Closure::<dyn Fn(u32, *const u8) -> i32>::new(move |tid, name| {
+            let name = match read_str(&ctx_f, name, "name") {
+                Ok(name) => name,
+                Err(code) => return code,
+            };
+            let process_manager_lock = ProcessManager::get();
+            let process_manager = process_manager_lock.spin_lock().unwrap();
+            let Some(process) = process_manager.process(ctx_f.pid()) else {
+                return no_such_thread(&ctx_f, tid);
+            };
+            match process.threads().set_name(tid, &name) {
+                true => 0,
+                false => no_such_thread(&ctx_f, tid),
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_get_name_length
+    // Get the length of the name of a thread, including the null terminator
+    // ### Returns
+    // - The length on success
+    // - `-1` If the thread does not exist
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_get_name_length",
+        Closure::<dyn Fn(u32) -> i32>::new(move |tid| {
+            let Some(name) = thread_name(&ctx_f, tid) else {
+                return no_such_thread(&ctx_f, tid);
+            };
+            name.len() as i32 + 1
+        })
+        .into_js_value(),
+    );
+
+    // hapi_thread_get_name
+    // Write the name of a thread to the buffer
+    // ### Returns
+    // - `0` On success
+    // - `-1` If the thread does not exist
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer size must be at least the size of `hapi_thread_get_name_length` or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_thread_get_name",
+        Closure::<dyn Fn(u32, *mut u8) -> i32>::new(move |tid, buffer| {
+            let Some(name) = thread_name(&ctx_f, tid) else {
+                return no_such_thread(&ctx_f, tid);
+            };
+            let cstring = CString::new(name).unwrap_or_default();
+            match write_mem(&ctx_f, buffer as u32, cstring.as_bytes_with_nul()) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
         })
         .into_js_value(),
     );
 }
+
+/// Get the return code of a thread spawn
+fn spawn_result(ctx: &ProcessCtx, result: Result<u32, ThreadError>) -> i32 {
+    match result {
+        Ok(tid) => tid as i32,
+        Err(e) => fail_with(ctx, &e),
+    }
+}
+
+/// Fail a call on a thread that does not exist
+fn no_such_thread(ctx: &ProcessCtx, tid: u32) -> i32 {
+    fail_with(ctx, &ThreadError::NoSuchThread(tid))
+}
+
+/// Fail a call because the process manager has been poisoned
+fn poisoned(ctx: &ProcessCtx) -> i32 {
+    fail(
+        ctx,
+        HapiError::Internal,
+        "The process manager has been poisoned",
+    )
+}
+
+/// Get the name of a thread of the process
+fn thread_name(ctx: &ProcessCtx, tid: u32) -> Option<String> {
+    let process_manager_lock = ProcessManager::get();
+    let process_manager = process_manager_lock.spin_lock().unwrap();
+    let process = process_manager.process(ctx.pid())?;
+    process.threads().get(tid).map(|thread| thread.name)
+}
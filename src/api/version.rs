@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use honeyos_process::context::{ApiModuleBuilder, ProcessCtx, HAPI_VERSION};
+use wasm_bindgen::closure::Closure;
+
+use super::error::{read_str, write_mem};
+
+/// Register the version api
+pub fn register_version_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    builder.feature("version");
+
+    // hapi_version
+    // Write the version of the api and the kernel to the buffer as five 32-bit integers:
+    // the major and minor version of the api, followed by the major, minor and patch version of the kernel.
+    // Binaries built against the same major version of the api run on any kernel with the same or a higher minor version.
+    // ### Returns
+    // - `0` On success
+    // - `-19` If the buffer is invalid
+    // ### Safety
+    // - The buffer must be at least 20 bytes in size or unallocated memory will be written to.
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_version",
+        Closure::<dyn Fn(*mut u32) -> i32>::new(move |buffer| {
+            let (major, minor) = HAPI_VERSION;
+            let version = [
+                major,
+                minor,
+                env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or_default(),
+                env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or_default(),
+                env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or_default(),
+            ];
+            let bytes = version
+                .iter()
+                .flat_map(|part: &u32| part.to_le_bytes())
+                .collect::<Vec<u8>>();
+            match write_mem(&ctx_f, buffer as u32, &bytes) {
+                Ok(_) => 0,
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // hapi_has_feature
+    // Check if the kernel provides a feature of the api, either a part of the api like `fs`, `klog` or `thread`
+    // or the name of a function like `hapi_klog_read`.
+    // Functions behind a capability the process lacks are provided, but return `-10`.
+    // ### Returns
+    // - `1` If the feature is provided
+    // - `0` If the feature is not provided
+    // - `-2` If the name is not a valid string
+    let mut features = builder.features();
+    features.insert("hapi_has_feature".to_string());
+    let ctx_f = ctx.clone();
+    builder.register(
+        "hapi_has_feature",
+        Closure::<dyn Fn(*const u8) -> i32>::new(move |name| {
+            match read_str(&ctx_f, name, "feature name") {
+                Ok(name) => features.contains(&name).into(),
+                Err(code) => code,
+            }
+        })
+        .into_js_value(),
+    );
+}
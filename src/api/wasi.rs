@@ -0,0 +1,1324 @@
+//! The WASI preview1 api, mapped onto the honeyos subsystems.
+//! The functions are registered in the `wasi_snapshot_preview1` import module,
+//! so stock `wasm32-wasi` binaries run unmodified.
+//! Paths are resolved relative to a directory descriptor, the preopens being the working directory
+//! and the root of each file system that was mounted when the process was spawned.
+use std::{sync::Arc, time::Duration};
+
+use honeyos_atomics::mutex::SpinMutex;
+use honeyos_fs::{error::Error, fshandler::FsHandler, util, FileResult, FsLabel, FsManager};
+use honeyos_process::{
+    capabilities::Capability,
+    context::{ApiModuleBuilder, ProcessCtx},
+    crash::EXIT_SENTINEL,
+    fd::{FileDescriptor, OpenFile},
+    memory::Memory,
+    ProcessManager,
+};
+use uuid::Uuid;
+use wasm_bindgen::{closure::Closure, JsValue};
+use web_sys::js_sys::{global, Array, Date, Function, Reflect, Uint8Array};
+
+/// The import module of WASI preview1
+const WASI_MODULE: &str = "wasi_snapshot_preview1";
+
+/// A WASI error number
+type Errno = u16;
+const ERRNO_BADF: Errno = 8;
+const ERRNO_EXIST: Errno = 20;
+const ERRNO_FAULT: Errno = 21;
+const ERRNO_INVAL: Errno = 28;
+const ERRNO_IO: Errno = 29;
+const ERRNO_ISDIR: Errno = 31;
+const ERRNO_MFILE: Errno = 33;
+const ERRNO_NOENT: Errno = 44;
+const ERRNO_NOTDIR: Errno = 54;
+const ERRNO_NOTSUP: Errno = 58;
+const ERRNO_PIPE: Errno = 64;
+const ERRNO_SPIPE: Errno = 70;
+const ERRNO_XDEV: Errno = 75;
+const ERRNO_NOTCAPABLE: Errno = 76;
+
+/// The file types
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+/// The flags for opening a path
+const OFLAGS_CREAT: u32 = 1;
+const OFLAGS_DIRECTORY: u32 = 2;
+const OFLAGS_EXCL: u32 = 4;
+const OFLAGS_TRUNC: u32 = 8;
+
+/// The descriptor flag for appending writes
+const FDFLAGS_APPEND: u32 = 1;
+
+/// The clocks
+const CLOCK_REALTIME: u32 = 0;
+const CLOCK_MONOTONIC: u32 = 1;
+const CLOCK_PROCESS_CPUTIME: u32 = 2;
+const CLOCK_THREAD_CPUTIME: u32 = 3;
+
+/// The event types of subscriptions
+const EVENTTYPE_CLOCK: u8 = 0;
+
+/// The subscription flag for absolute timeouts
+const SUBCLOCKFLAGS_ABSTIME: u16 = 1;
+
+/// The size of the structs in memory
+const FILESTAT_SIZE: usize = 64;
+const DIRENT_SIZE: usize = 24;
+const SUBSCRIPTION_SIZE: u32 = 48;
+const EVENT_SIZE: usize = 32;
+
+/// Functions that are not supported, as the file systems have no links, timestamps or deletion
+const UNSUPPORTED: [&str; 12] = [
+    "fd_filestat_set_times",
+    "path_filestat_set_times",
+    "path_link",
+    "path_readlink",
+    "path_remove_directory",
+    "path_symlink",
+    "path_unlink_file",
+    "proc_raise",
+    "sock_accept",
+    "sock_recv",
+    "sock_send",
+    "sock_shutdown",
+];
+
+/// Register the wasi api
+pub fn register_wasi_api(ctx: Arc<ProcessCtx>, builder: &mut ApiModuleBuilder) {
+    // args_sizes_get
+    // Write the amount of arguments and the size of the argument strings
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "args_sizes_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |argc_out, size_out| {
+            let args = ctx_f.args();
+            sizes_get(
+                &ctx_f,
+                args.iter().map(|arg| arg.as_str()),
+                argc_out,
+                size_out,
+            )
+        })
+        .into_js_value(),
+    );
+
+    // args_get
+    // Write the argument pointers and null-terminated strings
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "args_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |argv, argv_buf| {
+            let args = ctx_f.args();
+            strings_get(&ctx_f, args.iter().map(|arg| arg.as_str()), argv, argv_buf)
+        })
+        .into_js_value(),
+    );
+
+    // environ_sizes_get
+    // Write the amount of environment variables and the size of the `KEY=VALUE` strings
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "environ_sizes_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |count_out, size_out| {
+            let environ = environ(&ctx_f);
+            sizes_get(
+                &ctx_f,
+                environ.iter().map(|var| var.as_str()),
+                count_out,
+                size_out,
+            )
+        })
+        .into_js_value(),
+    );
+
+    // environ_get
+    // Write the environment variable pointers and null-terminated `KEY=VALUE` strings
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "environ_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |environ_ptr, environ_buf| {
+            let environ = environ(&ctx_f);
+            strings_get(
+                &ctx_f,
+                environ.iter().map(|var| var.as_str()),
+                environ_ptr,
+                environ_buf,
+            )
+        })
+        .into_js_value(),
+    );
+
+    // clock_res_get
+    // Write the resolution of a clock in nanoseconds
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "clock_res_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |id, resolution_out| {
+            let resolution: u64 = match id {
+                CLOCK_REALTIME => 1_000_000,
+                CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME | CLOCK_THREAD_CPUTIME => 1_000,
+                _ => return ERRNO_INVAL as i32,
+            };
+            errno(write_u64(&mut ctx_f.memory(), resolution_out, resolution))
+        })
+        .into_js_value(),
+    );
+
+    // clock_time_get
+    // Write the time of a clock in nanoseconds
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "clock_time_get",
+        Closure::<dyn Fn(u32, u64, u32) -> i32>::new(move |id, _precision, time_out| {
+            let Some(time) = clock_time(id) else {
+                return ERRNO_INVAL as i32;
+            };
+            errno(write_u64(&mut ctx_f.memory(), time_out, time))
+        })
+        .into_js_value(),
+    );
+
+    // fd_advise
+    // Advise the kernel about the access pattern of a file, which is ignored
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_advise",
+        Closure::<dyn Fn(u32, u64, u64, u32) -> i32>::new(move |fd, _offset, _len, _advice| {
+            errno(descriptor(&ctx_f, fd).map(|_| ()))
+        })
+        .into_js_value(),
+    );
+
+    // fd_allocate
+    // Grow a file to fit `offset + len` bytes
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_allocate",
+        Closure::<dyn Fn(u32, u64, u64) -> i32>::new(move |fd, offset: u64, len| {
+            errno(fd_allocate(&ctx_f, fd, offset.saturating_add(len)))
+        })
+        .into_js_value(),
+    );
+
+    // fd_close
+    // Close a file descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_close",
+        Closure::<dyn Fn(u32) -> i32>::new(move |fd| match ctx_f.fds().remove(fd) {
+            Some(_) => 0,
+            None => ERRNO_BADF as i32,
+        })
+        .into_js_value(),
+    );
+
+    // fd_datasync
+    // Synchronize the data of a file. Writes are never buffered, so this does nothing
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_datasync",
+        Closure::<dyn Fn(u32) -> i32>::new(move |fd| errno(descriptor(&ctx_f, fd).map(|_| ())))
+            .into_js_value(),
+    );
+
+    // fd_sync
+    // Synchronize the data and metadata of a file. Writes are never buffered, so this does nothing
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_sync",
+        Closure::<dyn Fn(u32) -> i32>::new(move |fd| errno(descriptor(&ctx_f, fd).map(|_| ())))
+            .into_js_value(),
+    );
+
+    // fd_fdstat_get
+    // Write the type and flags of a file descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_fdstat_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |fd, stat_out| {
+            errno(fd_fdstat_get(&ctx_f, fd, stat_out))
+        })
+        .into_js_value(),
+    );
+
+    // fd_fdstat_set_flags
+    // Set the flags of a file descriptor. Only the append flag of files is supported
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_fdstat_set_flags",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |fd, flags| {
+            let mut fds = ctx_f.fds();
+            match fds.get_mut(fd) {
+                Some(FileDescriptor::File(file)) => {
+                    file.append = flags & FDFLAGS_APPEND != 0;
+                    0
+                }
+                Some(_) => 0,
+                None => ERRNO_BADF as i32,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // fd_fdstat_set_rights
+    // Rights are not enforced, so this does nothing
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_fdstat_set_rights",
+        Closure::<dyn Fn(u32, u64, u64) -> i32>::new(move |fd, _base, _inheriting| {
+            errno(descriptor(&ctx_f, fd).map(|_| ()))
+        })
+        .into_js_value(),
+    );
+
+    // fd_filestat_get
+    // Write the attributes of an open file
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_filestat_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |fd, stat_out| {
+            errno(fd_filestat_get(&ctx_f, fd, stat_out))
+        })
+        .into_js_value(),
+    );
+
+    // fd_filestat_set_size
+    // Resize an open file
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_filestat_set_size",
+        Closure::<dyn Fn(u32, u64) -> i32>::new(move |fd, size| {
+            errno(fd_filestat_set_size(&ctx_f, fd, size))
+        })
+        .into_js_value(),
+    );
+
+    // fd_pread
+    // Read from a file at an offset, without moving the offset of the descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_pread",
+        Closure::<dyn Fn(u32, u32, u32, u64, u32) -> i32>::new(
+            move |fd, iovs, iovs_len, offset, nread_out| {
+                errno(fd_read(&ctx_f, fd, iovs, iovs_len, Some(offset), nread_out))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // fd_pwrite
+    // Write to a file at an offset, without moving the offset of the descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_pwrite",
+        Closure::<dyn Fn(u32, u32, u32, u64, u32) -> i32>::new(
+            move |fd, iovs, iovs_len, offset, nwritten_out| {
+                errno(fd_write(
+                    &ctx_f,
+                    fd,
+                    iovs,
+                    iovs_len,
+                    Some(offset),
+                    nwritten_out,
+                ))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // fd_read
+    // Read from a file descriptor. Reading the stdin blocks until input is available
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_read",
+        Closure::<dyn Fn(u32, u32, u32, u32) -> i32>::new(move |fd, iovs, iovs_len, nread_out| {
+            errno(fd_read(&ctx_f, fd, iovs, iovs_len, None, nread_out))
+        })
+        .into_js_value(),
+    );
+
+    // fd_write
+    // Write to a file descriptor. The stdout and stderr are written to the process's streams
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_write",
+        Closure::<dyn Fn(u32, u32, u32, u32) -> i32>::new(
+            move |fd, iovs, iovs_len, nwritten_out| {
+                errno(fd_write(&ctx_f, fd, iovs, iovs_len, None, nwritten_out))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // fd_prestat_get
+    // Write the length of the name of a preopened directory
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_prestat_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |fd, prestat_out| {
+            let Some(FileDescriptor::Preopen { name, .. }) = ctx_f.fds().get(fd).cloned() else {
+                return ERRNO_BADF as i32;
+            };
+            let mut prestat = [0u8; 8];
+            prestat[4..8].copy_from_slice(&(name.len() as u32).to_le_bytes());
+            errno(write_bytes(&mut ctx_f.memory(), prestat_out, &prestat))
+        })
+        .into_js_value(),
+    );
+
+    // fd_prestat_dir_name
+    // Write the name of a preopened directory, without a null terminator
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_prestat_dir_name",
+        Closure::<dyn Fn(u32, u32, u32) -> i32>::new(move |fd, path, len| {
+            let Some(FileDescriptor::Preopen { name, .. }) = ctx_f.fds().get(fd).cloned() else {
+                return ERRNO_BADF as i32;
+            };
+            if (len as usize) < name.len() {
+                return ERRNO_INVAL as i32;
+            }
+            errno(write_bytes(&mut ctx_f.memory(), path, name.as_bytes()))
+        })
+        .into_js_value(),
+    );
+
+    // fd_readdir
+    // Write the entries of a directory, starting at the entry after the cookie
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_readdir",
+        Closure::<dyn Fn(u32, u32, u32, u64, u32) -> i32>::new(
+            move |fd, buf, buf_len, cookie, bufused_out| {
+                errno(fd_readdir(&ctx_f, fd, buf, buf_len, cookie, bufused_out))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // fd_renumber
+    // Move a file descriptor to the number of another, closing the other
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_renumber",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |from, to| {
+            match ctx_f.fds().renumber(from, to) {
+                true => 0,
+                false => ERRNO_BADF as i32,
+            }
+        })
+        .into_js_value(),
+    );
+
+    // fd_seek
+    // Move the offset of a file descriptor and write the new offset
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_seek",
+        Closure::<dyn Fn(u32, i64, u32, u32) -> i32>::new(move |fd, offset, whence, offset_out| {
+            errno(fd_seek(&ctx_f, fd, offset, whence, offset_out))
+        })
+        .into_js_value(),
+    );
+
+    // fd_tell
+    // Write the offset of a file descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "fd_tell",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |fd, offset_out| {
+            errno(fd_seek(&ctx_f, fd, 0, 1, offset_out))
+        })
+        .into_js_value(),
+    );
+
+    // path_create_directory
+    // Create a directory relative to a directory descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "path_create_directory",
+        Closure::<dyn Fn(u32, u32, u32) -> i32>::new(move |fd, path, path_len| {
+            errno(path_create_directory(&ctx_f, fd, path, path_len))
+        })
+        .into_js_value(),
+    );
+
+    // path_filestat_get
+    // Write the attributes of a file or directory relative to a directory descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "path_filestat_get",
+        Closure::<dyn Fn(u32, u32, u32, u32, u32) -> i32>::new(
+            move |fd, _flags, path, path_len, stat_out| {
+                errno(path_filestat_get(&ctx_f, fd, path, path_len, stat_out))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // path_open
+    // Open a file or directory relative to a directory descriptor and write the new descriptor.
+    // Takes more arguments than a closure supports, so they are passed through a shim as an array.
+    let ctx_f = ctx.clone();
+    let path_open = Closure::<dyn Fn(Array) -> i32>::new(move |args: Array| {
+        let arg = |index: u32| args.get(index).as_f64().unwrap_or_default() as i64 as u32;
+        errno(path_open(
+            &ctx_f,
+            arg(0),
+            arg(2),
+            arg(3),
+            arg(4),
+            arg(7),
+            arg(8),
+        ))
+    })
+    .into_js_value();
+    builder.register_in(WASI_MODULE, "path_open", array_shim(&path_open));
+
+    // path_rename
+    // Move a file or directory, both paths being relative to a directory descriptor
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "path_rename",
+        Closure::<dyn Fn(u32, u32, u32, u32, u32, u32) -> i32>::new(
+            move |fd, old_path, old_len, new_fd, new_path, new_len| {
+                errno(path_rename(
+                    &ctx_f,
+                    (fd, old_path, old_len),
+                    (new_fd, new_path, new_len),
+                ))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // poll_oneoff
+    // Wait for one of the subscriptions. File descriptors are always ready, clocks are slept on
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "poll_oneoff",
+        Closure::<dyn Fn(u32, u32, u32, u32) -> i32>::new(
+            move |subscriptions, events, count, nevents_out| {
+                errno(poll_oneoff(
+                    &ctx_f,
+                    subscriptions,
+                    events,
+                    count,
+                    nevents_out,
+                ))
+            },
+        )
+        .into_js_value(),
+    );
+
+    // proc_exit
+    // Set the exit code of the process and terminate it.
+    // The calling thread unwinds immediately, while the kernel terminates the other threads.
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "proc_exit",
+        Closure::<dyn Fn(i32)>::new(move |code: i32| {
+            ctx_f.set_exit_code(code);
+            {
+                let process_manager_lock = ProcessManager::get();
+                let mut process_manager = process_manager_lock.spin_lock().unwrap();
+                process_manager.request_exit(ctx_f.pid());
+            }
+            wasm_bindgen::throw_str(&format!("{}({})", EXIT_SENTINEL, code));
+        })
+        .into_js_value(),
+    );
+
+    // sched_yield
+    // Yield the thread. Workers are scheduled by the browser, so this does nothing
+    builder.register_in(
+        WASI_MODULE,
+        "sched_yield",
+        Closure::<dyn Fn() -> i32>::new(move || 0).into_js_value(),
+    );
+
+    // random_get
+    // Fill a buffer with cryptographically secure random bytes
+    let ctx_f = ctx.clone();
+    builder.register_in(
+        WASI_MODULE,
+        "random_get",
+        Closure::<dyn Fn(u32, u32) -> i32>::new(move |buf, len| {
+            let Some(bytes) = random_bytes(len) else {
+                return ERRNO_IO as i32;
+            };
+            errno(write_bytes(&mut ctx_f.memory(), buf, &bytes))
+        })
+        .into_js_value(),
+    );
+
+    for name in UNSUPPORTED {
+        builder.register_in(
+            WASI_MODULE,
+            name,
+            Function::new_no_args(&format!("return {};", ERRNO_NOTSUP)).into(),
+        );
+    }
+}
+
+/// Convert a result to the return value of a wasi function
+fn errno(result: Result<(), Errno>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(errno) => errno as i32,
+    }
+}
+
+/// Convert a file system error to an error number
+fn fs_errno(error: Error) -> Errno {
+    match error {
+        Error::FileAlreadyExists(_) | Error::DirectoryAlreadyExists(_) => ERRNO_EXIST,
+        Error::IsDirectory(_) => ERRNO_ISDIR,
+        Error::IsFile(_) => ERRNO_NOTDIR,
+        Error::NoSuchFile(_)
+        | Error::NoSuchFileWithId(_)
+        | Error::NoSuchDirectory(_)
+        | Error::NoSuchDirectoryWithId(_)
+        | Error::NoSuchFileOrDirectory(_)
+        | Error::NoFsMounted(_)
+        | Error::NoFsLabel(_)
+        | Error::NotAFsLabel(_) => ERRNO_NOENT,
+        _ => ERRNO_IO,
+    }
+}
+
+/// Wrap a function taking an array in a function taking the arguments directly
+fn array_shim(f: &JsValue) -> JsValue {
+    Function::new_with_args(
+        "f",
+        "return function() { return f(Array.from(arguments)); };",
+    )
+    .call1(&JsValue::NULL, f)
+    .unwrap()
+}
+
+/// Get the environment variables as `KEY=VALUE` strings
+fn environ(ctx: &ProcessCtx) -> Vec<String> {
+    ctx.env()
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect()
+}
+
+/// Write the amount of strings and the size of the null-terminated strings
+fn sizes_get<'a>(
+    ctx: &ProcessCtx,
+    strings: impl Iterator<Item = &'a str>,
+    count_out: u32,
+    size_out: u32,
+) -> i32 {
+    let (count, size) = strings.fold((0u32, 0u32), |(count, size), string| {
+        (count + 1, size + string.len() as u32 + 1)
+    });
+    let mut memory = ctx.memory();
+    let result = write_u32(&mut memory, count_out, count);
+    errno(result.and_then(|_| write_u32(&mut memory, size_out, size)))
+}
+
+/// Write the string pointers and null-terminated strings
+fn strings_get<'a>(
+    ctx: &ProcessCtx,
+    strings: impl Iterator<Item = &'a str>,
+    pointers: u32,
+    buffer: u32,
+) -> i32 {
+    let mut memory = ctx.memory();
+    let mut pointer = pointers;
+    let mut position = buffer;
+    for string in strings {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        let result = write_u32(&mut memory, pointer, position);
+        if let Err(errno) = result.and_then(|_| write_bytes(&mut memory, position, &bytes)) {
+            return errno as i32;
+        }
+        pointer += 4;
+        position += string.len() as u32 + 1;
+    }
+    0
+}
+
+/// Get the time of a clock in nanoseconds
+fn clock_time(id: u32) -> Option<u64> {
+    match id {
+        CLOCK_REALTIME => Some((Date::now() * 1_000_000.0) as u64),
+        CLOCK_MONOTONIC | CLOCK_PROCESS_CPUTIME | CLOCK_THREAD_CPUTIME => {
+            let performance = Reflect::get(&global(), &"performance".into()).ok()?;
+            let now = Reflect::get(&performance, &"now".into()).ok()?;
+            let now = Function::from(now).call0(&performance).ok()?.as_f64()?;
+            Some((now * 1_000_000.0) as u64)
+        }
+        _ => None,
+    }
+}
+
+/// Get random bytes from the crypto api of the browser
+fn random_bytes(len: u32) -> Option<Vec<u8>> {
+    let crypto = Reflect::get(&global(), &"crypto".into()).ok()?;
+    let get_random_values = Function::from(Reflect::get(&crypto, &"getRandomValues".into()).ok()?);
+
+    // The crypto api fills at most 65536 bytes at once and cannot fill shared memory
+    let mut bytes = Vec::with_capacity(len as usize);
+    while bytes.len() < len as usize {
+        let chunk = Uint8Array::new_with_length((len as usize - bytes.len()).min(65536) as u32);
+        get_random_values.call1(&crypto, &chunk).ok()?;
+        bytes.extend(chunk.to_vec());
+    }
+    Some(bytes)
+}
+
+/// Check that a range of memory may be accessed by the process
+fn check_bytes(memory: &Memory, ptr: u32, len: u32) -> Result<(), Errno> {
+    memory.check_range(ptr, len).map_err(|_| ERRNO_FAULT)
+}
+
+/// Read bytes from memory, failing on ranges the process may not access
+fn read_bytes(memory: &Memory, ptr: u32, len: u32) -> Result<Vec<u8>, Errno> {
+    memory.read_checked(ptr, len).map_err(|_| ERRNO_FAULT)
+}
+
+/// Write bytes to memory, failing on ranges the process may not access
+fn write_bytes(memory: &mut Memory, ptr: u32, data: &[u8]) -> Result<(), Errno> {
+    memory.write_checked(ptr, data).map_err(|_| ERRNO_FAULT)
+}
+
+/// Read a little endian `u32` from memory
+fn read_u32(memory: &Memory, ptr: u32) -> Result<u32, Errno> {
+    let bytes = read_bytes(memory, ptr, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+/// Read a little endian `u64` from memory
+fn read_u64(memory: &Memory, ptr: u32) -> Result<u64, Errno> {
+    let bytes = read_bytes(memory, ptr, 8)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap_or_default()))
+}
+
+/// Write a little endian `u32` to memory
+fn write_u32(memory: &mut Memory, ptr: u32, value: u32) -> Result<(), Errno> {
+    write_bytes(memory, ptr, &value.to_le_bytes())
+}
+
+/// Write a little endian `u64` to memory
+fn write_u64(memory: &mut Memory, ptr: u32, value: u64) -> Result<(), Errno> {
+    write_bytes(memory, ptr, &value.to_le_bytes())
+}
+
+/// Read an array of io vectors as `(pointer, length)` pairs
+fn read_iovecs(memory: &Memory, iovs: u32, iovs_len: u32) -> Result<Vec<(u32, u32)>, Errno> {
+    check_bytes(memory, iovs, iovs_len.saturating_mul(8))?;
+    (0..iovs_len)
+        .map(|i| {
+            let iov = iovs + i * 8;
+            Ok((read_u32(memory, iov)?, read_u32(memory, iov + 4)?))
+        })
+        .collect()
+}
+
+/// Check if the process holds a capability
+fn require(ctx: &ProcessCtx, capability: Capability) -> Result<(), Errno> {
+    match ctx.capabilities().allows(capability) {
+        true => Ok(()),
+        false => Err(ERRNO_NOTCAPABLE),
+    }
+}
+
+/// Get a copy of a file descriptor
+fn descriptor(ctx: &ProcessCtx, fd: u32) -> Result<FileDescriptor, Errno> {
+    ctx.fds().get(fd).cloned().ok_or(ERRNO_BADF)
+}
+
+/// Get a copy of a file descriptor that refers to a file
+fn open_file(ctx: &ProcessCtx, fd: u32) -> Result<OpenFile, Errno> {
+    match descriptor(ctx, fd)? {
+        FileDescriptor::File(file) => Ok(file),
+        FileDescriptor::Directory { .. } | FileDescriptor::Preopen { .. } => Err(ERRNO_ISDIR),
+        _ => Err(ERRNO_SPIPE),
+    }
+}
+
+/// Update the offset of a file descriptor, if it still refers to the same file
+fn set_offset(ctx: &ProcessCtx, fd: u32, id: Uuid, offset: u64) {
+    if let Some(FileDescriptor::File(file)) = ctx.fds().get_mut(fd) {
+        if file.id == id {
+            file.offset = offset;
+        }
+    }
+}
+
+/// Run a closure with a locked file system
+fn with_fs<T>(
+    label: FsLabel,
+    f: impl FnOnce(&mut dyn FsHandler) -> Result<T, Errno>,
+) -> Result<T, Errno> {
+    let fs = FsManager::get().get_fs(label).map_err(fs_errno)?;
+    let mut fs = fs.write().map_err(|_| ERRNO_IO)?;
+    f(&mut *fs)
+}
+
+/// Look up a path within a file system. The empty path is the root directory
+fn lookup(fs: &dyn FsHandler, path: &str) -> Option<FileResult> {
+    if path.is_empty() {
+        return Some(FileResult::Directory(Uuid::nil()));
+    }
+    if let Ok(file) = fs.get_file(path) {
+        return Some(FileResult::File(file));
+    }
+    if let Ok(directory) = fs.get_directory(path) {
+        return Some(FileResult::Directory(directory));
+    }
+    None
+}
+
+/// Resolve a path relative to a directory descriptor.
+/// Returns the file system and the normalized path within it.
+/// Paths can't leave the directory, so a process can't reach past it's preopens.
+fn resolve_path(
+    ctx: &ProcessCtx,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+) -> Result<(FsLabel, String), Errno> {
+    let directory = match descriptor(ctx, fd)? {
+        FileDescriptor::Preopen { path, .. } | FileDescriptor::Directory { path } => path,
+        _ => return Err(ERRNO_NOTDIR),
+    };
+    let path = read_bytes(&ctx.memory(), path, path_len)?;
+    let path = String::from_utf8(path).map_err(|_| ERRNO_INVAL)?;
+    if !stays_within(&path) {
+        return Err(ERRNO_NOTCAPABLE);
+    }
+
+    let label = FsLabel::extract_from_path(&directory).map_err(fs_errno)?;
+    let (_, directory) = directory.split_at(3);
+    Ok((
+        label,
+        util::normalize_path(&format!("{}/{}", directory, path)),
+    ))
+}
+
+/// Check if a relative path stays within the directory it's resolved in.
+/// Absolute paths and `..` past the start of the path leave the directory.
+fn stays_within(path: &str) -> bool {
+    if path.starts_with('/') {
+        return false;
+    }
+    let mut depth = 0usize;
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            _ => depth += 1,
+        }
+    }
+    true
+}
+
+/// Format a path within a file system as an absolute path
+fn absolute_path(label: FsLabel, path: &str) -> String {
+    format!("{}:/{}", label.to_string().to_lowercase(), path)
+}
+
+/// Write a file stat struct
+fn write_filestat(
+    memory: &mut Memory,
+    ptr: u32,
+    label: FsLabel,
+    filetype: u8,
+    id: Uuid,
+    size: u64,
+) -> Result<(), Errno> {
+    let mut stat = [0u8; FILESTAT_SIZE];
+    stat[0..8].copy_from_slice(&(label as u64).to_le_bytes());
+    stat[8..16].copy_from_slice(&id.as_u64_pair().0.to_le_bytes());
+    stat[16] = filetype;
+    stat[24..32].copy_from_slice(&1u64.to_le_bytes());
+    stat[32..40].copy_from_slice(&size.to_le_bytes());
+    write_bytes(memory, ptr, &stat)
+}
+
+fn fd_allocate(ctx: &ProcessCtx, fd: u32, size: u64) -> Result<(), Errno> {
+    let file = open_file(ctx, fd)?;
+    require(ctx, Capability::FsWrite(file.label))?;
+    with_fs(file.label, |fs| {
+        if fs.file_size(file.id).map_err(fs_errno)? < size as usize {
+            fs.truncate(file.id, size as usize).map_err(fs_errno)?;
+        }
+        Ok(())
+    })
+}
+
+fn fd_fdstat_get(ctx: &ProcessCtx, fd: u32, stat_out: u32) -> Result<(), Errno> {
+    let (filetype, flags) = match descriptor(ctx, fd)? {
+        FileDescriptor::Stdin | FileDescriptor::Stdout | FileDescriptor::Stderr => {
+            (FILETYPE_CHARACTER_DEVICE, 0)
+        }
+        FileDescriptor::Preopen { .. } | FileDescriptor::Directory { .. } => {
+            (FILETYPE_DIRECTORY, 0)
+        }
+        FileDescriptor::File(file) => (
+            FILETYPE_REGULAR_FILE,
+            if file.append { FDFLAGS_APPEND } else { 0 },
+        ),
+    };
+
+    // Rights are not enforced, so every right is granted
+    let mut stat = [0u8; 24];
+    stat[0] = filetype;
+    stat[2..4].copy_from_slice(&(flags as u16).to_le_bytes());
+    stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    stat[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    write_bytes(&mut ctx.memory(), stat_out, &stat)
+}
+
+fn fd_filestat_get(ctx: &ProcessCtx, fd: u32, stat_out: u32) -> Result<(), Errno> {
+    match descriptor(ctx, fd)? {
+        FileDescriptor::File(file) => {
+            let size = with_fs(file.label, |fs| fs.file_size(file.id).map_err(fs_errno))?;
+            let mut memory = ctx.memory();
+            write_filestat(
+                &mut memory,
+                stat_out,
+                file.label,
+                FILETYPE_REGULAR_FILE,
+                file.id,
+                size as u64,
+            )?;
+        }
+        FileDescriptor::Preopen { path, .. } | FileDescriptor::Directory { path } => {
+            let label = FsLabel::extract_from_path(&path).map_err(fs_errno)?;
+            let mut memory = ctx.memory();
+            write_filestat(
+                &mut memory,
+                stat_out,
+                label,
+                FILETYPE_DIRECTORY,
+                Uuid::nil(),
+                0,
+            )?;
+        }
+        _ => {
+            let mut stat = [0u8; FILESTAT_SIZE];
+            stat[16] = FILETYPE_CHARACTER_DEVICE;
+            write_bytes(&mut ctx.memory(), stat_out, &stat)?;
+        }
+    }
+    Ok(())
+}
+
+fn fd_filestat_set_size(ctx: &ProcessCtx, fd: u32, size: u64) -> Result<(), Errno> {
+    let file = open_file(ctx, fd)?;
+    require(ctx, Capability::FsWrite(file.label))?;
+    with_fs(file.label, |fs| {
+        fs.truncate(file.id, size as usize).map_err(fs_errno)
+    })
+}
+
+/// Read into io vectors. Reads at the offset if given, otherwise at the offset of the descriptor
+fn fd_read(
+    ctx: &ProcessCtx,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    offset: Option<u64>,
+    nread_out: u32,
+) -> Result<(), Errno> {
+    // Check the buffers before consuming any input
+    let iovecs = {
+        let memory = ctx.memory();
+        let iovecs = read_iovecs(&memory, iovs, iovs_len)?;
+        for (ptr, len) in iovecs.iter() {
+            check_bytes(&memory, *ptr, *len)?;
+        }
+        check_bytes(&memory, nread_out, 4)?;
+        iovecs
+    };
+    let total = iovecs.iter().map(|(_, len)| *len as usize).sum::<usize>();
+
+    let data = match descriptor(ctx, fd)? {
+        FileDescriptor::Stdin if offset.is_some() => return Err(ERRNO_SPIPE),
+        FileDescriptor::Stdin => ctx.stdin().read_blocking(total),
+        FileDescriptor::File(file) => {
+            require(ctx, Capability::FsRead(file.label))?;
+            let start = offset.unwrap_or(file.offset) as usize;
+            let data = with_fs(file.label, |fs| fs.read(file.id).map_err(fs_errno))?;
+            let data = data
+                .get(start..)
+                .map(|data| data[..total.min(data.len())].to_vec())
+                .unwrap_or_default();
+            if offset.is_none() {
+                set_offset(ctx, fd, file.id, (start + data.len()) as u64);
+            }
+            data
+        }
+        FileDescriptor::Preopen { .. } | FileDescriptor::Directory { .. } => {
+            return Err(ERRNO_ISDIR)
+        }
+        _ => return Err(ERRNO_BADF),
+    };
+
+    // Scatter the data over the io vectors
+    let mut memory = ctx.memory();
+    let mut remaining = data.as_slice();
+    for (ptr, len) in iovecs {
+        let (chunk, rest) = remaining.split_at((len as usize).min(remaining.len()));
+        write_bytes(&mut memory, ptr, chunk)?;
+        remaining = rest;
+    }
+    write_u32(&mut memory, nread_out, data.len() as u32)
+}
+
+/// Write from io vectors. Writes at the offset if given, otherwise at the offset of the descriptor
+fn fd_write(
+    ctx: &ProcessCtx,
+    fd: u32,
+    iovs: u32,
+    iovs_len: u32,
+    offset: Option<u64>,
+    nwritten_out: u32,
+) -> Result<(), Errno> {
+    let data = {
+        let memory = ctx.memory();
+        check_bytes(&memory, nwritten_out, 4)?;
+        let mut data = Vec::new();
+        for (ptr, len) in read_iovecs(&memory, iovs, iovs_len)? {
+            data.extend(read_bytes(&memory, ptr, len)?);
+        }
+        data
+    };
+
+    match descriptor(ctx, fd)? {
+        FileDescriptor::Stdout | FileDescriptor::Stderr if offset.is_some() => {
+            return Err(ERRNO_SPIPE)
+        }
+        FileDescriptor::Stdout => ctx.stdout().write_bytes(&data).map_err(|_| ERRNO_PIPE)?,
+        FileDescriptor::Stderr => ctx.stderr().write_bytes(&data).map_err(|_| ERRNO_PIPE)?,
+        FileDescriptor::File(file) => {
+            require(ctx, Capability::FsWrite(file.label))?;
+            let end = with_fs(file.label, |fs| {
+                let size = fs.file_size(file.id).map_err(fs_errno)?;
+                let at = match (offset, file.append) {
+                    (Some(offset), _) => offset as usize,
+                    (None, true) => size,
+                    (None, false) => file.offset as usize,
+                };
+                // Writing past the end leaves a gap of zeroes
+                if at > size {
+                    fs.truncate(file.id, at).map_err(fs_errno)?;
+                }
+                fs.write(file.id, at, &data).map_err(fs_errno)?;
+                Ok(at + data.len())
+            })?;
+            if offset.is_none() {
+                set_offset(ctx, fd, file.id, end as u64);
+            }
+        }
+        FileDescriptor::Preopen { .. } | FileDescriptor::Directory { .. } => {
+            return Err(ERRNO_ISDIR)
+        }
+        FileDescriptor::Stdin => return Err(ERRNO_BADF),
+    }
+
+    write_u32(&mut ctx.memory(), nwritten_out, data.len() as u32)
+}
+
+fn fd_readdir(
+    ctx: &ProcessCtx,
+    fd: u32,
+    buf: u32,
+    buf_len: u32,
+    cookie: u64,
+    bufused_out: u32,
+) -> Result<(), Errno> {
+    let path = match descriptor(ctx, fd)? {
+        FileDescriptor::Preopen { path, .. } | FileDescriptor::Directory { path } => path,
+        _ => return Err(ERRNO_NOTDIR),
+    };
+    let label = FsLabel::extract_from_path(&path).map_err(fs_errno)?;
+    require(ctx, Capability::FsRead(label))?;
+    let (_, path) = path.split_at(3);
+
+    // Sort the entries, so the cookies stay valid between calls
+    let mut entries = with_fs(label, |fs| fs.list_directory(path).map_err(fs_errno))?;
+    entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    // Write as many entries as fit. A full buffer tells the process to read again
+    let mut bytes = Vec::new();
+    for (index, (name, result)) in entries.iter().enumerate().skip(cookie as usize) {
+        if bytes.len() >= buf_len as usize {
+            break;
+        }
+        let (id, filetype) = match result {
+            FileResult::File(id) => (id, FILETYPE_REGULAR_FILE),
+            FileResult::Directory(id) => (id, FILETYPE_DIRECTORY),
+        };
+        let mut dirent = [0u8; DIRENT_SIZE];
+        dirent[0..8].copy_from_slice(&(index as u64 + 1).to_le_bytes());
+        dirent[8..16].copy_from_slice(&id.as_u64_pair().0.to_le_bytes());
+        dirent[16..20].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        dirent[20] = filetype;
+        bytes.extend_from_slice(&dirent);
+        bytes.extend_from_slice(name.as_bytes());
+    }
+    bytes.truncate(buf_len as usize);
+
+    let mut memory = ctx.memory();
+    write_bytes(&mut memory, buf, &bytes)?;
+    write_u32(&mut memory, bufused_out, bytes.len() as u32)
+}
+
+fn fd_seek(
+    ctx: &ProcessCtx,
+    fd: u32,
+    offset: i64,
+    whence: u32,
+    offset_out: u32,
+) -> Result<(), Errno> {
+    let file = open_file(ctx, fd)?;
+    let base = match whence {
+        0 => 0,
+        1 => file.offset as i64,
+        2 => with_fs(file.label, |fs| fs.file_size(file.id).map_err(fs_errno))? as i64,
+        _ => return Err(ERRNO_INVAL),
+    };
+    let new_offset = base.checked_add(offset).filter(|offset| *offset >= 0);
+    let Some(new_offset) = new_offset else {
+        return Err(ERRNO_INVAL);
+    };
+
+    write_u64(&mut ctx.memory(), offset_out, new_offset as u64)?;
+    set_offset(ctx, fd, file.id, new_offset as u64);
+    Ok(())
+}
+
+fn path_create_directory(ctx: &ProcessCtx, fd: u32, path: u32, path_len: u32) -> Result<(), Errno> {
+    let (label, path) = resolve_path(ctx, fd, path, path_len)?;
+    require(ctx, Capability::FsWrite(label))?;
+    with_fs(label, |fs| {
+        if lookup(fs, &path).is_some() {
+            return Err(ERRNO_EXIST);
+        }
+        fs.create_directory(&path).map_err(fs_errno)?;
+        Ok(())
+    })
+}
+
+fn path_filestat_get(
+    ctx: &ProcessCtx,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+    stat_out: u32,
+) -> Result<(), Errno> {
+    let (label, path) = resolve_path(ctx, fd, path, path_len)?;
+    require(ctx, Capability::FsRead(label))?;
+    let (filetype, id, size) = with_fs(label, |fs| match lookup(fs, &path) {
+        Some(FileResult::File(id)) => Ok((
+            FILETYPE_REGULAR_FILE,
+            id,
+            fs.file_size(id).map_err(fs_errno)?,
+        )),
+        Some(FileResult::Directory(id)) => Ok((FILETYPE_DIRECTORY, id, 0)),
+        None => Err(ERRNO_NOENT),
+    })?;
+    write_filestat(
+        &mut ctx.memory(),
+        stat_out,
+        label,
+        filetype,
+        id,
+        size as u64,
+    )
+}
+
+fn path_open(
+    ctx: &ProcessCtx,
+    fd: u32,
+    path: u32,
+    path_len: u32,
+    oflags: u32,
+    fdflags: u32,
+    fd_out: u32,
+) -> Result<(), Errno> {
+    let (label, path) = resolve_path(ctx, fd, path, path_len)?;
+    check_bytes(&ctx.memory(), fd_out, 4)?;
+    // Opening needs either access, creating or truncating needs write access
+    if oflags & (OFLAGS_CREAT | OFLAGS_TRUNC) != 0 {
+        require(ctx, Capability::FsWrite(label))?;
+    } else {
+        require(ctx, Capability::FsRead(label)).or(require(ctx, Capability::FsWrite(label)))?;
+    }
+    // Check the limit before a file gets created, the descriptor is checked again when it's inserted
+    if ctx.check_open_files(1).is_err() {
+        return Err(ERRNO_MFILE);
+    }
+
+    let descriptor = with_fs(label, |fs| {
+        let id = match lookup(fs, &path) {
+            Some(FileResult::Directory(_)) if oflags & OFLAGS_CREAT != 0 => {
+                return Err(ERRNO_ISDIR)
+            }
+            Some(FileResult::Directory(_)) => {
+                return Ok(FileDescriptor::Directory {
+                    path: absolute_path(label, &path),
+                })
+            }
+            Some(FileResult::File(_)) if oflags & OFLAGS_DIRECTORY != 0 => {
+                return Err(ERRNO_NOTDIR)
+            }
+            Some(FileResult::File(_))
+                if oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_EXCL != 0 =>
+            {
+                return Err(ERRNO_EXIST)
+            }
+            Some(FileResult::File(id)) => id,
+            None if oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_DIRECTORY == 0 => {
+                fs.create_file(&path).map_err(fs_errno)?
+            }
+            None => return Err(ERRNO_NOENT),
+        };
+
+        if oflags & OFLAGS_TRUNC != 0 {
+            fs.truncate(id, 0).map_err(fs_errno)?;
+        }
+        Ok(FileDescriptor::File(OpenFile {
+            label,
+            id,
+            path: absolute_path(label, &path),
+            offset: 0,
+            append: fdflags & FDFLAGS_APPEND != 0,
+        }))
+    })?;
+
+    let fd = ctx.insert_fd(descriptor).map_err(|_| ERRNO_MFILE)?;
+    write_u32(&mut ctx.memory(), fd_out, fd)
+}
+
+fn path_rename(
+    ctx: &ProcessCtx,
+    (fd, old_path, old_len): (u32, u32, u32),
+    (new_fd, new_path, new_len): (u32, u32, u32),
+) -> Result<(), Errno> {
+    let (label, old_path) = resolve_path(ctx, fd, old_path, old_len)?;
+    let (new_label, new_path) = resolve_path(ctx, new_fd, new_path, new_len)?;
+    if label != new_label {
+        return Err(ERRNO_XDEV);
+    }
+    require(ctx, Capability::FsWrite(label))?;
+
+    with_fs(label, |fs| match lookup(fs, &old_path) {
+        Some(FileResult::File(_)) => fs.move_file(&old_path, &new_path).map_err(fs_errno),
+        Some(FileResult::Directory(_)) if old_path.is_empty() => Err(ERRNO_INVAL),
+        Some(FileResult::Directory(_)) => fs.move_directory(&old_path, &new_path).map_err(fs_errno),
+        None => Err(ERRNO_NOENT),
+    })
+}
+
+fn poll_oneoff(
+    ctx: &ProcessCtx,
+    subscriptions: u32,
+    events: u32,
+    count: u32,
+    nevents_out: u32,
+) -> Result<(), Errno> {
+    if count == 0 {
+        return Err(ERRNO_INVAL);
+    }
+
+    // Split the subscriptions into file descriptors, which are always ready, and clock deadlines
+    let mut ready = Vec::new();
+    let mut earliest: Option<(u64, u64)> = None; // The userdata and deadline of the earliest clock
+    {
+        let memory = ctx.memory();
+        check_bytes(
+            &memory,
+            subscriptions,
+            count.saturating_mul(SUBSCRIPTION_SIZE),
+        )?;
+        check_bytes(&memory, events, count.saturating_mul(EVENT_SIZE as u32))?;
+        check_bytes(&memory, nevents_out, 4)?;
+        for i in 0..count {
+            let subscription = subscriptions + i * SUBSCRIPTION_SIZE;
+            let userdata = read_u64(&memory, subscription)?;
+            let tag = read_bytes(&memory, subscription + 8, 1)?[0];
+            if tag != EVENTTYPE_CLOCK {
+                ready.push((userdata, tag));
+                continue;
+            }
+
+            let id = read_u32(&memory, subscription + 16)?;
+            let timeout = read_u64(&memory, subscription + 24)?;
+            let flags = read_bytes(&memory, subscription + 40, 2)?;
+            let flags = u16::from_le_bytes(flags.try_into().unwrap_or_default());
+            let now = clock_time(id).ok_or(ERRNO_INVAL)?;
+            let deadline = match flags & SUBCLOCKFLAGS_ABSTIME != 0 {
+                true => timeout,
+                false => now.saturating_add(timeout),
+            };
+            let remaining = deadline.saturating_sub(now);
+            if earliest.is_none_or(|(_, earliest)| remaining < earliest) {
+                earliest = Some((userdata, remaining));
+            }
+        }
+    }
+
+    // Sleep until the earliest clock if no file descriptor is ready
+    if ready.is_empty() {
+        if let Some((userdata, remaining)) = earliest {
+            std::thread::sleep(Duration::from_nanos(remaining));
+            ready.push((userdata, EVENTTYPE_CLOCK));
+        }
+    }
+
+    let mut memory = ctx.memory();
+    for (i, (userdata, tag)) in ready.iter().enumerate() {
+        let mut event = [0u8; EVENT_SIZE];
+        event[0..8].copy_from_slice(&userdata.to_le_bytes());
+        event[10] = *tag;
+        write_bytes(&mut memory, events + (i * EVENT_SIZE) as u32, &event)?;
+    }
+    write_u32(&mut memory, nevents_out, ready.len() as u32)
+}
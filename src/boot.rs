@@ -1,5 +1,5 @@
 use anyhow::anyhow;
-use honeyos_process::ProcessManager;
+use honeyos_process::{ProcessManager, SpawnOptions};
 use wasm_bindgen::{closure::Closure, JsCast};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
@@ -65,9 +65,17 @@ fn read_and_execute(blob: Blob) -> anyhow::Result<()> {
         // Summon the boot process
         let process_manager_lock = ProcessManager::get();
         let mut process_manager = process_manager_lock.try_lock().unwrap();
-        process_manager
-            .spawn(buffer, Some("BOOT".into()), "")
-            .unwrap();
+        if let Err(e) = process_manager.spawn(
+            buffer,
+            Some("BOOT".into()),
+            "",
+            SpawnOptions {
+                privileged: true,
+                ..Default::default()
+            },
+        ) {
+            log::error!("Failed to spawn the boot process: {}", e);
+        }
     }) as Box<dyn FnMut(_)>);
 
     file_reader.set_onload(Some(onload.as_ref().unchecked_ref()));
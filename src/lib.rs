@@ -6,8 +6,9 @@ use std::rc::Rc;
 use anyhow::anyhow;
 use honeyos_display::Display;
 use honeyos_fs::FsManager;
+use honeyos_klog::KernelLog;
 use honeyos_networking::NetworkingManager;
-use honeyos_process::ProcessManager;
+use honeyos_process::{handle::KernelObject, ProcessManager};
 use wasm_bindgen::prelude::{wasm_bindgen, Closure, JsCast};
 use web_sys::Window;
 
@@ -23,7 +24,7 @@ thread_local! {
 /// The kernel entrypoint
 #[wasm_bindgen]
 pub async fn main() {
-    console_log::init_with_level(log::Level::Info).unwrap();
+    KernelLog::init_once(log::Level::Info).unwrap();
     set_panic_hook();
 
     // Initialize kernel systems*
@@ -44,6 +45,7 @@ pub async fn main() {
 fn execution_loop() -> anyhow::Result<()> {
     update_process_manager();
     update_network_manager();
+    api::ring::update_rings();
     Ok(())
 }
 
@@ -81,9 +83,22 @@ fn start_execution_loop(window: &Window) -> anyhow::Result<i32> {
 /// Handle the spawn requests
 fn update_process_manager() {
     let process_manager_lock = ProcessManager::get();
-    if let Ok(mut process_manager) = process_manager_lock.try_lock() {
-        process_manager.update();
+    let Ok(mut process_manager) = process_manager_lock.try_lock() else {
+        return;
     };
+    process_manager.update();
+
+    // Drop the requests left open by dead processes.
+    // They are kept until the next cycle while the networking manager is in use
+    let networking_manager_lock = NetworkingManager::get();
+    let Ok(mut networking_manager) = networking_manager_lock.try_write() else {
+        return;
+    };
+    for object in process_manager.take_released() {
+        if let KernelObject::Request(id) = object {
+            networking_manager.remove(id);
+        }
+    }
 }
 
 /// Update the network manager